@@ -0,0 +1,136 @@
+use std::env;
+use std::io;
+use std::net;
+use std::os::unix;
+use std::os::unix::io::{FromRawFd, RawFd};
+use libc;
+use pyo3::*;
+use tokio_uds::UnixListener;
+
+use TokioEventLoop;
+use addrinfo;
+use server;
+use transport::{self, TransportOptions};
+
+// first fd passed by systemd, see sd_listen_fds(3)
+pub const LISTEN_FDS_START: RawFd = 3;
+
+
+///
+/// File descriptors passed by systemd socket activation, sd_listen_fds()
+/// semantics: LISTEN_PID has to match current process, LISTEN_FDS is
+/// number of fds starting from 3. Returned fds are marked FD_CLOEXEC.
+///
+pub fn listen_fds(py: Python, unset_environment: bool) -> PyResult<Vec<RawFd>> {
+    let pid = env::var("LISTEN_PID").ok();
+    let fds = env::var("LISTEN_FDS").ok();
+
+    if unset_environment {
+        // through os.environ, so python sees changes as well
+        let environ = py.import("os")?.getattr("environ")?;
+        for name in &["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+            environ.call_method1("pop", (*name, py.None()))?;
+        }
+    }
+
+    let pid: libc::pid_t = match pid.and_then(|pid| pid.parse().ok()) {
+        Some(pid) => pid,
+        None => return Ok(Vec::new()),
+    };
+    if pid != unsafe { libc::getpid() } {
+        return Ok(Vec::new())
+    }
+
+    let count: RawFd = match fds.map(|fds| fds.parse()) {
+        Some(Ok(count)) if count >= 0 => count,
+        Some(_) => return Err(exc::ValueError::new("invalid LISTEN_FDS value")),
+        None => return Ok(Vec::new()),
+    };
+
+    let fds: Vec<RawFd> = (LISTEN_FDS_START..LISTEN_FDS_START + count).collect();
+    for fd in &fds {
+        set_cloexec(*fd, true)?;
+    }
+    Ok(fds)
+}
+
+pub fn set_cloexec(fd: RawFd, cloexec: bool) -> io::Result<()> {
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+    if flags == -1 {
+        return Err(io::Error::last_os_error())
+    }
+    let flags = if cloexec { flags | libc::FD_CLOEXEC } else { flags & !libc::FD_CLOEXEC };
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, flags) } == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+// family of listening stream socket
+fn listener_family(fd: RawFd) -> PyResult<libc::c_int> {
+    let socktype = transport::getsockopt(fd, libc::SOL_SOCKET, libc::SO_TYPE)?;
+    let listening = transport::getsockopt(fd, libc::SOL_SOCKET, libc::SO_ACCEPTCONN)?;
+    if socktype != libc::SOCK_STREAM || listening == 0 {
+        return Err(exc::ValueError::new(
+            format!("fd {} is not a listening stream socket", fd)))
+    }
+    match transport::socket_family(fd) {
+        Some(family) if family == libc::AF_INET || family == libc::AF_INET6
+            || family == libc::AF_UNIX => Ok(family),
+        _ => Err(exc::ValueError::new(
+            format!("fd {} has unsupported address family", fd))),
+    }
+}
+
+///
+/// Create server for each listening socket fd, servers own the fds.
+/// All fds are checked before any server is created.
+///
+pub fn create_servers(py: Python, evloop: &TokioEventLoop, fds: &[RawFd],
+                      proto_factory: &PyObject, ssl: &Option<PyObject>)
+                      -> PyResult<Vec<PyObject>> {
+    let families = fds.iter()
+        .map(|fd| listener_family(*fd))
+        .collect::<PyResult<Vec<_>>>()?;
+
+    let mut servers = Vec::new();
+    for (fd, family) in fds.iter().zip(families) {
+        let ssl = ssl.as_ref().map(|ssl| ssl.clone_ref(py));
+        servers.push(fd_server(
+            py, evloop, *fd, family, proto_factory.clone_ref(py), ssl,
+            TransportOptions::default(), true, None)?);
+    }
+    Ok(servers)
+}
+
+///
+/// Create server for single listening socket fd, server owns the fd
+///
+pub fn create_server(py: Python, evloop: &TokioEventLoop, fd: RawFd,
+                     proto_factory: PyObject, ssl: Option<PyObject>,
+                     opts: TransportOptions, start_serving: bool,
+                     on_connect: Option<PyObject>) -> PyResult<PyObject> {
+    let family = listener_family(fd)?;
+    fd_server(py, evloop, fd, family, proto_factory, ssl, opts, start_serving, on_connect)
+}
+
+fn fd_server(py: Python, evloop: &TokioEventLoop, fd: RawFd, family: libc::c_int,
+             proto_factory: PyObject, ssl: Option<PyObject>,
+             opts: TransportOptions, start_serving: bool,
+             on_connect: Option<PyObject>) -> PyResult<PyObject> {
+    if family == libc::AF_UNIX {
+        let lst = unsafe { unix::net::UnixListener::from_raw_fd(fd) };
+        let lst = UnixListener::from_listener(lst, evloop.href())?;
+        server::create_uds_server(py, evloop, lst, ssl, proto_factory, start_serving, None)
+    } else {
+        let lst = unsafe { net::TcpListener::from_raw_fd(fd) };
+        let info = addrinfo::AddrInfo::new(
+            0, addrinfo::Family::from_int(family), addrinfo::SocketType::Stream,
+            addrinfo::Protocol::TCP, lst.local_addr()?, None);
+        let transport_factory = server::transport_factory(&ssl);
+        server::create_sock_server(
+            py, evloop, lst, info, ssl, proto_factory,
+            transport_factory, opts, start_serving, on_connect)
+    }
+}
@@ -389,18 +389,13 @@ pub fn start_workers(num: usize) -> LookupWorkerSender {
                 match r.recv() {
                     None => return,
                     Some((params, tx)) => {
-                        match lookup_addrinfo(params.host, params.port,
-                                              params.family, params.flags, params.socktype) {
-                            Err(err) => {
-                                let _ = tx.send(Err(err));
-                            },
-                            Ok(lookup) => {
-                                if let Err(_) = tx.send(Ok(lookup.collect())) {
-                                    // event loop is gone
-                                    return
-                                }
-                            },
-                        };
+                        // lookup is cancelled, skip work item
+                        if tx.is_canceled() {
+                            continue
+                        }
+                        let result = lookup_addrinfo(
+                            params.host, params.port, params.family, params.flags, params.socktype);
+                        let _ = tx.send(result.map(|lookup| lookup.collect()));
                     }
                 }
             }
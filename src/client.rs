@@ -1,51 +1,175 @@
 use std::io;
 use std::net;
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::os::unix::io::AsRawFd;
+use std::time::{Duration, Instant};
 use pyo3::*;
 use futures::{future, Future};
 use net2::TcpBuilder;
 use tokio_core::net::TcpStream;
+use tokio_core::reactor::Timeout;
+use tokio_io::io::write_all;
 
 use {PyFut, PyFuture, TokioEventLoop};
 use addrinfo::AddrInfo;
 use fut::{for_each, Until, UntilError};
 use pyunsafe::{GIL, Handle};
-use transport::{InitializedTransport, tcp_transport_factory};
+use utils::OperationError;
+use proxy::Proxy;
+use transport::{self, InitializedTransport, TransportOptions, tcp_transport_factory};
 
 
+///
+/// HAProxy PROXY protocol preamble, sent right after connect. Source
+/// address defaults to local address of connection, destination is peer
+///
+#[derive(Copy, Clone, Debug)]
+pub struct ProxyHeader {
+    pub version: u8,
+    pub source: Option<net::SocketAddr>,
+}
+
+const PROXY_V2_SIGNATURE: &[u8] = b"\r\n\r\n\x00\r\nQUIT\n";
+
+impl ProxyHeader {
+
+    pub fn new(version: Option<u8>, source: Option<(String, u16)>)
+               -> PyResult<Option<ProxyHeader>> {
+        let version = match version {
+            Some(version @ 1) | Some(version @ 2) => version,
+            Some(_) => return Err(exc::ValueError::new("proxy_protocol must be 1 or 2")),
+            None => {
+                if source.is_some() {
+                    return Err(exc::ValueError::new(
+                        "proxy_source is only meaningful with proxy_protocol"))
+                }
+                return Ok(None)
+            }
+        };
+        let source = match source {
+            Some((host, port)) => match host.parse::<net::IpAddr>() {
+                Ok(ip) => Some(net::SocketAddr::new(ip, port)),
+                Err(_) => return Err(exc::ValueError::new(
+                    format!("proxy_source host must be ip address: {:?}", host))),
+            },
+            None => None,
+        };
+        Ok(Some(ProxyHeader { version: version, source: source }))
+    }
+
+    pub fn encode(&self, local: net::SocketAddr, peer: net::SocketAddr) -> Vec<u8> {
+        // mixed families are sent as ipv4-mapped ipv6 addresses
+        let (src, dst) = match (self.source.unwrap_or(local), peer) {
+            (src @ net::SocketAddr::V4(_), dst @ net::SocketAddr::V4(_)) => (src, dst),
+            (src, dst) => (to_ipv6(src), to_ipv6(dst)),
+        };
+
+        if self.version == 1 {
+            let family = if src.is_ipv4() { "TCP4" } else { "TCP6" };
+            return format!("PROXY {} {} {} {} {}\r\n",
+                           family, src.ip(), dst.ip(), src.port(), dst.port()).into_bytes()
+        }
+
+        let mut buf = Vec::with_capacity(PROXY_V2_SIGNATURE.len() + 40);
+        buf.extend_from_slice(PROXY_V2_SIGNATURE);
+        buf.push(0x21); // version 2, PROXY command
+        match (src.ip(), dst.ip()) {
+            (net::IpAddr::V4(src), net::IpAddr::V4(dst)) => {
+                buf.extend_from_slice(&[0x11, 0, 12]); // TCP over IPv4
+                buf.extend_from_slice(&src.octets());
+                buf.extend_from_slice(&dst.octets());
+            },
+            (net::IpAddr::V6(src), net::IpAddr::V6(dst)) => {
+                buf.extend_from_slice(&[0x21, 0, 36]); // TCP over IPv6
+                buf.extend_from_slice(&src.octets());
+                buf.extend_from_slice(&dst.octets());
+            },
+            _ => unreachable!(),
+        }
+        buf.extend_from_slice(&[(src.port() >> 8) as u8, src.port() as u8,
+                                (dst.port() >> 8) as u8, dst.port() as u8]);
+        buf
+    }
+}
+
+fn to_ipv6(addr: net::SocketAddr) -> net::SocketAddr {
+    match addr {
+        net::SocketAddr::V4(addr) => net::SocketAddr::V6(
+            net::SocketAddrV6::new(addr.ip().to_ipv6_mapped(), addr.port(), 0, 0)),
+        addr => addr,
+    }
+}
+
+fn send_proxy_header(stream: TcpStream, header: Option<ProxyHeader>)
+                     -> Box<Future<Item=TcpStream, Error=io::Error>>
+{
+    let header = match header {
+        Some(header) => header,
+        None => return Box::new(future::ok(stream)),
+    };
+    let (local, peer) = match (stream.local_addr(), stream.peer_addr()) {
+        (Ok(local), Ok(peer)) => (local, peer),
+        (Err(err), _) | (_, Err(err)) => return Box::new(future::err(err)),
+    };
+
+    Box::new(
+        write_all(stream, header.encode(local, peer))
+            .map(|(stream, _)| stream)
+            .map_err(move |err| OperationError::new("write", Some(peer), err)))
+}
+
 pub fn create_sock_connection(
     factory: PyObject, evloop: Py<TokioEventLoop>,
     stream: TcpStream, addr: AddrInfo,
-    ssl: Option<PyObject>, hostname: Option<PyObject>, waiter: Py<PyFuture>)
-    -> Box<Future<Item=InitializedTransport, Error=io::Error>>
+    ssl: Option<PyObject>, hostname: Option<PyObject>, waiter: Py<PyFuture>, nodelay: bool,
+    proxy_header: Option<ProxyHeader>,
+    opts: TransportOptions) -> Box<Future<Item=InitializedTransport, Error=io::Error>>
 {
     let peer = stream.peer_addr().expect("should never happen");
+    let _ = stream.set_nodelay(nodelay);
 
-    let result = tcp_transport_factory(
-        evloop, false, &factory, &ssl,
-        hostname, stream, Some(&addr), Some(peer), Some(waiter.clone_ref(GIL::python())));
+    let transport = send_proxy_header(stream, proxy_header).and_then(move |stream| {
+        let result = tcp_transport_factory(
+            evloop, false, &factory, &ssl, hostname, stream,
+            Some(&addr), Some(peer), Some(waiter.clone_ref(GIL::python())), opts);
 
-    let waiter: PyFut = waiter.into();
-    Box::new(
+        let waiter: PyFut = waiter.into();
         waiter.then(move |_| match result {
             Ok(transport) => future::ok(transport),
             Err(err) => future::err(err)
-        }))
+        })
+    });
+    Box::new(transport)
 }
 
 pub fn create_connection(
     factory: PyObject, evloop: Py<TokioEventLoop>, addrs: Vec<AddrInfo>,
-    ssl: Option<PyObject>, hostname: Option<PyObject>, waiter: Py<PyFuture>)
-    -> Box<Future<Item=InitializedTransport, Error=io::Error>>
+    ssl: Option<PyObject>, hostname: Option<PyObject>, waiter: Py<PyFuture>, nodelay: bool,
+    interface: Option<String>, connect_timeout: Option<Duration>,
+    proxy_header: Option<ProxyHeader>, tunnel: Option<(Proxy, String, u16)>,
+    opts: TransportOptions) -> Box<Future<Item=InitializedTransport, Error=io::Error>>
 {
     let handle = evloop.as_ref(GIL::python()).get_handle();
-    let conn = connect(addrs, handle.clone());
+    let mut opts = opts;
+    opts.connect_started = Some(Instant::now());
+
+    // addrs are proxy addresses if tunnel is set
+    let conn = connect(addrs, handle.clone(), interface, connect_timeout)
+        .and_then(move |(socket, addr)| match tunnel {
+            Some((proxy, host, port)) => future::Either::A(
+                proxy.handshake(socket, host, port).map(|s| (s, addr))),
+            None => future::Either::B(future::ok((socket, addr))),
+        })
+        .and_then(move |(socket, addr)| send_proxy_header(socket, proxy_header).map(|s| (s, addr)));
 
     let transport = conn.and_then(
         move |(socket, addr)| {
             let peer = socket.peer_addr().expect("should never happen");
+            let _ = socket.set_nodelay(nodelay);
             let result = tcp_transport_factory(
                 evloop, false, &factory, &ssl, hostname,
-                socket, Some(&addr), Some(peer), Some(waiter.clone_ref(GIL::python())));
+                socket, Some(&addr), Some(peer), Some(waiter.clone_ref(GIL::python())), opts);
 
             let waiter: PyFut = waiter.into();
             waiter.then(move |_| match result {
@@ -56,9 +180,18 @@ pub fn create_connection(
     Box::new(transport)
 }
 
-fn connect(addrs: Vec<AddrInfo>, handle: Handle)
-           -> Box<Future<Item=(TcpStream, AddrInfo), Error=io::Error>>
+///
+/// Try addresses in order until one connects, timeout limits each
+/// attempt. If all fail, error lists errors of every attempt
+///
+pub fn connect(addrs: Vec<AddrInfo>, handle: Handle, interface: Option<String>,
+               timeout: Option<Duration>)
+               -> Box<Future<Item=(TcpStream, AddrInfo), Error=io::Error>>
 {
+    // connect errors, reported if all addresses fail
+    let errors: Rc<RefCell<Vec<io::Error>>> = Rc::new(RefCell::new(Vec::new()));
+    let conn_errors = errors.clone();
+
     let fut = for_each(addrs).until::<_, _, _, ()>(move |info| {
         let builder = match info.sockaddr {
             net::SocketAddr::V4(_) =>
@@ -78,29 +211,86 @@ fn connect(addrs: Vec<AddrInfo>, handle: Handle)
         };
 
         let info: AddrInfo = info.clone();
+        let addr = info.sockaddr;
+        let errors = conn_errors.clone();
+
+        if let Some(ref interface) = interface {
+            if let Err(err) = transport::bind_to_device(
+                builder.as_raw_fd(), interface, addr.is_ipv6())
+            {
+                errors.borrow_mut().push(OperationError::new("bind", Some(addr), err));
+                return future::Either::A(future::ok(None))
+            }
+        }
 
         // convert to tokio TcpStream and connect
         match builder.to_tcp_stream() {
             Ok(stream) =>
                 future::Either::B(
-                    TcpStream::connect_stream(stream, &info.sockaddr, &handle)
-                        .then(|res| match res {
+                    connect_stream(stream, addr, &handle, timeout)
+                        .then(move |res| match res {
                             Ok(conn) => future::ok(Some((conn, info))),
-                            Err(_) => future::ok(None)
+                            Err(err) => {
+                                errors.borrow_mut().push(
+                                    OperationError::new("connect", Some(addr), err));
+                                future::ok(None)
+                            }
                         })
                 ),
-            Err(_err) => {
-                //self.errors.push(with_py(|py| err.to_pyerr(py)));
+            Err(err) => {
+                errors.borrow_mut().push(OperationError::new("socket", Some(addr), err));
                 future::Either::A(future::ok(None))
             }
         }
-    }).map_err(|e| {
+    }).map_err(move |e| {
         match e {
-            UntilError::NoResult => io::Error::new(
-                io::ErrorKind::ConnectionRefused, "Can not connect to host"),
+            UntilError::NoResult => connect_error(errors.borrow_mut().split_off(0)),
             _ => unreachable!(),
         }
     });
 
     Box::new(fut)
 }
+
+fn connect_stream(stream: net::TcpStream, addr: net::SocketAddr,
+                  handle: &Handle, timeout: Option<Duration>)
+                  -> Box<Future<Item=TcpStream, Error=io::Error>>
+{
+    let conn = TcpStream::connect_stream(stream, &addr, handle);
+    let timeout = match timeout {
+        Some(timeout) => match Timeout::new(timeout, handle) {
+            Ok(timeout) => timeout,
+            Err(err) => return Box::new(future::err(err)),
+        },
+        None => return Box::new(conn),
+    };
+
+    Box::new(conn.select2(timeout).then(|res| match res {
+        Ok(future::Either::A((conn, _))) => Ok(conn),
+        Ok(future::Either::B(_)) =>
+            Err(io::Error::new(io::ErrorKind::TimedOut, "Connect timed out")),
+        Err(future::Either::A((err, _))) | Err(future::Either::B((err, _))) => Err(err),
+    }))
+}
+
+//
+// Same as asyncio, single error is reported as is (as cause), different
+// errors are combined into "Multiple exceptions" error
+//
+fn connect_error(errors: Vec<io::Error>) -> io::Error {
+    let messages: Vec<String> = errors.iter().map(|err| err.to_string()).collect();
+    let kind = match errors.first() {
+        Some(first) if errors.iter().all(|err| err.kind() == first.kind()) => first.kind(),
+        Some(_) => io::ErrorKind::Other,
+        None => io::ErrorKind::ConnectionRefused,
+    };
+
+    let err = if messages.len() > 1 && messages.iter().any(|msg| *msg != messages[0]) {
+        io::Error::new(kind, format!("Multiple exceptions: {}", messages.join(", ")))
+    } else if kind == io::ErrorKind::TimedOut {
+        io::Error::new(kind, "Connect timed out")
+    } else {
+        io::Error::new(kind, "Can not connect to host")
+    };
+    OperationError::with_errors("connect", None, err, errors)
+}
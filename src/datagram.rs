@@ -0,0 +1,801 @@
+use std::io;
+use std::fs;
+use std::ffi;
+use std::mem;
+use std::error::Error;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::collections::{HashMap, VecDeque};
+use std::os::unix;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use pyo3::*;
+use futures::{future, task, unsync, Async, Future, Poll, Stream};
+use futures::unsync::mpsc;
+use net2::UdpBuilder;
+use net2::unix::UnixUdpBuilderExt;
+use libc;
+use tokio_core::net::UdpSocket;
+use tokio_core::reactor::Handle;
+use tokio_uds::UnixDatagram;
+
+use TokioEventLoop;
+use addrinfo::{self, AddrInfo};
+use pyunsafe::{GIL, Sender};
+use server::{self, ConnectionGuard};
+use socket::{Socket, sockaddr_object};
+use transport::{getsockopt, setsockopt, socket_family};
+use utils::{self, Classes, PyLogger};
+
+// same as maximum udp payload
+const MAX_DATAGRAM: usize = 65536;
+// datagrams received per reactor wakeup
+const RECV_BATCH: usize = 64;
+
+#[cfg(target_os = "linux")]
+mod sockopt {
+    use libc::c_int;
+    pub const IP_MULTICAST_IF: c_int = 32;
+    pub const IP_MULTICAST_TTL: c_int = 33;
+    pub const IP_MULTICAST_LOOP: c_int = 34;
+    pub const IP_ADD_MEMBERSHIP: c_int = 35;
+    pub const IP_DROP_MEMBERSHIP: c_int = 36;
+    pub const IPV6_MULTICAST_IF: c_int = 17;
+    pub const IPV6_MULTICAST_HOPS: c_int = 18;
+    pub const IPV6_MULTICAST_LOOP: c_int = 19;
+    pub const IPV6_JOIN_GROUP: c_int = 20;
+    pub const IPV6_LEAVE_GROUP: c_int = 21;
+}
+#[cfg(not(target_os = "linux"))]
+mod sockopt {
+    use libc::c_int;
+    pub const IP_MULTICAST_IF: c_int = 9;
+    pub const IP_MULTICAST_TTL: c_int = 10;
+    pub const IP_MULTICAST_LOOP: c_int = 11;
+    pub const IP_ADD_MEMBERSHIP: c_int = 12;
+    pub const IP_DROP_MEMBERSHIP: c_int = 13;
+    pub const IPV6_MULTICAST_IF: c_int = 9;
+    pub const IPV6_MULTICAST_HOPS: c_int = 10;
+    pub const IPV6_MULTICAST_LOOP: c_int = 11;
+    pub const IPV6_JOIN_GROUP: c_int = 12;
+    pub const IPV6_LEAVE_GROUP: c_int = 13;
+}
+
+
+// destination of datagram, None is peer of connected socket
+#[derive(Clone, Debug, PartialEq)]
+pub enum DatagramAddr {
+    Inet(SocketAddr),
+    Unix(PathBuf),
+}
+
+pub enum DatagramMessage {
+    Send(Vec<u8>, Option<DatagramAddr>),
+    Close,
+    Abort,
+}
+
+// source of received datagram
+enum PeerAddr {
+    Inet(SocketAddr),
+    Unix(unix::net::SocketAddr),
+}
+
+impl PeerAddr {
+    // address of unnamed unix socket is None
+    fn to_object(&self, py: Python) -> PyObject {
+        match *self {
+            PeerAddr::Inet(ref addr) => sockaddr_object(py, addr),
+            PeerAddr::Unix(ref addr) => match addr.as_pathname() {
+                Some(path) => PyString::new(py, &path.to_string_lossy()).into(),
+                None => py.None(),
+            },
+        }
+    }
+}
+
+
+pub enum DatagramSocket {
+    Udp(UdpSocket),
+    Unix(UnixDatagram),
+}
+
+impl DatagramSocket {
+
+    fn send_to(&self, data: &[u8], addr: &Option<DatagramAddr>) -> io::Result<usize> {
+        match (self, addr) {
+            (&DatagramSocket::Udp(ref sock), &Some(DatagramAddr::Inet(ref addr))) =>
+                sock.send_to(data, addr),
+            (&DatagramSocket::Unix(ref sock), &Some(DatagramAddr::Unix(ref path))) =>
+                sock.send_to(data, path),
+            (&DatagramSocket::Udp(ref sock), &None) => sock.send(data),
+            (&DatagramSocket::Unix(ref sock), &None) => sock.send(data),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput, "address does not match socket family")),
+        }
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, PeerAddr)> {
+        match *self {
+            DatagramSocket::Udp(ref sock) =>
+                sock.recv_from(buf).map(|(size, addr)| (size, PeerAddr::Inet(addr))),
+            DatagramSocket::Unix(ref sock) =>
+                sock.recv_from(buf).map(|(size, addr)| (size, PeerAddr::Unix(addr))),
+        }
+    }
+
+    fn is_unix(&self) -> bool {
+        match *self {
+            DatagramSocket::Udp(_) => false,
+            DatagramSocket::Unix(_) => true,
+        }
+    }
+}
+
+impl AsRawFd for DatagramSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        match *self {
+            DatagramSocket::Udp(ref sock) => sock.as_raw_fd(),
+            DatagramSocket::Unix(ref sock) => sock.as_raw_fd(),
+        }
+    }
+}
+
+
+///
+/// Bind datagram socket for each resolved address,
+/// returns python sockets and tokio sockets
+///
+pub fn bind(py: Python, addrs: Vec<AddrInfo>,
+            reuse_address: Option<bool>, reuse_port: bool, allow_broadcast: bool,
+            handle: &Handle) -> PyResult<Vec<(Py<Socket>, UdpSocket)>> {
+    let mut sockets = Vec::new();
+    for info in addrs {
+        match info.family {
+            addrinfo::Family::Inet | addrinfo::Family::Inet6 => (),
+            _ => continue,
+        }
+        let sock = open_socket(
+            info.sockaddr, None, reuse_address, reuse_port, allow_broadcast, handle)?;
+
+        let mut addr = info.clone();
+        addr.sockaddr = sock.local_addr()?;
+        info!("Started listening on {:?} (udp)", addr.sockaddr);
+        sockets.push((Socket::new_listener(py, &addr, sock.as_raw_fd())?, sock));
+    }
+    Ok(sockets)
+}
+
+
+///
+/// Resolve (host, port) address of datagram endpoint, no address
+/// resolves to empty list
+///
+pub fn lookup(sender: &addrinfo::LookupWorkerSender, addr: Option<(String, u16)>,
+              family: i32, flags: i32) -> Box<Future<Item=Vec<AddrInfo>, Error=PyErr>> {
+    let (host, port) = match addr {
+        Some(addr) => addr,
+        None => return Box::new(future::ok(Vec::new())),
+    };
+    Box::new(
+        addrinfo::lookup(sender, Some(host), Some(port.to_string()),
+                         family, flags, addrinfo::SocketType::DGram)
+            .then(|result| match result {
+                Err(err) => Err(io::Error::new(io::ErrorKind::Other, err.description()).into()),
+                Ok(Err(err)) => Err(err.into()),
+                Ok(Ok(ref addrs)) if addrs.is_empty() => Err(
+                    exc::RuntimeError::new("getaddrinfo() returned empty list")),
+                Ok(Ok(addrs)) => Ok(addrs),
+            }))
+}
+
+
+///
+/// Create udp endpoint, socket is bound to first usable local address
+/// and connected to first remote address of same family. Without local
+/// address socket is bound to any address with port chosen by system
+///
+pub fn connect(py: Python, evloop: &TokioEventLoop, factory: &PyObject,
+               local: Vec<AddrInfo>, remote: Vec<AddrInfo>,
+               reuse_address: Option<bool>, reuse_port: bool, allow_broadcast: bool)
+               -> PyResult<PyObject> {
+    let mut targets: Vec<Option<SocketAddr>> =
+        remote.iter().map(|info| Some(info.sockaddr)).collect();
+    if targets.is_empty() {
+        targets.push(None);
+    }
+
+    let mut error = None;
+    for target in targets {
+        let addrs: Vec<SocketAddr> = match target {
+            Some(ref remote) if local.is_empty() => vec![unspecified(remote)],
+            Some(ref remote) => local.iter()
+                .filter(|info| info.sockaddr.is_ipv4() == remote.is_ipv4())
+                .map(|info| info.sockaddr).collect(),
+            None => local.iter().map(|info| info.sockaddr).collect(),
+        };
+
+        for addr in addrs {
+            let socket = match open_socket(
+                addr, target, reuse_address, reuse_port, allow_broadcast, evloop.href()) {
+                Ok(socket) => socket,
+                Err(err) => {
+                    error = Some(err);
+                    continue
+                }
+            };
+
+            let local_addr = socket.local_addr()?;
+            let info = AddrInfo::new(
+                0, if local_addr.is_ipv4() { addrinfo::Family::Inet }
+                   else { addrinfo::Family::Inet6 },
+                addrinfo::SocketType::DGram, addrinfo::Protocol::UDP, local_addr, None);
+            let fd = socket.as_raw_fd();
+            let (sock, peer) = match target {
+                Some(remote) => (Socket::new_peer(py, &info, remote, Some(fd))?,
+                                 Some(Some(DatagramAddr::Inet(remote)))),
+                None => (Socket::new_listener(py, &info, fd)?, None),
+            };
+            info!("Datagram endpoint on {:?}, remote {:?}", local_addr, target);
+
+            let (tr, proto) = start_transport(
+                py, evloop, factory, DatagramSocket::Udp(socket), sock.into(), peer, None, None)?;
+            return Ok((tr, proto).into_tuple(py).into())
+        }
+    }
+
+    Err(error.unwrap_or_else(
+        || exc::ValueError::new("local_addr and remote_addr address families do not match")))
+}
+
+
+// bind udp socket, enable broadcast and connect it to remote address
+fn open_socket(addr: SocketAddr, remote: Option<SocketAddr>,
+               reuse_address: Option<bool>, reuse_port: bool, allow_broadcast: bool,
+               handle: &Handle) -> PyResult<UdpSocket> {
+    let builder = match addr {
+        SocketAddr::V4(_) => UdpBuilder::new_v4()?,
+        SocketAddr::V6(_) => {
+            let builder = UdpBuilder::new_v6()?;
+            builder.only_v6(true)?;
+            builder
+        },
+    };
+    builder.reuse_address(reuse_address.unwrap_or(server::DEFAULT_REUSE_ADDRESS))?;
+    if reuse_port {
+        builder.reuse_port(true).map_err(server::reuse_port_error)?;
+    }
+    let sock = UdpSocket::from_socket(builder.bind(addr)?, handle)?;
+    if allow_broadcast {
+        sock.set_broadcast(true)?;
+    }
+    if let Some(remote) = remote {
+        sock.connect(&remote)?;
+    }
+    Ok(sock)
+}
+
+// any address of same family with port chosen by system
+fn unspecified(addr: &SocketAddr) -> SocketAddr {
+    match *addr {
+        SocketAddr::V4(_) => SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0),
+        SocketAddr::V6(_) => SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0)), 0),
+    }
+}
+
+
+///
+/// Bind unix datagram socket to path, stale socket file is removed first
+///
+pub fn bind_unix(path: &Path, handle: &Handle) -> io::Result<UnixDatagram> {
+    if let Ok(meta) = fs::symlink_metadata(path) {
+        if meta.file_type().is_socket() {
+            fs::remove_file(path)?;
+        }
+    }
+    UnixDatagram::bind(path, handle)
+}
+
+
+///
+/// Python socket object for duplicated fd of unix datagram socket,
+/// it is closed with transport
+///
+pub fn unix_socket_object(py: Python, socket: &UnixDatagram) -> PyResult<PyObject> {
+    let fd = unsafe { libc::fcntl(socket.as_raw_fd(), libc::F_DUPFD_CLOEXEC, 0) };
+    if fd == -1 {
+        return Err(io::Error::last_os_error().into())
+    }
+    Ok(Classes.Socket.as_ref(py).call1(
+        "socket", (libc::AF_UNIX, libc::SOCK_DGRAM, 0, fd))?.into())
+}
+
+
+///
+/// Create protocol and datagram transport for bound socket, sock is
+/// python socket object of it. Transport of connected socket sends to its
+/// peer. Transport is closed when stop is received
+///
+pub fn start_transport(py: Python, evloop: &TokioEventLoop, factory: &PyObject,
+                       socket: DatagramSocket, sock: PyObject,
+                       peer: Option<Option<DatagramAddr>>,
+                       stop: Option<unsync::oneshot::Receiver<()>>,
+                       guard: Option<ConnectionGuard>)
+                       -> PyResult<(Py<PyDatagramTransport>, PyObject)> {
+    let proto = factory.call0(py).log_error(py, "Protocol factory failure")?;
+
+    let connected = peer.is_some();
+    let mut info: HashMap<&'static str, PyObject> = HashMap::new();
+    info.insert("sockname", sock.call_method0(py, "getsockname")?);
+    if connected {
+        info.insert("peername", sock.call_method0(py, "getpeername")?);
+    }
+    info.insert("socket", sock);
+
+    let (tx, rx) = mpsc::unbounded();
+    let tr = PyDatagramTransport::new(
+        py, evloop, Sender::new(tx), proto.as_ref(py), info,
+        socket.as_raw_fd(), socket.is_unix(), peer)?;
+    let conn = tr.clone_ref(py);
+
+    let transport = UdpTransport {
+        socket: socket,
+        intake: rx,
+        stop: stop,
+        transport: tr.clone_ref(py),
+        queue: VecDeque::new(),
+        buf: vec![0; MAX_DATAGRAM],
+        connected: connected,
+        closing: false,
+    };
+    evloop.href().spawn(
+        transport.then(move |res| {
+            let py = GIL::python();
+            conn.as_mut(py).connection_lost(py, res.err());
+            drop(guard);
+            Ok(())
+        }));
+    Ok((tr, proto))
+}
+
+
+#[py::class(weakref)]
+pub struct PyDatagramTransport {
+    evloop: Py<TokioEventLoop>,
+    protocol: PyObject,
+    transport: Sender<DatagramMessage>,
+    info: HashMap<&'static str, PyObject>,
+    fd: RawFd,
+    unix: bool,
+    // connected socket, remote address is not known for socketpair()
+    peer: Option<Option<DatagramAddr>>,
+    buffer_size: usize,
+    closing: bool,
+    token: PyToken,
+}
+
+#[py::methods]
+impl PyDatagramTransport {
+
+    fn is_closing(&self) -> PyResult<bool> {
+        Ok(self.closing)
+    }
+
+    fn get_extra_info(&self, py: Python, name: &str, default: Option<PyObject>)
+                      -> PyResult<PyObject> {
+        match self.info.get(name) {
+            Some(val) => Ok(val.clone_ref(py)),
+            None => Ok(default.unwrap_or_else(|| py.None())),
+        }
+    }
+
+    ///
+    /// send datagram to addr, (host, port) tuple with ip address or path
+    /// of unix socket. addr has to be None or remote address
+    /// for connected transport
+    ///
+    #[args(addr="None")]
+    fn sendto(&mut self, py: Python, data: &PyObjectRef, addr: Option<&PyObjectRef>)
+              -> PyResult<()> {
+        let data = buffer::PyBuffer::get(py, data)
+            .map_err(|_| exc::TypeError::new("data argument must be a bytes-like object"))?
+            .to_vec::<u8>(py)?;
+        let addr = match addr {
+            Some(addr) => Some(parse_address(addr, self.unix)?),
+            None => None,
+        };
+        let addr = match self.peer {
+            Some(ref peer) => {
+                if addr.is_some() && addr != *peer {
+                    return Err(exc::ValueError::new(
+                        "Invalid address: must be None or remote address of transport"))
+                }
+                None
+            },
+            None => match addr {
+                Some(addr) => Some(addr),
+                None => return Err(exc::ValueError::new(
+                    "addr is required, transport is not connected")),
+            },
+        };
+
+        // closing transport does not accept new data
+        if self.closing {
+            return Ok(())
+        }
+        self.buffer_size += data.len();
+        let _ = self.transport.send(DatagramMessage::Send(data, addr));
+        Ok(())
+    }
+
+    fn get_write_buffer_size(&self) -> PyResult<usize> {
+        Ok(self.buffer_size)
+    }
+
+    ///
+    /// join multicast group, interface is ip address of local interface
+    /// for ipv4 group, name or index of interface for ipv6 group.
+    /// System chooses interface if it is None
+    ///
+    #[args(interface="None")]
+    fn join_multicast_group(&self, group: &str, interface: Option<&PyObjectRef>)
+                            -> PyResult<()> {
+        self.multicast_membership(group, interface, true)
+    }
+
+    ///
+    /// leave multicast group joined with join_multicast_group()
+    ///
+    #[args(interface="None")]
+    fn leave_multicast_group(&self, group: &str, interface: Option<&PyObjectRef>)
+                             -> PyResult<()> {
+        self.multicast_membership(group, interface, false)
+    }
+
+    ///
+    /// set time-to-live (hop limit for ipv6) of sent multicast datagrams
+    ///
+    fn set_multicast_ttl(&self, ttl: u32) -> PyResult<()> {
+        if ttl > 255 {
+            return Err(exc::ValueError::new("ttl must be in range 0-255"))
+        }
+        let (fd, v6) = self.multicast_fd()?;
+        if v6 {
+            setsockopt(fd, libc::IPPROTO_IPV6, sockopt::IPV6_MULTICAST_HOPS, ttl as libc::c_int)?;
+        } else {
+            setsockopt(fd, libc::IPPROTO_IP, sockopt::IP_MULTICAST_TTL, ttl as u8)?;
+        }
+        Ok(())
+    }
+
+    ///
+    /// enable or disable loopback of sent multicast datagrams to local sockets
+    ///
+    fn set_multicast_loop(&self, flag: bool) -> PyResult<()> {
+        let (fd, v6) = self.multicast_fd()?;
+        if v6 {
+            setsockopt(fd, libc::IPPROTO_IPV6, sockopt::IPV6_MULTICAST_LOOP, flag as libc::c_int)?;
+        } else {
+            setsockopt(fd, libc::IPPROTO_IP, sockopt::IP_MULTICAST_LOOP, flag as u8)?;
+        }
+        Ok(())
+    }
+
+    ///
+    /// select interface for sent multicast datagrams, same values
+    /// as interface of join_multicast_group()
+    ///
+    fn set_multicast_interface(&self, interface: &PyObjectRef) -> PyResult<()> {
+        let (fd, v6) = self.multicast_fd()?;
+        if v6 {
+            let idx = interface_index(interface)?;
+            setsockopt(fd, libc::IPPROTO_IPV6, sockopt::IPV6_MULTICAST_IF, idx as libc::c_int)?;
+        } else {
+            let addr = interface_addr(interface)?;
+            setsockopt(fd, libc::IPPROTO_IP, sockopt::IP_MULTICAST_IF, in_addr(&addr))?;
+        }
+        Ok(())
+    }
+
+    fn get_protocol(&self, py: Python) -> PyResult<PyObject> {
+        Ok(self.protocol.clone_ref(py))
+    }
+
+    fn set_protocol(&mut self, protocol: PyObject) -> PyResult<()> {
+        self.protocol = protocol;
+        Ok(())
+    }
+
+    ///
+    /// close transport, queued datagrams are sent first
+    ///
+    fn close(&mut self) -> PyResult<()> {
+        if !self.closing {
+            self.closing = true;
+            let _ = self.transport.send(DatagramMessage::Close);
+        }
+        Ok(())
+    }
+
+    ///
+    /// close transport, queued datagrams are dropped
+    ///
+    fn abort(&mut self) -> PyResult<()> {
+        self.closing = true;
+        let _ = self.transport.send(DatagramMessage::Abort);
+        Ok(())
+    }
+}
+
+impl PyDatagramTransport {
+
+    fn new(py: Python, evloop: &TokioEventLoop, sender: Sender<DatagramMessage>,
+           protocol: &PyObjectRef, info: HashMap<&'static str, PyObject>,
+           fd: RawFd, unix: bool, peer: Option<Option<DatagramAddr>>)
+           -> PyResult<Py<PyDatagramTransport>> {
+        let connection_made = protocol.getattr("connection_made")?;
+
+        let transport = py.init(|token| PyDatagramTransport {
+            evloop: evloop.into(),
+            protocol: protocol.into(),
+            transport: sender,
+            info: info,
+            fd: fd,
+            unix: unix,
+            peer: peer,
+            buffer_size: 0,
+            closing: false,
+            token: token})?;
+
+        let _ = connection_made.call1((transport.clone_ref(py),))
+            .map_err(|err| {
+                transport.as_mut(py).closing = true;
+                let _ = transport.as_mut(py).transport.send(DatagramMessage::Close);
+                evloop.log_error(err, "Protocol.connection_made error")
+            });
+
+        Ok(transport)
+    }
+
+    // fd of open udp socket for multicast options, and whether it is ipv6
+    fn multicast_fd(&self) -> PyResult<(RawFd, bool)> {
+        if self.closing || self.fd == -1 {
+            return Err(exc::RuntimeError::new("Transport is closing"))
+        }
+        match socket_family(self.fd) {
+            Some(libc::AF_INET) => Ok((self.fd, false)),
+            Some(libc::AF_INET6) => Ok((self.fd, true)),
+            _ => Err(exc::ValueError::new("multicast is supported for udp sockets only")),
+        }
+    }
+
+    fn multicast_membership(&self, group: &str, interface: Option<&PyObjectRef>,
+                            join: bool) -> PyResult<()> {
+        let (fd, v6) = self.multicast_fd()?;
+        let group = match group.parse::<IpAddr>() {
+            Ok(ip) if ip.is_multicast() => ip,
+            _ => return Err(exc::ValueError::new(
+                format!("group has to be multicast ip address: {:?}", group))),
+        };
+
+        match group {
+            IpAddr::V4(group) if !v6 => {
+                let mut mreq: libc::ip_mreq = unsafe { mem::zeroed() };
+                mreq.imr_multiaddr = in_addr(&group);
+                if let Some(interface) = interface {
+                    mreq.imr_interface = in_addr(&interface_addr(interface)?);
+                }
+                let name = if join { sockopt::IP_ADD_MEMBERSHIP }
+                           else { sockopt::IP_DROP_MEMBERSHIP };
+                setsockopt(fd, libc::IPPROTO_IP, name, mreq)?;
+            },
+            IpAddr::V6(group) if v6 => {
+                let mut mreq: libc::ipv6_mreq = unsafe { mem::zeroed() };
+                mreq.ipv6mr_multiaddr.s6_addr = group.octets();
+                if let Some(interface) = interface {
+                    mreq.ipv6mr_interface = interface_index(interface)?;
+                }
+                let name = if join { sockopt::IPV6_JOIN_GROUP }
+                           else { sockopt::IPV6_LEAVE_GROUP };
+                setsockopt(fd, libc::IPPROTO_IPV6, name, mreq)?;
+            },
+            _ => return Err(exc::ValueError::new(
+                "group address family does not match socket family")),
+        }
+        Ok(())
+    }
+
+    fn call_protocol<A>(&self, py: Python, name: &str, args: A) where A: IntoPyTuple {
+        if let Ok(cb) = self.protocol.getattr(py, name) {
+            trace!("Protocol.{}()", name);
+            self.evloop.as_ref(py).with(
+                &format!("protocol.{}() failed", name), || cb.call1(py, args));
+        }
+    }
+
+    fn datagram_received(&self, py: Python, data: &[u8], addr: PeerAddr) {
+        self.call_protocol(
+            py, "datagram_received", (PyBytes::new(py, data), addr.to_object(py)));
+    }
+
+    fn error_received(&self, py: Python, err: io::Error) {
+        self.call_protocol(py, "error_received", (utils::to_pyerr(py, err),));
+    }
+
+    fn written(&mut self, len: usize) {
+        self.buffer_size = self.buffer_size.saturating_sub(len);
+    }
+
+    fn connection_lost(&mut self, py: Python, err: Option<io::Error>) {
+        self.closing = true;
+        self.fd = -1;
+        if let Some(sock) = self.info.get("socket") {
+            if let Ok(sock) = Socket::try_from_mut(sock.as_ref(py)) {
+                sock.forget_fd();
+            } else {
+                let _ = sock.call_method0(py, "close");
+            }
+        }
+        match err {
+            Some(err) => self.call_protocol(
+                py, "connection_lost", (utils::to_pyerr(py, err),)),
+            None => self.call_protocol(py, "connection_lost", (py.None(),)),
+        }
+    }
+}
+
+
+// (host, port) tuple, host has to be ip address, or path of unix socket
+fn parse_address(addr: &PyObjectRef, unix: bool) -> PyResult<DatagramAddr> {
+    if unix {
+        let path: String = addr.extract()
+            .map_err(|_| exc::TypeError::new("addr must be path of unix socket"))?;
+        return Ok(DatagramAddr::Unix(PathBuf::from(path)))
+    }
+
+    let addr = PyTuple::try_from(addr)
+        .map_err(|_| exc::TypeError::new("addr must be (host, port) tuple"))?;
+    if addr.len() < 2 {
+        return Err(exc::TypeError::new("addr must be (host, port) tuple"))
+    }
+    let host: String = addr.get_item(0).extract()?;
+    let port: u16 = addr.get_item(1).extract()?;
+    match host.parse::<IpAddr>() {
+        Ok(ip) => Ok(DatagramAddr::Inet(SocketAddr::new(ip, port))),
+        Err(_) => Err(exc::ValueError::new(
+            format!("host has to be ip address: {:?}", host))),
+    }
+}
+
+
+// ipv4 interface is selected by its address
+fn interface_addr(interface: &PyObjectRef) -> PyResult<Ipv4Addr> {
+    let addr: String = interface.extract()
+        .map_err(|_| exc::TypeError::new("interface must be ipv4 address"))?;
+    addr.parse::<Ipv4Addr>().map_err(|_| exc::ValueError::new(
+        format!("interface has to be ipv4 address: {:?}", addr)))
+}
+
+// ipv6 interface is selected by its index or name
+fn interface_index(interface: &PyObjectRef) -> PyResult<u32> {
+    if let Ok(idx) = interface.extract::<u32>() {
+        return Ok(idx)
+    }
+    let name: String = interface.extract()
+        .map_err(|_| exc::TypeError::new("interface must be index or name"))?;
+    let cname = ffi::CString::new(name.as_bytes())
+        .map_err(|_| exc::ValueError::new("invalid interface name"))?;
+    let idx = unsafe { libc::if_nametoindex(cname.as_ptr()) };
+    if idx == 0 {
+        return Err(io::Error::last_os_error().into())
+    }
+    Ok(idx)
+}
+
+fn in_addr(addr: &Ipv4Addr) -> libc::in_addr {
+    libc::in_addr { s_addr: u32::from(*addr).to_be() }
+}
+
+
+struct UdpTransport {
+    socket: DatagramSocket,
+    intake: mpsc::UnboundedReceiver<DatagramMessage>,
+    stop: Option<unsync::oneshot::Receiver<()>>,
+    transport: Py<PyDatagramTransport>,
+    queue: VecDeque<(Vec<u8>, Option<DatagramAddr>)>,
+    buf: Vec<u8>,
+    connected: bool,
+    closing: bool,
+}
+
+impl Future for UdpTransport {
+    type Item = ();
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let gil = Python::acquire_gil();
+        let py = gil.python();
+        let tr = self.transport.as_mut(py);
+
+        // server is closed
+        if !self.closing {
+            if let Some(ref mut stop) = self.stop {
+                match stop.poll() {
+                    Ok(Async::NotReady) => (),
+                    _ => {
+                        self.closing = true;
+                        tr.closing = true;
+                    },
+                }
+            }
+        }
+
+        // commands from transport
+        loop {
+            match self.intake.poll() {
+                Ok(Async::Ready(Some(DatagramMessage::Send(data, addr)))) =>
+                    self.queue.push_back((data, addr)),
+                Ok(Async::Ready(Some(DatagramMessage::Close))) =>
+                    self.closing = true,
+                Ok(Async::Ready(Some(DatagramMessage::Abort))) =>
+                    return Ok(Async::Ready(())),
+                Ok(Async::Ready(None)) | Err(_) => {
+                    self.closing = true;
+                    break
+                },
+                Ok(Async::NotReady) => break,
+            }
+        }
+
+        // send queued datagrams, failed send is reported to protocol
+        while let Some((data, addr)) = self.queue.pop_front() {
+            match self.socket.send_to(&data, &addr) {
+                Ok(_) => tr.written(data.len()),
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    self.queue.push_front((data, addr));
+                    break
+                },
+                Err(err) => {
+                    tr.written(data.len());
+                    tr.error_received(py, err);
+                },
+            }
+        }
+
+        if self.closing {
+            if self.queue.is_empty() {
+                return Ok(Async::Ready(()))
+            }
+            return Ok(Async::NotReady)
+        }
+
+        // icmp error of connected socket wakes transport with error readiness,
+        // socket is not readable, so pending error is taken explicitly
+        if self.connected {
+            match getsockopt(self.socket.as_raw_fd(), libc::SOL_SOCKET, libc::SO_ERROR) {
+                Ok(0) => (),
+                Ok(err) => tr.error_received(py, io::Error::from_raw_os_error(err)),
+                Err(err) => tr.error_received(py, err),
+            }
+        }
+
+        // receive
+        for _ in 0..RECV_BATCH {
+            match self.socket.recv_from(&mut self.buf) {
+                Ok((size, addr)) => tr.datagram_received(py, &self.buf[..size], addr),
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock =>
+                    return Ok(Async::NotReady),
+                Err(err) => tr.error_received(py, err),
+            }
+            // protocol closed transport
+            if tr.closing {
+                break
+            }
+        }
+
+        // batch is exhausted, poll again on next turn
+        task::current().notify();
+        Ok(Async::NotReady)
+    }
+}
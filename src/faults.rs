@@ -0,0 +1,287 @@
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::time::{Duration, Instant};
+use pyo3::*;
+use futures::{Future, Poll};
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_core::reactor::{Handle, Timeout};
+
+use utils;
+use transport::RateLimit;
+
+const DEFAULT_SEED: u64 = 0x2545_f491_4f6c_dd1d;
+const LATENCY_CHUNK: usize = 64 * 1024;
+
+
+///
+/// Network faults injected into transport, for testing only
+///
+/// latency: seconds each received chunk is held before delivery
+/// bandwidth: bytes per second, for each direction
+/// drop_rate: probability of connection going silent on each read/write,
+///            data is discarded and nothing is received anymore
+/// reset_rate: probability of connection reset on each read/write
+/// partial_writes: socket accepts random part of written data
+/// seed: random generator seed, same seed gives same sequence of faults
+///
+#[derive(Copy, Clone, Debug, Default)]
+pub struct FaultConfig {
+    pub latency: Option<Duration>,
+    pub bandwidth: Option<u64>,
+    pub drop_rate: f64,
+    pub reset_rate: f64,
+    pub partial_writes: bool,
+    pub seed: u64,
+}
+
+impl FaultConfig {
+
+    pub fn parse(faults: &PyObjectRef) -> PyResult<FaultConfig> {
+        let faults = PyDict::try_from(faults)
+            .map_err(|_| exc::TypeError::new("faults must be a dict"))?;
+
+        let mut cfg = FaultConfig { seed: DEFAULT_SEED, ..FaultConfig::default() };
+        for (key, value) in faults.iter() {
+            let key: String = key.extract()?;
+            match key.as_str() {
+                "latency" => cfg.latency = utils::parse_seconds("latency", value)?,
+                "bandwidth" => {
+                    let bandwidth: u64 = value.extract()?;
+                    if bandwidth == 0 {
+                        return Err(exc::ValueError::new("bandwidth must be positive"))
+                    }
+                    cfg.bandwidth = Some(bandwidth);
+                },
+                "drop_rate" => cfg.drop_rate = parse_probability("drop_rate", value)?,
+                "reset_rate" => cfg.reset_rate = parse_probability("reset_rate", value)?,
+                "partial_writes" => cfg.partial_writes = value.is_true()?,
+                "seed" => cfg.seed = value.extract()?,
+                _ => return Err(exc::ValueError::new(format!("unknown fault: {:?}", key))),
+            }
+        }
+        Ok(cfg)
+    }
+}
+
+fn parse_probability(name: &str, value: &PyObjectRef) -> PyResult<f64> {
+    let val: f64 = value.extract()?;
+    if val < 0.0 || val > 1.0 {
+        Err(exc::ValueError::new(format!("{} must be in range 0.0-1.0", name)))
+    } else {
+        Ok(val)
+    }
+}
+
+
+//
+// xorshift64*, deterministic and good enough for fault injection
+//
+struct Random(u64);
+
+impl Random {
+
+    fn new(seed: u64) -> Random {
+        Random(if seed == 0 { DEFAULT_SEED } else { seed })
+    }
+
+    fn next(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.0 = x;
+        x.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        if probability <= 0.0 {
+            return false
+        }
+        ((self.next() >> 11) as f64 / (1u64 << 53) as f64) < probability
+    }
+}
+
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum FaultState {
+    Normal,
+    Dropped,
+    Reset,
+}
+
+//
+// Socket wrapper which injects configured faults into reads and writes
+//
+pub struct FaultyStream<T> {
+    io: T,
+    cfg: FaultConfig,
+    state: FaultState,
+    random: Random,
+
+    // received data, held until latency timer fires
+    delayed: Option<(Vec<u8>, usize)>,
+    timer: Option<Timeout>,
+
+    read_limit: Option<RateLimit>,
+    write_limit: Option<RateLimit>,
+}
+
+impl<T> FaultyStream<T> {
+
+    pub fn new(io: T, cfg: FaultConfig, handle: &Handle) -> io::Result<FaultyStream<T>> {
+        let timer = match cfg.latency {
+            Some(_) => Some(Timeout::new(Duration::new(0, 0), handle)?),
+            None => None,
+        };
+        let (read_limit, write_limit) = match cfg.bandwidth {
+            Some(rate) => (Some(RateLimit::new(rate, handle)?),
+                           Some(RateLimit::new(rate, handle)?)),
+            None => (None, None),
+        };
+
+        Ok(FaultyStream {
+            io: io,
+            cfg: cfg,
+            state: FaultState::Normal,
+            random: Random::new(cfg.seed),
+            delayed: None,
+            timer: timer,
+            read_limit: read_limit,
+            write_limit: write_limit,
+        })
+    }
+
+    // roll the dice for every transfer
+    fn inject(&mut self) -> io::Result<()> {
+        if self.state == FaultState::Normal {
+            if self.random.chance(self.cfg.reset_rate) {
+                self.state = FaultState::Reset;
+            } else if self.random.chance(self.cfg.drop_rate) {
+                self.state = FaultState::Dropped;
+            }
+        }
+        match self.state {
+            FaultState::Reset => Err(reset()),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn would_block() -> io::Error {
+    io::Error::new(io::ErrorKind::WouldBlock, "fault injection")
+}
+
+fn reset() -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionReset, "Connection reset (injected fault)")
+}
+
+impl<T: Read> Read for FaultyStream<T> {
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.state {
+            // dropped connection never receives anything
+            FaultState::Dropped => return Err(would_block()),
+            FaultState::Reset => return Err(reset()),
+            FaultState::Normal => (),
+        }
+
+        if let Some(ref mut limit) = self.read_limit {
+            if !limit.poll_ready()? {
+                return Err(would_block())
+            }
+        }
+
+        // deliver delayed data once timer fires
+        if let Some(ref mut timer) = self.timer {
+            if self.delayed.is_none() {
+                let mut chunk = vec![0; LATENCY_CHUNK];
+                let size = self.io.read(&mut chunk)?;
+                if size == 0 {
+                    return Ok(0)
+                }
+                chunk.truncate(size);
+                self.delayed = Some((chunk, 0));
+                timer.reset(Instant::now() + self.cfg.latency.unwrap());
+            }
+            if timer.poll()?.is_not_ready() {
+                return Err(would_block())
+            }
+        }
+
+        let size = if let Some((chunk, pos)) = self.delayed.take() {
+            let size = ::std::cmp::min(buf.len(), chunk.len() - pos);
+            buf[..size].copy_from_slice(&chunk[pos..pos + size]);
+            if pos + size < chunk.len() {
+                self.delayed = Some((chunk, pos + size));
+            }
+            size
+        } else {
+            self.io.read(buf)?
+        };
+
+        if size > 0 {
+            self.inject()?;
+            if self.state == FaultState::Dropped {
+                return Err(would_block())
+            }
+            if let Some(ref mut limit) = self.read_limit {
+                limit.consume(size);
+            }
+        }
+        Ok(size)
+    }
+}
+
+impl<T: Write> Write for FaultyStream<T> {
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return self.io.write(buf)
+        }
+
+        if let Some(ref mut limit) = self.write_limit {
+            if !limit.poll_ready()? {
+                return Err(would_block())
+            }
+        }
+
+        self.inject()?;
+        let size = if self.cfg.partial_writes {
+            1 + (self.random.next() % buf.len() as u64) as usize
+        } else {
+            buf.len()
+        };
+
+        // dropped connection discards written data
+        let size = if self.state == FaultState::Dropped {
+            size
+        } else {
+            self.io.write(&buf[..size])?
+        };
+
+        if let Some(ref mut limit) = self.write_limit {
+            limit.consume(size);
+        }
+        Ok(size)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.io.flush()
+    }
+}
+
+impl<T: AsyncRead> AsyncRead for FaultyStream<T> {}
+
+impl<T: AsyncWrite> AsyncWrite for FaultyStream<T> {
+
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.io.shutdown()
+    }
+}
+
+impl<T: AsRawFd> AsRawFd for FaultyStream<T> {
+
+    fn as_raw_fd(&self) -> RawFd {
+        self.io.as_raw_fd()
+    }
+}
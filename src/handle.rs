@@ -1,6 +1,6 @@
 // Copyright (c) 2017-present PyO3 Project and Contributors
 
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use pyo3::*;
 use futures::future::{self, Future};
@@ -137,11 +137,15 @@ impl PyHandlePtr {
         let h = self.0.clone_ref(py);
 
         // start timer
+        let deadline = Instant::now() + when;
         let fut = Timeout::new(when, evloop.href()).unwrap().select2(rx)
             .then(move |res| {
                 if let Ok(future::Either::A(_)) = res {
                     // timeout got fired, call callback
-                    h.into_py(|py, h| h.run(py));
+                    h.into_py(|py, h| {
+                        h.evloop.as_mut(py).record_wakeup(deadline);
+                        h.run(py)
+                    });
                 }
                 future::ok(())
             });
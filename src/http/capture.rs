@@ -0,0 +1,150 @@
+use std::io;
+use std::cmp;
+use std::collections::HashMap;
+
+use pyo3::*;
+use bytes::BytesMut;
+use futures::{task, Async, Poll};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use TokioEventLoop;
+use http::HeaderEncoding;
+use http::transport::{start_http_transport, RequestTimeouts};
+use pyunsafe::GIL;
+
+
+///
+/// Http connection without socket, records parsed requests
+/// and serialized responses. Testing helper.
+///
+#[py::class(weakref)]
+pub struct HttpCapture {
+    requests: Py<PyList>,
+    responses: Py<PyList>,
+    incoming: BytesMut,
+    outgoing: BytesMut,
+    eof: bool,
+    task: Option<task::Task>,
+    token: PyToken,
+}
+
+#[py::methods]
+impl HttpCapture {
+
+    #[getter]
+    fn requests(&self) -> PyResult<PyObject> {
+        Ok(self.requests.to_object(self.py()))
+    }
+
+    #[getter]
+    fn responses(&self) -> PyResult<PyObject> {
+        Ok(self.responses.to_object(self.py()))
+    }
+
+    ///
+    /// feed raw request data to connection
+    ///
+    fn feed_data(&mut self, py: Python, data: &PyObjectRef) -> PyResult<()> {
+        let data = buffer::PyBuffer::get(py, data)?;
+        self.incoming.extend(data.to_vec::<u8>(py)?);
+        self.wakeup();
+        Ok(())
+    }
+
+    ///
+    /// close incoming side of connection
+    ///
+    fn feed_eof(&mut self) -> PyResult<()> {
+        self.eof = true;
+        self.wakeup();
+        Ok(())
+    }
+}
+
+impl HttpCapture {
+
+    pub fn new(py: Python, evloop: &TokioEventLoop, factory: &PyObject,
+               header_encoding: HeaderEncoding, concurrency: Option<usize>)
+               -> PyResult<Py<HttpCapture>>
+    {
+        let capture = py.init(|token| HttpCapture {
+            requests: PyList::empty(py).into(),
+            responses: PyList::empty(py).into(),
+            incoming: BytesMut::new(),
+            outgoing: BytesMut::new(),
+            eof: false,
+            task: None,
+            token: token})?;
+
+        let stream = CaptureStream(capture.clone_ref(py));
+        start_http_transport(
+            py, evloop, factory, stream, HashMap::new(), Some(capture.clone_ref(py)),
+            header_encoding, None, concurrency, None, None, RequestTimeouts::default(), None, None)?;
+
+        Ok(capture)
+    }
+
+    pub fn request_received<T>(&mut self, py: Python, req: &Py<T>) {
+        let _ = self.requests.as_ref(py).append(req.to_object(py));
+    }
+
+    pub fn response_data(&mut self, data: &[u8]) {
+        self.outgoing.extend(data);
+    }
+
+    pub fn response_completed(&mut self, py: Python) {
+        let data = self.outgoing.take();
+        let _ = self.responses.as_ref(py).append(PyBytes::new(py, &data));
+    }
+
+    fn wakeup(&mut self) {
+        if let Some(task) = self.task.take() {
+            task.notify();
+        }
+    }
+}
+
+
+///
+/// In-memory stream, reads fed data, written data is recorded by codec
+///
+struct CaptureStream(Py<HttpCapture>);
+
+impl io::Read for CaptureStream {
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let capture = self.0.as_mut(GIL::python());
+
+        if capture.incoming.is_empty() {
+            if capture.eof {
+                return Ok(0)
+            }
+            capture.task = Some(task::current());
+            return Err(io::ErrorKind::WouldBlock.into())
+        }
+
+        let len = cmp::min(buf.len(), capture.incoming.len());
+        buf[..len].copy_from_slice(&capture.incoming.split_to(len));
+        Ok(len)
+    }
+}
+
+impl io::Write for CaptureStream {
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AsyncRead for CaptureStream {}
+
+impl AsyncWrite for CaptureStream {
+
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        Ok(Async::Ready(()))
+    }
+}
@@ -1,28 +1,106 @@
 use std::io;
+use std::rc::Rc;
+use std::cell::Cell;
 use pyo3::*;
 use bytes::{Bytes, BytesMut};
 use tokio_io::codec::{Encoder, Decoder};
 
 use http;
-// use pyunsafe::GIL;
+use http::capture::HttpCapture;
+use http::file::FileBody;
+use pyunsafe::GIL;
+
+
+const CRLF: &'static [u8] = b"\r\n";
+const LAST_CHUNK: &'static [u8] = b"0\r\n\r\n";
 
 
 pub enum EncoderMessage {
     Bytes(Bytes),
-    PyBytes(PyBytes),
+    PyBytes(Py<PyBytes>),
+    // payload framed with chunked transfer-encoding
+    Chunk(Bytes),
+    PyChunk(Py<PyBytes>),
+    EofChunk,
+    // file content, transport reads it in chunks
+    File(FileBody),
+}
+
+impl EncoderMessage {
+    pub fn len(&self) -> usize {
+        match *self {
+            EncoderMessage::Bytes(ref bytes) |
+            EncoderMessage::Chunk(ref bytes) => bytes.len(),
+            EncoderMessage::PyBytes(ref bytes) |
+            EncoderMessage::PyChunk(ref bytes) => bytes.as_ref(GIL::python()).data().len(),
+            EncoderMessage::EofChunk => 0,
+            EncoderMessage::File(ref body) => body.len() as usize,
+        }
+    }
+
+    /// Same payload as chunk of chunked body
+    pub fn chunk(self) -> EncoderMessage {
+        match self {
+            EncoderMessage::Bytes(bytes) => EncoderMessage::Chunk(bytes),
+            EncoderMessage::PyBytes(bytes) => EncoderMessage::PyChunk(bytes),
+            msg => msg,
+        }
+    }
+}
+
+// empty chunk would terminate body, so it is skipped
+fn encode_chunk(data: &[u8], dst: &mut BytesMut) {
+    if !data.is_empty() {
+        dst.extend(format!("{:x}\r\n", data.len()).as_bytes());
+        dst.extend(data);
+        dst.extend(CRLF);
+    }
 }
 
 
 pub struct HttpTransportCodec {
     decoder: http::RequestDecoder,
+    capture: Option<Py<HttpCapture>>,
+    // request head is partially received
+    head: Rc<Cell<bool>>,
+    // upgrade request is completed, cleared by transport if upgrade is declined
+    tunnel: Rc<Cell<bool>>,
 }
 
 impl HttpTransportCodec {
     pub fn new() -> HttpTransportCodec {
         HttpTransportCodec {
             decoder: http::RequestDecoder::new(),
+            capture: None,
+            head: Rc::new(Cell::new(false)),
+            tunnel: Rc::new(Cell::new(false)),
         }
     }
+
+    /// Codec that also records encoded output into capture object
+    pub fn with_capture(capture: Py<HttpCapture>) -> HttpTransportCodec {
+        HttpTransportCodec {
+            decoder: http::RequestDecoder::new(),
+            capture: Some(capture),
+            head: Rc::new(Cell::new(false)),
+            tunnel: Rc::new(Cell::new(false)),
+        }
+    }
+
+    pub fn set_max_body_size(&mut self, max_body_size: Option<u64>) {
+        self.decoder.set_max_body_size(max_body_size);
+    }
+
+    /// Flag which is set while request line or headers are partially received
+    pub fn head_state(&self) -> Rc<Cell<bool>> {
+        self.head.clone()
+    }
+
+    /// Flag which is set once upgrade request is completed, data after
+    /// it is parsed as http again only after flag is cleared
+    pub fn tunnel_state(&self) -> Rc<Cell<bool>> {
+        self.tunnel.clone()
+    }
 }
 
 impl Decoder for HttpTransportCodec {
@@ -31,7 +109,13 @@ impl Decoder for HttpTransportCodec {
 
     #[inline]
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        self.decoder.decode(src)
+        if !self.tunnel.get() {
+            self.decoder.resume();
+        }
+        let res = self.decoder.decode(src);
+        self.head.set(self.decoder.parsing_head());
+        self.tunnel.set(self.decoder.tunnel());
+        res
     }
 
 }
@@ -41,13 +125,31 @@ impl Encoder for HttpTransportCodec {
     type Error = io::Error;
 
     fn encode(&mut self, msg: EncoderMessage, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let start = dst.len();
+
         match msg {
             EncoderMessage::Bytes(bytes) => {
                 dst.extend(bytes);
             },
             EncoderMessage::PyBytes(bytes) => {
-                dst.extend(bytes.data());
+                dst.extend(bytes.as_ref(GIL::python()).data());
+            },
+            EncoderMessage::Chunk(bytes) => {
+                encode_chunk(&bytes, dst);
+            },
+            EncoderMessage::PyChunk(bytes) => {
+                encode_chunk(bytes.as_ref(GIL::python()).data(), dst);
+            },
+            EncoderMessage::EofChunk => {
+                dst.extend(LAST_CHUNK);
             },
+            EncoderMessage::File(mut body) => {
+                body.read_all(dst)?;
+            },
+        }
+
+        if let Some(ref capture) = self.capture {
+            capture.as_mut(GIL::python()).response_data(&dst[start..]);
         }
         Ok(())
     }
@@ -27,6 +27,10 @@ pub enum Error {
     BadHeader,
     /// Line is too long.
     LineTooLong,
+    /// Header line is too long.
+    HeaderTooLarge,
+    /// Body is larger than allowed
+    BodyTooLarge,
     /// Bad status line
     BadStatusLine,
     /// Invalid content-length header
@@ -47,6 +51,8 @@ impl Error {
         match *self {
             Error::BadHeader => "bad header",
             Error::LineTooLong => "line too long",
+            Error::HeaderTooLarge => "header is too large",
+            Error::BodyTooLarge => "body is too large",
             Error::BadStatusLine => "bad status line",
             Error::ContentLength => "invalid content length",
             Error::ContentLengthAndTE => "Both defined Content-Length and Trasnfer-Encoding: chunked length",
@@ -55,6 +61,19 @@ impl Error {
             Error::IOError(_) => "io error",
         }
     }
+
+    ///
+    /// Status of response to rejected request, incomplete
+    /// payload and io errors are not answered
+    ///
+    pub fn status(&self) -> Option<&'static str> {
+        match *self {
+            Error::HeaderTooLarge => Some("431 Request Header Fields Too Large"),
+            Error::BodyTooLarge => Some("413 Payload Too Large"),
+            Error::PayloadNotCompleted | Error::IOError(_) => None,
+            _ => Some("400 Bad Request"),
+        }
+    }
 }
 
 impl std::fmt::Display for Error {
@@ -69,11 +88,13 @@ impl std::error::Error for Error {
     }
 }
 
-/// Convert Error to io::Error
+/// Convert Error to io::Error, parse error is kept as inner error
 impl std::convert::From<Error> for std::io::Error {
     fn from(err: Error) -> Self {
-        std::io::Error::new(
-            std::io::ErrorKind::Other, format!("Python exception: {:?}", err.description()))
+        match err {
+            Error::IOError(err) => err,
+            err => std::io::Error::new(std::io::ErrorKind::InvalidData, err),
+        }
     }
 }
 
@@ -351,6 +372,8 @@ enum State {
     Header(ParseHeader),
     Body(ParseBody),
     Done,
+    // upgrade request is completed, data after it is not parsed until resume()
+    Tunnel,
 }
 
 pub struct RequestDecoder {
@@ -362,9 +385,14 @@ pub struct RequestDecoder {
     path_pos: u8,
 
     request: Request,
+    // current request is upgrade or CONNECT request
+    upgrade: bool,
 
     length: Option<u64>,
     chunked: bool,
+    // body size limit, chunked body is counted by chunk sizes
+    max_body_size: Option<u64>,
+    body_size: u64,
 
     header: Header,
     has_header: bool,
@@ -372,9 +400,14 @@ pub struct RequestDecoder {
     header_name: ParseHeaderName,
     header_name_hash: DefaultHasher,
 
+    // received chunked trailers, current line and total
+    trailer_line: usize,
+    trailers_size: usize,
+
     max_line_size: u16,
     max_headers: u16,
     max_field_size: u16,
+    max_trailers_size: usize,
 }
 
 impl RequestDecoder {
@@ -384,17 +417,59 @@ impl RequestDecoder {
             start: 0, state: State::Status(ParseStatusLine::Skip(CRLF::CR)),
             meth_pos: 0, meth_end: 0, path_pos: 0, path_end: 0,
 
-            request: Request::new(),
+            request: Request::new(), upgrade: false,
 
             header: Header::new(), has_header: false, header_token: ParseTokens::New,
             header_name: ParseHeaderName::General, header_name_hash: DefaultHasher::new(),
 
-            length: None, chunked: false,
+            length: None, chunked: false, max_body_size: None, body_size: 0,
+
+            trailer_line: 0, trailers_size: 0,
 
             max_line_size: 8190, max_headers: 32768, max_field_size: 8190,
+            max_trailers_size: 32768,
+        }
+    }
+
+    /// Body of larger request is rejected with BodyTooLarge error
+    pub fn set_max_body_size(&mut self, max_body_size: Option<u64>) {
+        self.max_body_size = max_body_size;
+    }
+
+    /// Upgrade request is completed, following data is not parsed
+    pub fn tunnel(&self) -> bool {
+        match self.state {
+            State::Tunnel => true,
+            _ => false,
+        }
+    }
+
+    /// Parse following data as http after declined upgrade
+    pub fn resume(&mut self) {
+        if self.tunnel() {
+            self.state = State::Status(ParseStatusLine::Skip(CRLF::CR));
         }
     }
 
+    /// Request line or headers are partially received
+    pub fn parsing_head(&self) -> bool {
+        match self.state {
+            State::Status(ParseStatusLine::Skip(CRLF::CR)) => false,
+            State::Status(..) | State::Header(..) => true,
+            _ => false,
+        }
+    }
+
+    fn trailers_received(&mut self, len: usize) -> std::result::Result<(), Error> {
+        self.trailer_line += len;
+        self.trailers_size += len;
+        if self.trailer_line > self.max_line_size as usize ||
+            self.trailers_size > self.max_trailers_size {
+            return Err(Error::HeaderTooLarge)
+        }
+        Ok(())
+    }
+
     fn update_msg_state(&mut self, token: ParseTokens) {
         match self.header_name {
             ParseHeaderName::Connection(..) =>
@@ -571,8 +646,16 @@ impl Decoder for RequestDecoder {
                                             },
                                         None => 0,
                                     };
+                                    if self.max_body_size.map_or(false, |max| length > max) {
+                                        return Err(Error::BodyTooLarge);
+                                    }
+                                    self.body_size = 0;
 
                                     self.start = 0;
+                                    self.request.upgrade =
+                                        self.request.connection == ConnectionType::Upgrade
+                                        || self.request.method() == "CONNECT";
+                                    self.upgrade = self.request.upgrade;
                                     if self.chunked {
                                         self.state = State::Body(ParseBody::ChunkSize(0));
                                     } else if length > 0 {
@@ -618,7 +701,7 @@ impl Decoder for RequestDecoder {
                             self.header.set_hash(h);
                             self.header.update_name_len(idx);
                             if self.header.is_overflow(self.max_line_size) {
-                                return Err(Error::LineTooLong)
+                                return Err(Error::HeaderTooLarge)
                             }
 
                             // move char pointer and prepare value parse
@@ -645,7 +728,7 @@ impl Decoder for RequestDecoder {
                     self.header_name = header_name;
                     self.header.update_name_len(len);
                     if self.header.is_overflow(self.max_line_size) {
-                        return Err(Error::LineTooLong)
+                        return Err(Error::HeaderTooLarge)
                     }
                     break
                 },
@@ -701,7 +784,7 @@ impl Decoder for RequestDecoder {
                             state = State::Header(ParseHeader::ValueEol);
                             self.header.update_value_len(idx);
                             if self.header.is_overflow(self.max_line_size) {
-                                return Err(Error::LineTooLong)
+                                return Err(Error::HeaderTooLarge)
                             }
                             continue 'run
                         } else if ! (is_vchar(ch) || is_obs_text(ch) || is_ows(ch)) {
@@ -723,7 +806,7 @@ impl Decoder for RequestDecoder {
                     self.header_token = header_token;
                     self.header.update_value_len(len);
                     if self.header.is_overflow(self.max_line_size) {
-                        return Err(Error::LineTooLong)
+                        return Err(Error::HeaderTooLarge)
                     }
                     break
                 },
@@ -758,7 +841,7 @@ impl Decoder for RequestDecoder {
                     let len = bytes.len();
                     for idx in 0..len {
                         let ch = bytes.get();
-                        if ch == b';' || ch == CR {
+                        if ch == b';' || ch == CR || is_ows(ch) {
                             // convert chunk size in hex to u64
                             let count = count + idx;
                             let origin = bytes.origin(count);
@@ -770,6 +853,10 @@ impl Decoder for RequestDecoder {
                                 Ok(v) => v,
                                 Err(..) => return Err(Error::TransferEncoding),
                             };
+                            self.body_size = self.body_size.saturating_add(size);
+                            if self.max_body_size.map_or(false, |max| self.body_size > max) {
+                                return Err(Error::BodyTooLarge);
+                            }
 
                             if let Some(ch2) = bytes.get_next_maybe() {
                                 if ch2 == LF && ch == CR {
@@ -790,6 +877,10 @@ impl Decoder for RequestDecoder {
                         }
                         bytes.bump();
                     }
+                    // chunk size does not fit u64
+                    if count + len > 16 {
+                        return Err(Error::TransferEncoding);
+                    }
                     state = State::Body(ParseBody::ChunkSize(count+len));
                     break
                 },
@@ -803,6 +894,8 @@ impl Decoder for RequestDecoder {
                         if ch == LF && prev == CR {
                             bytes.advance(idx+1);
                             if size == 0 {
+                                self.trailer_line = 0;
+                                self.trailers_size = 0;
                                 state = State::Body(ParseBody::ChunkMaybeTrailers);
                             } else {
                                 state = State::Body(ParseBody::Chunk(size));
@@ -811,6 +904,9 @@ impl Decoder for RequestDecoder {
                         }
                         prev = ch;
                     }
+                    if len > self.max_line_size as usize {
+                        return Err(Error::LineTooLong);
+                    }
                     break
                 },
                 ParseBody::Chunk(remaining) => {
@@ -868,12 +964,15 @@ impl Decoder for RequestDecoder {
                         break
                     },
                 ParseBody::ChunkTrailers(marker) => {
+                    // trailers are limited same as headers
                     let len = bytes.len();
                     for idx in 0..len {
                         let ch = bytes.next();
                         if ch == marker.val() {
                             bytes.advance(idx+1);
+                            self.trailers_received(idx+1)?;
                             if marker.val() == LF {
+                                self.trailer_line = 0;
                                 state = State::Body(ParseBody::ChunkMaybeTrailers);
                             } else {
                                 state = State::Body(ParseBody::ChunkTrailers(CRLF::LF));
@@ -882,6 +981,7 @@ impl Decoder for RequestDecoder {
                         }
                     }
                     bytes.advance(len);
+                    self.trailers_received(len)?;
                     break
                 },
                 ParseBody::Unsized =>
@@ -896,9 +996,14 @@ impl Decoder for RequestDecoder {
                 self.start = 0;
                 self.meth_pos = 0;
                 self.meth_end = 0;
-                self.state = State::Status(ParseStatusLine::Skip(CRLF::CR));
+                if std::mem::replace(&mut self.upgrade, false) {
+                    self.state = State::Tunnel;
+                } else {
+                    self.state = State::Status(ParseStatusLine::Skip(CRLF::CR));
+                }
                 return Ok(Some(RequestMessage::Completed))
-            }
+            },
+            State::Tunnel => return Ok(None),
             }}
         self.start = bytes.pos();
         self.state = state;
@@ -1002,7 +1107,7 @@ fn is_num(ch: u8) -> bool {
 }
 
 fn is_hex(ch: u8) -> bool {
-    is_num(ch) || ch >= b'a' && ch <= b'f'
+    is_num(ch) || lower(ch) >= b'a' && lower(ch) <= b'f'
 }
 
 #[inline]
@@ -1125,7 +1230,7 @@ fn parse_crlf(bytes: &mut BytesPtr, marker: CRLF, err: Error) -> Result<(), CRLF
                     }
                 }
             },
-            None => Ok(Status::Partial(CRLF::LF)),
+            None => Ok(Status::Partial(CRLF::CR)),
         },
         CRLF::LF => match bytes.next_maybe() {
             Some(ch) => {
@@ -0,0 +1,56 @@
+use std;
+use std::io;
+use pyo3::*;
+
+use http::decoder;
+
+
+// http exception hierarchy, HttpError is base class
+py_exception!(_tokio, HttpError, exc::Exception);
+
+// malformed http message
+py_exception!(_tokio, HttpParseError, HttpError);
+
+// connection lost or protocol error while payload is being received
+py_exception!(_tokio, PayloadError, HttpError);
+
+// connection closed by idle timeout
+py_exception!(_tokio, ServerTimeoutError, HttpError);
+
+// failed websocket handshake or protocol violation
+py_exception!(_tokio, WebSocketError, HttpError);
+
+
+///
+/// Incomplete payload is reported as PayloadError,
+/// other decoder errors as HttpParseError
+///
+pub fn decoder_error(err: &decoder::Error) -> PyErr {
+    match *err {
+        decoder::Error::PayloadNotCompleted => PayloadError::new(format!("{}", err)),
+        _ => HttpParseError::new(format!("Invalid http message: {}", err)),
+    }
+}
+
+///
+/// Convert transport error to python exception, decoder errors
+/// are converted with decoder_error() and timeout is ServerTimeoutError
+///
+pub fn transport_error(err: io::Error) -> PyErr {
+    if err.kind() == io::ErrorKind::TimedOut {
+        return ServerTimeoutError::new("Connection timeout")
+    }
+    if let Some(err) = err.get_ref().and_then(|err| err.downcast_ref::<decoder::Error>()) {
+        return decoder_error(err)
+    }
+    err.into()
+}
+
+pub fn register(py: Python, m: &PyModule) -> PyResult<()> {
+    m.add("HttpError", py.get_type::<HttpError>())?;
+    m.add("HttpParseError", py.get_type::<HttpParseError>())?;
+    m.add("PayloadError", py.get_type::<PayloadError>())?;
+    m.add("ServerTimeoutError", py.get_type::<ServerTimeoutError>())?;
+    m.add("WebSocketError", py.get_type::<WebSocketError>())?;
+    Ok(())
+}
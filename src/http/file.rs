@@ -0,0 +1,107 @@
+use std::cmp;
+use std::io::{self, Read};
+use std::fs::File;
+use std::path::Path;
+use bytes::{Bytes, BytesMut};
+
+
+// size of single read from file
+const CHUNK_SIZE: usize = 65536;
+
+const CONTENT_TYPES: &'static [(&'static str, &'static str)] = &[
+    ("html", "text/html; charset=utf-8"),
+    ("htm", "text/html; charset=utf-8"),
+    ("css", "text/css; charset=utf-8"),
+    ("js", "application/javascript; charset=utf-8"),
+    ("json", "application/json"),
+    ("txt", "text/plain; charset=utf-8"),
+    ("xml", "application/xml"),
+    ("csv", "text/csv; charset=utf-8"),
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("svg", "image/svg+xml"),
+    ("ico", "image/x-icon"),
+    ("webp", "image/webp"),
+    ("woff", "font/woff"),
+    ("woff2", "font/woff2"),
+    ("pdf", "application/pdf"),
+    ("zip", "application/zip"),
+    ("gz", "application/gzip"),
+    ("wasm", "application/wasm"),
+    ("mp4", "video/mp4"),
+    ("mp3", "audio/mpeg")];
+
+const DEFAULT_CONTENT_TYPE: &'static str = "application/octet-stream";
+
+
+///
+/// Body of file response, file is read chunk by chunk
+/// while connection accepts data
+///
+pub struct FileBody {
+    file: File,
+    remaining: u64,
+}
+
+impl FileBody {
+
+    /// Open regular file, size is taken at open time
+    pub fn open(path: &Path) -> io::Result<FileBody> {
+        let file = File::open(path)?;
+        let meta = file.metadata()?;
+        if !meta.is_file() {
+            return Err(io::Error::new(io::ErrorKind::Other, "Not a regular file"))
+        }
+        Ok(FileBody { file: file, remaining: meta.len() })
+    }
+
+    /// Number of bytes which are not read yet
+    pub fn len(&self) -> u64 {
+        self.remaining
+    }
+
+    ///
+    /// Next chunk of file, None once whole body is read. File which
+    /// is truncated after open is an error, length is already sent
+    ///
+    pub fn read_chunk(&mut self) -> io::Result<Option<Bytes>> {
+        if self.remaining == 0 {
+            return Ok(None)
+        }
+        let size = cmp::min(self.remaining, CHUNK_SIZE as u64) as usize;
+
+        let mut buf = vec![0; size];
+        let n = self.file.read(&mut buf)?;
+        if n == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "File is truncated"))
+        }
+        buf.truncate(n);
+        self.remaining -= n as u64;
+        Ok(Some(Bytes::from(buf)))
+    }
+
+    /// Rest of file at once
+    pub fn read_all(&mut self, dst: &mut BytesMut) -> io::Result<()> {
+        while let Some(chunk) = self.read_chunk()? {
+            dst.extend(chunk);
+        }
+        Ok(())
+    }
+}
+
+
+///
+/// Content-Type by file extension
+///
+pub fn content_type(path: &Path) -> &'static str {
+    let ext = match path.extension().and_then(|ext| ext.to_str()) {
+        Some(ext) => ext.to_ascii_lowercase(),
+        None => return DEFAULT_CONTENT_TYPE,
+    };
+    CONTENT_TYPES.iter()
+        .find(|&&(name, _)| name == ext)
+        .map(|&(_, ctype)| ctype)
+        .unwrap_or(DEFAULT_CONTENT_TYPE)
+}
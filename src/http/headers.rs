@@ -5,14 +5,52 @@ use std;
 use std::ops::Range;
 use std::hash::Hasher;
 use std::ascii::AsciiExt;
-use std::collections::HashMap;
 use std::collections::hash_map::DefaultHasher;
 use bytes::{Bytes, BytesMut};
+use pyo3::{exc, PyResult};
+
+
+///
+/// How header values are exposed to python. Raw bytes are always
+/// kept, decoding happens on access and never fails
+///
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum HeaderEncoding {
+    // every byte maps to code point, same as WSGI
+    Latin1,
+    // invalid sequences are decoded with surrogateescape
+    Utf8,
+    // values are returned as bytes
+    Bytes,
+}
 
+impl HeaderEncoding {
 
+    pub fn parse(name: &str) -> PyResult<HeaderEncoding> {
+        match name.to_ascii_lowercase().as_str() {
+            "latin-1" | "latin1" | "iso-8859-1" => Ok(HeaderEncoding::Latin1),
+            "utf-8" | "utf8" => Ok(HeaderEncoding::Utf8),
+            "bytes" => Ok(HeaderEncoding::Bytes),
+            _ => Err(exc::ValueError::new(
+                format!("Unknown header encoding: {}", name))),
+        }
+    }
+}
+
+impl Default for HeaderEncoding {
+    fn default() -> HeaderEncoding {
+        HeaderEncoding::Utf8
+    }
+}
+
+
+///
+/// Request headers in received order, names are matched case-insensitively,
+/// repeated header keeps all its values
+///
 #[derive(Debug)]
 pub struct Headers {
-    headers: HashMap<u64, Header>,
+    headers: Vec<Header>,
     bytes: Option<Bytes>,
     last_pos: u16,
 }
@@ -20,74 +58,81 @@ pub struct Headers {
 impl Headers {
 
     pub fn new() -> Headers {
-        Headers { headers: HashMap::with_capacity(64),
+        Headers { headers: Vec::with_capacity(16),
                   bytes: None,
                   last_pos: 0,
         }
     }
 
     pub fn headers(&self) -> Vec<(String, String)> {
+        self.raw_headers()
+            .into_iter()
+            .map(|(name, value)| (String::from_utf8_lossy(name).into_owned(),
+                                  String::from_utf8_lossy(value).into_owned()))
+            .collect()
+    }
+
+    pub fn raw_headers(&self) -> Vec<(&[u8], &[u8])> {
         let mut vec = Vec::new();
 
         if let Some(ref bytes) = self.bytes {
-            for header in self.headers.values() {
-                vec.push((
-                    String::from(
-                        unsafe {
-                            std::str::from_utf8_unchecked(&bytes[header.name_range()])
-                        }
-                    ),
-
-                    String::from(
-                        unsafe {
-                            std::str::from_utf8_unchecked(&bytes[header.value_range()])
-                        }
-                    )));
+            for header in self.headers.iter() {
+                vec.push((&bytes[header.name_range()], &bytes[header.value_range()]));
             }
         }
         vec
     }
-    
+
+    pub fn len(&self) -> usize {
+        self.headers.len()
+    }
+
     pub fn get(&self, name: &str) -> Option<&str> {
+        self.get_raw(name).and_then(|val| std::str::from_utf8(val).ok())
+    }
+
+    /// First value of header
+    pub fn get_raw(&self, name: &str) -> Option<&[u8]> {
+        self.get_all_raw(name).into_iter().next()
+    }
+
+    /// All values of header in received order
+    pub fn get_all_raw(&self, name: &str) -> Vec<&[u8]> {
         let mut hasher = DefaultHasher::new();
         for byte in name.bytes().map(|b| b.to_ascii_lowercase()) {
             hasher.write_u8(byte);
         }
         let hash = hasher.finish();
 
-        if let Some(ref header) = self.headers.get(&hash) {
-            if let Some(ref bytes) = self.bytes {
-                Some(
-                    unsafe {
-                        std::str::from_utf8_unchecked(&bytes[header.value_range()])
-                    })
-            } else {
-                None
+        let mut values = Vec::new();
+        if let Some(ref bytes) = self.bytes {
+            for header in self.headers.iter() {
+                if header.hash == hash
+                    && bytes[header.name_range()].eq_ignore_ascii_case(name.as_bytes()) {
+                    values.push(&bytes[header.value_range()]);
+                }
             }
-        } else {
-            None
         }
+        values
     }
 
     pub fn get_case(&self, name: &str) -> Option<&str> {
-        let mut hasher = DefaultHasher::new(); //self.hasher.borrow_mut();
+        let mut hasher = DefaultHasher::new();
         for byte in name.bytes() {
             hasher.write_u8(byte);
         }
         let hash = hasher.finish();
 
-        if let Some(ref header) = self.headers.get(&hash) {
-            if let Some(ref bytes) = self.bytes {
-                Some(
-                    unsafe {
+        if let Some(ref bytes) = self.bytes {
+            for header in self.headers.iter() {
+                if header.hash == hash {
+                    return Some(unsafe {
                         std::str::from_utf8_unchecked(&bytes[header.value_range()])
                     })
-            } else {
-                None
+                }
             }
-        } else {
-            None
         }
+        None
     }
 
     pub fn has(&self) -> bool {
@@ -108,7 +153,7 @@ impl WriteHeaders for Headers {
 
     fn append(&mut self, header: Header) {
         self.last_pos = header.end();
-        self.headers.insert(header.hash, header);
+        self.headers.push(header);
     }
 
     fn flush(&mut self, src: &mut BytesMut) {
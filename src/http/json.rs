@@ -0,0 +1,123 @@
+use std::fmt::Write;
+
+use pyo3::*;
+use bytes::{BufMut, BytesMut};
+
+
+const MAX_DEPTH: usize = 128;
+
+
+//
+// Serialize python object to json. dict with str keys, list, tuple,
+// str, int, float, bool and None are supported, returns false
+// if object contains anything else
+//
+pub fn encode(obj: &PyObjectRef, dst: &mut BytesMut) -> PyResult<bool> {
+    encode_value(obj, dst, 0)
+}
+
+fn encode_value(obj: &PyObjectRef, dst: &mut BytesMut, depth: usize) -> PyResult<bool> {
+    if depth > MAX_DEPTH {
+        return Ok(false)
+    }
+    let py = obj.py();
+
+    if obj.is_none() {
+        dst.extend(b"null");
+    }
+    else if let Ok(val) = PyBool::try_from_exact(obj) {
+        dst.extend(if val.is_true() { &b"true"[..] } else { &b"false"[..] });
+    }
+    else if let Ok(s) = PyString::try_from_exact(obj) {
+        match s.to_string() {
+            Ok(s) => encode_str(&s, dst),
+            Err(_) => return Ok(false),
+        }
+    }
+    else if PyLong::try_from_exact(obj).is_ok() {
+        match obj.extract::<i64>() {
+            Ok(val) => write_fmt(dst, format_args!("{}", val)),
+            // big int, python representation is valid json number
+            Err(_) => dst.extend(obj.str()?.to_string()?.as_bytes()),
+        }
+    }
+    else if let Ok(val) = PyFloat::try_from_exact(obj) {
+        let val = val.value();
+        if !val.is_finite() {
+            return Ok(false)
+        }
+        write_fmt(dst, format_args!("{:?}", val));
+    }
+    else if let Ok(dict) = PyDict::try_from_exact(obj) {
+        dst.put_u8(b'{');
+        for (idx, (key, value)) in dict.iter().enumerate() {
+            if idx > 0 {
+                dst.extend(b", ");
+            }
+            match PyString::try_from_exact(key).map(|key| key.to_string()) {
+                Ok(Ok(key)) => encode_str(&key, dst),
+                _ => return Ok(false),
+            }
+            dst.extend(b": ");
+            if !encode_value(value, dst, depth + 1)? {
+                return Ok(false)
+            }
+        }
+        dst.put_u8(b'}');
+    }
+    else if let Ok(list) = PyList::try_from_exact(obj) {
+        dst.put_u8(b'[');
+        for (idx, item) in list.iter().enumerate() {
+            if idx > 0 {
+                dst.extend(b", ");
+            }
+            if !encode_value(item, dst, depth + 1)? {
+                return Ok(false)
+            }
+        }
+        dst.put_u8(b']');
+    }
+    else if let Ok(tuple) = PyTuple::try_from_exact(obj) {
+        dst.put_u8(b'[');
+        for (idx, item) in tuple.as_slice().iter().enumerate() {
+            if idx > 0 {
+                dst.extend(b", ");
+            }
+            if !encode_value(item.as_ref(py), dst, depth + 1)? {
+                return Ok(false)
+            }
+        }
+        dst.put_u8(b']');
+    }
+    else {
+        return Ok(false)
+    }
+
+    Ok(true)
+}
+
+fn encode_str(s: &str, dst: &mut BytesMut) {
+    dst.reserve(s.len() + 2);
+    dst.put_u8(b'"');
+    for ch in s.chars() {
+        match ch {
+            '"' => dst.extend(b"\\\""),
+            '\\' => dst.extend(b"\\\\"),
+            '\n' => dst.extend(b"\\n"),
+            '\r' => dst.extend(b"\\r"),
+            '\t' => dst.extend(b"\\t"),
+            ch if (ch as u32) < 0x20 => write_fmt(dst, format_args!("\\u{:04x}", ch as u32)),
+            ch => {
+                let mut buf = [0; 4];
+                dst.extend(ch.encode_utf8(&mut buf).as_bytes());
+            }
+        }
+    }
+    dst.put_u8(b'"');
+}
+
+fn write_fmt(dst: &mut BytesMut, args: ::std::fmt::Arguments) {
+    let mut s = String::new();
+    let _ = s.write_fmt(args);
+    dst.extend(s.as_bytes());
+}
@@ -32,6 +32,8 @@ pub struct Request {
     pub connection: ConnectionType,
     pub chunked: bool,
     pub websocket: bool,
+    // Connection: upgrade or CONNECT, rest of stream is not http if accepted
+    pub upgrade: bool,
     pub compress: ContentCompression,
 
     bytes: Bytes,
@@ -70,6 +72,7 @@ impl RequestUpdater for Request {
             connection: ConnectionType::KeepAlive,
             chunked: false,
             websocket: false,
+            upgrade: false,
             compress: ContentCompression::Default,
             meth: (0, 0),
             path: (0, 0),
@@ -1,14 +1,37 @@
 mod codec;
 mod decoder;
+mod errors;
+mod file;
 mod headers;
+mod json;
 mod message;
-//mod transport;
-//pub mod pyreq;
-//pub mod pytransport;
+mod parser;
+mod pyheaders;
+mod response;
+mod span;
+mod transport;
+mod url;
+pub mod capture;
+pub mod pyreq;
+pub mod pytransport;
+pub mod strings;
+pub mod websocket;
 
 pub use self::codec::{EncoderMessage, HttpTransportCodec};
-pub use self::headers::{Headers};
+pub use self::file::{FileBody, content_type};
+pub use self::headers::{Headers, HeaderEncoding};
 pub use self::decoder::{Error, RequestDecoder, RequestMessage};
+pub use self::errors::{HttpError, HttpParseError, PayloadError, ServerTimeoutError,
+                       WebSocketError};
+pub use self::errors::register as register_errors;
 pub use self::message::{Version, Request, ContentCompression, ConnectionType};
-//pub use self::transport::{http_transport_factory};
-//pub use self::pyreq::{PyRequest, StreamReader, RawHeaders, Url, PayloadWriter};
+pub use self::response::{Response, ResponseDecoder, ResponseMessage};
+pub use self::parser::{HttpRequestParser, HttpResponseParser};
+pub use self::transport::{http_transport_factory};
+pub use self::url::{RequestTarget, parse_query, percent_decode};
+pub use self::capture::HttpCapture;
+pub use self::strings::Strings;
+pub use self::span::Span;
+pub use self::pyheaders::ResponseHeaders;
+pub use self::pyreq::{PyRequest, StreamReader, RawHeaders, Url, Query, PayloadWriter};
+pub use self::websocket::WebSocketTransport;
@@ -0,0 +1,262 @@
+use pyo3::*;
+use bytes::BytesMut;
+use tokio_io::codec::Decoder;
+
+use http::decoder::{Error, RequestDecoder, RequestMessage};
+use http::errors;
+use http::response::{ResponseDecoder, ResponseMessage};
+use http::message::{Version, ConnectionType};
+
+
+// protocol callbacks, missing methods are skipped
+struct Callbacks {
+    on_message_begin: Option<PyObject>,
+    on_url: Option<PyObject>,
+    on_status: Option<PyObject>,
+    on_header: Option<PyObject>,
+    on_headers_complete: Option<PyObject>,
+    on_body: Option<PyObject>,
+    on_message_complete: Option<PyObject>,
+}
+
+impl Callbacks {
+
+    fn new(py: Python, protocol: &PyObjectRef) -> Callbacks {
+        let get = |name: &str| protocol.getattr(name).ok().map(|cb| cb.to_object(py));
+
+        Callbacks {
+            on_message_begin: get("on_message_begin"),
+            on_url: get("on_url"),
+            on_status: get("on_status"),
+            on_header: get("on_header"),
+            on_headers_complete: get("on_headers_complete"),
+            on_body: get("on_body"),
+            on_message_complete: get("on_message_complete"),
+        }
+    }
+}
+
+macro_rules! callback {
+    ($py:ident, $cb:expr) => {
+        if let Some(ref cb) = $cb {
+            cb.call0($py)?;
+        }
+    };
+    ($py:ident, $cb:expr, $($arg:expr),+) => {
+        if let Some(ref cb) = $cb {
+            cb.call1($py, ($(PyBytes::new($py, $arg),)+))?;
+        }
+    };
+}
+
+fn parser_error(err: Error) -> PyErr {
+    errors::decoder_error(&err)
+}
+
+fn version_tuple(py: Python, version: Version) -> PyObject {
+    match version {
+        Version::Http10 => (1, 0).to_object(py),
+        Version::Http11 => (1, 1).to_object(py),
+    }
+}
+
+
+///
+/// Standalone http request parser, feed_data() calls protocol's
+/// on_message_begin(), on_url(url), on_header(name, value),
+/// on_headers_complete(), on_body(body) and on_message_complete()
+///
+#[py::class]
+pub struct HttpRequestParser {
+    decoder: RequestDecoder,
+    buf: BytesMut,
+    callbacks: Callbacks,
+    method: Option<String>,
+    version: Option<Version>,
+    connection: ConnectionType,
+    token: PyToken,
+}
+
+#[py::methods]
+impl HttpRequestParser {
+
+    #[new]
+    fn __new__(obj: &PyRawObject, protocol: &PyObjectRef) -> PyResult<()> {
+        let callbacks = Callbacks::new(obj.py(), protocol);
+        obj.init(|token| HttpRequestParser {
+            decoder: RequestDecoder::new(),
+            buf: BytesMut::new(),
+            callbacks: callbacks,
+            method: None,
+            version: None,
+            connection: ConnectionType::KeepAlive,
+            token: token,
+        })
+    }
+
+    fn feed_data(&mut self, py: Python, data: &PyObjectRef) -> PyResult<()> {
+        let data = buffer::PyBuffer::get(py, data)?;
+        self.buf.extend(data.to_vec::<u8>(py)?);
+
+        loop {
+            match self.decoder.decode(&mut self.buf).map_err(parser_error)? {
+                Some(msg) => self.process(py, msg)?,
+                None => return Ok(()),
+            }
+        }
+    }
+
+    fn feed_eof(&mut self, py: Python) -> PyResult<()> {
+        loop {
+            match self.decoder.decode_eof(&mut self.buf).map_err(parser_error)? {
+                Some(msg) => self.process(py, msg)?,
+                None => return Ok(()),
+            }
+        }
+    }
+
+    fn get_method(&self, py: Python) -> PyResult<PyObject> {
+        Ok(self.method.to_object(py))
+    }
+
+    fn get_http_version(&self, py: Python) -> PyResult<PyObject> {
+        match self.version {
+            Some(version) => Ok(version_tuple(py, version)),
+            None => Ok(py.None()),
+        }
+    }
+
+    fn should_keep_alive(&self) -> PyResult<bool> {
+        Ok(self.connection == ConnectionType::KeepAlive)
+    }
+
+    fn should_upgrade(&self) -> PyResult<bool> {
+        Ok(self.connection == ConnectionType::Upgrade)
+    }
+}
+
+impl HttpRequestParser {
+
+    fn process(&mut self, py: Python, msg: RequestMessage) -> PyResult<()> {
+        match msg {
+            RequestMessage::Message(req) => {
+                self.method = Some(req.method().to_owned());
+                self.version = Some(req.version);
+                self.connection = req.connection;
+
+                callback!(py, self.callbacks.on_message_begin);
+                callback!(py, self.callbacks.on_url, req.path().as_bytes());
+                if self.callbacks.on_header.is_some() {
+                    for (name, value) in req.headers.raw_headers() {
+                        callback!(py, self.callbacks.on_header, name, value);
+                    }
+                }
+                callback!(py, self.callbacks.on_headers_complete);
+            },
+            RequestMessage::Body(body) =>
+                callback!(py, self.callbacks.on_body, &body),
+            RequestMessage::Completed =>
+                callback!(py, self.callbacks.on_message_complete),
+        }
+        Ok(())
+    }
+}
+
+
+///
+/// Standalone http response parser for client code, protocol
+/// receives on_status(reason) instead of on_url()
+///
+#[py::class]
+pub struct HttpResponseParser {
+    decoder: ResponseDecoder,
+    buf: BytesMut,
+    callbacks: Callbacks,
+    status: Option<u16>,
+    version: Option<Version>,
+    connection: ConnectionType,
+    token: PyToken,
+}
+
+#[py::methods]
+impl HttpResponseParser {
+
+    #[new]
+    fn __new__(obj: &PyRawObject, protocol: &PyObjectRef) -> PyResult<()> {
+        let callbacks = Callbacks::new(obj.py(), protocol);
+        obj.init(|token| HttpResponseParser {
+            decoder: ResponseDecoder::new(),
+            buf: BytesMut::new(),
+            callbacks: callbacks,
+            status: None,
+            version: None,
+            connection: ConnectionType::KeepAlive,
+            token: token,
+        })
+    }
+
+    fn feed_data(&mut self, py: Python, data: &PyObjectRef) -> PyResult<()> {
+        let data = buffer::PyBuffer::get(py, data)?;
+        self.buf.extend(data.to_vec::<u8>(py)?);
+
+        loop {
+            match self.decoder.decode(&mut self.buf).map_err(parser_error)? {
+                Some(msg) => self.process(py, msg)?,
+                None => return Ok(()),
+            }
+        }
+    }
+
+    ///
+    /// complete response without content-length, that is
+    /// delimited by connection close
+    ///
+    fn feed_eof(&mut self, py: Python) -> PyResult<()> {
+        loop {
+            match self.decoder.decode_eof(&mut self.buf).map_err(parser_error)? {
+                Some(msg) => self.process(py, msg)?,
+                None => return Ok(()),
+            }
+        }
+    }
+
+    fn get_status_code(&self, py: Python) -> PyResult<PyObject> {
+        Ok(self.status.to_object(py))
+    }
+
+    fn get_http_version(&self, py: Python) -> PyResult<PyObject> {
+        match self.version {
+            Some(version) => Ok(version_tuple(py, version)),
+            None => Ok(py.None()),
+        }
+    }
+
+    fn should_keep_alive(&self) -> PyResult<bool> {
+        Ok(self.connection == ConnectionType::KeepAlive)
+    }
+}
+
+impl HttpResponseParser {
+
+    fn process(&mut self, py: Python, msg: ResponseMessage) -> PyResult<()> {
+        match msg {
+            ResponseMessage::Message(resp) => {
+                self.status = Some(resp.status);
+                self.version = Some(resp.version);
+                self.connection = resp.connection;
+
+                callback!(py, self.callbacks.on_message_begin);
+                callback!(py, self.callbacks.on_status, &resp.reason);
+                for &(ref name, ref value) in resp.headers.iter() {
+                    callback!(py, self.callbacks.on_header, name, value);
+                }
+                callback!(py, self.callbacks.on_headers_complete);
+            },
+            ResponseMessage::Body(body) =>
+                callback!(py, self.callbacks.on_body, &body),
+            ResponseMessage::Completed =>
+                callback!(py, self.callbacks.on_message_complete),
+        }
+        Ok(())
+    }
+}
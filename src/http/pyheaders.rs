@@ -0,0 +1,196 @@
+use pyo3::*;
+
+
+///
+/// Mutable headers for responses. Names are matched case-insensitively,
+/// name can have several values, order of added headers is kept.
+/// Values which are not str are converted with str()
+///
+#[py::class]
+pub struct ResponseHeaders {
+    items: Vec<(String, String)>,
+    token: PyToken,
+}
+
+#[py::methods]
+impl ResponseHeaders {
+
+    #[new]
+    fn __new__(obj: &PyRawObject, headers: Option<&PyObjectRef>) -> PyResult<()> {
+        let mut items = Vec::new();
+        if let Some(headers) = headers {
+            extend_items(&mut items, headers)?;
+        }
+        obj.init(|token| ResponseHeaders {items: items, token: token})
+    }
+
+    ///
+    /// add value, existing values of name are kept
+    ///
+    fn add(&mut self, key: String, value: &PyObjectRef) -> PyResult<()> {
+        self.items.push((key, to_value(value)?));
+        Ok(())
+    }
+
+    ///
+    /// add all items of mapping or of sequence of (name, value) pairs
+    ///
+    fn extend(&mut self, headers: &PyObjectRef) -> PyResult<()> {
+        extend_items(&mut self.items, headers)
+    }
+
+    fn get(&self, py: Python, key: &str, default: Option<PyObject>) -> PyResult<PyObject> {
+        match self.find(key) {
+            Some(value) => Ok(PyString::new(py, value).into()),
+            None => Ok(default.unwrap_or_else(|| py.None())),
+        }
+    }
+
+    fn getone(&self, py: Python, key: &str, default: Option<PyObject>) -> PyResult<PyObject> {
+        match self.find(key) {
+            Some(value) => Ok(PyString::new(py, value).into()),
+            None => default.ok_or_else(|| exc::KeyError::new(key.to_owned())),
+        }
+    }
+
+    ///
+    /// list of all values of key, KeyError is raised if key
+    /// is missing and default is not provided
+    ///
+    fn getall(&self, py: Python, key: &str, default: Option<PyObject>) -> PyResult<PyObject> {
+        let values: Vec<PyObject> = self.items.iter()
+            .filter(|&&(ref name, _)| name.eq_ignore_ascii_case(key))
+            .map(|&(_, ref value)| PyString::new(py, value).into())
+            .collect();
+        if values.is_empty() {
+            default.ok_or_else(|| exc::KeyError::new(key.to_owned()))
+        } else {
+            Ok(PyList::new(py, values.as_slice()).into())
+        }
+    }
+
+    ///
+    /// remove all values of key and return first one
+    ///
+    fn pop(&mut self, py: Python, key: &str, default: Option<PyObject>) -> PyResult<PyObject> {
+        let value = self.getone(py, key, default)?;
+        self.items.retain(|&(ref name, _)| !name.eq_ignore_ascii_case(key));
+        Ok(value)
+    }
+
+    fn keys(&self, py: Python) -> PyResult<PyObject> {
+        let keys: Vec<PyObject> = self.items.iter()
+            .map(|&(ref name, _)| PyString::new(py, name).into())
+            .collect();
+        Ok(PyList::new(py, keys.as_slice()).into())
+    }
+
+    fn values(&self, py: Python) -> PyResult<PyObject> {
+        let values: Vec<PyObject> = self.items.iter()
+            .map(|&(_, ref value)| PyString::new(py, value).into())
+            .collect();
+        Ok(PyList::new(py, values.as_slice()).into())
+    }
+
+    fn items(&self, py: Python) -> PyResult<PyObject> {
+        let items: Vec<PyObject> = self.items.iter()
+            .map(|&(ref name, ref value)| (name.as_str(), value.as_str()).to_object(py))
+            .collect();
+        Ok(PyList::new(py, items.as_slice()).into())
+    }
+}
+
+#[py::proto]
+impl PyMappingProtocol for ResponseHeaders {
+
+    fn __len__(&self) -> PyResult<usize> {
+        Ok(self.items.len())
+    }
+
+    fn __getitem__(&self, key: &str) -> PyResult<PyObject> {
+        self.getone(self.py(), key, None)
+    }
+
+    // first value is replaced, other values of key are removed
+    fn __setitem__(&mut self, key: String, value: &PyObjectRef) -> PyResult<()> {
+        let value = to_value(value)?;
+        match self.items.iter().position(|&(ref name, _)| name.eq_ignore_ascii_case(&key)) {
+            Some(pos) => {
+                let tail = self.items.split_off(pos + 1);
+                self.items.extend(
+                    tail.into_iter().filter(|&(ref name, _)| !name.eq_ignore_ascii_case(&key)));
+                self.items[pos] = (key, value);
+            },
+            None => self.items.push((key, value)),
+        }
+        Ok(())
+    }
+
+    fn __delitem__(&mut self, key: &str) -> PyResult<()> {
+        let len = self.items.len();
+        self.items.retain(|&(ref name, _)| !name.eq_ignore_ascii_case(key));
+        if self.items.len() == len {
+            Err(exc::KeyError::new(key.to_owned()))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[py::proto]
+impl PySequenceProtocol for ResponseHeaders {
+
+    fn __contains__(&self, key: &str) -> PyResult<bool> {
+        Ok(self.find(key).is_some())
+    }
+}
+
+#[py::proto]
+impl PyIterProtocol for ResponseHeaders {
+
+    fn __iter__(&mut self) -> PyResult<PyObject> {
+        let py = self.py();
+        self.keys(py)?.call_method0(py, "__iter__")
+    }
+}
+
+impl ResponseHeaders {
+
+    /// (name, value) pairs in order of addition
+    pub fn pairs(&self) -> &[(String, String)] {
+        &self.items
+    }
+
+    fn find(&self, key: &str) -> Option<&str> {
+        self.items.iter()
+            .find(|&&(ref name, _)| name.eq_ignore_ascii_case(key))
+            .map(|&(_, ref value)| value.as_str())
+    }
+}
+
+
+fn to_value(value: &PyObjectRef) -> PyResult<String> {
+    if let Ok(value) = PyString::try_from(value) {
+        Ok(value.to_string()?.into_owned())
+    } else {
+        Ok(format!("{}", value))
+    }
+}
+
+// mapping with items() or iterable of (name, value) pairs
+fn extend_items(items: &mut Vec<(String, String)>, headers: &PyObjectRef) -> PyResult<()> {
+    let pairs = if headers.hasattr("items")? {
+        headers.call_method0("items")?
+    } else {
+        headers
+    };
+    for item in pairs.iter()? {
+        let item = PyTuple::try_from(item?)?;
+        if item.len() != 2 {
+            return Err(exc::ValueError::new("headers item should be (name, value) pair"));
+        }
+        let name = PyString::try_from(item.get_item(0))?.to_string()?.into_owned();
+        items.push((name, to_value(item.get_item(1))?));
+    }
+    Ok(())
+}
@@ -1,32 +1,42 @@
-#[allow(non_snake_case)]
-
-use std::borrow::Borrow;
-use std::iter::Iterator;
+use std::str;
+use std::path::Path;
 use std::collections::VecDeque;
 
 use pyo3::*;
 use bytes::{Bytes, BytesMut};
-use futures::{Async, Future, Poll, Stream, future};
+use futures::{task, Future};
 
-use {PyFuture, PyFuturePtr, TokioEventLoop, TokioEventLoopPtr, pybytes, with_py};
-use pyunsafe::{GIL, Sender};
+use {Classes, PyFuture, PyFut, TokioEventLoop, pybytes, utils};
+use pyunsafe::BoundedSender;
 use http::codec::EncoderMessage;
-use http::{Request, Version, Headers, ConnectionType, ContentCompression};
+use http::errors::WebSocketError;
+use http::pytransport::{Handover, PyHttpTransport};
+use http::json;
+use http::websocket;
+use http::strings::Strings;
+use http::{FileBody, RequestTarget, ResponseHeaders, content_type, parse_query, Request, Version, Headers, HeaderEncoding, ConnectionType, ContentCompression, Span};
 
 
-#[py::class]
+#[py::class(weakref)]
 pub struct PyRequest {
-    _loop: TokioEventLoop,
+    evloop: Py<TokioEventLoop>,
     connection: ConnectionType,
-    method: PyString,
-    url: Url,
-    path: PyString,
-    version: PyTuple,
-    headers: RawHeaders,
-    _content: StreamReader,
-    _match_info: PyObject,
-    _writer: PayloadWriter,
-    _time_service: PyObject,
+    upgrade: bool,
+    http_version: Version,
+    transport: Py<PyHttpTransport>,
+    method: Py<PyString>,
+    url: Py<Url>,
+    // parsed on first access
+    query: Option<Py<Query>>,
+    version: Py<PyTuple>,
+    headers: Py<RawHeaders>,
+    content: Py<StreamReader>,
+    span: Py<Span>,
+    match_info: PyObject,
+    writer: Py<PayloadWriter>,
+    time_service: PyObject,
+    peername: PyObject,
+    token: PyToken,
 }
 
 
@@ -34,160 +44,341 @@ pub struct PyRequest {
 impl PyRequest {
 
     #[getter(_method)]
-    fn get_method_prop(&self, py: Python) -> PyResult<PyString> {
-        Ok(self.method.clone_ref(py))
+    fn get_method_prop(&self) -> PyResult<Py<PyString>> {
+        Ok(self.method.clone_ref(self.py()))
     }
 
     #[getter]
-    fn get_method(&self, py: Python) -> PyResult<PyString> {
-        Ok(self.method.clone_ref(py))
+    fn get_method(&self) -> PyResult<Py<PyString>> {
+        Ok(self.method.clone_ref(self.py()))
     }
 
+    ///
+    /// Percent-decoded path of request target, without query string
+    ///
     #[getter]
-    fn get_path(&self, py: Python) -> PyResult<PyString> {
-        Ok(self.path.clone_ref(py))
+    fn get_path(&self) -> PyResult<Py<PyString>> {
+        let py = self.py();
+        Ok(self.url.as_ref(py).path.clone_ref(py))
     }
+    ///
+    /// Path and query string as sent, scheme and host of absolute url are stripped
+    ///
     #[getter]
-    fn get_rel_url(&self, py: Python) -> PyResult<Url> {
-        Ok(self.url.clone_ref(py))
+    fn get_raw_path(&self) -> PyResult<Py<PyString>> {
+        let py = self.py();
+        Ok(self.url.as_ref(py).raw_path.clone_ref(py))
     }
     #[getter]
-    fn get_version(&self, py: Python) -> PyResult<PyTuple> {
-        Ok(self.version.clone_ref(py))
+    fn get_query_string(&self) -> PyResult<Py<PyString>> {
+        let py = self.py();
+        Ok(self.url.as_ref(py).query_string.clone_ref(py))
     }
+    ///
+    /// Multi-value mapping of decoded query parameters
+    ///
     #[getter]
-    fn get_headers(&self, py: Python) -> PyResult<RawHeaders> {
-        Ok(self.headers.clone_ref(py))
+    fn get_query(&mut self) -> PyResult<Py<Query>> {
+        let py = self.py();
+        if self.query.is_none() {
+            let query = self.url.as_ref(py).query_string.as_ref(py).to_string()?;
+            self.query = Some(Query::new(py, parse_query(&query))?);
+        }
+        Ok(self.query.as_ref().unwrap().clone_ref(py))
     }
     #[getter]
-    fn get_content(&self, py: Python) -> PyResult<StreamReader> {
-        Ok(self._content.clone_ref(py))
+    fn get_rel_url(&self) -> PyResult<Py<Url>> {
+        Ok(self.url.clone_ref(self.py()))
     }
     #[getter]
-    fn get_keep_alive(&self, py: Python) -> PyResult<bool> {
-        if let &ConnectionType::KeepAlive = self.connection {
-            Ok(true)
-        } else {
-            Ok(false)
+    fn get_version(&self) -> PyResult<Py<PyTuple>> {
+        Ok(self.version.clone_ref(self.py()))
+    }
+    #[getter]
+    fn get_headers(&self) -> PyResult<Py<RawHeaders>> {
+        Ok(self.headers.clone_ref(self.py()))
+    }
+    #[getter]
+    fn get_content(&self) -> PyResult<Py<StreamReader>> {
+        Ok(self.content.clone_ref(self.py()))
+    }
+    #[getter]
+    fn get_span(&self) -> PyResult<Py<Span>> {
+        Ok(self.span.clone_ref(self.py()))
+    }
+    ///
+    /// Address of connected client, same as transport.get_extra_info('peername')
+    ///
+    #[getter]
+    fn get_peername(&self) -> PyResult<PyObject> {
+        Ok(self.peername.clone_ref(self.py()))
+    }
+    ///
+    /// IP address of connected client, path for unix socket
+    ///
+    #[getter]
+    fn get_remote(&self) -> PyResult<PyObject> {
+        let py = self.py();
+        match PyTuple::try_from(self.peername.as_ref(py)) {
+            Ok(addr) if addr.len() > 0 => Ok(addr.get_item(0).into()),
+            _ => Ok(self.peername.clone_ref(py)),
         }
     }
     #[getter]
-    fn get_match_info(&self, py: Python) -> PyResult<PyObject> {
-        Ok(self._match_info.clone_ref(py))
+    fn get_keep_alive(&self) -> PyResult<bool> {
+        Ok(self.connection == ConnectionType::KeepAlive)
+    }
+    ///
+    /// Request with Connection: upgrade or CONNECT method, connection
+    /// is not parsed as http after it once "101 Switching Protocols"
+    /// or 2xx response to CONNECT is sent or request is detached
+    ///
+    #[getter]
+    fn get_upgrade(&self) -> PyResult<bool> {
+        Ok(self.upgrade)
+    }
+    #[getter]
+    fn get_match_info(&self) -> PyResult<PyObject> {
+        Ok(self.match_info.clone_ref(self.py()))
     }
     #[setter]
-    fn set_match_info(&mut self, py: Python, value: &PyObject) -> PyResult<()> {
-        self._match_info = value.clone_ref(py);
+    fn set_match_info(&mut self, value: PyObject) -> PyResult<()> {
+        self.match_info = value;
+        Ok(())
+    }
+    // aiohttp router sets match info of request
+    #[getter(_match_info)]
+    fn get_match_info_prop(&self) -> PyResult<PyObject> {
+        Ok(self.match_info.clone_ref(self.py()))
+    }
+    #[setter(_match_info)]
+    fn set_match_info_prop(&mut self, value: PyObject) -> PyResult<()> {
+        self.match_info = value;
         Ok(())
     }
     #[getter(_writer)]
-    fn get_writer_prop(&self, py: Python) -> PyResult<PayloadWriter> {
-        Ok(self._writer.clone_ref(py))
+    fn get_writer_prop(&self) -> PyResult<Py<PayloadWriter>> {
+        Ok(self.writer.clone_ref(self.py()))
+    }
+    #[getter(_payload_writer)]
+    fn get_payload_writer_prop(&self) -> PyResult<Py<PayloadWriter>> {
+        Ok(self.writer.clone_ref(self.py()))
     }
     #[getter]
-    fn get_writer(&self, py: Python) -> PyResult<PayloadWriter> {
-        Ok(self._writer.clone_ref(py))
+    fn get_writer(&self) -> PyResult<Py<PayloadWriter>> {
+        Ok(self.writer.clone_ref(self.py()))
     }
     #[getter]
-    fn get_time_service(&self, py: Python) -> PyResult<PyObject> {
-        Ok(self._time_service.clone_ref(py))
+    fn get_time_service(&self) -> PyResult<PyObject> {
+        Ok(self.time_service.clone_ref(self.py()))
     }
     #[setter]
-    fn set_time_service(&mut self, py: Python, value: &PyObject) -> PyResult<()> {
-        self._time_service = value.clone_ref(py);
+    fn set_time_service(&mut self, value: PyObject) -> PyResult<()> {
+        self.time_service = value;
         Ok(())
     }
 
-    fn _prepare_hook(&self, py: Python, _resp: &PyObject) -> PyResult<PyObject> {
-        Ok(PyFuture::done_fut(py, self._loop, py.None())?.into())
+    ///
+    /// Hand connection of upgrade request over to new protocol, e.g. after
+    /// "101 Switching Protocols" or CONNECT response is sent. Connection is
+    /// switched once responses are written, data received after request
+    /// headers is passed to protocol first. Returns future with
+    /// (transport, protocol), http protocol gets connection_lost(None).
+    ///
+    fn detach(&self, py: Python, protocol_factory: PyObject) -> PyResult<Py<PyFuture>> {
+        if !self.upgrade {
+            return Err(exc::RuntimeError::new("Request is not upgrade or CONNECT request"))
+        }
+        self.transport.as_mut(py).detach(py, Handover::Protocol(protocol_factory))
+    }
+
+    ///
+    /// Complete websocket handshake, "101 Switching Protocols" is sent and
+    /// connection is handed over to websocket transport. First of client
+    /// subprotocols which is listed in protocols is selected, max_size limits
+    /// size of received message. permessage-deflate is used if compress is set
+    /// and client offers it. Returns future with WebSocketTransport.
+    ///
+    #[args(protocols="None", max_size="4194304", compress=true)]
+    fn accept_websocket(&self, py: Python, protocols: Option<&PyObjectRef>,
+                        max_size: usize, compress: bool) -> PyResult<Py<PyFuture>> {
+        let mut names = Vec::new();
+        if let Some(protocols) = protocols {
+            for name in protocols.iter()? {
+                names.push(name?.extract::<String>()?);
+            }
+        }
+
+        let method = self.method.as_ref(py).to_string()?;
+        let hs = websocket::handshake(
+            &method, self.http_version, self.upgrade,
+            self.headers.as_ref(py).headers(), &names, compress)
+            .map_err(|err| WebSocketError::new(format!("{}", err)))?;
+
+        self.writer.as_mut(py).write_upgrade(py, Bytes::from(hs.response()))?;
+        self.transport.as_mut(py).detach(
+            py, Handover::WebSocket(websocket::Options {
+                protocol: hs.protocol, max_size: max_size, deflate: hs.deflate}))
+    }
+
+    ///
+    /// Send file as complete response. Content-Type is guessed from file
+    /// extension unless content_type is set. File content is read and sent
+    /// by connection, it is not passed through python. OSError is raised
+    /// if file can not be opened. Returns drain future.
+    ///
+    #[args(content_type="None", status="200")]
+    fn send_file(&self, py: Python, path: &PyObjectRef,
+                 content_type: Option<&str>, status: u16) -> PyResult<Py<PyFuture>> {
+        let path = match PyString::try_from(path) {
+            Ok(path) => path.to_string()?.into_owned(),
+            Err(_) => format!("{}", path),
+        };
+        let head = self.method.as_ref(py).to_string()? == "HEAD";
+        self.writer.as_mut(py).send_file(py, Path::new(&path), content_type, status, !head)
+    }
+
+    fn _prepare_hook(&self, py: Python, _resp: &PyObjectRef) -> PyResult<Py<PyFuture>> {
+        PyFuture::done_fut(py, self.evloop.clone_ref(py), py.None())
+    }
+
+    ///
+    /// read request body and parse it as json. body size is limited by max_size
+    /// and checked while data arrives, loads defaults to json.loads
+    ///
+    #[args(max_size="1048576", loads="None")]
+    fn json(&self, py: Python, max_size: usize, loads: Option<PyObject>)
+            -> PyResult<Py<PyFuture>> {
+        let loads = loads.unwrap_or_else(|| Classes.JsonLoads.clone_ref(py));
+        let fut = PyFuture::new(py, self.evloop.clone_ref(py))?;
+        self.content.as_mut(py).read_json(py, fut.clone_ref(py), max_size, loads)?;
+        Ok(fut)
     }
 }
 
 
 impl PyRequest {
 
-    pub fn new(py: Python, req: Request,
-               evloop: &TokioEventLoop, sender: Sender<EncoderMessage>) -> PyResult<PyRequest> {
-        let conn = req.connection;
-        let meth = PyString::new(py, req.method());
-        let path = PyString::new(py, req.path());
-        let url = Url::new(py, path.clone_ref(py))?;
+    pub fn new(py: Python, req: Request, evloop: &TokioEventLoop,
+               sender: BoundedSender<EncoderMessage>, transport: Py<PyHttpTransport>)
+               -> PyResult<Py<PyRequest>> {
+        // response to last allowed request closes connection
+        let last = transport.as_ref(py).last_request();
+        let conn = if last { ConnectionType::Close } else { req.connection };
+        let chunking = req.version == Version::Http11 && req.method() != "HEAD";
+        let upgrade = req.upgrade;
+        let meth = Strings.method(py, req.method());
+        let url = Url::new(py, &RequestTarget::parse(req.path()))?;
         let version = match req.version {
-            Version::Http10 => PyTuple::new(
-                py, &[(1 as i32).to_py_object(py).into_object(),
-                      (0 as i32).to_py_object(py).into_object()]),
-            Version::Http11 => PyTuple::new(
-                py, &[(1 as i32).to_py_object(py).into_object(),
-                      (1 as i32).to_py_object(py).into_object()]),
+            Version::Http10 => (1, 0).into_tuple(py),
+            Version::Http11 => (1, 1).into_tuple(py),
         };
+        let span = Span::new(py, format!("{} {}", req.method(), req.path()))?;
+        transport.as_mut(py).request_started(py, &span);
         let content = StreamReader::new(py, evloop)?;
-        let headers = RawHeaders::create_instance(py, req.headers)?;
-        let writer = PayloadWriter::new(py, evloop, sender)?;
+        let encoding = transport.as_ref(py).header_encoding();
+        let headers = RawHeaders::new(py, req.headers, encoding)?;
+        let peername = transport.as_ref(py).extra_info(py, "peername")
+            .unwrap_or_else(|| py.None());
+        let writer = PayloadWriter::new(
+            py, evloop, sender, transport.clone_ref(py), content.clone_ref(py),
+            chunking, last, upgrade, req.method() == "CONNECT")?;
+
+        py.init(|token| PyRequest {
+            evloop: evloop.into(),
+            connection: conn,
+            upgrade: upgrade,
+            http_version: req.version,
+            transport: transport,
+            method: meth,
+            url: url,
+            query: None,
+            version: version,
+            headers: headers,
+            content: content,
+            span: span,
+            match_info: py.None(),
+            writer: writer,
+            time_service: py.None(),
+            peername: peername,
+            token: token})
+    }
 
-        PyRequest::create_instance(
-            py, evloop.clone_ref(py), conn, meth, url, path,
-            version, headers, content, py.None(), writer, py.None())
+    pub fn content(&self, py: Python) -> Py<StreamReader> {
+        self.content.clone_ref(py)
     }
 
-    pub fn content(&self) -> &StreamReader {
-        self._content(GIL::python())
+    pub fn writer(&self, py: Python) -> Py<PayloadWriter> {
+        self.writer.clone_ref(py)
     }
 }
 
-#[py::class]
+
+// connection stops reading request body while this much is not consumed
+const PAYLOAD_HIGH_WATER: usize = 256 * 1024;
+const PAYLOAD_LOW_WATER: usize = 64 * 1024;
+
+
+#[py::class(weakref)]
 pub struct StreamReader {
-    _loop: TokioEventLoopPtr,
-    _size: usize,
-    _total_bytes: usize,
-    _eof: bool,
-    _eof_waiter: Option<PyFuturePtr>,
-    _waiter: Option<PyFuturePtr>,
-    _buffer: VecDeque<pybytes::PyBytesPtr>,
-    _exception: Option<PyObject>,
+    evloop: Py<TokioEventLoop>,
+    size: usize,
+    total_bytes: usize,
+    // connection task which waits for body to be consumed
+    paused: Option<task::Task>,
+    // read() or json() collects whole body
+    unbounded: bool,
+    // response is finished, rest of body is dropped
+    discard: bool,
+    eof: bool,
+    eof_waiter: Option<Py<PyFuture>>,
+    waiter: Option<Py<PyFuture>>,
+    buffer: VecDeque<Py<pybytes::PyBytes>>,
+    exception: Option<PyObject>,
+    token: PyToken,
 }
 
 
 #[py::methods]
 impl StreamReader {
+
     #[getter]
-    fn get_total_bytes(&self, py: Python) -> PyResult<usize> {
-        Ok(self._total_bytes)
+    fn get_total_bytes(&self) -> PyResult<usize> {
+        Ok(self.total_bytes)
     }
 
     fn exception(&self, py: Python) -> PyResult<PyObject> {
-        if let Some(ref exc) = self._exception {
+        if let Some(ref exc) = self.exception {
             Ok(exc.clone_ref(py))
         } else {
             Ok(py.None())
         }
     }
 
-    fn on_eof(&self, py: Python) -> PyResult<()> {
+    fn on_eof(&self) -> PyResult<()> {
         Ok(())
     }
 
-    fn is_eof(&self, py: Python) -> PyResult<bool> {
-        Ok(*self._eof(py))
+    fn is_eof(&self) -> PyResult<bool> {
+        Ok(self.eof)
     }
 
-    fn at_eof(&self, py: Python) -> PyResult<bool> {
-        Ok(*self._eof(py) && self._buffer(py).len() == 0)
+    fn at_eof(&self) -> PyResult<bool> {
+        Ok(self.eof && self.buffer.is_empty())
     }
 
-    fn wait_eof(&self, py: Python) -> PyResult<PyObject> {
-        let fut = if let Some(ref fut) = *self._eof_waiter(py) {
-            Some(fut.clone_ref(py))
-        } else {
-            None
-        };
+    fn wait_eof(&mut self, py: Python) -> PyResult<Py<PyFuture>> {
+        if self.eof {
+            return PyFuture::done_fut(py, self.evloop.clone_ref(py), py.None())
+        }
 
-        if let Some(fut) = fut {
-            Ok(fut.into_object())
-        } else {
-            let fut = PyFuture::new(py, self._loop(py))?;
-            *self._eof_waiter_mut(py) = Some(fut.clone_ref(py));
-            Ok(fut.into_object())
+        if let Some(ref fut) = self.eof_waiter {
+            return Ok(fut.clone_ref(py))
         }
+
+        let fut = PyFuture::new(py, self.evloop.clone_ref(py))?;
+        self.eof_waiter = Some(fut.clone_ref(py));
+        Ok(fut)
     }
 
     fn unread_data(&self, py: Python) -> PyResult<PyObject> {
@@ -198,102 +389,47 @@ impl StreamReader {
         Ok(py.None())
     }
 
-    #[defaults(n="-1")]
-    fn read(&self, py: Python, n: isize) -> PyResult<PyObject> {
+    #[args(n="-1")]
+    fn read(&mut self, py: Python, n: isize) -> PyResult<Py<PyFuture>> {
+        self.check_exception(py)?;
+
         if n == 0 {
-            let chunk = pybytes::PyBytes::new(py, Bytes::new())?.into_object();
-            Ok(PyFuture::done_fut(py, self._loop(py), chunk)?.into_object())
+            let chunk = pybytes::PyBytes::new(py, Bytes::new())?;
+            PyFuture::done_fut(py, self.evloop.clone_ref(py), chunk.into())
         } else if n < 0 {
-            let fut = PyFuture::new(py, self._loop(py))?;
-            let fut_read = fut.clone_ref(py);
-
-            let stream = self.clone_ref(py).collect().and_then(move |chunks| {
-                let gil = Python::acquire_gil();
-                let py = gil.python();
-
-                let mut size = 0;
-                for chunk in chunks.iter() {
-                    if let &Ok(ref chunk) = chunk {
-                        size += chunk.len();
-                    }
-                }
-
-                let mut buf = BytesMut::with_capacity(size);
-                for chunk in chunks {
-                    chunk.unwrap().extend_into(py, &mut buf);
-                }
-                fut_read.set(py, pybytes::PyBytes::new(
-                    py, buf.freeze()).map(|b| b.into_object()));
-                future::ok(())
-            });
-            self._loop(py).href().spawn(stream.map_err(|_| {}));
-
-            Ok(fut.into_object())
-
-        } else {
-            let fut = PyFuture::new(py, self._loop(py))?;
-            let fut_read = fut.clone_ref(py);
-            *self._waiter_mut(py) = Some(fut.clone_ref(py));
-
+            let fut = PyFuture::new(py, self.evloop.clone_ref(py))?;
+            self.read_all(py, fut.clone_ref(py))?;
+            Ok(fut)
+        } else if self.buffer.is_empty() && !self.eof {
             // wait until we get more data
-            let stream = self.clone_ref(py);
-            let waiter = fut.clone_ref(py).and_then(move |_| {
-                with_py(|py| fut_read.set(
-                    py, stream._read_nowait(py, n).map(|b| b.into_object())));
-                future::ok(())
-            });
-            self._loop(py).href().spawn(waiter.map_err(|_| {}));
-
-            Ok(fut.into_object())
+            self.wait(py, move |py, stream| stream._read_nowait(py, n))
+        } else {
+            let chunk = self._read_nowait(py, n)?;
+            PyFuture::done_fut(py, self.evloop.clone_ref(py), chunk.into())
         }
     }
 
-    fn readany(&self, py: Python) -> PyResult<PyObject> {
-        if let Some(ref exc) = *self._exception(py).borrow() {
-            Err(PyErr::from_instance(py, exc.clone_ref(py)))
-        } else if self._buffer(py).borrow().is_empty() && !*self._eof(py) {
-            let fut = PyFuture::new(py, self._loop(py))?;
-            let fut_read = fut.clone_ref(py);
-            *self._waiter_mut(py) = Some(fut.clone_ref(py));
+    fn readany(&mut self, py: Python) -> PyResult<Py<PyFuture>> {
+        self.check_exception(py)?;
 
+        if self.buffer.is_empty() && !self.eof {
             // wait until we get more data
-            let stream = self.clone_ref(py);
-            let waiter = fut.clone_ref(py).and_then(move |_| {
-                with_py(|py| fut_read.set(py, stream._read_nowait(py, -1)
-                                          .map(|b| b.into_object())));
-                future::ok(())
-            }).map_err(|_| {});
-            self._loop(py).href().spawn(waiter);
-
-            Ok(fut.into_object())
+            self.wait(py, |py, stream| stream._read_nowait(py, -1))
         } else {
             let chunk = self._read_nowait(py, -1)?;
-            Ok(PyFuture::done_fut(py, self._loop(py), chunk.into_object())?.into_object())
+            PyFuture::done_fut(py, self.evloop.clone_ref(py), chunk.into())
         }
     }
 
-    fn readchunk(&self, py: Python) -> PyResult<PyObject> {
-        if let Some(ref exc) = *self._exception(py).borrow() {
-            Err(PyErr::from_instance(py, exc.clone_ref(py)))
-        } else if self._buffer(py).borrow().is_empty() && !*self._eof(py) {
-            let fut = PyFuture::new(py, self._loop(py))?;
-            let fut_read = fut.clone_ref(py);
-            *self._waiter_mut(py) = Some(fut.clone_ref(py));
+    fn readchunk(&mut self, py: Python) -> PyResult<Py<PyFuture>> {
+        self.check_exception(py)?;
 
+        if self.buffer.is_empty() && !self.eof {
             // wait until we get more data
-            let stream = self.clone_ref(py);
-            let waiter = fut.clone_ref(py).and_then(move |_| {
-                with_py(|py|
-                        fut_read.set(
-                            py, stream._read_nowait_chunk(py, -1).map(|b| b.into_object())));
-                future::ok(())
-            }).map_err(|_| {});
-            self._loop(py).href().spawn(waiter);
-
-            Ok(fut.into_object())
+            self.wait(py, |py, stream| stream._read_nowait_chunk(py, -1))
         } else {
             let chunk = self._read_nowait_chunk(py, -1)?;
-            Ok(PyFuture::done_fut(py, self._loop(py), chunk.into_object())?.into_object())
+            PyFuture::done_fut(py, self.evloop.clone_ref(py), chunk.into())
         }
     }
 
@@ -301,15 +437,15 @@ impl StreamReader {
         Ok(py.None())
     }
 
-    #[defaults(n="-1")]
-    fn read_nowait(&self, py: Python, n: isize) -> PyResult<PyObject> {
-        if let Some(ref exc) = *self._exception(py).borrow() {
-            Err(PyErr::from_instance(py, exc.clone_ref(py)))
-        } else if let Some(_) = *self._waiter(py).borrow() {
-            Err(PyErr::new::<exc::RuntimeError, _>(
-                py, "Called while some coroutine is waiting for incoming data."))
+    #[args(n="-1")]
+    fn read_nowait(&mut self, py: Python, n: isize) -> PyResult<Py<pybytes::PyBytes>> {
+        self.check_exception(py)?;
+
+        if let Some(_) = self.waiter {
+            Err(exc::RuntimeError::new(
+                "Called while some coroutine is waiting for incoming data."))
         } else {
-            self._read_nowait(py, n).map(|b| b.into_object())
+            self._read_nowait(py, n)
         }
     }
 }
@@ -317,317 +453,955 @@ impl StreamReader {
 
 impl StreamReader {
 
-    fn new(py: Python, evloop: &TokioEventLoop) -> PyResult<StreamReader> {
-        StreamReader::create_instance(
-            py, evloop.clone_ref(py), 0, 0, false, None, None, VecDeque::new(), None)
+    fn new(py: Python, evloop: &TokioEventLoop) -> PyResult<Py<StreamReader>> {
+        py.init(|token| StreamReader {
+            evloop: evloop.into(),
+            size: 0,
+            total_bytes: 0,
+            paused: None,
+            unbounded: false,
+            discard: false,
+            eof: false,
+            eof_waiter: None,
+            waiter: None,
+            buffer: VecDeque::new(),
+            exception: None,
+            token: token})
     }
 
-    pub fn set_exception(&self) {
+    pub fn set_exception(&mut self, py: Python, exc: PyObject) {
+        self.exception = Some(exc);
+
+        if let Some(fut) = self.waiter.take() {
+            fut.as_mut(py).set(py, Ok(py.None()));
+        }
     }
 
-    pub fn feed_eof(&self, py: Python) {
-        *self._eof_mut(py) = true
+    pub fn feed_eof(&mut self, py: Python) {
+        self.eof = true;
+
+        if let Some(fut) = self.waiter.take() {
+            fut.as_mut(py).set(py, Ok(py.None()));
+        }
+        if let Some(fut) = self.eof_waiter.take() {
+            fut.as_mut(py).set(py, Ok(py.None()));
+        }
     }
 
-    pub fn feed_data(&self, py: Python, bytes: pybytes::PyBytes) {
-        let total_bytes = self._total_bytes_mut(py);
-        *total_bytes = *total_bytes + bytes.len();
-        self._buffer_mut(py).push_back(bytes);
+    pub fn feed_data(&mut self, py: Python, bytes: Py<pybytes::PyBytes>) {
+        let len = bytes.as_ref(py).len();
+        self.total_bytes += len;
+        if self.discard {
+            return
+        }
+        self.size += len;
+        self.buffer.push_back(bytes);
 
-        if let Some(fut) = self._waiter_mut(py).take() {
-            let _ = fut.set(py, Ok(py.None()));
+        if let Some(fut) = self.waiter.take() {
+            fut.as_mut(py).set(py, Ok(py.None()));
         }
     }
 
-    pub fn _read_nowait_chunk(&self, py: Python, n: isize) -> PyResult<pybytes::PyBytes> {
-        let size = if n < 0 { 0 } else { n as usize };
-        let mut buffer = self._buffer_mut(py);
+    ///
+    /// Too much unconsumed data is buffered, connection task is
+    /// notified once buffer size falls below low watermark
+    ///
+    pub fn paused(&mut self) -> bool {
+        if self.size > PAYLOAD_HIGH_WATER && !self.unbounded && !self.discard {
+            self.paused = Some(task::current());
+            true
+        } else {
+            false
+        }
+    }
 
-        let first_chunk = buffer.pop_front().unwrap();
-        let result = if n != -1 && first_chunk.len() > size {
-            buffer.push_front(first_chunk.slice_from(py, size)?);
-            first_chunk.slice_to(py, size)?
+    ///
+    /// Response is finished, unread body is not needed anymore
+    ///
+    pub fn release(&mut self) {
+        if self.waiter.is_none() {
+            self.discard = true;
+            self.buffer.clear();
+            self.size = 0;
         } else {
-            first_chunk
+            self.unbounded = true;
+        }
+        self.maybe_resume();
+    }
+
+    fn maybe_resume(&mut self) {
+        if self.size <= PAYLOAD_LOW_WATER || self.unbounded || self.discard {
+            if let Some(task) = self.paused.take() {
+                task.notify();
+            }
+        }
+    }
+
+    fn check_exception(&self, py: Python) -> PyResult<()> {
+        if let Some(ref exc) = self.exception {
+            Err(PyErr::from_instance(exc.as_ref(py)))
+        } else {
+            Ok(())
+        }
+    }
+
+    //
+    // wait for incoming data, then resolve result future with f()
+    //
+    fn wait<F>(&mut self, py: Python, f: F) -> PyResult<Py<PyFuture>>
+        where F: FnOnce(Python, &mut StreamReader) -> PyResult<Py<pybytes::PyBytes>> + 'static
+    {
+        let fut = PyFuture::new(py, self.evloop.clone_ref(py))?;
+        let fut_read = fut.clone_ref(py);
+        let stream: Py<StreamReader> = self.into();
+
+        let waiter: PyFut = self.waiter(py)?.into();
+        self.evloop.as_ref(py).href().spawn(
+            waiter.then(move |_| {
+                let gil = Python::acquire_gil();
+                let py = gil.python();
+
+                let stream = stream.as_mut(py);
+                let res = match stream.check_exception(py) {
+                    Ok(_) => f(py, stream).map(|b| b.into()),
+                    Err(err) => Err(err),
+                };
+                fut_read.as_mut(py).set(py, res);
+                Ok(())
+            }));
+
+        Ok(fut)
+    }
+
+    //
+    // read everything until eof
+    //
+    fn read_all(&mut self, py: Python, fut: Py<PyFuture>) -> PyResult<()> {
+        self.unbounded = true;
+        self.maybe_resume();
+        if self.eof {
+            let res = self._read_nowait(py, -1).map(|b| b.into());
+            fut.as_mut(py).set(py, res);
+            return Ok(())
+        }
+
+        let stream: Py<StreamReader> = self.into();
+        let waiter: PyFut = self.waiter(py)?.into();
+        self.evloop.as_ref(py).href().spawn(
+            waiter.then(move |_| {
+                let gil = Python::acquire_gil();
+                let py = gil.python();
+
+                let res = match stream.as_ref(py).check_exception(py) {
+                    Ok(_) => stream.as_mut(py).read_all(py, fut.clone_ref(py)),
+                    Err(err) => Err(err),
+                };
+                if let Err(err) = res {
+                    fut.as_mut(py).set(py, Err(err));
+                }
+                Ok(())
+            }));
+        Ok(())
+    }
+
+    //
+    // read everything until eof, validate utf-8 and parse with loads
+    //
+    fn read_json(&mut self, py: Python, fut: Py<PyFuture>,
+                 max_size: usize, loads: PyObject) -> PyResult<()> {
+        if self.total_bytes > max_size {
+            return Err(exc::ValueError::new(
+                format!("Request body is too large, max size is {}", max_size)))
+        }
+        self.unbounded = true;
+        self.maybe_resume();
+
+        if self.eof {
+            let mut body = BytesMut::with_capacity(self.size);
+            for chunk in self.buffer.drain(..) {
+                chunk.as_ref(py).extend_into(&mut body);
+            }
+            self.size = 0;
+
+            let res = match str::from_utf8(&body) {
+                Ok(text) => loads.call1(py, (text,)),
+                Err(err) => Err(exc::ValueError::new(
+                    format!("Request body is not valid utf-8: {}", err))),
+            };
+            fut.as_mut(py).set(py, res);
+            return Ok(())
+        }
+
+        let stream: Py<StreamReader> = self.into();
+        let waiter: PyFut = self.waiter(py)?.into();
+        self.evloop.as_ref(py).href().spawn(
+            waiter.then(move |_| {
+                let gil = Python::acquire_gil();
+                let py = gil.python();
+
+                let res = match stream.as_ref(py).check_exception(py) {
+                    Ok(_) => stream.as_mut(py).read_json(
+                        py, fut.clone_ref(py), max_size, loads),
+                    Err(err) => Err(err),
+                };
+                if let Err(err) = res {
+                    fut.as_mut(py).set(py, Err(err));
+                }
+                Ok(())
+            }));
+        Ok(())
+    }
+
+    fn waiter(&mut self, py: Python) -> PyResult<Py<PyFuture>> {
+        let fut = PyFuture::new(py, self.evloop.clone_ref(py))?;
+        self.waiter = Some(fut.clone_ref(py));
+        Ok(fut)
+    }
+
+    pub fn _read_nowait_chunk(&mut self, py: Python, n: isize) -> PyResult<Py<pybytes::PyBytes>> {
+        let chunk = match self.buffer.pop_front() {
+            Some(chunk) => chunk,
+            None => return pybytes::PyBytes::new(py, Bytes::new()),
+        };
+
+        let result = if n >= 0 && chunk.as_ref(py).len() > n as usize {
+            self.buffer.push_front(chunk.as_ref(py).slice_from(py, n as usize)?);
+            chunk.as_ref(py).slice_to(py, n as usize)?
+        } else {
+            chunk
         };
 
-        *self._size_mut(py) = *self._size(py) - result.len();
+        self.size -= result.as_ref(py).len();
+        self.maybe_resume();
         Ok(result)
     }
 
-    pub fn _read_nowait(&self, py: Python, n: isize) -> PyResult<pybytes::PyBytes> {
+    pub fn _read_nowait(&mut self, py: Python, n: isize) -> PyResult<Py<pybytes::PyBytes>> {
         let mut size = 0;
         let mut chunks = Vec::new();
-        let mut counter = if n < 0 { 0 } else { n as usize };
-        let mut buffer = self._buffer_mut(py);
-
-        loop {
-            if let Some(chunk) = buffer.pop_front() {
-                let result = if n != -1 && chunk.len() > counter {
-                    buffer.push_front(chunk.slice_from(py, counter)?);
-                    chunk.slice_to(py, counter)?
-                } else {
-                    chunk
-                };
-                size += result.len();
-                if counter > 0 {
-                    counter = counter - result.len()
-                } else {
-                    break
-                }
-            } else {
+
+        while let Some(chunk) = self.buffer.pop_front() {
+            let len = chunk.as_ref(py).len();
+
+            if n >= 0 && size + len > n as usize {
+                let rest = n as usize - size;
+                self.buffer.push_front(chunk.as_ref(py).slice_from(py, rest)?);
+                chunks.push(chunk.as_ref(py).slice_to(py, rest)?);
+                size += rest;
+                break
+            }
+
+            size += len;
+            chunks.push(chunk);
+            if n >= 0 && size == n as usize {
                 break
             }
         }
+        self.size -= size;
+        self.maybe_resume();
 
         if chunks.len() == 1 {
-            Ok(pybytes::PyBytes::new(py, chunks.pop().unwrap())?)
+            Ok(chunks.pop().unwrap())
         } else {
             let mut buf = BytesMut::with_capacity(size);
             for chunk in chunks {
-                buf.extend(chunk);
+                chunk.as_ref(py).extend_into(&mut buf);
             }
-            Ok(pybytes::PyBytes::new(py, buf.freeze())?)
+            pybytes::PyBytes::new(py, buf.freeze())
         }
     }
 }
 
+
 #[py::class]
 pub struct RawHeaders {
     headers: Headers,
+    encoding: HeaderEncoding,
+    token: PyToken,
 }
 
 #[py::methods]
 impl RawHeaders {
 
+    ///
+    /// list of (name, value) pairs, decoded according to header encoding
+    ///
     fn items(&self, py: Python) -> PyResult<PyObject> {
         let mut items = Vec::new();
+        for (name, value) in self.headers.raw_headers() {
+            items.push((self.decode_name(py, name)?, self.decode(py, value)?).to_object(py));
+        }
+        Ok(PyList::new(py, items.as_slice()).into())
+    }
+
+    ///
+    /// list of (name, value) pairs as received, both are bytes
+    ///
+    fn raw_items(&self, py: Python) -> PyResult<PyObject> {
+        let items: Vec<PyObject> = self.headers.raw_headers()
+            .into_iter()
+            .map(|(name, value)| (PyBytes::new(py, name), PyBytes::new(py, value)).to_object(py))
+            .collect();
+
+        Ok(PyList::new(py, items.as_slice()).into())
+    }
 
-        for (name, value) in self.headers(py).headers() {
-            items.push(
-                (PyString::new(py, name.as_str()),
-                 PyString::new(py, value.as_str())).to_py_object(py).into_object());
+    ///
+    /// list of header names in received order
+    ///
+    fn keys(&self, py: Python) -> PyResult<PyObject> {
+        let mut keys = Vec::new();
+        for (name, _) in self.headers.raw_headers() {
+            keys.push(self.decode_name(py, name)?);
         }
-        Ok(PyList::new(py, items.as_slice()).into_object())
+        Ok(PyList::new(py, keys.as_slice()).into())
     }
 
-    fn get(&self, py: Python, key: &PyString, default: Option<PyObject>) -> PyResult<PyObject> {
-        let key = key.to_string(py)?;
-        if let Some(val) = self.headers(py).get(key.borrow()) {
-            Ok(PyString::new(py, val).into_object())
+    fn values(&self, py: Python) -> PyResult<PyObject> {
+        let mut values = Vec::new();
+        for (_, value) in self.headers.raw_headers() {
+            values.push(self.decode(py, value)?);
+        }
+        Ok(PyList::new(py, values.as_slice()).into())
+    }
+
+    ///
+    /// first value of header, names are case-insensitive
+    ///
+    fn get(&self, py: Python, key: &str, default: Option<PyObject>) -> PyResult<PyObject> {
+        if let Some(val) = self.headers.get_raw(key) {
+            self.decode(py, val)
         } else {
-            if let Some(default) = default {
-                Ok(default)
-            } else {
-                Ok(py.None())
+            match default {
+                Some(default) => Ok(default),
+                None => Ok(py.None()),
             }
         }
     }
 
-    fn __getitem__(&self, py: Python, key: PyString) -> PyResult<PyObject> {
-        let key = key.to_string(py)?;
-        if let Some(val) = self.headers(py).get(key.borrow()) {
-            Ok(PyString::new(py, val).into_object())
+    fn getone(&self, py: Python, key: &str, default: Option<PyObject>) -> PyResult<PyObject> {
+        match self.headers.get_raw(key) {
+            Some(val) => self.decode(py, val),
+            None => default.ok_or_else(|| exc::KeyError::new(key.to_owned())),
+        }
+    }
+
+    ///
+    /// list of all values of repeated header, KeyError is raised
+    /// if header is missing and default is not provided
+    ///
+    fn getall(&self, py: Python, key: &str, default: Option<PyObject>) -> PyResult<PyObject> {
+        let raw = self.headers.get_all_raw(key);
+        if raw.is_empty() {
+            return default.ok_or_else(|| exc::KeyError::new(key.to_owned()))
+        }
+        let mut values = Vec::with_capacity(raw.len());
+        for val in raw {
+            values.push(self.decode(py, val)?);
+        }
+        Ok(PyList::new(py, values.as_slice()).into())
+    }
+
+    ///
+    /// header value as received, without decoding
+    ///
+    fn getraw(&self, py: Python, key: &str, default: Option<PyObject>) -> PyResult<PyObject> {
+        if let Some(val) = self.headers.get_raw(key) {
+            Ok(PyBytes::new(py, val).into())
         } else {
-            Err(PyErr::new::<exc::KeyError, _>(py, "item not found"))
+            match default {
+                Some(default) => Ok(default),
+                None => Ok(py.None()),
+            }
         }
     }
 
-    fn __contains__(&self, py: Python, key: PyString) -> PyResult<bool> {
-        let key = key.to_string(py)?;
-        if let Some(_) = self.headers(py).get(key.borrow()) {
-            Ok(true)
+    #[getter]
+    fn get_encoding(&self) -> PyResult<&'static str> {
+        Ok(match self.encoding {
+            HeaderEncoding::Latin1 => "latin-1",
+            HeaderEncoding::Utf8 => "utf-8",
+            HeaderEncoding::Bytes => "bytes",
+        })
+    }
+}
+
+#[py::proto]
+impl PyMappingProtocol for RawHeaders {
+
+    fn __len__(&self) -> PyResult<usize> {
+        Ok(self.headers.len())
+    }
+
+    fn __getitem__(&self, key: &str) -> PyResult<PyObject> {
+        if let Some(val) = self.headers.get_raw(key) {
+            self.decode(self.py(), val)
         } else {
-            Ok(false)
+            Err(exc::KeyError::new("item not found"))
         }
     }
 }
 
-impl RawHeaders {
-    pub fn new(py: Python, headers: Headers) -> PyResult<RawHeaders> {
-        RawHeaders::create_instance(py, headers)
+#[py::proto]
+impl PySequenceProtocol for RawHeaders {
+
+    fn __contains__(&self, key: &str) -> PyResult<bool> {
+        Ok(self.headers.get_raw(key).is_some())
     }
 }
 
+#[py::proto]
+impl PyIterProtocol for RawHeaders {
 
-impl Stream for StreamReader {
-    type Item = PyResult<pybytes::PyBytes>;
-    type Error = ();
+    fn __iter__(&mut self) -> PyResult<PyObject> {
+        let py = self.py();
+        self.keys(py)?.call_method0(py, "__iter__")
+    }
+}
 
-    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
-        let gil = Python::acquire_gil();
-        let py = gil.python();
+impl RawHeaders {
+    pub fn new(py: Python, headers: Headers, encoding: HeaderEncoding)
+               -> PyResult<Py<RawHeaders>> {
+        py.init(|token| RawHeaders {headers: headers, encoding: encoding, token: token})
+    }
 
-        if *self._size(py) > 0 {
-            Ok(Async::Ready(Some(self._read_nowait_chunk(py, -1))))
-        } else {
-            let mut fut = if let Some(fut) = self._waiter_mut(py).take() {
-                fut
-            } else {
-                PyFuture::new(py, self._loop(py)).unwrap()
-            };
+    pub fn headers(&self) -> &Headers {
+        &self.headers
+    }
 
-            match fut.poll() {
-                Ok(Async::Ready(_)) => {
-                    self.poll()
-                },
-                Ok(Async::NotReady) => Ok(Async::NotReady),
-                Err(_) => Err(())
-            }
+    fn decode(&self, py: Python, val: &[u8]) -> PyResult<PyObject> {
+        match self.encoding {
+            HeaderEncoding::Latin1 => {
+                let s: String = val.iter().map(|b| *b as char).collect();
+                Ok(PyString::new(py, &s).into())
+            },
+            HeaderEncoding::Utf8 => match str::from_utf8(val) {
+                Ok(s) => Ok(PyString::new(py, s).into()),
+                Err(_) => PyBytes::new(py, val).to_object(py)
+                    .call_method1(py, "decode", ("utf-8", "surrogateescape")),
+            },
+            HeaderEncoding::Bytes => Ok(PyBytes::new(py, val).into()),
+        }
+    }
+
+    fn decode_name(&self, py: Python, name: &[u8]) -> PyResult<PyObject> {
+        match (self.encoding, str::from_utf8(name)) {
+            (HeaderEncoding::Bytes, _) => Ok(PyBytes::new(py, name).into()),
+            (_, Ok(name)) => Ok(Strings.header(py, name).into()),
+            (_, Err(_)) => self.decode(py, name),
         }
     }
 }
 
+
 #[py::class]
 pub struct Url {
-    path: PyString
+    path: Py<PyString>,
+    raw_path: Py<PyString>,
+    query_string: Py<PyString>,
+    token: PyToken,
 }
 
 #[py::methods]
 impl Url {
 
     #[getter]
-    fn get_raw_path(&self, py: Python) -> PyResult<PyString> {
-        Ok(self.path(py).clone_ref(py))
+    fn get_path(&self) -> PyResult<Py<PyString>> {
+        Ok(self.path.clone_ref(self.py()))
+    }
+    #[getter]
+    fn get_raw_path(&self) -> PyResult<Py<PyString>> {
+        Ok(self.raw_path.clone_ref(self.py()))
+    }
+    #[getter]
+    fn get_query_string(&self) -> PyResult<Py<PyString>> {
+        Ok(self.query_string.clone_ref(self.py()))
     }
 }
 
 
 impl Url {
-    fn new(py: Python, path: PyString) -> PyResult<Url> {
-        Url::create_instance(py, path)
+    fn new(py: Python, target: &RequestTarget) -> PyResult<Py<Url>> {
+        let path = PyString::new(py, &target.decoded_path());
+        let raw_path = PyString::new(py, target.raw_path);
+        let query_string = PyString::new(py, target.query);
+        py.init(|token| Url {
+            path: path, raw_path: raw_path, query_string: query_string, token: token})
+    }
+}
+
+
+///
+/// Query parameters, case-sensitive mapping with multiple values per key.
+/// Item access returns first value, getall() returns all of them
+///
+#[py::class]
+pub struct Query {
+    items: Vec<(String, String)>,
+    token: PyToken,
+}
+
+#[py::methods]
+impl Query {
+
+    fn get(&self, py: Python, key: &str, default: Option<PyObject>) -> PyResult<PyObject> {
+        match self.items.iter().find(|&&(ref name, _)| name == key) {
+            Some(&(_, ref value)) => Ok(PyString::new(py, value).into()),
+            None => Ok(default.unwrap_or_else(|| py.None())),
+        }
+    }
+
+    fn getone(&self, py: Python, key: &str, default: Option<PyObject>) -> PyResult<PyObject> {
+        match self.items.iter().find(|&&(ref name, _)| name == key) {
+            Some(&(_, ref value)) => Ok(PyString::new(py, value).into()),
+            None => default.ok_or_else(|| exc::KeyError::new(key.to_owned())),
+        }
+    }
+
+    ///
+    /// list of all values of key, KeyError is raised if key
+    /// is missing and default is not provided
+    ///
+    fn getall(&self, py: Python, key: &str, default: Option<PyObject>) -> PyResult<PyObject> {
+        let values: Vec<PyObject> = self.items.iter()
+            .filter(|&&(ref name, _)| name == key)
+            .map(|&(_, ref value)| PyString::new(py, value).into())
+            .collect();
+        if values.is_empty() {
+            default.ok_or_else(|| exc::KeyError::new(key.to_owned()))
+        } else {
+            Ok(PyList::new(py, values.as_slice()).into())
+        }
+    }
+
+    fn keys(&self, py: Python) -> PyResult<PyObject> {
+        let keys: Vec<PyObject> = self.items.iter()
+            .map(|&(ref name, _)| PyString::new(py, name).into())
+            .collect();
+        Ok(PyList::new(py, keys.as_slice()).into())
+    }
+
+    fn values(&self, py: Python) -> PyResult<PyObject> {
+        let values: Vec<PyObject> = self.items.iter()
+            .map(|&(_, ref value)| PyString::new(py, value).into())
+            .collect();
+        Ok(PyList::new(py, values.as_slice()).into())
+    }
+
+    ///
+    /// list of (name, value) pairs in query string order
+    ///
+    fn items(&self, py: Python) -> PyResult<PyObject> {
+        let items: Vec<PyObject> = self.items.iter()
+            .map(|&(ref name, ref value)| (name.as_str(), value.as_str()).to_object(py))
+            .collect();
+        Ok(PyList::new(py, items.as_slice()).into())
+    }
+}
+
+#[py::proto]
+impl PyMappingProtocol for Query {
+
+    fn __len__(&self) -> PyResult<usize> {
+        Ok(self.items.len())
+    }
+
+    fn __getitem__(&self, key: &str) -> PyResult<PyObject> {
+        self.getone(self.py(), key, None)
+    }
+}
+
+#[py::proto]
+impl PySequenceProtocol for Query {
+
+    fn __contains__(&self, key: &str) -> PyResult<bool> {
+        Ok(self.items.iter().any(|&(ref name, _)| name == key))
+    }
+}
+
+#[py::proto]
+impl PyIterProtocol for Query {
+
+    fn __iter__(&mut self) -> PyResult<PyObject> {
+        let py = self.py();
+        self.keys(py)?.call_method0(py, "__iter__")
+    }
+}
+
+impl Query {
+    fn new(py: Python, items: Vec<(String, String)>) -> PyResult<Py<Query>> {
+        py.init(|token| Query {items: items, token: token})
     }
 }
 
 
 const SEP: &'static [u8] = b": ";
 const END: &'static [u8] = b"\r\n";
+const CONNECTION_CLOSE: &'static [u8] = b"Connection: close\r\n";
+const TRANSFER_ENCODING_CHUNKED: &'static [u8] = b"Transfer-Encoding: chunked\r\n";
 
 
 #[py::class]
 pub struct PayloadWriter {
-    _loop: TokioEventLoop,
-    _sender: Option<Sender<EncoderMessage>>,
-    _length: u64,
-    _chunked: bool,
-    _compress: ContentCompression,
+    evloop: Py<TokioEventLoop>,
+    sender: Option<BoundedSender<EncoderMessage>>,
+    transport: Py<PyHttpTransport>,
+    content: Py<StreamReader>,
+    length: u64,
+    chunked: bool,
+    // http/1.1 request which expects response body
+    chunking: bool,
+    compress: ContentCompression,
+    close_connection: bool,
+    // response status of upgrade request is not sent yet
+    upgrade: bool,
+    connect: bool,
+    token: PyToken,
 }
 
 #[py::methods]
 impl PayloadWriter {
 
     #[getter]
-    fn get_length(&self, py: Python) -> PyResult<u64> {
-        Ok(*self._length(py))
+    fn get_length(&self) -> PyResult<u64> {
+        Ok(self.length)
     }
     #[setter]
-    fn set_length(&self, py: Python, value: u64) -> PyResult<()> {
-        *self._length_mut(py) = value;
+    fn set_length(&mut self, value: u64) -> PyResult<()> {
+        self.length = value;
         Ok(())
     }
     #[getter]
-    fn get_output_size(&self, py: Python) -> PyResult<u64> {
-        Ok(*self._length(py))
+    fn get_output_size(&self) -> PyResult<u64> {
+        Ok(self.length)
     }
 
-    fn enable_chunking(&self, py: Python) -> PyResult<PyObject> {
-        *self._chunked_mut(py) = true;
-        Ok(py.None())
+    fn enable_chunking(&mut self) -> PyResult<()> {
+        self.chunked = true;
+        Ok(())
     }
 
-    fn enable_compression(&self, py: Python, encoding: Option<PyString>) -> PyResult<PyObject> {
-        let enc = if let Some(encoding) = encoding {
-            let enc = encoding.to_string(py)?;
-            if enc == "deflate" {
-                ContentCompression::Deflate
-            } else if enc == "gzip" {
-                ContentCompression::Gzip
-            } else {
-                return Err(PyErr::new::<exc::ValueError, _>(py, py.None()));
-            }
-        } else {
-            ContentCompression::Deflate
+    fn enable_compression(&mut self, encoding: Option<&str>) -> PyResult<()> {
+        self.compress = match encoding {
+            Some("deflate") | None => ContentCompression::Deflate,
+            Some("gzip") => ContentCompression::Gzip,
+            Some(enc) => return Err(exc::ValueError::new(
+                format!("Unsupported compression: {}", enc))),
         };
-        *self._compress_mut(py) = enc;
-
-        Ok(py.None())
+        Ok(())
     }
 
-    #[defaults(_drain=true)]
-    fn write(&self, py: Python, chunk: PyBytes, _drain: bool) -> PyResult<PyObject> {
-        self.send_maybe(py, EncoderMessage::PyBytes(chunk));
-        Ok(PyFuture::done_fut(py, self._loop(py), py.None())?.into_object())
+    #[args(_drain=true)]
+    fn write(&mut self, py: Python, chunk: &PyObjectRef, _drain: bool) -> PyResult<Py<PyFuture>> {
+        let msg = self.payload(py, chunk)?;
+        self.send_maybe(msg);
+        if _drain {
+            self.transport.as_mut(py).drain_waiter(py)
+        } else {
+            PyFuture::done_fut(py, self.evloop.clone_ref(py), py.None())
+        }
     }
 
     // Build Request message from status line and headers object
     // status_line - string with \r\n
-    // headers = dict like object
-    fn write_headers(&self, py: Python, status_line: &PyString, headers: &PyObject) -> PyResult<PyObject> {
+    // headers = ResponseHeaders or dict like object
+    // Response without Content-Length is sent with chunked transfer-encoding,
+    // unless request is http/1.0 or HEAD, or status does not allow body
+    fn write_headers(&mut self, status_line: &str, headers: &PyObjectRef) -> PyResult<()> {
         let mut buf = BytesMut::with_capacity(512);
+        let mut has_length = false;
+        let mut has_te = false;
+        self.upgrade_status(status_code(status_line));
+        if !self.chunking || !body_allowed(status_line) {
+            self.chunked = false;
+        }
 
-        buf.extend(status_line.to_string(py)?.as_bytes());
+        buf.extend(status_line.as_bytes());
+
+        // ResponseHeaders is read directly, other objects through items()
+        let pairs = match ResponseHeaders::try_from(headers) {
+            Ok(headers) => headers.pairs().to_vec(),
+            Err(_) => {
+                let mut pairs = Vec::new();
+                let items = headers.call_method0("items")?;
+                for item in items.iter()? {
+                    let item = PyTuple::try_from(item?)?;
+                    if item.len() < 2 {
+                        return Err(exc::ValueError::new(
+                            "headers item should be (name, value) pair"));
+                    }
 
-        let items = headers.call_method(py, "items", NoArgs, None)?;
-        let items = items.call_method(py, "__iter__", NoArgs, None)?;
-        let mut iter = PyIterator::from_object(py, items)?;
-        loop {
-            if let Some(item) = iter.next() {
-                let item = PyTuple::downcast_from(py, item?)?;
-                if item.len(py) < 2 {
-                    return Err(PyErr::new::<exc::ValueError, _>(py, py.None()));
+                    // encode name
+                    let key = PyString::try_from(item.get_item(0))?;
+                    let key = key.to_string()?.into_owned();
+
+                    // get string or convert to string
+                    let value = item.get_item(1);
+                    let value = if let Ok(value) = PyString::try_from(value) {
+                        value.to_string()?.into_owned()
+                    } else {
+                        format!("{}", value)
+                    };
+                    pairs.push((key, value));
                 }
+                pairs
+            }
+        };
 
-                // encode name
-                let key = PyString::downcast_from(py, item.get_item(py, 0))?;
-                buf.extend(key.to_string(py)?.as_bytes());
-                buf.extend(SEP);
+        for (key, value) in pairs {
+            if self.close_connection && key.eq_ignore_ascii_case("connection") {
+                continue
+            }
 
-                // encode value, get string or convert to string
-                let value = item.get_item(py, 1);
-                if let Ok(value) = PyString::downcast_from(py, value.clone_ref(py)) {
-                    buf.extend(value.to_string(py)?.as_bytes());
-                } else {
-                    buf.extend(format!("{}", value).as_bytes());
+            if key.eq_ignore_ascii_case("content-length") {
+                // length is not known for chunked body
+                if self.chunked {
+                    continue
+                }
+                has_length = true;
+            } else if key.eq_ignore_ascii_case("transfer-encoding") {
+                has_te = true;
+                if value.to_ascii_lowercase().contains("chunked") {
+                    self.chunked = self.chunking;
                 }
-
-                buf.extend(END);
-            } else {
-                break
             }
+
+            buf.extend(key.as_bytes());
+            buf.extend(SEP);
+            buf.extend(value.as_bytes());
+            buf.extend(END);
+        }
+        if !has_length && !has_te && self.chunking && body_allowed(status_line) {
+            self.chunked = true;
+        }
+        if self.chunked && !has_te {
+            buf.extend(TRANSFER_ENCODING_CHUNKED);
+        }
+        if self.close_connection {
+            buf.extend(CONNECTION_CLOSE);
         }
         buf.extend(END);
-        self.send_maybe(py, EncoderMessage::Bytes(buf.freeze()));
+        self.send_maybe(EncoderMessage::Bytes(buf.freeze()));
 
-        Ok(py.None())
+        Ok(())
     }
 
-    fn write_eof(&self, py: Python, chunk: Option<PyBytes>) -> PyResult<PyObject> {
+    fn write_eof(&mut self, py: Python, chunk: Option<&PyObjectRef>) -> PyResult<Py<PyFuture>> {
         if let Some(chunk) = chunk {
-            self.send_maybe(py, EncoderMessage::PyBytes(chunk));
+            let msg = self.payload(py, chunk)?;
+            self.send_maybe(msg);
+        }
+        if self.chunked {
+            self.send_maybe(EncoderMessage::EofChunk);
+        }
+        self.finish(py);
+
+        self.transport.as_mut(py).drain_waiter(py)
+    }
+
+    ///
+    /// write complete json response in one message, common python types
+    /// are serialized natively, everything else goes through dumps
+    ///
+    #[args(status="200", dumps="None")]
+    fn send_json(&mut self, py: Python, obj: &PyObjectRef,
+                 status: u16, dumps: Option<PyObject>) -> PyResult<Py<PyFuture>> {
+        self.upgrade_status(Some(status));
+        let mut body = BytesMut::with_capacity(256);
+        if !json::encode(obj, &mut body)? {
+            let dumps = dumps.unwrap_or_else(|| Classes.JsonDumps.clone_ref(py));
+            let data = dumps.call1(py, (obj,))?;
+            let data = data.as_ref(py);
+
+            body.clear();
+            if let Ok(data) = PyBytes::try_from(data) {
+                body.extend(data.data());
+            } else {
+                body.extend(PyString::try_from(data)?.to_string()?.as_bytes());
+            }
         }
-        self._sender_mut(py).take();
 
-        Ok(PyFuture::done_fut(py, self._loop(py), py.None())?.into_object())
+        let mut buf = BytesMut::with_capacity(body.len() + 128);
+        match Strings.status_line(py, status) {
+            Some(line) => buf.extend(line.as_ref(py).to_string()?.as_bytes()),
+            None => buf.extend(format!("HTTP/1.1 {} Unknown\r\n", status).as_bytes()),
+        }
+        buf.extend(b"Content-Type: application/json; charset=utf-8\r\n");
+        if self.close_connection {
+            buf.extend(CONNECTION_CLOSE);
+        }
+        buf.extend(format!("Content-Length: {}\r\n\r\n", body.len()).as_bytes());
+        buf.extend_from_slice(&body);
+
+        self.send_maybe(EncoderMessage::Bytes(buf.freeze()));
+        self.finish(py);
+
+        self.transport.as_mut(py).drain_waiter(py)
     }
 
-    #[defaults(_last=false)]
-    fn drain(&self, py: Python, _last: bool) -> PyResult<PyObject> {
-        Ok(PyFuture::done_fut(py, self._loop(py), py.None())?.into_object())
+    #[args(_last=false)]
+    fn drain(&self, py: Python, _last: bool) -> PyResult<Py<PyFuture>> {
+        self.transport.as_mut(py).drain_waiter(py)
     }
 }
 
 
 impl PayloadWriter {
 
-    pub fn new(py: Python, evloop: &TokioEventLoop,
-               sender: Sender<EncoderMessage>) -> PyResult<PayloadWriter> {
-        PayloadWriter::create_instance(
-            py, evloop.clone_ref(py), Some(sender), 0, false, ContentCompression::Default)
+    pub fn new(py: Python, evloop: &TokioEventLoop, sender: BoundedSender<EncoderMessage>,
+               transport: Py<PyHttpTransport>, content: Py<StreamReader>,
+               chunking: bool, close_connection: bool,
+               upgrade: bool, connect: bool) -> PyResult<Py<PayloadWriter>> {
+        py.init(|token| PayloadWriter {
+            evloop: evloop.into(),
+            sender: Some(sender),
+            transport: transport,
+            content: content,
+            length: 0,
+            chunked: false,
+            chunking: chunking,
+            compress: ContentCompression::Default,
+            close_connection: close_connection,
+            upgrade: upgrade,
+            connect: connect,
+            token: token})
+    }
+
+    // first response status of upgrade request accepts upgrade with
+    // "101 Switching Protocols" or 2xx to CONNECT, response has no body then
+    fn upgrade_status(&mut self, status: Option<u16>) {
+        if self.upgrade {
+            self.upgrade = false;
+            let accepted = match status {
+                Some(101) => true,
+                Some(code) => self.connect && code >= 200 && code < 300,
+                None => false,
+            };
+            if accepted {
+                self.chunking = false;
+            }
+            let py = self.py();
+            self.transport.as_mut(py).upgrade_response(accepted);
+        }
+    }
+
+    // python bytes are sent as is, write buffer content is taken,
+    // other bytes-like objects get copied
+    fn message(py: Python, chunk: &PyObjectRef) -> PyResult<EncoderMessage> {
+        if let Ok(bytes) = PyBytes::try_from_exact(chunk) {
+            Ok(EncoderMessage::PyBytes(bytes.into()))
+        } else if let Ok(wbuf) = pybytes::WriteBuffer::try_from_mut(chunk) {
+            Ok(EncoderMessage::Bytes(wbuf.take_bytes()))
+        } else {
+            let buf = buffer::PyBuffer::get(py, chunk)?;
+            Ok(EncoderMessage::Bytes(Bytes::from(buf.to_vec::<u8>(py)?)))
+        }
+    }
+
+    ///
+    /// Stop accepting response data, returns true if response
+    /// was not finished with write_eof() or send_json()
+    ///
+    pub fn abandon(&mut self) -> bool {
+        let py = self.py();
+        let open = self.sender.is_some();
+        self.finish(py);
+        open
+    }
+
+    ///
+    /// Send complete head of accepted upgrade, response has no body
+    ///
+    pub fn write_upgrade(&mut self, py: Python, head: Bytes) -> PyResult<()> {
+        if self.sender.is_none() {
+            return Err(exc::RuntimeError::new("Response is already sent"))
+        }
+        self.upgrade_status(Some(101));
+        self.send_maybe(EncoderMessage::Bytes(head));
+        self.finish(py);
+        Ok(())
+    }
+
+    ///
+    /// Send file response, body is omitted for HEAD request
+    ///
+    pub fn send_file(&mut self, py: Python, path: &Path, ctype: Option<&str>,
+                     status: u16, body: bool) -> PyResult<Py<PyFuture>> {
+        let file = FileBody::open(path).map_err(|err| utils::to_pyerr(py, err))?;
+        self.upgrade_status(Some(status));
+
+        let mut buf = BytesMut::with_capacity(256);
+        match Strings.status_line(py, status) {
+            Some(line) => buf.extend(line.as_ref(py).to_string()?.as_bytes()),
+            None => buf.extend(format!("HTTP/1.1 {} Unknown\r\n", status).as_bytes()),
+        }
+        buf.extend(format!("Content-Type: {}\r\n",
+                           ctype.unwrap_or_else(|| content_type(path))).as_bytes());
+        if self.close_connection {
+            buf.extend(CONNECTION_CLOSE);
+        }
+        buf.extend(format!("Content-Length: {}\r\n\r\n", file.len()).as_bytes());
+
+        self.send_maybe(EncoderMessage::Bytes(buf.freeze()));
+        if body && file.len() > 0 {
+            self.send_maybe(EncoderMessage::File(file));
+        }
+        self.finish(py);
+
+        self.transport.as_mut(py).drain_waiter(py)
     }
 
-    fn send_maybe(&self, py: Python, msg: EncoderMessage) {
-        if let Some(ref mut sender) = *self._sender_mut(py) {
-            let _ = sender.send(msg);
+    fn finish(&mut self, py: Python) {
+        self.sender.take();
+        self.content.as_mut(py).release();
+    }
+
+    // chunked body frames each write separately
+    fn payload(&self, py: Python, chunk: &PyObjectRef) -> PyResult<EncoderMessage> {
+        let msg = PayloadWriter::message(py, chunk)?;
+        if self.chunked {
+            Ok(msg.chunk())
+        } else {
+            Ok(msg)
+        }
+    }
+
+    // queue is limited, writer which does not wait for drain() aborts
+    // connection. file is read by connection, it is not queued
+    fn send_maybe(&mut self, msg: EncoderMessage) {
+        let py = self.py();
+        let overflow = match self.sender {
+            Some(ref sender) => {
+                let len = msg.len();
+                let size = match msg {
+                    EncoderMessage::File(..) => 0,
+                    _ => len,
+                };
+                if sender.send(msg, size).is_ok() {
+                    self.transport.as_mut(py).buffered(py, len);
+                    false
+                } else {
+                    true
+                }
+            },
+            None => false,
+        };
+        if overflow {
+            self.finish(py);
+            self.transport.as_mut(py).write_overflow();
         }
     }
 }
+
+
+fn status_code(status_line: &str) -> Option<u16> {
+    status_line.split(' ').nth(1).and_then(|code| code.trim().parse::<u16>().ok())
+}
+
+// 1xx, 204 and 304 responses do not have body
+fn body_allowed(status_line: &str) -> bool {
+    match status_code(status_line) {
+        Some(code) => code >= 200 && code != 204 && code != 304,
+        None => true,
+    }
+}
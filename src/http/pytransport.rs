@@ -1,296 +1,514 @@
-#![allow(unused_variables)]
 #![allow(dead_code)]
 
 use std::io;
-use std::collections::VecDeque;
+use std::collections::{VecDeque, HashMap};
 
 use pyo3::*;
-use futures::unsync::mpsc;
-use futures::{Async, Future, Poll};
+use boxfnonce::BoxFnOnce;
 
-use {TokioEventLoop, PyFuture, PyFuturePtr, PyTask, PyTaskPtr, pybytes};
-use http::{self, pyreq, codec};
-use http::pyreq::{PyRequest, PyRequestPtr, StreamReader, StreamReaderPtr};
-use utils::{Classes, PyLogger, with_py};
-use pyunsafe::{GIL, Sender};
+use {TokioEventLoop, PyFuture, PyTask};
+use http::{self, codec, HeaderEncoding, Span};
+use http::capture::HttpCapture;
+use http::errors;
+use http::pyreq::{PyRequest, StreamReader};
+use http::websocket;
+use pybytes;
+use transport::{write_buffer_limits, write_queue_limit, write_queue_overflow,
+                DEFAULT_HIGH_WATER, DEFAULT_LOW_WATER};
+use utils::PyLogger;
+use pyunsafe::{self, BoundedReceiver, GIL, Sender};
 
 
 pub enum PyHttpTransportMessage {
     Close(Option<PyErr>),
+    // switch connection to new owner, resolve future
+    Detach(Handover, Py<PyFuture>),
+    // response queue overflow, connection_lost() gets error
+    Abort(io::Error),
 }
 
-const CONCURENCY_LEVEL: usize = 1;
+///
+/// New owner of upgraded connection
+///
+pub enum Handover {
+    // tcp transport for protocol created by factory
+    Protocol(PyObject),
+    // websocket transport
+    WebSocket(websocket::Options),
+}
 
-#[py::class]
+#[py::class(weakref)]
 pub struct PyHttpTransport {
-    _loop: TokioEventLoopPtr,
-    _connection_lost: PyObject,
-    _data_received: PyObject,
-    _request_handler: PyObject,
-    _socket: PyObject,
+    evloop: Py<TokioEventLoop>,
+    connection_lost: PyObject,
+    data_received: PyObject,
+    request_handler: Option<PyObject>,
+    pause_writing: Option<PyObject>,
+    resume_writing: Option<PyObject>,
     transport: Sender<PyHttpTransportMessage>,
-    req: Option<pyreq::PyRequestPtr>,
+    info: HashMap<&'static str, PyObject>,
+    capture: Option<Py<HttpCapture>>,
+    header_encoding: HeaderEncoding,
+    closing: bool,
+    // upgrade request is received, reading stops after its body
+    // until response status accepts or declines upgrade
+    upgrading: bool,
+    // upgrade is accepted or connection is detached, nothing is parsed anymore
+    tunnel: bool,
     req_count: usize,
-
+    max_requests: Option<usize>,
+    recycling: bool,
+    drain: Option<Py<PyFuture>>,
+    buffer_size: usize,
+    low_water: usize,
+    high_water: usize,
+    writing_paused: bool,
+
+    // requests are dispatched to protocol while number
+    // of unfinished responses is below concurrency
+    concurrency: Option<usize>,
     inflight: usize,
-    reqs: VecDeque<(http::Request, Sender<codec::EncoderMessage>)>,
-    payloads: VecDeque<StreamReaderPtr>,
+    reqs: VecDeque<Py<PyRequest>>,
+    payloads: VecDeque<Py<StreamReader>>,
+    spans: VecDeque<Py<Span>>,
 
     token: PyToken,
 }
 
+pub struct PyHttpTransportPtr(pub Py<PyHttpTransport>);
+
 
 #[py::methods]
 impl PyHttpTransport {
 
-    fn get_extra_info(&self, py: Python, _name: PyString,
-                       default: Option<PyObject>) -> PyResult<PyObject> {
-        Ok(self._socket(py).clone_ref(py))
+    fn is_closing(&self) -> PyResult<bool> {
+        Ok(self.closing)
+    }
+
+    fn get_extra_info(&self, py: Python, name: &str, default: Option<PyObject>)
+                      -> PyResult<PyObject> {
+        if let Some(val) = self.info.get(name) {
+            Ok(val.clone_ref(py))
+        } else {
+            match default {
+                Some(val) => Ok(val),
+                None => Ok(py.None())
+            }
+        }
+    }
+
+    ///
+    /// write bytes to transport
+    ///
+    fn write(&self, _data: &PyObjectRef) -> PyResult<()> {
+        Err(exc::RuntimeError::new(
+            "write() method is not available, use PayloadWriter"))
+    }
+
+    ///
+    /// wait until write buffer size falls below low watermark
+    ///
+    fn drain(&mut self, py: Python) -> PyResult<Py<PyFuture>> {
+        self.drain_waiter(py)
     }
 
-    //
-    // write bytes to transport
-    //
-    fn write(&self, py: Python, data: PyBytes) -> PyResult<PyObject> {
-        Err(PyErr::new::<exc::RuntimeError, _>(
-            py, "write() method is not available, use PayloadWriter"))
+    fn get_write_buffer_size(&self) -> PyResult<usize> {
+        Ok(self.buffer_size)
     }
 
-    //
-    // send buffered data to socket
-    //
-    fn drain(&self, py: Python) -> PyResult<PyFuture> {
-        Ok(PyFuture::done_fut(py, self._loop(py), py.None())?)
+    fn get_write_buffer_limits(&self) -> PyResult<(usize, usize)> {
+        Ok((self.low_water, self.high_water))
     }
 
-    //
-    // close transport
-    //
-    fn close(&self, py: Python) -> PyResult<PyObject> {
-        let _ = self.transport(py).send(PyHttpTransportMessage::Close(None));
-        Ok(py.None())
+    #[args(high="None", low="None")]
+    fn set_write_buffer_limits(&mut self, py: Python,
+                               high: Option<usize>, low: Option<usize>) -> PyResult<()> {
+        let (high, low) = write_buffer_limits(high, low)?;
+        self.high_water = high;
+        self.low_water = low;
+        self.maybe_pause_writing(py);
+        self.wakeup_drain(py);
+        Ok(())
+    }
+
+    ///
+    /// close transport
+    ///
+    fn close(&mut self) -> PyResult<()> {
+        if !self.closing {
+            self.closing = true;
+            let _ = self.transport.send(PyHttpTransportMessage::Close(None));
+        }
+        Ok(())
     }
 }
 
 
 impl PyHttpTransport {
 
+    pub fn drain_waiter(&mut self, py: Python) -> PyResult<Py<PyFuture>> {
+        if self.buffer_size <= self.low_water {
+            PyFuture::done_fut(py, self.evloop.clone_ref(py), py.None())
+        } else {
+            if let Some(ref fut) = self.drain {
+                Ok(fut.clone_ref(py))
+            } else {
+                let fut = PyFuture::new(py, self.evloop.clone_ref(py))?;
+                self.drain = Some(fut.clone_ref(py));
+                Ok(fut)
+            }
+        }
+    }
+
+    // drain() waiter does not wait for lost connection
+    fn drain_lost(&mut self, py: Python, err: Option<PyErr>) {
+        if let Some(fut) = self.drain.take() {
+            let res = match err {
+                Some(err) => Err(err),
+                None => Ok(py.None()),
+            };
+            let _ = fut.as_mut(py).set(py, res);
+        }
+    }
+
+    // connection is closed before request payloads are completed
+    fn payload_lost(&mut self, py: Python) {
+        for payload in self.payloads.drain(..) {
+            let exc = errors::PayloadError::new(
+                "Connection lost before payload is completed");
+            payload.as_mut(py).set_exception(py, exc.into_object(py));
+        }
+    }
+
+    // responses are written in request order
+    pub fn request_started(&mut self, py: Python, span: &Py<Span>) {
+        self.spans.push_back(span.clone_ref(py));
+    }
+
+    pub fn header_encoding(&self) -> HeaderEncoding {
+        self.header_encoding
+    }
+
+    // pipelined requests wait until earlier responses are sent
+    fn dispatch(&mut self, py: Python) {
+        while self.concurrency.map_or(true, |limit| self.inflight < limit) {
+            let req = match self.reqs.pop_front() {
+                Some(req) => req,
+                None => break,
+            };
+            self.inflight += 1;
+
+            if let Some(handler) = self.request_handler.as_ref().map(|h| h.clone_ref(py)) {
+                if let Err(err) = self.start_handler(py, &handler, req) {
+                    self.evloop.as_ref(py).log_error(err, "handle_request error");
+                    self.close_transport();
+                }
+            } else {
+                let cb = self.data_received.clone_ref(py);
+                self.evloop.as_ref(py).with(
+                    "data_received error", || cb.call1(py, (req,)));
+            }
+        }
+    }
+
+    // handle_request() coroutine runs as task, response which is not
+    // finished by the time task is done closes connection
+    fn start_handler(&mut self, py: Python, handler: &PyObject, req: Py<PyRequest>)
+                     -> PyResult<()> {
+        let coro = handler.call1(py, (req.clone_ref(py),))?;
+        let task = PyTask::new(py, coro, self.evloop.as_ref(py))?;
+
+        let evloop = self.evloop.clone_ref(py);
+        let sender = self.transport.clone();
+        task.as_mut(py).add_callback(py, BoxFnOnce::from(move |result: PyResult<PyObject>| {
+            let py = GIL::python();
+            let unfinished = req.as_ref(py).writer(py).as_mut(py).abandon();
+            if let Err(err) = result {
+                evloop.as_ref(py).log_error(err, "handle_request error");
+            }
+            if unfinished {
+                let _ = sender.send(PyHttpTransportMessage::Close(None));
+            }
+        }));
+        Ok(())
+    }
+
+    pub fn detach(&mut self, py: Python, handover: Handover) -> PyResult<Py<PyFuture>> {
+        if self.closing {
+            return Err(exc::RuntimeError::new("Transport is closing"))
+        }
+        self.closing = true;
+        self.tunnel = true;
+        let fut = PyFuture::new(py, self.evloop.clone_ref(py))?;
+        let _ = self.transport.send(
+            PyHttpTransportMessage::Detach(handover, fut.clone_ref(py)));
+        Ok(fut)
+    }
+
+    // response status of upgrade request is sent, accepted upgrade switches
+    // connection to tunnel, otherwise following requests are parsed
+    pub fn upgrade_response(&mut self, accepted: bool) {
+        self.upgrading = false;
+        if accepted {
+            self.tunnel = true;
+        }
+    }
+
+    // response writer does not wait for drain(), queued data can not grow
+    // without limit, connection is aborted
+    pub fn write_overflow(&mut self) {
+        self.closing = true;
+        let _ = self.transport.send(PyHttpTransportMessage::Abort(write_queue_overflow()));
+    }
+
+    fn close_transport(&mut self) {
+        self.closing = true;
+        let _ = self.transport.send(PyHttpTransportMessage::Close(None));
+    }
+
+    // current request is last one allowed on this connection
+    pub fn last_request(&self) -> bool {
+        self.max_requests.map_or(false, |max| self.req_count >= max)
+    }
+
+    pub fn buffered(&mut self, py: Python, len: usize) {
+        self.buffer_size += len;
+        self.maybe_pause_writing(py);
+    }
+
+    fn maybe_pause_writing(&mut self, py: Python) {
+        if !self.writing_paused && self.buffer_size > self.high_water {
+            self.writing_paused = true;
+            if let Some(ref cb) = self.pause_writing {
+                self.evloop.as_ref(py).with(
+                    "protocol.pause_writing() failed", || cb.call0(py));
+            }
+        }
+    }
+
+    fn wakeup_drain(&mut self, py: Python) {
+        if self.buffer_size <= self.low_water {
+            if let Some(fut) = self.drain.take() {
+                let _ = fut.as_mut(py).set(py, Ok(py.None()));
+            }
+            if self.writing_paused {
+                self.writing_paused = false;
+                if let Some(ref cb) = self.resume_writing {
+                    self.evloop.as_ref(py).with(
+                        "protocol.resume_writing() failed", || cb.call0(py));
+                }
+            }
+        }
+    }
+}
+
+
+impl PyHttpTransport {
+
+    pub fn extra_info(&self, py: Python, name: &str) -> Option<PyObject> {
+        self.info.get(name).map(|val| val.clone_ref(py))
+    }
+}
+
+
+impl PyHttpTransportPtr {
+
     pub fn new(py: Python, evloop: &TokioEventLoop,
                sender: Sender<PyHttpTransportMessage>,
-               proto: &PyObject, sock: PyObject) -> PyResult<PyHttpTransport> {
+               protocol: &PyObjectRef, info: HashMap<&'static str, PyObject>,
+               capture: Option<Py<HttpCapture>>,
+               header_encoding: HeaderEncoding,
+               max_requests: Option<usize>,
+               concurrency: Option<usize>) -> PyResult<PyHttpTransportPtr>
+    {
         // get protocol callbacks
-        let connection_made = proto.getattr(py, "connection_made")?;
-        let connection_lost = proto.getattr(py, "connection_lost")?;
-        let data_received = proto.getattr(py, "data_received")?;
-        let request_handler = proto.getattr(py, "handle_request")?;
-        //let request_handler = proto.getattr(py, "_request_handler")?;
-
-        let transport = PyHttpTransport::create_instance(
-            py, evloop.clone_ref(py),
-            connection_lost, data_received, request_handler, sock,
-            sender,
-            None, 0, 0,
-            VecDeque::with_capacity(12),
-            VecDeque::with_capacity(CONCURENCY_LEVEL))?;
+        let connection_made = protocol.getattr("connection_made")?;
+        let connection_lost = protocol.getattr("connection_lost")?;
+        let data_received = protocol.getattr("data_received")?;
+        let request_handler = protocol.getattr("handle_request").ok().map(|h| h.into());
+        let pause_writing = protocol.getattr("pause_writing").ok().map(|h| h.into());
+        let resume_writing = protocol.getattr("resume_writing").ok().map(|h| h.into());
+
+        let transport = py.init(|token| PyHttpTransport {
+            evloop: evloop.into(),
+            connection_lost: connection_lost.into(),
+            data_received: data_received.into(),
+            request_handler: request_handler,
+            pause_writing: pause_writing,
+            resume_writing: resume_writing,
+            transport: sender,
+            info: info,
+            capture: capture,
+            header_encoding: header_encoding,
+            closing: false,
+            upgrading: false,
+            tunnel: false,
+            req_count: 0,
+            max_requests: max_requests,
+            recycling: false,
+            drain: None,
+            buffer_size: 0,
+            low_water: DEFAULT_LOW_WATER,
+            high_water: DEFAULT_HIGH_WATER,
+            writing_paused: false,
+            concurrency: concurrency,
+            inflight: 0,
+            reqs: VecDeque::new(),
+            payloads: VecDeque::new(),
+            spans: VecDeque::new(),
+            token: token})?;
 
         // connection made
-        connection_made.call(
-            py, PyTuple::new(
-                py, &[transport.clone_ref(py).into_object()]), None)
-            .log_error(py, "Protocol.connection_made error")?;
+        let _ = connection_made.call1((transport.clone_ref(py),))
+            .map_err(|err| {
+                transport.as_mut(py).closing = true;
+                let _ = transport.as_mut(py).transport.send(PyHttpTransportMessage::Close(None));
+                evloop.log_error(err, "Protocol.connection_made error")
+            });
+
+        Ok(PyHttpTransportPtr(transport))
+    }
 
-        Ok(transport)
+    pub fn clone_ref(&self, py: Python) -> PyHttpTransportPtr {
+        PyHttpTransportPtr(self.0.clone_ref(py))
     }
 
     pub fn connection_lost(&self) {
         trace!("Protocol.connection_lost(None)");
-        with_py(|py| {
-            self.reqs_mut(py).clear();
-
-            self._connection_lost(py).call(py, PyTuple::new(py, &[py.None()]), None)
+        self.0.with_mut(|py, tr| {
+            tr.reqs.clear();
+            tr.payload_lost(py);
+            tr.drain_lost(py, None);
+            tr.connection_lost.call1(py, (py.None(),))
                 .into_log(py, "connection_lost error");
         });
     }
 
     pub fn connection_error(&self, err: io::Error) {
         trace!("Protocol.connection_lost({:?})", err);
-        with_py(|py| {
-            self.reqs_mut(py).clear();
-
-            match err.kind() {
-                io::ErrorKind::TimedOut => {
-                    trace!("socket.timeout");
-                    with_py(|py| {
-                        let e = Classes.SocketTimeout.call(
-                            py, NoArgs, None).unwrap();
-
-                        self._connection_lost(py).call(py, PyTuple::new(py, &[e]), None)
-                            .into_log(py, "connection_lost error");
-                    });
-                },
-                _ => {
-                    trace!("Protocol.connection_lost(err): {:?}", err);
-                    with_py(|py| {
-                        let mut e = err.to_pyerr(py);
-                        self._connection_lost(py).call(py,
-                                                       PyTuple::new(py, &[e.instance(py)]), None)
-                            .into_log(py, "connection_lost error");
-                    });
-                }
-            }
+        self.0.with_mut(|py, tr| {
+            tr.reqs.clear();
+            tr.payload_lost(py);
+
+            let e = errors::transport_error(err);
+            tr.drain_lost(py, Some(e.clone_ref(py)));
+            tr.connection_lost.call1(py, (e,))
+                .into_log(py, "connection_lost error");
         });
     }
 
     pub fn data_received(&self, msg: http::RequestMessage)
-                         -> PyResult<Option<mpsc::UnboundedReceiver<codec::EncoderMessage>>> {
+                         -> PyResult<Option<BoundedReceiver<codec::EncoderMessage>>> {
+        let py = GIL::python();
+        let tr = self.0.as_mut(py);
+
         match msg {
             http::RequestMessage::Message(msg) => {
-                let (sender, recv) = mpsc::unbounded();
+                let (sender, recv) = pyunsafe::bounded(write_queue_limit(tr.high_water));
+                tr.req_count += 1;
+                if msg.upgrade {
+                    tr.upgrading = true;
+                }
 
-                with_py(|py| match pyreq::PyRequest::new(
-                    py, msg, self._loop(py).clone(), Sender::new(sender)) {
+                match PyRequest::new(py, msg, tr.evloop.as_ref(py),
+                                     sender, self.0.clone_ref(py)) {
                     Err(err) => {
                         error!("{:?}", err);
-                        err.clone_ref(py).print(py);
+                        err.print(py);
                     },
                     Ok(req) => {
-                        self.payloads_mut(py).push_back(req.content().clone_ref(py));
-                        self._data_received(py).call(
-                            py, PyTuple::new(py, &[req.into_object()]), None)
-                            .into_log(py, "data_received error");
+                        tr.payloads.push_back(req.as_ref(py).content(py));
+                        if let Some(ref capture) = tr.capture {
+                            capture.as_mut(py).request_received(py, &req);
+                        }
+                        tr.reqs.push_back(req);
+                        tr.dispatch(py);
                     }
-                });
-                return Ok(Some(recv));
-
-                /*let py = GIL::python();
-                let count = self.req_count(py);
-                count.set(count.get() + 1);
-
-                let inflight = self.inflight(py);
-                if inflight.get() < CONCURENCY_LEVEL {
-                    inflight.set(inflight.get() + 1);
-
-                    // start handler task
-                    let tx = self.transport(py).clone();
-                    let handler = RequestHandler::new(
-                        self._loop(py).clone(), msg, Sender::new(sender),
-                        self.clone_ref(py), self._request_handler(py).clone_ref(py))?;
-
-                    self._loop(GIL::python()).spawn(handler.map_err(move |err| {
-                        // close connection with error
-                        let _ = tx.send(PyHttpTransportMessage::Close(Some(err)));
-                    }));
-                } else {
-                    //println!("wait");
-                    self.reqs(py).borrow_mut().push_back((msg, Sender::new(sender)));
                 }
-                return Ok(Some(recv));*/
+                return Ok(Some(recv));
             },
             http::RequestMessage::Body(chunk) => {
-                with_py(|py| {
-                    if let Some(payload) = self.payloads_mut(py).pop_front() {
-                        match pybytes::PyBytes::new(py, chunk) {
-                            Ok(bytes) => payload.feed_data(py, bytes),
-                            Err(err) =>  {
-                                // close connection with error
-                                let _ = self.transport(py).send(
-                                    PyHttpTransportMessage::Close(Some(err)));
-                            }
+                if let Some(payload) = tr.payloads.front() {
+                    match pybytes::PyBytes::new(py, chunk) {
+                        Ok(bytes) => payload.as_mut(py).feed_data(py, bytes),
+                        Err(err) =>  {
+                            // close connection with error
+                            let _ = tr.transport.send(PyHttpTransportMessage::Close(Some(err)));
                         }
                     }
-                });
+                }
             },
             http::RequestMessage::Completed => {
-                with_py(|py| {
-                    if let Some(payload) = self.payloads_mut(py).pop_front() {
-                        payload.feed_eof(py);
-                    }
-                });
+                if let Some(payload) = tr.payloads.pop_front() {
+                    payload.as_mut(py).feed_eof(py);
+                }
+                if tr.last_request() {
+                    tr.closing = true;
+                    tr.recycling = true;
+                }
             }
         };
         Ok(None)
     }
-}
-
-
-struct RequestHandler {
-    evloop: TokioEventLoopPtr,
-    tr: PyHttpTransportPtr,
-    handler: PyObject,
-    task: PyTaskPtr,
-    inflight: PyRequestPtr,
-}
-
-impl RequestHandler {
-
-    fn new(evloop: TokioEventLoopPtr, msg: http::Request, tx: Sender<codec::EncoderMessage>,
-           tr: PyHttpTransportPtr, handler: PyObject) -> PyResult<RequestHandler> {
 
-        let (task, req) = RequestHandler::start_task(&evloop, msg, tx, &handler)?;
-
-        Ok(RequestHandler {
-            evloop: evloop,
-            tr: tr,
-            handler: handler,
-            task: task,
-            inflight: req,
+    ///
+    /// Request is rejected by server, payload of request
+    /// which is being received fails with rejection error
+    ///
+    pub fn request_rejected(&self, err: PyErr) {
+        self.0.with_mut(|py, tr| {
+            tr.closing = true;
+            let exc = err.into_object(py);
+            for payload in tr.payloads.drain(..) {
+                payload.as_mut(py).set_exception(py, exc.clone_ref(py));
+            }
         })
     }
 
-    pub fn start_task(evloop: &TokioEventLoopPtr, msg: http::Request,
-                      sender: Sender<codec::EncoderMessage>,
-                      handler: &PyObject) -> PyResult<(PyTaskPtr, PyRequestPtr)> {
-        // start python task
-        with_py(|py| {
-            let req = pyreq::PyRequest::new(py, msg, &evloop, sender)?;
-            req.content().feed_eof(py);
+    ///
+    /// Connection reached max_requests, it is closed once
+    /// responses for received requests are sent
+    ///
+    pub fn recycling(&self) -> bool {
+        self.0.as_ref(GIL::python()).recycling
+    }
 
-            let coro = handler.call(
-                py, PyTuple::new(py, &[req.clone_ref(py).into_object()]), None)?;
+    ///
+    /// Connection accepts more data, reading is paused while pipelined
+    /// requests wait for dispatch, request body is not consumed or
+    /// response to completed upgrade request is not started
+    ///
+    pub fn reading(&self) -> bool {
+        let py = GIL::python();
+        let tr = self.0.as_ref(py);
+        if tr.recycling || tr.tunnel || !tr.reqs.is_empty() {
+            return false
+        }
+        match tr.payloads.front() {
+            Some(payload) => !payload.as_mut(py).paused(),
+            None => !tr.upgrading,
+        }
+    }
 
-            let task = PyTask::new(py, coro, evloop)?;
-            Ok((task, req))
+    pub fn written(&self, len: usize) {
+        self.0.with_mut(|py, tr| {
+            tr.buffer_size = tr.buffer_size.saturating_sub(len);
+            tr.wakeup_drain(py);
         })
     }
-}
 
-
-impl Future for RequestHandler {
-    type Item = ();
-    type Error = PyErr;
-
-    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        let result = self.task.poll();
-
-        // process result from python task
-        match result {
-            Ok(Async::Ready(res)) => {
-                // select next message
-                //if self.tr.reqs(GIL::python()).borrow_mut().len() > 0 {
-                //    println!("num: {}", self.tr.reqs(GIL::python()).borrow_mut().len());
-                //}
-                let tr = self.tr.as_mut(GIL::python());
-                let (msg, sender) = match tr.reqs.pop_front() {
-                    Some((msg, sender)) => (msg, sender),
-                    None => {
-                        // nothing to process, decrease number of inflight tasks and exit
-                        tr.inflight = tr.inflight - 1;
-
-                        //println!("no requests in queue");
-                        return Ok(Async::Ready(()))
-                    }
-                };
-                let (task, req) = RequestHandler::start_task(
-                    &self.evloop, msg, sender, &self.handler)?;
-                self.inflight = req;
-                self.task = task;
-                self.poll()
-            }
-            Ok(Async::NotReady) => Ok(Async::NotReady),
-            Err(err) => {
-                // close connection with error
-                Ok(Async::Ready(()))
-            }
+    pub fn response_completed(&self) {
+        let py = GIL::python();
+        let tr = self.0.as_mut(py);
+        if let Some(ref capture) = tr.capture {
+            capture.as_mut(py).response_completed(py);
+        }
+        if let Some(span) = tr.spans.pop_front() {
+            let _ = span.as_mut(py).finish(py);
+            tr.evloop.as_ref(py).export_span(py, &span);
         }
+        tr.inflight = tr.inflight.saturating_sub(1);
+        tr.dispatch(py);
     }
 }
+
@@ -0,0 +1,283 @@
+use std;
+use std::ascii::AsciiExt;
+use bytes::{Bytes, BytesMut};
+use tokio_io::codec::Decoder;
+use twoway;
+
+use http::decoder::Error;
+use http::message::{Version, ConnectionType};
+
+
+const MAX_HEAD_SIZE: usize = 65536;
+
+
+/// Parsed response head
+#[derive(Debug)]
+pub struct Response {
+    pub version: Version,
+    pub status: u16,
+    pub reason: Bytes,
+    pub headers: Vec<(Bytes, Bytes)>,
+    pub connection: ConnectionType,
+    pub chunked: bool,
+}
+
+/// Parsed response
+#[derive(Debug)]
+pub enum ResponseMessage {
+    Message(Response),
+    Body(Bytes),
+    Completed,
+}
+
+#[derive(Copy, Clone, Debug)]
+enum State {
+    Head,
+    Length(u64),
+    ChunkSize,
+    Chunk(u64),
+    ChunkEol,
+    Trailers,
+    Eof,
+    Done,
+}
+
+///
+/// Client side http/1.x response decoder. Response head is parsed
+/// once it is completely received, body is streamed
+///
+pub struct ResponseDecoder {
+    state: State,
+}
+
+impl ResponseDecoder {
+
+    pub fn new() -> ResponseDecoder {
+        ResponseDecoder { state: State::Head }
+    }
+}
+
+impl Decoder for ResponseDecoder {
+    type Item = ResponseMessage;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        loop {
+            match self.state {
+                State::Head => {
+                    let end = match twoway::find_bytes(src.as_ref(), b"\r\n\r\n") {
+                        Some(end) => end,
+                        None => {
+                            if src.len() > MAX_HEAD_SIZE {
+                                return Err(Error::LineTooLong)
+                            }
+                            return Ok(None)
+                        }
+                    };
+                    let head = src.split_to(end + 4).freeze();
+                    let (resp, length) = parse_head(head.slice(0, end + 2))?;
+
+                    self.state = if resp.status < 200 || resp.status == 204 || resp.status == 304 {
+                        State::Done
+                    } else if resp.chunked {
+                        State::ChunkSize
+                    } else {
+                        match length {
+                            Some(0) => State::Done,
+                            Some(length) => State::Length(length),
+                            None => State::Eof,
+                        }
+                    };
+                    return Ok(Some(ResponseMessage::Message(resp)))
+                },
+                State::Length(remaining) => {
+                    if src.is_empty() {
+                        return Ok(None)
+                    }
+                    let len = src.len() as u64;
+                    if remaining > len {
+                        self.state = State::Length(remaining - len);
+                        return Ok(Some(ResponseMessage::Body(src.take().freeze())))
+                    } else {
+                        self.state = State::Done;
+                        return Ok(Some(ResponseMessage::Body(
+                            src.split_to(remaining as usize).freeze())))
+                    }
+                },
+                State::ChunkSize => {
+                    let line = match twoway::find_bytes(src.as_ref(), b"\r\n") {
+                        Some(pos) => src.split_to(pos + 2),
+                        None => return Ok(None),
+                    };
+                    // chunk-size [ chunk-ext ] CRLF
+                    let size = line[..line.len()-2].split(|ch| *ch == b';').next().unwrap_or(&[]);
+                    let size = std::str::from_utf8(size).ok()
+                        .and_then(|s| u64::from_str_radix(s.trim(), 16).ok())
+                        .ok_or(Error::TransferEncoding)?;
+                    self.state = if size == 0 { State::Trailers } else { State::Chunk(size) };
+                },
+                State::Chunk(remaining) => {
+                    if src.is_empty() {
+                        return Ok(None)
+                    }
+                    let len = src.len() as u64;
+                    if remaining > len {
+                        self.state = State::Chunk(remaining - len);
+                        return Ok(Some(ResponseMessage::Body(src.take().freeze())))
+                    } else {
+                        self.state = State::ChunkEol;
+                        return Ok(Some(ResponseMessage::Body(
+                            src.split_to(remaining as usize).freeze())))
+                    }
+                },
+                State::ChunkEol => {
+                    if src.len() < 2 {
+                        return Ok(None)
+                    }
+                    if &src[..2] != b"\r\n" {
+                        return Err(Error::TransferEncoding)
+                    }
+                    src.split_to(2);
+                    self.state = State::ChunkSize;
+                },
+                State::Trailers => {
+                    // trailers are skipped
+                    match twoway::find_bytes(src.as_ref(), b"\r\n") {
+                        Some(0) => {
+                            src.split_to(2);
+                            self.state = State::Done;
+                        },
+                        Some(pos) => {
+                            src.split_to(pos + 2);
+                        },
+                        None => return Ok(None),
+                    }
+                },
+                State::Eof => {
+                    if src.is_empty() {
+                        return Ok(None)
+                    }
+                    return Ok(Some(ResponseMessage::Body(src.take().freeze())))
+                },
+                State::Done => {
+                    self.state = State::Head;
+                    return Ok(Some(ResponseMessage::Completed))
+                },
+            }
+        }
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if let Some(item) = self.decode(src)? {
+            return Ok(Some(item))
+        }
+        match self.state {
+            State::Head if src.is_empty() => Ok(None),
+            // body without length is completed by eof
+            State::Eof => {
+                self.state = State::Head;
+                Ok(Some(ResponseMessage::Completed))
+            },
+            _ => Err(Error::PayloadNotCompleted),
+        }
+    }
+}
+
+
+fn parse_head(head: Bytes) -> Result<(Response, Option<u64>), Error> {
+    let mut lines = Vec::new();
+    let mut start = 0;
+    while let Some(pos) = twoway::find_bytes(&head[start..], b"\r\n") {
+        lines.push(head.slice(start, start + pos));
+        start += pos + 2;
+    }
+
+    // status line: HTTP/1.x SP status-code SP reason-phrase
+    let status_line = lines.get(0).ok_or(Error::BadStatusLine)?;
+    if status_line.len() < 12 || &status_line[..7] != b"HTTP/1." || status_line[8] != b' ' {
+        return Err(Error::BadStatusLine)
+    }
+    let version = match status_line[7] {
+        b'0' => Version::Http10,
+        b'1' => Version::Http11,
+        _ => return Err(Error::BadStatusLine),
+    };
+    let status = std::str::from_utf8(&status_line[9..12]).ok()
+        .and_then(|s| s.parse::<u16>().ok())
+        .ok_or(Error::BadStatusLine)?;
+    let reason = if status_line.len() > 12 && status_line[12] == b' ' {
+        status_line.slice_from(13)
+    } else if status_line.len() == 12 {
+        Bytes::new()
+    } else {
+        return Err(Error::BadStatusLine)
+    };
+
+    let mut resp = Response {
+        version: version,
+        status: status,
+        reason: reason,
+        headers: Vec::with_capacity(lines.len()),
+        connection: if version == Version::Http10 {
+            ConnectionType::Close
+        } else {
+            ConnectionType::KeepAlive
+        },
+        chunked: false,
+    };
+    let mut length = None;
+
+    for line in lines.iter().skip(1) {
+        let colon = match line.iter().position(|ch| *ch == b':') {
+            Some(0) | None => return Err(Error::BadHeader),
+            Some(colon) => colon,
+        };
+        let name = line.slice(0, colon);
+        if name.iter().any(|ch| *ch <= b' ' || *ch >= 0x7f) {
+            return Err(Error::BadHeader)
+        }
+        let value = trim(line.slice_from(colon + 1));
+
+        if name.eq_ignore_ascii_case(b"content-length") {
+            let len = std::str::from_utf8(&value).ok()
+                .and_then(|s| s.parse::<u64>().ok())
+                .ok_or(Error::ContentLength)?;
+            length = Some(len);
+        } else if name.eq_ignore_ascii_case(b"transfer-encoding") {
+            resp.chunked = value.to_ascii_lowercase().ends_with(b"chunked");
+        } else if name.eq_ignore_ascii_case(b"connection") {
+            let value = value.to_ascii_lowercase();
+            for token in value.split(|ch| *ch == b',').map(|t| trim_slice(t)) {
+                match token {
+                    b"close" => resp.connection = ConnectionType::Close,
+                    b"keep-alive" => resp.connection = ConnectionType::KeepAlive,
+                    b"upgrade" => resp.connection = ConnectionType::Upgrade,
+                    _ => (),
+                }
+            }
+        }
+        resp.headers.push((name, value));
+    }
+
+    if resp.chunked && length.is_some() {
+        return Err(Error::ContentLengthAndTE)
+    }
+    Ok((resp, length))
+}
+
+fn trim(value: Bytes) -> Bytes {
+    let start = value.iter().position(|ch| !is_ows(*ch)).unwrap_or(value.len());
+    let end = value.iter().rposition(|ch| !is_ows(*ch)).map(|p| p + 1).unwrap_or(start);
+    value.slice(start, end)
+}
+
+fn trim_slice(value: &[u8]) -> &[u8] {
+    let start = value.iter().position(|ch| !is_ows(*ch)).unwrap_or(value.len());
+    let end = value.iter().rposition(|ch| !is_ows(*ch)).map(|p| p + 1).unwrap_or(start);
+    &value[start..end]
+}
+
+#[inline]
+fn is_ows(ch: u8) -> bool {
+    ch == b' ' || ch == b'\t'
+}
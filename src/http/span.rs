@@ -0,0 +1,113 @@
+use std::time::Instant;
+
+use pyo3::*;
+
+use utils;
+
+
+///
+/// Lightweight timing span. Request span is finished when response
+/// is completely written and passed to loop's span exporter
+///
+#[py::class(weakref)]
+pub struct Span {
+    name: String,
+    start: Instant,
+    end: Option<Instant>,
+    annotations: Vec<(String, PyObject)>,
+    children: Vec<Py<Span>>,
+    token: PyToken,
+}
+
+#[py::methods]
+impl Span {
+
+    #[getter]
+    fn get_name(&self) -> PyResult<&str> {
+        Ok(&self.name)
+    }
+
+    ///
+    /// span duration in seconds, None if span is not finished
+    ///
+    #[getter]
+    fn get_duration(&self) -> PyResult<Option<f64>> {
+        Ok(self.end.map(|end| utils::duration_to_secs(end - self.start)))
+    }
+
+    #[getter]
+    fn get_annotations(&self, py: Python) -> PyResult<PyObject> {
+        let dict = PyDict::new(py);
+        for &(ref key, ref value) in self.annotations.iter() {
+            dict.set_item(key, value.clone_ref(py))?;
+        }
+        Ok(dict.into())
+    }
+
+    #[getter]
+    fn get_children(&self, py: Python) -> PyResult<PyObject> {
+        let children: Vec<PyObject> =
+            self.children.iter().map(|child| child.to_object(py)).collect();
+        Ok(PyList::new(py, children.as_slice()).into())
+    }
+
+    fn annotate(&mut self, key: String, value: PyObject) -> PyResult<()> {
+        self.annotations.push((key, value));
+        Ok(())
+    }
+
+    fn start_child(&mut self, py: Python, name: String) -> PyResult<Py<Span>> {
+        let child = Span::new(py, name)?;
+        self.children.push(child.clone_ref(py));
+        Ok(child)
+    }
+
+    ///
+    /// finish span and all unfinished child spans
+    ///
+    fn finish(&mut self, py: Python) -> PyResult<()> {
+        if self.end.is_none() {
+            let now = Instant::now();
+            self.finish_at(py, now);
+        }
+        Ok(())
+    }
+
+    fn to_dict(&self, py: Python) -> PyResult<PyObject> {
+        let children = PyList::empty(py);
+        for child in self.children.iter() {
+            children.append(child.as_ref(py).to_dict(py)?)?;
+        }
+
+        let dict = PyDict::new(py);
+        dict.set_item("name", &self.name)?;
+        dict.set_item("duration", self.get_duration()?)?;
+        dict.set_item("annotations", self.get_annotations(py)?)?;
+        dict.set_item("children", children)?;
+        Ok(dict.into())
+    }
+}
+
+
+impl Span {
+
+    pub fn new(py: Python, name: String) -> PyResult<Py<Span>> {
+        py.init(|token| Span {
+            name: name,
+            start: Instant::now(),
+            end: None,
+            annotations: Vec::new(),
+            children: Vec::new(),
+            token: token})
+    }
+
+    fn finish_at(&mut self, py: Python, now: Instant) {
+        for child in self.children.iter() {
+            let child = child.as_mut(py);
+            if child.end.is_none() {
+                child.finish_at(py, now);
+            }
+        }
+        self.end = Some(now);
+    }
+}
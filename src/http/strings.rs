@@ -0,0 +1,124 @@
+#![allow(non_upper_case_globals)]
+
+use std::ffi::CString;
+use std::collections::HashMap;
+
+use pyo3::*;
+
+
+const METHODS: &'static [&'static str] = &[
+    "GET", "HEAD", "POST", "PUT", "DELETE", "CONNECT", "OPTIONS", "TRACE", "PATCH"];
+
+const HEADERS: &'static [&'static str] = &[
+    "Accept", "Accept-Encoding", "Accept-Language", "Authorization", "Cache-Control",
+    "Connection", "Content-Encoding", "Content-Length", "Content-Type", "Cookie",
+    "Date", "Host", "If-Modified-Since", "If-None-Match", "Origin", "Referer",
+    "Transfer-Encoding", "Upgrade", "User-Agent", "X-Forwarded-For",
+    "X-Forwarded-Proto", "X-Requested-With"];
+
+const REASONS: &'static [(u16, &'static str)] = &[
+    (100, "Continue"),
+    (101, "Switching Protocols"),
+    (200, "OK"),
+    (201, "Created"),
+    (202, "Accepted"),
+    (204, "No Content"),
+    (206, "Partial Content"),
+    (301, "Moved Permanently"),
+    (302, "Found"),
+    (303, "See Other"),
+    (304, "Not Modified"),
+    (307, "Temporary Redirect"),
+    (308, "Permanent Redirect"),
+    (400, "Bad Request"),
+    (401, "Unauthorized"),
+    (403, "Forbidden"),
+    (404, "Not Found"),
+    (405, "Method Not Allowed"),
+    (408, "Request Timeout"),
+    (409, "Conflict"),
+    (411, "Length Required"),
+    (413, "Payload Too Large"),
+    (414, "URI Too Long"),
+    (415, "Unsupported Media Type"),
+    (429, "Too Many Requests"),
+    (431, "Request Header Fields Too Large"),
+    (500, "Internal Server Error"),
+    (501, "Not Implemented"),
+    (502, "Bad Gateway"),
+    (503, "Service Unavailable"),
+    (504, "Gateway Timeout")];
+
+
+///
+/// Python strings for common methods, header names, status lines and
+/// reason phrases. Created once, same objects are returned for every request
+///
+pub struct InternedStrings {
+    methods: HashMap<&'static str, Py<PyString>>,
+    headers: HashMap<String, Py<PyString>>,
+    reasons: HashMap<u16, Py<PyString>>,
+    status_lines: HashMap<u16, Py<PyString>>,
+}
+
+lazy_static! {
+    pub static ref Strings: InternedStrings = {
+        let gil = Python::acquire_gil();
+        let py = gil.python();
+
+        let methods = METHODS.iter().map(|m| (*m, intern(py, m))).collect();
+
+        let mut headers = HashMap::new();
+        for name in HEADERS {
+            headers.insert(name.to_string(), intern(py, name));
+            headers.insert(name.to_lowercase(), intern(py, &name.to_lowercase()));
+        }
+
+        let mut reasons = HashMap::new();
+        let mut status_lines = HashMap::new();
+        for &(code, reason) in REASONS {
+            reasons.insert(code, intern(py, reason));
+            status_lines.insert(
+                code, intern(py, &format!("HTTP/1.1 {} {}\r\n", code, reason)));
+        }
+
+        InternedStrings {
+            methods: methods,
+            headers: headers,
+            reasons: reasons,
+            status_lines: status_lines,
+        }
+    };
+}
+
+impl InternedStrings {
+
+    pub fn method(&self, py: Python, method: &str) -> Py<PyString> {
+        match self.methods.get(method) {
+            Some(s) => s.clone_ref(py),
+            None => PyString::new(py, method),
+        }
+    }
+
+    pub fn header(&self, py: Python, name: &str) -> Py<PyString> {
+        match self.headers.get(name) {
+            Some(s) => s.clone_ref(py),
+            None => PyString::new(py, name),
+        }
+    }
+
+    pub fn reason(&self, py: Python, code: u16) -> Option<Py<PyString>> {
+        self.reasons.get(&code).map(|s| s.clone_ref(py))
+    }
+
+    pub fn status_line(&self, py: Python, code: u16) -> Option<Py<PyString>> {
+        self.status_lines.get(&code).map(|s| s.clone_ref(py))
+    }
+}
+
+fn intern(_py: Python, s: &str) -> Py<PyString> {
+    let s = CString::new(s).expect("static string");
+    unsafe {
+        Py::from_owned_ptr_or_panic(ffi::PyUnicode_InternFromString(s.as_ptr()))
+    }
+}
@@ -1,35 +1,73 @@
 use std::io;
+use std::mem;
+use std::rc::Rc;
+use std::cell::Cell;
 use std::net::SocketAddr;
-use std::convert::Into;
+use std::time::{Duration, Instant};
 use std::collections::{VecDeque, HashMap};
-use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::io::AsRawFd;
 use pyo3::*;
 use futures::unsync::mpsc;
-use futures::{Async, AsyncSink, Stream, Future, Poll, Sink};
-use tokio_io::AsyncRead;
+use boxfnonce::BoxFnOnce;
+use bytes::Bytes;
+use futures::{task, Async, AsyncSink, Stream, Future, Poll, Sink};
+use tokio_io::{AsyncRead, AsyncWrite};
 use tokio_io::codec::Framed;
-use tokio_core::net::TcpStream;
+use tokio_core::reactor::{Handle, Timeout};
 
-use {PyFuture, PyFuturePtr, TokioEventLoop, TokioEventLoopPtr};
+use {PyFuture, TokioEventLoop};
 use addrinfo::AddrInfo;
+use http::{HeaderEncoding, RequestMessage};
+use http::capture::HttpCapture;
+use http::errors;
 use http::codec::{HttpTransportCodec, EncoderMessage};
-use http::pytransport::{PyHttpTransport, PyHttpTransportMessage};
+use http::file::FileBody;
+use http::pytransport::{Handover, PyHttpTransport, PyHttpTransportPtr, PyHttpTransportMessage};
+use http::websocket;
+use server::ConnectionGuard;
+use sniff::PrefixedStream;
 use socket::Socket;
-use utils::PyLogger;
-use pyunsafe::Sender;
-use transport::InitializedTransport;
+use utils::{self, PyLogger};
+use pyunsafe::{self, BoundedReceiver, GIL, Sender};
+use transport::{tcp_transport_factory, InitializedTransport, TransportOptions};
 
 
-pub fn http_transport_factory(
-    evloop: TokioEventLoopPtr, _server: bool, factory: &PyObject,
+///
+/// Hands upgraded connection over to new owner, socket is passed
+/// with data which is received but not parsed
+///
+pub type Detach<T> = BoxFnOnce<(T, Vec<u8>, Handover), PyResult<PyObject>>;
+
+const REQUEST_TIMEOUT: &'static str = "408 Request Timeout";
+
+
+///
+/// Deadlines of request receiving, slow clients can not hold
+/// connection by sending request byte by byte
+///
+#[derive(Copy, Clone, Debug, Default)]
+pub struct RequestTimeouts {
+    // request line and headers
+    pub header: Option<Duration>,
+    // time between chunks of body, initial allowance if body_min_rate is set
+    pub body: Option<Duration>,
+    // bytes per second, each chunk of body extends deadline
+    pub body_min_rate: Option<u64>,
+}
+
+
+pub fn http_transport_factory<T>(
+    evloop: Py<TokioEventLoop>, _server: bool, factory: &PyObject,
     _ssl: &Option<PyObject>, _server_hostname: Option<PyObject>,
-    socket: TcpStream, addr: Option<&AddrInfo>,
-    peer: Option<SocketAddr>, _waiter: Option<PyFuturePtr>) -> io::Result<InitializedTransport>
+    socket: T, addr: Option<&AddrInfo>,
+    peer: Option<SocketAddr>, waiter: Option<Py<PyFuture>>,
+    opts: TransportOptions) -> io::Result<InitializedTransport>
+
+    where T: AsyncRead + AsyncWrite + AsRawFd + 'static
 {
     let gil = Python::acquire_gil();
     let py = gil.python();
 
-    let ev = evloop.as_ref(py);
     let mut info: HashMap<&'static str, PyObject> = HashMap::new();
 
     if let (Some(ref addr), Some(peer)) = (addr, peer) {
@@ -40,76 +78,298 @@ pub fn http_transport_factory(
         info.insert("socket", sock.clone_ref(py).into());
     }
 
+    if let Some(waiter) = waiter {
+        waiter.as_mut(py).set(py, Ok(py.None()));
+    }
+
+    let ev = evloop.clone_ref(py);
+    let addr = addr.cloned();
+    let detach: Detach<T> = BoxFnOnce::from(move |socket: T, buf: Vec<u8>, handover: Handover| {
+        let py = GIL::python();
+        let socket = PrefixedStream::new(buf, socket);
+        match handover {
+            Handover::Protocol(factory) =>
+                tcp_transport_factory(ev, true, &factory, &None, None,
+                                      socket, addr.as_ref(), peer, None, opts)
+                .map(|init| init.into_tuple(py).into())
+                .map_err(|err| utils::to_pyerr(py, err)),
+            Handover::WebSocket(ws) => {
+                let guard = opts.server.map(|id| ev.as_ref(py).server_connection(id, peer));
+                websocket::start_websocket(py, ev.as_ref(py), socket, ws, guard)
+                    .map(|tr| tr.into())
+            },
+        }
+    });
+
+    let timeouts = RequestTimeouts {
+        header: opts.header_timeout,
+        body: opts.body_timeout,
+        body_min_rate: opts.body_min_rate,
+    };
+    let guard = opts.server.map(|id| evloop.as_ref(py).server_connection(id, peer));
+    let (tr, proto) = start_http_transport(
+        py, evloop.as_ref(py), factory, socket, info, None,
+        opts.header_encoding, opts.max_requests, opts.concurrency, opts.keepalive_timeout,
+        opts.max_body_size, timeouts, Some(detach), guard)?;
+
+    Ok(InitializedTransport::new(tr.into(), proto))
+}
+
+
+///
+/// Create protocol and http transport, start connection processing
+///
+pub fn start_http_transport<T>(py: Python, evloop: &TokioEventLoop, factory: &PyObject,
+                               socket: T, info: HashMap<&'static str, PyObject>,
+                               capture: Option<Py<HttpCapture>>,
+                               header_encoding: HeaderEncoding,
+                               max_requests: Option<usize>,
+                               concurrency: Option<usize>,
+                               keepalive_timeout: Option<Duration>,
+                               max_body_size: Option<u64>,
+                               timeouts: RequestTimeouts,
+                               detach: Option<Detach<T>>,
+                               guard: Option<ConnectionGuard>)
+                               -> PyResult<(Py<PyHttpTransport>, PyObject)>
+    where T: AsyncRead + AsyncWrite + 'static
+{
     // create protocol
-    let proto = factory.call(py, NoArgs, None).log_error(py, "Protocol factory failure")?;
+    let proto = factory.call0(py).log_error(py, "Protocol factory failure")?;
+
+    // capture encoder output if requested
+    let mut codec = match capture {
+        Some(ref capture) => HttpTransportCodec::with_capture(capture.clone_ref(py)),
+        None => HttpTransportCodec::new(),
+    };
+    codec.set_max_body_size(max_body_size);
 
+    // control channel, responses are sent through bounded per request streams
     let (tx, rx) = mpsc::unbounded();
-    let tr = PyHttpTransport::new(py, ev, Sender::new(tx), &proto, py.None())?;
-    let tr2 = tr.clone_ref(py).into();
-    let tr3 = tr.clone_ref(py).into();
+    let tr = PyHttpTransportPtr::new(
+        py, evloop, Sender::new(tx), proto.as_ref(py), info, capture,
+        header_encoding, max_requests, concurrency)?;
+    let conn = tr.clone_ref(py);
 
-    // create internal wire transport
-    let transport = HttpTransport::new(socket, rx, tr.clone_ref(py));
+    let keepalive = match keepalive_timeout {
+        Some(timeout) => Some((timeout, Timeout::new(timeout, evloop.href())?)),
+        None => None,
+    };
+
+    // create internal wire transport, it counts bad requests of server
+    let guard = guard.map(Rc::new);
+    let transport = HttpTransport::new(
+        socket, codec, rx, tr.clone_ref(py), detach, keepalive, timeouts, evloop.href().clone(),
+        guard.clone());
 
     // start connection processing
-    ev.href().spawn(
-        transport.map(move |_| {
-            tr2.connection_lost()
-        }).map_err(move |err| {
-            tr3.connection_error(err)
+    evloop.href().spawn(
+        transport.then(move |res| {
+            match res {
+                Ok(_) => conn.connection_lost(),
+                Err(err) => conn.connection_error(err),
+            }
+            drop(guard);
+            Ok(())
         })
     );
-    Ok(InitializedTransport::new(tr.into(), proto))
+
+    Ok((tr.0, proto))
 }
 
 
-struct HttpTransport {
-    framed: Framed<TcpStream, HttpTransportCodec>,
+struct HttpTransport<T> {
+    framed: Option<Framed<T, HttpTransportCodec>>,
     intake: mpsc::UnboundedReceiver<PyHttpTransportMessage>,
     transport: PyHttpTransportPtr,
+    detach: Option<Detach<T>>,
+    // new owner and waiter of requested detach
+    detaching: Option<(Handover, Py<PyFuture>)>,
 
     buf: Option<EncoderMessage>,
-    streams: VecDeque<mpsc::UnboundedReceiver<EncoderMessage>>,
+    // file response which is being sent
+    file: Option<FileBody>,
+    written: usize,
+    streams: VecDeque<BoundedReceiver<EncoderMessage>>,
     incoming_eof: bool,
     flushed: bool,
     closing: bool,
+
+    // keep-alive timer runs while there is no request in progress
+    keepalive: Option<(Duration, Timeout)>,
+    active: bool,
+
+    // request head and body deadlines, timer is shared
+    timeouts: RequestTimeouts,
+    head: Rc<Cell<bool>>,
+    // upgrade request is parsed, reading resumes only if upgrade is declined
+    tunnel: Rc<Cell<bool>>,
+    head_deadline: Option<Instant>,
+    body_deadline: Option<Instant>,
+    deadline: Option<(Instant, Timeout)>,
+    handle: Handle,
+    // deadlines do not run while reading is paused
+    paused: bool,
+    // connection is closed after response to rejected request
+    error: Option<io::Error>,
+    guard: Option<Rc<ConnectionGuard>>,
 }
 
-impl HttpTransport {
+impl<T> HttpTransport<T>
+    where T: AsyncRead + AsyncWrite
+{
 
-    fn new(socket: TcpStream,
+    fn new(socket: T, codec: HttpTransportCodec,
            intake: mpsc::UnboundedReceiver<PyHttpTransportMessage>,
-           transport: PyHttpTransport) -> HttpTransport {
+           transport: PyHttpTransportPtr, detach: Option<Detach<T>>,
+           keepalive: Option<(Duration, Timeout)>,
+           timeouts: RequestTimeouts, handle: Handle,
+           guard: Option<Rc<ConnectionGuard>>) -> HttpTransport<T> {
 
+        let head = codec.head_state();
+        let tunnel = codec.tunnel_state();
         HttpTransport {
-            framed: socket.framed(HttpTransportCodec::new()),
+            framed: Some(socket.framed(codec)),
             intake: intake,
             transport: transport,
+            detach: detach,
+            detaching: None,
 
             buf: None,
+            file: None,
+            written: 0,
             streams: VecDeque::new(),
             incoming_eof: false,
             flushed: false,
             closing: false,
+
+            keepalive: keepalive,
+            active: true,
+
+            timeouts: timeouts,
+            head: head,
+            tunnel: tunnel,
+            head_deadline: None,
+            body_deadline: None,
+            deadline: None,
+            handle: handle,
+            paused: false,
+            error: None,
+            guard: guard,
+        }
+    }
+
+    fn framed(&mut self) -> &mut Framed<T, HttpTransportCodec> {
+        self.framed.as_mut().expect("framed")
+    }
+
+    // responses are sent, switch socket to protocol created by factory
+    fn detach_connection(&mut self, handover: Handover, waiter: Py<PyFuture>) {
+        let py = GIL::python();
+        let res = match (self.detach.take(), self.framed.take()) {
+            (Some(detach), Some(framed)) => {
+                let parts = framed.into_parts();
+                detach.call(parts.inner, parts.readbuf.to_vec(), handover)
+            },
+            _ => Err(exc::RuntimeError::new("Connection can not be detached")),
+        };
+        waiter.as_mut(py).set(py, res);
+    }
+
+    // body deadline starts with request head and moves with received chunks
+    fn request_progress(&mut self, msg: &RequestMessage) {
+        match *msg {
+            RequestMessage::Message(_) =>
+                self.body_deadline = self.timeouts.body.map(|timeout| Instant::now() + timeout),
+            RequestMessage::Body(ref chunk) => if let Some(deadline) = self.body_deadline {
+                self.body_deadline = match (self.timeouts.body_min_rate, self.timeouts.body) {
+                    (Some(rate), _) => {
+                        let len = chunk.len() as u64;
+                        Some(deadline + Duration::new(
+                            len / rate, ((len % rate) * 1_000_000_000 / rate) as u32))
+                    },
+                    (None, Some(timeout)) => Some(Instant::now() + timeout),
+                    (None, None) => None,
+                }
+            },
+            RequestMessage::Completed => self.body_deadline = None,
         }
     }
+
+    // check deadline of request which is being received
+    fn poll_deadline(&mut self) -> io::Result<bool> {
+        // head deadline starts with first byte of request
+        match (self.timeouts.header, self.head.get()) {
+            (Some(timeout), true) => if self.head_deadline.is_none() {
+                self.head_deadline = Some(Instant::now() + timeout);
+            },
+            _ => self.head_deadline = None,
+        }
+        let at = match self.head_deadline.or(self.body_deadline) {
+            Some(at) => at,
+            None => return Ok(false),
+        };
+
+        let mut timer = match self.deadline.take() {
+            Some((current, mut timer)) => {
+                if current != at {
+                    timer.reset(at);
+                }
+                timer
+            },
+            None => Timeout::new_at(at, &self.handle)?,
+        };
+        let expired = timer.poll()?.is_ready();
+        self.deadline = Some((at, timer));
+        Ok(expired)
+    }
+
+    // request is rejected, nothing is read anymore. error response is queued
+    // behind responses to pipelined requests, connection is closed once it is sent
+    fn reject(&mut self, status: &str, err: io::Error, exc: PyErr) {
+        trace!("Reject request with {}: {}", status, err);
+        let response = format!(
+            "HTTP/1.1 {}\r\nConnection: close\r\nContent-Length: 0\r\n\r\n", status);
+        let (tx, rx) = pyunsafe::bounded(response.len());
+        let _ = tx.send(EncoderMessage::Bytes(Bytes::from(response)), 0);
+        self.streams.push_back(rx);
+        self.transport.request_rejected(exc);
+        self.incoming_eof = true;
+        self.error = Some(err);
+    }
 }
 
 
-impl Future for HttpTransport
+impl<T> Future for HttpTransport<T>
+    where T: AsyncRead + AsyncWrite
 {
     type Item = ();
     type Error = io::Error;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        // poll for incoming data
-        if !self.incoming_eof {
+        // poll for incoming data, nothing is read after last allowed request
+        // or while pipelined requests wait for dispatch
+        let reading = !self.incoming_eof && self.transport.reading();
+        if reading {
+            // transport reads after upgrade request only if upgrade is declined
+            self.tunnel.set(false);
+
+            // client is not waited for while reading is paused
+            if mem::replace(&mut self.paused, false) {
+                self.head_deadline = None;
+                if self.body_deadline.is_some() {
+                    self.body_deadline = self.timeouts.body.map(|t| Instant::now() + t);
+                }
+            }
             loop {
-                match self.framed.poll() {
+                match self.framed().poll() {
                     Ok(Async::Ready(Some(msg))) => {
+                        self.request_progress(&msg);
                         if let Some(recv) = self.transport.data_received(msg)? {
                             self.streams.push_back(recv);
                         }
+                        if !self.transport.reading() {
+                            break
+                        }
                         continue
                     },
                     Ok(Async::Ready(None)) => {
@@ -118,22 +378,42 @@ impl Future for HttpTransport
                         return Ok(Async::Ready(()))
                     },
                     Ok(Async::NotReady) => (),
-                    Err(err) => return Err(err.into()),
+                    Err(err) => match err.status() {
+                        Some(status) => {
+                            if let Some(ref guard) = self.guard {
+                                guard.bad_request();
+                            }
+                            let exc = errors::decoder_error(&err);
+                            self.reject(status, err.into(), exc);
+                        },
+                        None => return Err(err.into()),
+                    },
                 }
                 break
             }
+            if !self.incoming_eof && self.transport.reading() && self.poll_deadline()? {
+                self.reject(REQUEST_TIMEOUT,
+                            io::Error::new(io::ErrorKind::TimedOut, "Request timeout"),
+                            errors::ServerTimeoutError::new("Request timeout"));
+            }
+        } else {
+            self.paused = true;
         }
 
         // process outgoing data
         'sink: loop {
             if let Some(msg) = self.buf.take() {
                 self.flushed = false;
+                let len = msg.len();
 
-                let enc_msg = match self.framed.start_send(msg) {
+                let enc_msg = match self.framed().start_send(msg) {
                     Ok(AsyncSink::NotReady(bytes)) => {
                         Some(bytes)
                     },
-                    Ok(AsyncSink::Ready) => None,
+                    Ok(AsyncSink::Ready) => {
+                        self.written += len;
+                        None
+                    },
                     Err(_) => return Err(io::Error::new(io::ErrorKind::Other, "Closed")),
                 };
                 // unprocessed data
@@ -143,11 +423,24 @@ impl Future for HttpTransport
                 }
             }
 
+            // next chunk of file is read once previous one is accepted
+            if let Some(mut body) = self.file.take() {
+                if let Some(chunk) = body.read_chunk()? {
+                    self.file = Some(body);
+                    self.buf = Some(EncoderMessage::Bytes(chunk));
+                    continue 'sink
+                }
+            }
+
             // poll streams
             'streams: loop {
                 match self.streams.front_mut() {
                     Some(ref mut stream) => {
                         match stream.poll() {
+                            Ok(Async::Ready(Some(EncoderMessage::File(body)))) => {
+                                self.file = Some(body);
+                                continue 'sink
+                            },
                             Ok(Async::Ready(Some(msg))) => {     // data available, try to send
                                 self.buf = Some(msg);
                                 continue 'sink
@@ -161,6 +454,40 @@ impl Future for HttpTransport
                 }
                 // this can happen only if stream is empty
                 let _ = self.streams.pop_front();
+                self.transport.response_completed();
+            }
+        }
+
+        // sent responses made room for queued requests, resume reading
+        if !reading && !self.incoming_eof && self.transport.reading() {
+            task::current().notify();
+        }
+
+        // last allowed response is sent, recycle connection
+        if self.buf.is_none() && self.streams.is_empty() && self.transport.recycling() {
+            self.closing = true;
+        }
+
+        // response to rejected request is sent
+        if self.error.is_some() && self.buf.is_none() && self.file.is_none() &&
+            self.streams.is_empty() {
+            self.closing = true;
+        }
+
+        // close idle connection which does not start new request in time,
+        // partially received request head is activity as well
+        if let Some((timeout, ref mut timer)) = self.keepalive {
+            if self.buf.is_none() && self.file.is_none() && self.streams.is_empty() &&
+                !self.head.get() {
+                if mem::replace(&mut self.active, false) {
+                    timer.reset(Instant::now() + timeout);
+                }
+                if timer.poll()?.is_ready() {
+                    trace!("Close idle keep-alive connection");
+                    self.closing = true;
+                }
+            } else {
+                self.active = true;
             }
         }
 
@@ -170,7 +497,18 @@ impl Future for HttpTransport
                 match msg {
                     PyHttpTransportMessage::Close(_) => {
                         trace!("Start transport closing procesdure");
-                        self.closing = true;
+                        // rejected connection is closed after error response
+                        if self.error.is_none() {
+                            self.closing = true;
+                        }
+                    }
+                    PyHttpTransportMessage::Detach(handover, waiter) => {
+                        trace!("Detach connection after sent responses");
+                        self.detaching = Some((handover, waiter));
+                    }
+                    PyHttpTransportMessage::Abort(err) => {
+                        trace!("Abort connection: {}", err);
+                        return Err(err)
                     }
                 }
             },
@@ -178,14 +516,34 @@ impl Future for HttpTransport
             Err(_) => return Err(io::Error::new(io::ErrorKind::Other, "Closed")),
         }
 
+        // all responses are flushed, hand socket over to new protocol
+        if self.detaching.is_some() && self.buf.is_none() && self.streams.is_empty() {
+            if self.framed().poll_complete()?.is_ready() {
+                if let Some((handover, waiter)) = self.detaching.take() {
+                    self.detach_connection(handover, waiter);
+                }
+                return Ok(Async::Ready(()))
+            }
+            return Ok(Async::NotReady)
+        }
+
         // close
         if self.closing {
-            return self.framed.close()
+            let res = self.framed().close()?;
+            if res.is_ready() {
+                if let Some(err) = self.error.take() {
+                    return Err(err)
+                }
+            }
+            return Ok(res)
         }
 
         // flush sink
         if !self.flushed {
-            self.flushed = self.framed.poll_complete()?.is_ready();
+            self.flushed = self.framed().poll_complete()?.is_ready();
+            if self.flushed {
+                self.transport.written(mem::replace(&mut self.written, 0));
+            }
         }
 
         Ok(Async::NotReady)
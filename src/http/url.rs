@@ -0,0 +1,120 @@
+///
+/// Request target split into path, query string and fragment,
+/// scheme and authority of absolute-form target are stripped
+///
+#[derive(Debug, PartialEq)]
+pub struct RequestTarget<'a> {
+    // path and query as sent
+    pub raw_path: &'a str,
+    pub path: &'a str,
+    pub query: &'a str,
+    pub fragment: &'a str,
+}
+
+impl<'a> RequestTarget<'a> {
+
+    pub fn parse(target: &'a str) -> RequestTarget<'a> {
+        let target = strip_authority(target);
+
+        let (raw_path, fragment) = match target.find('#') {
+            Some(idx) => (&target[..idx], &target[idx+1..]),
+            None => (target, ""),
+        };
+        let (path, query) = match raw_path.find('?') {
+            Some(idx) => (&raw_path[..idx], &raw_path[idx+1..]),
+            None => (raw_path, ""),
+        };
+        // path of absolute-form can be empty, RFC 7230 section 5.3.2
+        let path = if path.is_empty() { "/" } else { path };
+        RequestTarget { raw_path: raw_path, path: path, query: query, fragment: fragment }
+    }
+
+    /// Percent-decoded path
+    pub fn decoded_path(&self) -> String {
+        percent_decode(self.path, false)
+    }
+}
+
+
+// "http://host:port/path" -> "/path", origin, asterisk
+// and authority forms are returned as is
+fn strip_authority(target: &str) -> &str {
+    if target.starts_with('/') {
+        return target
+    }
+    let scheme = match target.find("://") {
+        Some(idx) => &target[..idx],
+        None => return target,
+    };
+    let valid = scheme.bytes().next().map(|ch| ch.is_ascii_alphabetic()).unwrap_or(false)
+        && scheme.bytes().all(|ch| ch.is_ascii_alphanumeric() || b"+-.".contains(&ch));
+    if !valid {
+        return target
+    }
+
+    let rest = &target[scheme.len()+3..];
+    match rest.find(|ch| ch == '/' || ch == '?' || ch == '#') {
+        Some(idx) => &rest[idx..],
+        None => "/",
+    }
+}
+
+
+///
+/// Decode %XX escapes, invalid escapes are kept as is. "+" is decoded
+/// as space if plus is set (query string). Invalid utf-8 is replaced.
+///
+pub fn percent_decode(val: &str, plus: bool) -> String {
+    if !val.contains('%') && !(plus && val.contains('+')) {
+        return val.to_owned()
+    }
+
+    let bytes = val.as_bytes();
+    let mut buf = Vec::with_capacity(bytes.len());
+    let mut idx = 0;
+    while idx < bytes.len() {
+        match bytes[idx] {
+            b'%' => match val.get(idx+1..idx+3).and_then(unhex) {
+                Some(ch) => {
+                    buf.push(ch);
+                    idx += 3;
+                    continue
+                },
+                None => buf.push(b'%'),
+            },
+            b'+' if plus => buf.push(b' '),
+            ch => buf.push(ch),
+        }
+        idx += 1;
+    }
+
+    match String::from_utf8(buf) {
+        Ok(s) => s,
+        Err(err) => String::from_utf8_lossy(err.as_bytes()).into_owned(),
+    }
+}
+
+fn unhex(hex: &str) -> Option<u8> {
+    if hex.bytes().all(|ch| ch.is_ascii_hexdigit()) {
+        u8::from_str_radix(hex, 16).ok()
+    } else {
+        None
+    }
+}
+
+
+///
+/// Decoded (name, value) pairs of query string in original order,
+/// pair without "=" has empty value
+///
+pub fn parse_query(query: &str) -> Vec<(String, String)> {
+    query.split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let mut parts = pair.splitn(2, '=');
+            let name = parts.next().unwrap_or("");
+            let value = parts.next().unwrap_or("");
+            (percent_decode(name, true), percent_decode(value, true))
+        })
+        .collect()
+}
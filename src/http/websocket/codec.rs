@@ -0,0 +1,280 @@
+use std::io;
+use std::fmt;
+use std::error;
+use bytes::{Bytes, BytesMut};
+use tokio_io::codec::{Encoder, Decoder};
+
+
+// close status codes, RFC 6455 section 7.4.1
+pub const CLOSE_NORMAL: u16 = 1000;
+pub const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+pub const CLOSE_NO_STATUS: u16 = 1005;
+pub const CLOSE_ABNORMAL: u16 = 1006;
+pub const CLOSE_INVALID_DATA: u16 = 1007;
+pub const CLOSE_TOO_BIG: u16 = 1009;
+
+// payload of control frame is limited to 125 bytes
+const MAX_CONTROL_PAYLOAD: usize = 125;
+
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum OpCode {
+    Continue,
+    Text,
+    Binary,
+    Close,
+    Ping,
+    Pong,
+}
+
+impl OpCode {
+    fn from_u8(code: u8) -> Option<OpCode> {
+        match code {
+            0x0 => Some(OpCode::Continue),
+            0x1 => Some(OpCode::Text),
+            0x2 => Some(OpCode::Binary),
+            0x8 => Some(OpCode::Close),
+            0x9 => Some(OpCode::Ping),
+            0xA => Some(OpCode::Pong),
+            _ => None,
+        }
+    }
+
+    fn as_u8(&self) -> u8 {
+        match *self {
+            OpCode::Continue => 0x0,
+            OpCode::Text => 0x1,
+            OpCode::Binary => 0x2,
+            OpCode::Close => 0x8,
+            OpCode::Ping => 0x9,
+            OpCode::Pong => 0xA,
+        }
+    }
+
+    pub fn is_control(&self) -> bool {
+        match *self {
+            OpCode::Close | OpCode::Ping | OpCode::Pong => true,
+            _ => false,
+        }
+    }
+}
+
+
+#[derive(Debug)]
+pub struct Frame {
+    pub fin: bool,
+    pub opcode: OpCode,
+    // RSV1, first frame of message compressed with permessage-deflate
+    pub compressed: bool,
+    pub payload: Bytes,
+}
+
+impl Frame {
+    pub fn new(opcode: OpCode, payload: Bytes) -> Frame {
+        Frame { fin: true, opcode: opcode, compressed: false, payload: payload }
+    }
+
+    /// Close frame with status code and utf-8 reason
+    pub fn close(code: u16, reason: &str) -> Frame {
+        let mut payload = BytesMut::with_capacity(2 + reason.len());
+        payload.extend(&[(code >> 8) as u8, code as u8]);
+        // reason has to fit into control frame
+        let mut len = reason.len().min(MAX_CONTROL_PAYLOAD - 2);
+        while !reason.is_char_boundary(len) {
+            len -= 1;
+        }
+        payload.extend(reason[..len].as_bytes());
+        Frame::new(OpCode::Close, payload.freeze())
+    }
+
+    ///
+    /// Status code and reason of close frame, frame without
+    /// payload has CLOSE_NO_STATUS code
+    ///
+    pub fn close_status(&self) -> Result<(u16, String), Error> {
+        match self.payload.len() {
+            0 => Ok((CLOSE_NO_STATUS, String::new())),
+            1 => Err(Error::Protocol("close frame payload is too short")),
+            _ => {
+                let code = (self.payload[0] as u16) << 8 | self.payload[1] as u16;
+                if code < 1000 || code == CLOSE_NO_STATUS || code == CLOSE_ABNORMAL
+                    || (code > 1011 && code < 3000) || code > 4999 {
+                    return Err(Error::Protocol("invalid close code"))
+                }
+                match String::from_utf8(self.payload[2..].to_vec()) {
+                    Ok(reason) => Ok((code, reason)),
+                    Err(_) => Err(Error::InvalidUtf8),
+                }
+            }
+        }
+    }
+}
+
+
+#[derive(Debug)]
+pub enum Error {
+    Protocol(&'static str),
+    TooBig,
+    InvalidUtf8,
+    Io(io::Error),
+}
+
+impl Error {
+    /// Status code of close frame sent to peer
+    pub fn close_code(&self) -> u16 {
+        match *self {
+            Error::Protocol(..) => CLOSE_PROTOCOL_ERROR,
+            Error::TooBig => CLOSE_TOO_BIG,
+            Error::InvalidUtf8 => CLOSE_INVALID_DATA,
+            Error::Io(..) => CLOSE_ABNORMAL,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Protocol(msg) => write!(f, "WebSocket protocol error: {}", msg),
+            Error::TooBig => write!(f, "WebSocket message is too big"),
+            Error::InvalidUtf8 => write!(f, "WebSocket text is not valid utf-8"),
+            Error::Io(ref err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::Protocol(msg) => msg,
+            Error::TooBig => "WebSocket message is too big",
+            Error::InvalidUtf8 => "WebSocket text is not valid utf-8",
+            Error::Io(ref err) => err.description(),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
+    }
+}
+
+
+///
+/// Server side frame codec, incoming frames has to be masked,
+/// outgoing frames are sent unmasked
+///
+pub struct WebSocketCodec {
+    max_size: usize,
+    deflate: bool,
+}
+
+impl WebSocketCodec {
+    pub fn new(max_size: usize) -> WebSocketCodec {
+        WebSocketCodec { max_size: max_size, deflate: false }
+    }
+
+    /// Codec for connection with negotiated permessage-deflate, RSV1 is allowed
+    pub fn with_deflate(max_size: usize) -> WebSocketCodec {
+        WebSocketCodec { max_size: max_size, deflate: true }
+    }
+}
+
+impl Decoder for WebSocketCodec {
+    type Item = Frame;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Frame>, Error> {
+        if src.len() < 2 {
+            return Ok(None)
+        }
+        let first = src[0];
+        let second = src[1];
+
+        let fin = first & 0x80 != 0;
+        let opcode = match OpCode::from_u8(first & 0x0F) {
+            Some(opcode) => opcode,
+            None => return Err(Error::Protocol("unknown opcode")),
+        };
+        // RSV1 marks compressed message, it is set on first frame only
+        let compressed = first & 0x40 != 0;
+        if first & 0x30 != 0 || (compressed && (!self.deflate || opcode.is_control()
+                                                || opcode == OpCode::Continue)) {
+            return Err(Error::Protocol("reserved bits are set"))
+        }
+        if second & 0x80 == 0 {
+            return Err(Error::Protocol("client frame is not masked"))
+        }
+
+        let (len, pos) = match second & 0x7F {
+            126 => {
+                if src.len() < 4 {
+                    return Ok(None)
+                }
+                ((src[2] as u64) << 8 | src[3] as u64, 4)
+            },
+            127 => {
+                if src.len() < 10 {
+                    return Ok(None)
+                }
+                (src[2..10].iter().fold(0, |len, b| len << 8 | *b as u64), 10)
+            },
+            len => (len as u64, 2),
+        };
+
+        if opcode.is_control() && (!fin || len > MAX_CONTROL_PAYLOAD as u64) {
+            return Err(Error::Protocol("invalid control frame"))
+        }
+        if len > self.max_size as u64 {
+            return Err(Error::TooBig)
+        }
+
+        let len = len as usize;
+        if src.len() < pos + 4 + len {
+            src.reserve(pos + 4 + len);
+            return Ok(None)
+        }
+        let mask = [src[pos], src[pos+1], src[pos+2], src[pos+3]];
+        src.split_to(pos + 4);
+
+        let mut payload = src.split_to(len);
+        apply_mask(&mut payload, mask);
+
+        Ok(Some(Frame {
+            fin: fin, opcode: opcode, compressed: compressed, payload: payload.freeze() }))
+    }
+}
+
+impl Encoder for WebSocketCodec {
+    type Item = Frame;
+    type Error = io::Error;
+
+    fn encode(&mut self, frame: Frame, dst: &mut BytesMut) -> io::Result<()> {
+        let len = frame.payload.len();
+        dst.reserve(len + 10);
+
+        let mut first = (if frame.fin { 0x80 } else { 0 }) | frame.opcode.as_u8();
+        if frame.compressed {
+            first |= 0x40;
+        }
+        if len < 126 {
+            dst.extend(&[first, len as u8]);
+        } else if len <= 0xFFFF {
+            dst.extend(&[first, 126, (len >> 8) as u8, len as u8]);
+        } else {
+            dst.extend(&[first, 127]);
+            for shift in (0..8).rev() {
+                dst.extend(&[((len as u64) >> (shift * 8)) as u8]);
+            }
+        }
+        dst.extend(&frame.payload);
+        Ok(())
+    }
+}
+
+
+pub fn apply_mask(buf: &mut [u8], mask: [u8; 4]) {
+    for (idx, byte) in buf.iter_mut().enumerate() {
+        *byte ^= mask[idx & 3];
+    }
+}
@@ -0,0 +1,529 @@
+//! Raw DEFLATE (RFC 1951) for permessage-deflate extension (RFC 7692).
+//! Compressor emits single fixed huffman block per message.
+
+use std::mem;
+use std::usize;
+
+use super::codec::Error;
+
+
+// appended to compressed message before decompression, RFC 7692 section 7.2.2
+const TAIL: &'static [u8] = &[0x00, 0x00, 0xFF, 0xFF];
+
+// decompressor keeps largest possible window
+const WINDOW_SIZE: usize = 32768;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31,
+    35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2,
+    3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193,
+    257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577];
+const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6,
+    7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+// order of code length code lengths in dynamic block header
+const CODE_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+const MAX_CHAIN: usize = 64;
+const HASH_BITS: usize = 15;
+
+lazy_static! {
+    // fixed huffman codes, RFC 1951 section 3.2.6
+    static ref FIXED_TABLES: (Huffman, Huffman) = fixed_tables();
+}
+
+
+fn invalid() -> Error {
+    Error::Protocol("invalid compressed data")
+}
+
+
+///
+/// Decompressor of client messages, window is kept
+/// between messages unless context takeover is disabled
+///
+pub struct Inflater {
+    window: Vec<u8>,
+    takeover: bool,
+}
+
+impl Inflater {
+    pub fn new(takeover: bool) -> Inflater {
+        Inflater { window: Vec::new(), takeover: takeover }
+    }
+
+    ///
+    /// Decompress payload of message, decompressed size is limited by max_size
+    ///
+    pub fn inflate(&mut self, data: &[u8], max_size: usize) -> Result<Vec<u8>, Error> {
+        let mut input = Vec::with_capacity(data.len() + TAIL.len());
+        input.extend_from_slice(data);
+        input.extend_from_slice(TAIL);
+
+        // back references may point into window
+        let start = self.window.len();
+        let mut out = mem::replace(&mut self.window, Vec::new());
+        let limit = start + max_size;
+        {
+            let mut bits = Bits::new(&input);
+            while !bits.exhausted() {
+                let last = bits.take(1)?;
+                match bits.take(2)? {
+                    0 => stored(&mut bits, &mut out, limit)?,
+                    1 => {
+                        let (ref lit, ref dist) = *FIXED_TABLES;
+                        codes(&mut bits, &mut out, lit, dist, limit)?;
+                    },
+                    2 => {
+                        let (lit, dist) = dynamic_tables(&mut bits)?;
+                        codes(&mut bits, &mut out, &lit, &dist, limit)?;
+                    },
+                    _ => return Err(invalid()),
+                }
+                if last == 1 {
+                    break
+                }
+            }
+        }
+
+        let msg = out[start..].to_vec();
+        if self.takeover {
+            let keep = out.len().saturating_sub(WINDOW_SIZE);
+            out.drain(..keep);
+            self.window = out;
+        }
+        Ok(msg)
+    }
+}
+
+
+///
+/// Compressor of server messages, distances are limited by window bits
+///
+pub struct Deflater {
+    window_bits: u8,
+    takeover: bool,
+    history: Vec<u8>,
+    // hash chains of history, slide with it between messages
+    chains: Chains,
+}
+
+impl Deflater {
+    pub fn new(window_bits: u8, takeover: bool) -> Deflater {
+        Deflater { window_bits: window_bits, takeover: takeover,
+                   history: Vec::new(), chains: Chains::new() }
+    }
+
+    ///
+    /// Compress message, output is sync flushed and tail is removed
+    ///
+    pub fn deflate(&mut self, data: &[u8]) -> Vec<u8> {
+        let max_dist = 1usize << self.window_bits;
+        let start = self.history.len();
+        let mut buf = mem::replace(&mut self.history, Vec::new());
+        buf.extend_from_slice(data);
+
+        let mut out = BitWriter::new(data.len() / 2 + 16);
+        // BFINAL=0, fixed huffman codes
+        out.put(0, 1);
+        out.put(1, 2);
+
+        // last bytes of history are hashed once following data is known
+        let chains = &mut self.chains;
+        chains.reserve(buf.len());
+        for pos in start.saturating_sub(MIN_MATCH - 1)..start {
+            chains.insert(&buf, pos);
+        }
+
+        let mut pos = start;
+        while pos < buf.len() {
+            let (len, dist) = chains.longest_match(&buf, pos, max_dist);
+            if len >= MIN_MATCH {
+                put_length(&mut out, len);
+                put_distance(&mut out, dist);
+                for idx in pos..pos + len {
+                    chains.insert(&buf, idx);
+                }
+                pos += len;
+            } else {
+                put_literal(&mut out, buf[pos] as u16);
+                chains.insert(&buf, pos);
+                pos += 1;
+            }
+        }
+        put_literal(&mut out, 256);
+
+        // sync flush, empty stored block without LEN and NLEN
+        out.put(0, 3);
+        let compressed = out.finish();
+
+        if self.takeover {
+            let keep = buf.len().saturating_sub(max_dist);
+            buf.drain(..keep);
+            chains.slide(keep);
+            self.history = buf;
+        } else {
+            chains.slide(buf.len());
+        }
+        compressed
+    }
+}
+
+
+struct Bits<'a> {
+    data: &'a [u8],
+    pos: usize,
+    bits: u32,
+    cnt: u32,
+}
+
+impl<'a> Bits<'a> {
+    fn new(data: &'a [u8]) -> Bits<'a> {
+        Bits { data: data, pos: 0, bits: 0, cnt: 0 }
+    }
+
+    fn take(&mut self, n: u32) -> Result<u32, Error> {
+        while self.cnt < n {
+            if self.pos >= self.data.len() {
+                return Err(invalid())
+            }
+            self.bits |= (self.data[self.pos] as u32) << self.cnt;
+            self.pos += 1;
+            self.cnt += 8;
+        }
+        let val = self.bits & ((1 << n) - 1);
+        self.bits >>= n;
+        self.cnt -= n;
+        Ok(val)
+    }
+
+    // drop bits of partially consumed byte
+    fn align(&mut self) {
+        self.bits = 0;
+        self.cnt = 0;
+    }
+
+    fn exhausted(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+}
+
+
+///
+/// Canonical huffman code, decoded bit by bit
+///
+struct Huffman {
+    count: [u16; 16],
+    symbol: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Result<Huffman, Error> {
+        let mut count = [0u16; 16];
+        for len in lengths {
+            count[*len as usize] += 1;
+        }
+
+        // over-subscribed set of lengths is invalid
+        let mut left: i32 = 1;
+        for len in 1..16 {
+            left <<= 1;
+            left -= count[len] as i32;
+            if left < 0 {
+                return Err(invalid())
+            }
+        }
+
+        let mut offs = [0u16; 16];
+        for len in 1..15 {
+            offs[len + 1] = offs[len] + count[len];
+        }
+        let mut symbol = vec![0u16; lengths.len()];
+        for (sym, len) in lengths.iter().enumerate() {
+            if *len != 0 {
+                symbol[offs[*len as usize] as usize] = sym as u16;
+                offs[*len as usize] += 1;
+            }
+        }
+        count[0] = 0;
+
+        Ok(Huffman { count: count, symbol: symbol })
+    }
+
+    fn decode(&self, bits: &mut Bits) -> Result<u16, Error> {
+        let mut code: i32 = 0;
+        let mut first: i32 = 0;
+        let mut index: i32 = 0;
+        for len in 1..16 {
+            code |= bits.take(1)? as i32;
+            let count = self.count[len] as i32;
+            if code - count < first {
+                return Ok(self.symbol[(index + (code - first)) as usize])
+            }
+            index += count;
+            first += count;
+            first <<= 1;
+            code <<= 1;
+        }
+        Err(invalid())
+    }
+}
+
+
+fn fixed_tables() -> (Huffman, Huffman) {
+    let mut lengths = [0u8; 288];
+    for (sym, len) in lengths.iter_mut().enumerate() {
+        *len = if sym < 144 { 8 } else if sym < 256 { 9 } else if sym < 280 { 7 } else { 8 };
+    }
+    let lit = Huffman::new(&lengths).expect("fixed literal codes");
+    let dist = Huffman::new(&[5u8; 30]).expect("fixed distance codes");
+    (lit, dist)
+}
+
+fn dynamic_tables(bits: &mut Bits) -> Result<(Huffman, Huffman), Error> {
+    let nlen = bits.take(5)? as usize + 257;
+    let ndist = bits.take(5)? as usize + 1;
+    let ncode = bits.take(4)? as usize + 4;
+    if nlen > 286 || ndist > 30 {
+        return Err(invalid())
+    }
+
+    let mut lengths = [0u8; 19];
+    for idx in 0..ncode {
+        lengths[CODE_ORDER[idx]] = bits.take(3)? as u8;
+    }
+    let lencode = Huffman::new(&lengths)?;
+
+    let mut lengths = vec![0u8; nlen + ndist];
+    let mut idx = 0;
+    while idx < nlen + ndist {
+        let sym = lencode.decode(bits)?;
+        if sym < 16 {
+            lengths[idx] = sym as u8;
+            idx += 1;
+            continue
+        }
+        let (len, repeat) = match sym {
+            16 => {
+                if idx == 0 {
+                    return Err(invalid())
+                }
+                (lengths[idx - 1], 3 + bits.take(2)? as usize)
+            },
+            17 => (0, 3 + bits.take(3)? as usize),
+            _ => (0, 11 + bits.take(7)? as usize),
+        };
+        if idx + repeat > nlen + ndist {
+            return Err(invalid())
+        }
+        for _ in 0..repeat {
+            lengths[idx] = len;
+            idx += 1;
+        }
+    }
+    // end of block code is required
+    if lengths[256] == 0 {
+        return Err(invalid())
+    }
+
+    Ok((Huffman::new(&lengths[..nlen])?, Huffman::new(&lengths[nlen..])?))
+}
+
+fn stored(bits: &mut Bits, out: &mut Vec<u8>, limit: usize) -> Result<(), Error> {
+    bits.align();
+    let data = bits.data;
+    let pos = bits.pos;
+    if pos + 4 > data.len() {
+        return Err(invalid())
+    }
+    let len = data[pos] as usize | (data[pos + 1] as usize) << 8;
+    let nlen = data[pos + 2] as usize | (data[pos + 3] as usize) << 8;
+    if len != !nlen & 0xFFFF || pos + 4 + len > data.len() {
+        return Err(invalid())
+    }
+    if out.len() + len > limit {
+        return Err(Error::TooBig)
+    }
+    out.extend_from_slice(&data[pos + 4..pos + 4 + len]);
+    bits.pos = pos + 4 + len;
+    Ok(())
+}
+
+fn codes(bits: &mut Bits, out: &mut Vec<u8>,
+         lit: &Huffman, dist: &Huffman, limit: usize) -> Result<(), Error> {
+    loop {
+        let sym = lit.decode(bits)? as usize;
+        if sym < 256 {
+            out.push(sym as u8);
+        } else if sym == 256 {
+            return Ok(())
+        } else {
+            let sym = sym - 257;
+            if sym >= 29 {
+                return Err(invalid())
+            }
+            let len = LENGTH_BASE[sym] as usize + bits.take(LENGTH_EXTRA[sym] as u32)? as usize;
+
+            let sym = dist.decode(bits)? as usize;
+            if sym >= 30 {
+                return Err(invalid())
+            }
+            let distance = DIST_BASE[sym] as usize + bits.take(DIST_EXTRA[sym] as u32)? as usize;
+            if distance > out.len() {
+                return Err(invalid())
+            }
+            let from = out.len() - distance;
+            for idx in 0..len {
+                let byte = out[from + idx];
+                out.push(byte);
+            }
+        }
+        if out.len() > limit {
+            return Err(Error::TooBig)
+        }
+    }
+}
+
+
+struct BitWriter {
+    out: Vec<u8>,
+    bits: u32,
+    cnt: u32,
+}
+
+impl BitWriter {
+    fn new(capacity: usize) -> BitWriter {
+        BitWriter { out: Vec::with_capacity(capacity), bits: 0, cnt: 0 }
+    }
+
+    fn put(&mut self, value: u32, n: u32) {
+        self.bits |= value << self.cnt;
+        self.cnt += n;
+        while self.cnt >= 8 {
+            self.out.push(self.bits as u8);
+            self.bits >>= 8;
+            self.cnt -= 8;
+        }
+    }
+
+    // huffman codes are packed starting with most significant bit
+    fn put_code(&mut self, code: u32, n: u32) {
+        let mut rev = 0;
+        for idx in 0..n {
+            rev |= ((code >> idx) & 1) << (n - 1 - idx);
+        }
+        self.put(rev, n);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.cnt > 0 {
+            self.out.push(self.bits as u8);
+        }
+        self.out
+    }
+}
+
+fn put_literal(out: &mut BitWriter, sym: u16) {
+    let sym = sym as u32;
+    if sym < 144 {
+        out.put_code(0x30 + sym, 8);
+    } else if sym < 256 {
+        out.put_code(0x190 + sym - 144, 9);
+    } else if sym < 280 {
+        out.put_code(sym - 256, 7);
+    } else {
+        out.put_code(0xC0 + sym - 280, 8);
+    }
+}
+
+fn put_length(out: &mut BitWriter, len: usize) {
+    let sym = (0..29).rev().find(|sym| LENGTH_BASE[*sym] as usize <= len).unwrap_or(0);
+    put_literal(out, 257 + sym as u16);
+    out.put((len - LENGTH_BASE[sym] as usize) as u32, LENGTH_EXTRA[sym] as u32);
+}
+
+fn put_distance(out: &mut BitWriter, dist: usize) {
+    let sym = (0..30).rev().find(|sym| DIST_BASE[*sym] as usize <= dist).unwrap_or(0);
+    out.put_code(sym as u32, 5);
+    out.put((dist - DIST_BASE[sym] as usize) as u32, DIST_EXTRA[sym] as u32);
+}
+
+
+///
+/// Hash chains of 3 byte sequences. Positions are absolute, base is
+/// position of first byte of buffer, older positions are out of window
+///
+struct Chains {
+    head: Vec<usize>,
+    prev: Vec<usize>,
+    base: usize,
+}
+
+impl Chains {
+    fn new() -> Chains {
+        Chains { head: vec![usize::MAX; 1 << HASH_BITS], prev: Vec::new(), base: 0 }
+    }
+
+    fn reserve(&mut self, size: usize) {
+        self.prev.resize(size, usize::MAX);
+    }
+
+    // first bytes of buffer are dropped
+    fn slide(&mut self, cnt: usize) {
+        let cnt = cnt.min(self.prev.len());
+        self.prev.drain(..cnt);
+        self.base += cnt;
+    }
+
+    fn hash(buf: &[u8], pos: usize) -> usize {
+        ((buf[pos] as usize) << 10 ^ (buf[pos + 1] as usize) << 5 ^ buf[pos + 2] as usize)
+            & ((1 << HASH_BITS) - 1)
+    }
+
+    fn insert(&mut self, buf: &[u8], pos: usize) {
+        if pos + MIN_MATCH <= buf.len() {
+            let hash = Chains::hash(buf, pos);
+            self.prev[pos] = self.head[hash];
+            self.head[hash] = self.base + pos;
+        }
+    }
+
+    fn longest_match(&self, buf: &[u8], pos: usize, max_dist: usize) -> (usize, usize) {
+        if pos + MIN_MATCH > buf.len() {
+            return (0, 0)
+        }
+        let max_len = (buf.len() - pos).min(MAX_MATCH);
+        let (mut best_len, mut best_dist) = (0, 0);
+
+        let mut next = self.head[Chains::hash(buf, pos)];
+        let mut chain = 0;
+        while next != usize::MAX && next >= self.base && chain < MAX_CHAIN {
+            let candidate = next - self.base;
+            let dist = pos - candidate;
+            if dist > max_dist {
+                break
+            }
+            let len = buf[candidate..candidate + max_len].iter()
+                .zip(&buf[pos..pos + max_len])
+                .take_while(|&(a, b)| a == b)
+                .count();
+            if len > best_len {
+                best_len = len;
+                best_dist = dist;
+                if len == max_len {
+                    break
+                }
+            }
+            next = self.prev[candidate];
+            chain += 1;
+        }
+        (best_len, best_dist)
+    }
+}
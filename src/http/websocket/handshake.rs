@@ -0,0 +1,261 @@
+use std::fmt;
+
+use http::{Headers, Version};
+use utils::{BASE64, base64_encode};
+
+
+// appended to Sec-WebSocket-Key, RFC 6455 section 1.3
+const WS_GUID: &'static [u8] = b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const WS_VERSION: &'static str = "13";
+
+
+#[derive(Debug, PartialEq)]
+pub enum HandshakeError {
+    Method,
+    Version,
+    Upgrade,
+    WebSocketVersion,
+    Key,
+}
+
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let msg = match *self {
+            HandshakeError::Method => "Only GET method is allowed",
+            HandshakeError::Version => "HTTP/1.1 is required",
+            HandshakeError::Upgrade => "No websocket upgrade requested",
+            HandshakeError::WebSocketVersion => "Unsupported Sec-WebSocket-Version",
+            HandshakeError::Key => "Invalid Sec-WebSocket-Key",
+        };
+        write!(f, "WebSocket handshake error: {}", msg)
+    }
+}
+
+
+///
+/// Accepted handshake, 101 response, negotiated subprotocol and extension
+///
+#[derive(Debug)]
+pub struct Handshake {
+    pub accept: String,
+    pub protocol: Option<String>,
+    pub deflate: Option<PerMessageDeflate>,
+}
+
+impl Handshake {
+    pub fn response(&self) -> String {
+        let mut resp = format!(
+            "HTTP/1.1 101 Switching Protocols\r\n\
+             Upgrade: websocket\r\n\
+             Connection: Upgrade\r\n\
+             Sec-WebSocket-Accept: {}\r\n", self.accept);
+        if let Some(ref protocol) = self.protocol {
+            resp.push_str(&format!("Sec-WebSocket-Protocol: {}\r\n", protocol));
+        }
+        if let Some(ref deflate) = self.deflate {
+            resp.push_str(&format!("Sec-WebSocket-Extensions: {}\r\n", deflate.header()));
+        }
+        resp.push_str("\r\n");
+        resp
+    }
+}
+
+
+///
+/// Negotiated parameters of permessage-deflate extension, RFC 7692
+///
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct PerMessageDeflate {
+    pub server_no_context_takeover: bool,
+    pub client_no_context_takeover: bool,
+    pub server_max_window_bits: Option<u8>,
+}
+
+impl PerMessageDeflate {
+
+    ///
+    /// First acceptable permessage-deflate offer of Sec-WebSocket-Extensions
+    ///
+    pub fn negotiate(extensions: &str) -> Option<PerMessageDeflate> {
+        extensions.split(',').filter_map(PerMessageDeflate::parse_offer).next()
+    }
+
+    // offer with unknown or repeated parameter is declined
+    fn parse_offer(offer: &str) -> Option<PerMessageDeflate> {
+        let mut params = offer.split(';').map(|param| param.trim());
+        if params.next() != Some("permessage-deflate") {
+            return None
+        }
+
+        let mut deflate = PerMessageDeflate::default();
+        let mut seen = Vec::new();
+        for param in params {
+            let mut parts = param.splitn(2, '=');
+            let name = parts.next().unwrap_or("").trim();
+            let value = parts.next().map(|val| val.trim().trim_matches('"'));
+            if seen.contains(&name) {
+                return None
+            }
+            seen.push(name);
+
+            match (name, value) {
+                ("server_no_context_takeover", None) =>
+                    deflate.server_no_context_takeover = true,
+                ("client_no_context_takeover", None) =>
+                    deflate.client_no_context_takeover = true,
+                ("server_max_window_bits", Some(bits)) =>
+                    deflate.server_max_window_bits = Some(window_bits(bits)?),
+                // decompressor keeps full window, limit is not requested
+                ("client_max_window_bits", None) => (),
+                ("client_max_window_bits", Some(bits)) => {
+                    window_bits(bits)?;
+                },
+                _ => return None,
+            }
+        }
+        Some(deflate)
+    }
+
+    ///
+    /// Sec-WebSocket-Extensions value of response
+    ///
+    pub fn header(&self) -> String {
+        let mut header = String::from("permessage-deflate");
+        if self.server_no_context_takeover {
+            header.push_str("; server_no_context_takeover");
+        }
+        if self.client_no_context_takeover {
+            header.push_str("; client_no_context_takeover");
+        }
+        if let Some(bits) = self.server_max_window_bits {
+            header.push_str(&format!("; server_max_window_bits={}", bits));
+        }
+        header
+    }
+}
+
+fn window_bits(val: &str) -> Option<u8> {
+    match val.parse::<u8>() {
+        Ok(bits) if bits >= 8 && bits <= 15 => Some(bits),
+        _ => None,
+    }
+}
+
+
+///
+/// Validate websocket upgrade request, first of client subprotocols
+/// which is listed in protocols is selected. permessage-deflate
+/// is negotiated if compress is set and client offers it.
+///
+pub fn handshake(method: &str, version: Version, upgrade: bool, headers: &Headers,
+                 protocols: &[String], compress: bool) -> Result<Handshake, HandshakeError> {
+    if method != "GET" {
+        return Err(HandshakeError::Method)
+    }
+    if version != Version::Http11 {
+        return Err(HandshakeError::Version)
+    }
+    let websocket = headers.get("upgrade")
+        .map(|val| val.split(',').any(|val| val.trim().eq_ignore_ascii_case("websocket")))
+        .unwrap_or(false);
+    if !upgrade || !websocket {
+        return Err(HandshakeError::Upgrade)
+    }
+    if headers.get("sec-websocket-version").map(|val| val.trim()) != Some(WS_VERSION) {
+        return Err(HandshakeError::WebSocketVersion)
+    }
+    let key = match headers.get("sec-websocket-key") {
+        Some(key) if valid_key(key.trim()) => key.trim(),
+        _ => return Err(HandshakeError::Key),
+    };
+
+    let protocol = headers.get("sec-websocket-protocol").and_then(|val| {
+        val.split(',')
+            .map(|proto| proto.trim())
+            .find(|proto| protocols.iter().any(|p| p == proto))
+            .map(|proto| proto.to_owned())
+    });
+
+    let deflate = if compress {
+        headers.get("sec-websocket-extensions").and_then(PerMessageDeflate::negotiate)
+    } else {
+        None
+    };
+
+    Ok(Handshake { accept: accept_key(key.as_bytes()), protocol: protocol, deflate: deflate })
+}
+
+///
+/// Sec-WebSocket-Accept value for Sec-WebSocket-Key
+///
+pub fn accept_key(key: &[u8]) -> String {
+    let mut data = key.to_vec();
+    data.extend_from_slice(WS_GUID);
+    base64_encode(&sha1(&data))
+}
+
+
+// base64 encoded 16 bytes value
+fn valid_key(key: &str) -> bool {
+    let key = key.as_bytes();
+    key.len() == 24 && &key[22..] == b"==" && key[..22].iter().all(|ch| BASE64.contains(ch))
+}
+
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+
+    // padding, message length in bits at the end of last block
+    let mut msg = data.to_vec();
+    let bits = (data.len() as u64).wrapping_mul(8);
+    msg.push(0x80);
+    while msg.len() % 64 != 56 {
+        msg.push(0);
+    }
+    for shift in (0..8).rev() {
+        msg.push((bits >> (shift * 8)) as u8);
+    }
+
+    for block in msg.chunks(64) {
+        let mut w = [0u32; 80];
+        for idx in 0..16 {
+            w[idx] = (block[idx*4] as u32) << 24 | (block[idx*4+1] as u32) << 16
+                | (block[idx*4+2] as u32) << 8 | block[idx*4+3] as u32;
+        }
+        for idx in 16..80 {
+            w[idx] = (w[idx-3] ^ w[idx-8] ^ w[idx-14] ^ w[idx-16]).rotate_left(1);
+        }
+
+        let (mut a, mut b, mut c, mut d, mut e) = (h[0], h[1], h[2], h[3], h[4]);
+        for idx in 0..80 {
+            let (f, k) = if idx < 20 {
+                ((b & c) | (!b & d), 0x5A827999)
+            } else if idx < 40 {
+                (b ^ c ^ d, 0x6ED9EBA1)
+            } else if idx < 60 {
+                ((b & c) | (b & d) | (c & d), 0x8F1BBCDC)
+            } else {
+                (b ^ c ^ d, 0xCA62C1D6)
+            };
+            let tmp = a.rotate_left(5).wrapping_add(f).wrapping_add(e)
+                .wrapping_add(k).wrapping_add(w[idx]);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = tmp;
+        }
+        h[0] = h[0].wrapping_add(a);
+        h[1] = h[1].wrapping_add(b);
+        h[2] = h[2].wrapping_add(c);
+        h[3] = h[3].wrapping_add(d);
+        h[4] = h[4].wrapping_add(e);
+    }
+
+    let mut out = [0u8; 20];
+    for (idx, word) in h.iter().enumerate() {
+        for byte in 0..4 {
+            out[idx*4 + byte] = (word >> (24 - byte * 8)) as u8;
+        }
+    }
+    out
+}
@@ -0,0 +1,9 @@
+mod codec;
+mod deflate;
+mod handshake;
+mod transport;
+
+pub use self::codec::{Error, Frame, OpCode, WebSocketCodec, apply_mask};
+pub use self::deflate::{Deflater, Inflater};
+pub use self::handshake::{Handshake, HandshakeError, PerMessageDeflate, accept_key, handshake};
+pub use self::transport::{Options, WebSocketTransport, start_websocket};
@@ -0,0 +1,432 @@
+use std::io;
+use std::str;
+use std::collections::VecDeque;
+
+use pyo3::*;
+use bytes::{Bytes, BytesMut};
+use futures::{Async, AsyncSink, Future, Poll, Sink, Stream};
+use futures::unsync::mpsc;
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_io::codec::{Framed, FramedParts};
+
+use {PyFuture, TokioEventLoop};
+use http::errors::WebSocketError;
+use pyunsafe::{GIL, Sender};
+use server::ConnectionGuard;
+use super::codec::{self, Error, Frame, OpCode, WebSocketCodec};
+use super::deflate::{Deflater, Inflater};
+use super::handshake::PerMessageDeflate;
+
+
+///
+/// Settings of accepted websocket connection
+///
+pub struct Options {
+    pub protocol: Option<String>,
+    pub max_size: usize,
+    pub deflate: Option<PerMessageDeflate>,
+}
+
+
+pub enum WebSocketMessage {
+    Frame(Frame),
+    Close(u16, String),
+}
+
+
+///
+/// Start websocket connection on socket, data received
+/// after handshake request is part of socket stream
+///
+pub fn start_websocket<T>(py: Python, evloop: &TokioEventLoop, socket: T,
+                          opts: Options, guard: Option<ConnectionGuard>)
+                          -> PyResult<Py<WebSocketTransport>>
+    where T: AsyncRead + AsyncWrite + 'static
+{
+    let (tx, rx) = mpsc::unbounded();
+    let tr = py.init(|token| WebSocketTransport {
+        evloop: evloop.into(),
+        transport: Sender::new(tx),
+        protocol: opts.protocol,
+        messages: VecDeque::new(),
+        waiter: None,
+        close_waiters: Vec::new(),
+        exception: None,
+        close_code: None,
+        closing: false,
+        closed: false,
+        token: token})?;
+    let conn = tr.clone_ref(py);
+
+    let (codec, inflater, deflater) = match opts.deflate {
+        Some(deflate) => (
+            WebSocketCodec::with_deflate(opts.max_size),
+            Some(Inflater::new(!deflate.client_no_context_takeover)),
+            Some(Deflater::new(deflate.server_max_window_bits.unwrap_or(15),
+                               !deflate.server_no_context_takeover))),
+        None => (WebSocketCodec::new(opts.max_size), None, None),
+    };
+
+    let parts = FramedParts {
+        inner: socket, readbuf: BytesMut::new(), writebuf: BytesMut::new()};
+    let connection = WebSocketConnection {
+        framed: Framed::from_parts(parts, codec),
+        intake: rx,
+        transport: tr.clone_ref(py),
+        partial: None,
+        inflater: inflater,
+        deflater: deflater,
+        max_size: opts.max_size,
+        queue: VecDeque::new(),
+        close_sent: false,
+        close_received: false,
+        flushed: true,
+    };
+
+    evloop.href().spawn(
+        connection.then(move |res| {
+            let py = GIL::python();
+            conn.as_mut(py).connection_lost(py, res.err());
+            drop(guard);
+            Ok(())
+        }));
+
+    Ok(tr)
+}
+
+
+#[py::class(weakref)]
+pub struct WebSocketTransport {
+    evloop: Py<TokioEventLoop>,
+    transport: Sender<WebSocketMessage>,
+    protocol: Option<String>,
+    // received text and binary messages
+    messages: VecDeque<PyObject>,
+    waiter: Option<Py<PyFuture>>,
+    close_waiters: Vec<Py<PyFuture>>,
+    exception: Option<PyObject>,
+    close_code: Option<u16>,
+    closing: bool,
+    closed: bool,
+    token: PyToken,
+}
+
+#[py::methods]
+impl WebSocketTransport {
+
+    ///
+    /// Negotiated subprotocol or None
+    ///
+    #[getter]
+    fn get_protocol(&self) -> PyResult<Option<String>> {
+        Ok(self.protocol.clone())
+    }
+
+    ///
+    /// Status code of close frame, None while connection is open
+    ///
+    #[getter]
+    fn get_close_code(&self) -> PyResult<Option<u16>> {
+        Ok(self.close_code)
+    }
+
+    #[getter]
+    fn get_closed(&self) -> PyResult<bool> {
+        Ok(self.closed)
+    }
+
+    fn is_closing(&self) -> PyResult<bool> {
+        Ok(self.closing)
+    }
+
+    fn send_text(&mut self, data: String) -> PyResult<()> {
+        self.send(OpCode::Text, Bytes::from(data.into_bytes()))
+    }
+
+    fn send_bytes(&mut self, py: Python, data: &PyObjectRef) -> PyResult<()> {
+        let data = buffer::PyBuffer::get(py, data)
+            .map_err(|_| exc::TypeError::new("data argument must be a bytes-like object"))?
+            .to_vec::<u8>(py)?;
+        self.send(OpCode::Binary, Bytes::from(data))
+    }
+
+    fn ping(&mut self, py: Python, data: Option<&PyObjectRef>) -> PyResult<()> {
+        let data = match data {
+            Some(data) => buffer::PyBuffer::get(py, data)?.to_vec::<u8>(py)?,
+            None => Vec::new(),
+        };
+        if data.len() > 125 {
+            return Err(exc::ValueError::new("Ping payload is limited to 125 bytes"))
+        }
+        self.send(OpCode::Ping, Bytes::from(data))
+    }
+
+    ///
+    /// Next received message, str for text and bytes for binary message.
+    /// None is returned once connection is closed.
+    ///
+    fn receive(&mut self, py: Python) -> PyResult<Py<PyFuture>> {
+        if self.waiter.is_some() {
+            return Err(exc::RuntimeError::new(
+                "Called while some coroutine is waiting for incoming message."))
+        }
+        let fut = PyFuture::new(py, self.evloop.clone_ref(py))?;
+        if let Some(msg) = self.messages.pop_front() {
+            fut.as_mut(py).set(py, Ok(msg));
+        } else if let Some(exc) = self.exception.take() {
+            fut.as_mut(py).set(py, Err(PyErr::from_instance(exc.as_ref(py))));
+        } else if self.closed {
+            fut.as_mut(py).set(py, Ok(py.None()));
+        } else {
+            self.waiter = Some(fut.clone_ref(py));
+        }
+        Ok(fut)
+    }
+
+    ///
+    /// Start closing handshake, returned future is resolved
+    /// once connection is closed
+    ///
+    #[args(code="1000", reason="None")]
+    fn close(&mut self, py: Python, code: u16, reason: Option<&str>) -> PyResult<Py<PyFuture>> {
+        if self.closed {
+            return PyFuture::done_fut(py, self.evloop.clone_ref(py), py.None())
+        }
+        if !self.closing {
+            self.closing = true;
+            let reason = reason.unwrap_or("").to_owned();
+            let _ = self.transport.send(WebSocketMessage::Close(code, reason));
+        }
+        let fut = PyFuture::new(py, self.evloop.clone_ref(py))?;
+        self.close_waiters.push(fut.clone_ref(py));
+        Ok(fut)
+    }
+}
+
+impl WebSocketTransport {
+
+    fn send(&mut self, opcode: OpCode, data: Bytes) -> PyResult<()> {
+        if self.closing {
+            return Err(exc::RuntimeError::new("WebSocket is closing"))
+        }
+        let _ = self.transport.send(WebSocketMessage::Frame(Frame::new(opcode, data)));
+        Ok(())
+    }
+
+    fn message_received(&mut self, py: Python, msg: PyObject) {
+        match self.waiter.take() {
+            Some(fut) => fut.as_mut(py).set(py, Ok(msg)),
+            None => self.messages.push_back(msg),
+        }
+    }
+
+    fn close_received(&mut self, code: u16) {
+        self.closing = true;
+        self.close_code = Some(code);
+    }
+
+    fn protocol_error(&mut self, py: Python, err: &Error) {
+        self.closing = true;
+        self.close_code = Some(err.close_code());
+        let exc = WebSocketError::new(format!("{}", err));
+        match self.waiter.take() {
+            Some(fut) => fut.as_mut(py).set(py, Err(exc)),
+            None => self.exception = Some(exc.into_object(py)),
+        }
+    }
+
+    fn connection_lost(&mut self, py: Python, err: Option<io::Error>) {
+        self.closing = true;
+        self.closed = true;
+        if self.close_code.is_none() {
+            self.close_code = Some(codec::CLOSE_ABNORMAL);
+        }
+        if let Some(fut) = self.waiter.take() {
+            match err {
+                Some(err) => fut.as_mut(py).set(py, Err(err.into())),
+                None => fut.as_mut(py).set(py, Ok(py.None())),
+            }
+        }
+        for fut in self.close_waiters.drain(..) {
+            fut.as_mut(py).set(py, Ok(py.None()));
+        }
+    }
+}
+
+
+struct WebSocketConnection<T> {
+    framed: Framed<T, WebSocketCodec>,
+    intake: mpsc::UnboundedReceiver<WebSocketMessage>,
+    transport: Py<WebSocketTransport>,
+    // opcode, compression and payload of fragmented message
+    partial: Option<(OpCode, bool, BytesMut)>,
+    // permessage-deflate state
+    inflater: Option<Inflater>,
+    deflater: Option<Deflater>,
+    max_size: usize,
+    queue: VecDeque<Frame>,
+    close_sent: bool,
+    close_received: bool,
+    flushed: bool,
+}
+
+impl<T> WebSocketConnection<T> {
+
+    // data messages are compressed if permessage-deflate is negotiated
+    fn compress(&mut self, frame: Frame) -> Frame {
+        match self.deflater {
+            Some(ref mut deflater) if !frame.opcode.is_control() => Frame {
+                fin: frame.fin,
+                opcode: frame.opcode,
+                compressed: true,
+                payload: Bytes::from(deflater.deflate(&frame.payload)),
+            },
+            _ => frame,
+        }
+    }
+
+    fn send_close(&mut self, code: u16, reason: &str) {
+        if !self.close_sent {
+            self.close_sent = true;
+            self.queue.push_back(Frame::close(code, reason));
+        }
+    }
+
+    fn frame_received(&mut self, py: Python, frame: Frame) -> Result<(), Error> {
+        match frame.opcode {
+            OpCode::Ping => {
+                if !self.close_sent {
+                    self.queue.push_back(Frame::new(OpCode::Pong, frame.payload));
+                }
+            },
+            OpCode::Pong => (),
+            OpCode::Close => {
+                let (code, _) = frame.close_status()?;
+                self.close_received = true;
+                self.transport.as_mut(py).close_received(code);
+                // echo status code, RFC 6455 section 5.5.1
+                let code = if code == codec::CLOSE_NO_STATUS { codec::CLOSE_NORMAL } else { code };
+                self.send_close(code, "");
+            },
+            OpCode::Continue => {
+                let (opcode, compressed, mut payload) = match self.partial.take() {
+                    Some(partial) => partial,
+                    None => return Err(Error::Protocol("unexpected continuation frame")),
+                };
+                if payload.len() + frame.payload.len() > self.max_size {
+                    return Err(Error::TooBig)
+                }
+                payload.extend(&frame.payload);
+                if frame.fin {
+                    self.message_received(py, opcode, compressed, payload.freeze())?;
+                } else {
+                    self.partial = Some((opcode, compressed, payload));
+                }
+            },
+            OpCode::Text | OpCode::Binary => {
+                if self.partial.is_some() {
+                    return Err(Error::Protocol("fragmented message is not finished"))
+                }
+                if frame.fin {
+                    self.message_received(py, frame.opcode, frame.compressed, frame.payload)?;
+                } else {
+                    self.partial = Some(
+                        (frame.opcode, frame.compressed, BytesMut::from(&frame.payload[..])));
+                }
+            },
+        }
+        Ok(())
+    }
+
+    fn message_received(&mut self, py: Python, opcode: OpCode, compressed: bool,
+                        payload: Bytes) -> Result<(), Error> {
+        // messages after sent close frame are dropped
+        if self.close_sent {
+            return Ok(())
+        }
+        let payload = match self.inflater {
+            Some(ref mut inflater) if compressed =>
+                Bytes::from(inflater.inflate(&payload, self.max_size)?),
+            _ => payload,
+        };
+        let msg: PyObject = if opcode == OpCode::Text {
+            match str::from_utf8(&payload) {
+                Ok(text) => PyString::new(py, text).into(),
+                Err(_) => return Err(Error::InvalidUtf8),
+            }
+        } else {
+            PyBytes::new(py, &payload).into()
+        };
+        self.transport.as_mut(py).message_received(py, msg);
+        Ok(())
+    }
+}
+
+impl<T> Future for WebSocketConnection<T>
+    where T: AsyncRead + AsyncWrite
+{
+    type Item = ();
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let py = GIL::python();
+
+        // commands from transport
+        loop {
+            match self.intake.poll() {
+                Ok(Async::Ready(Some(WebSocketMessage::Frame(frame)))) =>
+                    if !self.close_sent {
+                        let frame = self.compress(frame);
+                        self.queue.push_back(frame)
+                    },
+                Ok(Async::Ready(Some(WebSocketMessage::Close(code, reason)))) =>
+                    self.send_close(code, &reason),
+                Ok(Async::Ready(None)) | Err(_) => {
+                    self.send_close(codec::CLOSE_NORMAL, "");
+                    break
+                },
+                Ok(Async::NotReady) => break,
+            }
+        }
+
+        // incoming frames, after close frame is received nothing is read
+        while !self.close_received {
+            match self.framed.poll() {
+                Ok(Async::Ready(Some(frame))) => {
+                    if let Err(err) = self.frame_received(py, frame) {
+                        self.close_received = true;
+                        self.transport.as_mut(py).protocol_error(py, &err);
+                        self.send_close(err.close_code(), "");
+                    }
+                },
+                Ok(Async::Ready(None)) => return Ok(Async::Ready(())),
+                Ok(Async::NotReady) => break,
+                Err(Error::Io(err)) => return Err(err),
+                Err(err) => {
+                    self.close_received = true;
+                    self.transport.as_mut(py).protocol_error(py, &err);
+                    self.send_close(err.close_code(), "");
+                },
+            }
+        }
+
+        // outgoing frames
+        while let Some(frame) = self.queue.pop_front() {
+            self.flushed = false;
+            if let AsyncSink::NotReady(frame) = self.framed.start_send(frame)? {
+                self.queue.push_front(frame);
+                break
+            }
+        }
+        if !self.flushed {
+            self.flushed = self.framed.poll_complete()?.is_ready();
+        }
+
+        // closing handshake is completed
+        if self.close_sent && self.close_received && self.queue.is_empty() && self.flushed {
+            return self.framed.close()
+        }
+
+        Ok(Async::NotReady)
+    }
+}
@@ -16,7 +16,7 @@ extern crate tokio_signal;
 extern crate tokio_uds;
 extern crate boxfnonce;
 extern crate env_logger;
-extern crate pyo3;
+#[macro_use] extern crate pyo3;
 #[macro_use] extern crate log;
 #[macro_use] extern crate lazy_static;
 
@@ -32,9 +32,15 @@ pub mod pyunsafe;
 mod fd;
 mod event_loop;
 mod transport;
+mod faults;
 mod socket;
 mod server;
+mod activation;
+mod sniff;
+mod datagram;
 mod client;
+mod proxy;
+mod logconn;
 mod signals;
 mod callbacks;
 
@@ -59,6 +65,30 @@ fn init_async_tokio(py: Python, m: &PyModule) -> PyResult<()> {
         new_event_loop(py).into()
     }
 
+    #[pyfn(m, "status_line")]
+    /// Shared "HTTP/1.1 <code> <reason>\r\n" string for common status codes
+    fn _status_line(py: Python, code: u16) -> PyResult<PyObject> {
+        Ok(http::Strings.status_line(py, code).to_object(py))
+    }
+
+    #[pyfn(m, "reason_phrase")]
+    /// Shared reason phrase string for common status codes
+    fn _reason_phrase(py: Python, code: u16) -> PyResult<PyObject> {
+        Ok(http::Strings.reason(py, code).to_object(py))
+    }
+
+    #[pyfn(m, "waiters_stats")]
+    /// Future waiter allocation counters: (allocated, reused, free)
+    fn _waiters_stats(py: Python) -> PyResult<PyObject> {
+        Ok(pyfuture::waiters_stats().to_object(py))
+    }
+
+    #[pyfn(m, "read_buffers_stats")]
+    /// Transport read buffer pool counters: (allocated, reused, free)
+    fn _read_buffers_stats(py: Python) -> PyResult<PyObject> {
+        Ok(pybytes::read_buffers_stats().to_object(py))
+    }
+
     register_classes(py, m)
 }
 
@@ -68,17 +98,28 @@ pub fn register_classes(_py: pyo3::Python, m: &pyo3::PyModule) -> pyo3::PyResult
     m.add_class::<pytask::PyTask>()?;
     m.add_class::<pyfuture::PyFuture>()?;
     m.add_class::<pybytes::PyBytes>()?;
+    m.add_class::<pybytes::WriteBuffer>()?;
     m.add_class::<handle::PyHandle>()?;
     m.add_class::<server::TokioServer>()?;
     m.add_class::<socket::Socket>()?;
     m.add_class::<transport::PyTcpTransport>()?;
+    m.add_class::<sniff::SniffRoutes>()?;
+    m.add_class::<logconn::LogTransport>()?;
 
-    //m.add_class::<http::PyRequest>(py)?;
-    //m.add_class::<http::StreamReader>(py)?;
-    //m.add_class::<http::RawHeaders>(py)?;
-    //m.add_class::<http::Url>(py)?;
-    //m.add_class::<http::PayloadWriter>(py)?;
-    //m.add_class::<http::pytransport::PyHttpTransport>(py)?;
+    m.add_class::<http::PyRequest>()?;
+    m.add_class::<http::StreamReader>()?;
+    m.add_class::<http::RawHeaders>()?;
+    m.add_class::<http::ResponseHeaders>()?;
+    m.add_class::<http::Url>()?;
+    m.add_class::<http::Query>()?;
+    m.add_class::<http::Span>()?;
+    m.add_class::<http::PayloadWriter>()?;
+    m.add_class::<http::WebSocketTransport>()?;
+    m.add_class::<http::HttpCapture>()?;
+    m.add_class::<http::HttpRequestParser>()?;
+    m.add_class::<http::HttpResponseParser>()?;
+    http::register_errors(_py, m)?;
+    m.add_class::<http::pytransport::PyHttpTransport>()?;
 
     Ok(())
 }
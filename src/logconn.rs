@@ -0,0 +1,363 @@
+use std::io::{self, Read, Write};
+use std::cmp;
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::error::Error;
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use pyo3::*;
+use futures::{future, task, Async, Future, Poll};
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::Timeout;
+
+use addrinfo::{self, LookupWorkerSender, SocketType};
+use client;
+use pyunsafe::Handle;
+
+
+pub const DEFAULT_LOG_BUFFER: usize = 1024 * 1024;
+pub const DEFAULT_RECONNECT_DELAY: u64 = 100;
+pub const DEFAULT_MAX_RECONNECT_DELAY: u64 = 30_000;
+
+
+//
+// Ring buffer shared between LogTransport and LogConnection
+//
+struct LogBuffer {
+    chunks: VecDeque<Vec<u8>>,
+    // bytes of front chunk written to current connection
+    pos: usize,
+    size: usize,
+    capacity: usize,
+    dropped: u64,
+    dropped_bytes: u64,
+    sent_bytes: u64,
+    reconnects: u64,
+    connected: bool,
+    closed: bool,
+    task: Option<task::Task>,
+}
+
+impl LogBuffer {
+
+    fn push(&mut self, data: Vec<u8>) {
+        if data.len() > self.capacity {
+            self.dropped += 1;
+            self.dropped_bytes += data.len() as u64;
+            return
+        }
+
+        // drop oldest chunks, partially written chunk has to be finished
+        while self.size + data.len() > self.capacity {
+            let idx = if self.pos > 0 { 1 } else { 0 };
+            match self.chunks.remove(idx) {
+                Some(chunk) => {
+                    self.size -= chunk.len();
+                    self.dropped += 1;
+                    self.dropped_bytes += chunk.len() as u64;
+                },
+                None => break,
+            }
+        }
+
+        self.size += data.len();
+        self.chunks.push_back(data);
+        self.notify();
+    }
+
+    fn notify(&mut self) {
+        if let Some(task) = self.task.take() {
+            task.notify();
+        }
+    }
+
+    fn write_to(&mut self, socket: &mut TcpStream) -> io::Result<()> {
+        loop {
+            let size = match self.chunks.front() {
+                Some(chunk) => socket.write(&chunk[self.pos..])?,
+                None => return Ok(()),
+            };
+            if size == 0 {
+                return Err(io::Error::new(io::ErrorKind::WriteZero, "failed to write log data"))
+            }
+            self.pos += size;
+            self.sent_bytes += size as u64;
+
+            if self.chunks.front().map(|chunk| chunk.len() == self.pos).unwrap_or(false) {
+                if let Some(chunk) = self.chunks.pop_front() {
+                    self.size -= chunk.len();
+                }
+                self.pos = 0;
+            }
+        }
+    }
+
+    fn disconnected(&mut self) {
+        // partially written chunk is sent again over new connection
+        self.connected = false;
+        self.pos = 0;
+        self.reconnects += 1;
+    }
+}
+
+
+///
+/// Write-behind transport, never blocks writer. Data is buffered
+/// in bounded ring buffer and oldest data is dropped on overflow
+///
+#[py::class(weakref)]
+pub struct LogTransport {
+    buf: Rc<RefCell<LogBuffer>>,
+    token: PyToken,
+}
+
+#[py::methods]
+impl LogTransport {
+
+    ///
+    /// Queue data, drops oldest buffered data if buffer is full
+    ///
+    fn write(&self, py: Python, data: &PyObjectRef) -> PyResult<()> {
+        let data = buffer::PyBuffer::get(py, data)
+            .and_then(|buf| buf.to_vec::<u8>(py))
+            .map_err(|_| exc::TypeError::new("data argument must be a bytes-like object"))?;
+
+        let mut buf = self.buf.borrow_mut();
+        if buf.closed {
+            return Err(exc::RuntimeError::new("Transport is closed"))
+        }
+        if !data.is_empty() {
+            buf.push(data);
+        }
+        Ok(())
+    }
+
+    ///
+    /// Close transport, buffered data is flushed if connection is established
+    ///
+    fn close(&self) -> PyResult<()> {
+        let mut buf = self.buf.borrow_mut();
+        buf.closed = true;
+        buf.notify();
+        Ok(())
+    }
+
+    fn is_closing(&self) -> PyResult<bool> {
+        Ok(self.buf.borrow().closed)
+    }
+
+    #[getter]
+    fn get_connected(&self) -> PyResult<bool> {
+        Ok(self.buf.borrow().connected)
+    }
+
+    /// number of dropped writes
+    #[getter]
+    fn get_dropped(&self) -> PyResult<u64> {
+        Ok(self.buf.borrow().dropped)
+    }
+
+    #[getter]
+    fn get_dropped_bytes(&self) -> PyResult<u64> {
+        Ok(self.buf.borrow().dropped_bytes)
+    }
+
+    #[getter]
+    fn get_sent_bytes(&self) -> PyResult<u64> {
+        Ok(self.buf.borrow().sent_bytes)
+    }
+
+    /// number of bytes waiting in buffer
+    #[getter]
+    fn get_buffered(&self) -> PyResult<usize> {
+        let buf = self.buf.borrow();
+        Ok(buf.size - buf.pos)
+    }
+
+    #[getter]
+    fn get_reconnects(&self) -> PyResult<u64> {
+        Ok(self.buf.borrow().reconnects)
+    }
+}
+
+
+///
+/// Create log transport and spawn connection future
+///
+pub fn create_log_connection(py: Python, handle: Handle, lookup: LookupWorkerSender,
+                             host: String, port: u16, family: i32, capacity: usize,
+                             delay: Duration, max_delay: Duration) -> PyResult<Py<LogTransport>>
+{
+    let buf = Rc::new(RefCell::new(LogBuffer {
+        chunks: VecDeque::new(),
+        pos: 0,
+        size: 0,
+        capacity: capacity,
+        dropped: 0,
+        dropped_bytes: 0,
+        sent_bytes: 0,
+        reconnects: 0,
+        connected: false,
+        closed: false,
+        task: None,
+    }));
+
+    let port = port.to_string();
+    let fut = connect(&handle, &lookup, &host, &port, family);
+    handle.spawn(LogConnection {
+        buf: buf.clone(),
+        state: State::Connecting(fut),
+        handle: handle.clone(),
+        lookup: lookup,
+        host: host,
+        port: port,
+        family: family,
+        delay: delay,
+        min_delay: delay,
+        max_delay: max_delay,
+    });
+
+    py.init(|token| LogTransport {buf: buf, token: token})
+}
+
+
+// address is resolved on each attempt
+fn connect(handle: &Handle, lookup: &LookupWorkerSender, host: &str, port: &str, family: i32)
+           -> Box<Future<Item=TcpStream, Error=io::Error>>
+{
+    let handle = handle.clone();
+    Box::new(
+        addrinfo::lookup(lookup, Some(host.to_owned()), Some(port.to_owned()),
+                         family, 0, SocketType::Stream)
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err.description()))
+            .and_then(move |addrs| match addrs {
+                Err(err) => future::Either::A(
+                    future::err(io::Error::new(io::ErrorKind::Other, err.description()))),
+                Ok(addrs) => future::Either::B(
+                    client::connect(addrs, handle, None, None).map(|(socket, _)| socket)),
+            }))
+}
+
+
+enum State {
+    Connecting(Box<Future<Item=TcpStream, Error=io::Error>>),
+    Connected(TcpStream),
+    Backoff(Timeout),
+}
+
+struct LogConnection {
+    buf: Rc<RefCell<LogBuffer>>,
+    state: State,
+    handle: Handle,
+    lookup: LookupWorkerSender,
+    host: String,
+    port: String,
+    family: i32,
+    delay: Duration,
+    min_delay: Duration,
+    max_delay: Duration,
+}
+
+impl LogConnection {
+
+    fn connect(&self) -> Box<Future<Item=TcpStream, Error=io::Error>> {
+        connect(&self.handle, &self.lookup, &self.host, &self.port, self.family)
+    }
+
+    // exponential backoff between reconnect attempts
+    fn backoff(&mut self) -> State {
+        let delay = self.delay;
+        self.delay = cmp::min(self.delay * 2, self.max_delay);
+        match Timeout::new(delay, &self.handle) {
+            Ok(timeout) => State::Backoff(timeout),
+            Err(_) => State::Connecting(self.connect()),
+        }
+    }
+}
+
+// check if peer closed connection, received data is ignored
+fn peer_closed(socket: &mut TcpStream) -> io::Result<bool> {
+    let mut chunk = [0; 256];
+    loop {
+        match socket.read(&mut chunk) {
+            Ok(0) => return Ok(true),
+            Ok(_) => (),
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+enum Action {
+    Connected(TcpStream),
+    Reconnect,
+    Connect,
+}
+
+impl Future for LogConnection {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        self.buf.borrow_mut().task = Some(task::current());
+
+        loop {
+            let closed = self.buf.borrow().closed;
+
+            let action = match self.state {
+                State::Connecting(ref mut fut) => match fut.poll() {
+                    Ok(Async::Ready(socket)) => Action::Connected(socket),
+                    Ok(Async::NotReady) =>
+                        return if closed { Ok(Async::Ready(())) } else { Ok(Async::NotReady) },
+                    Err(err) => {
+                        debug!("Log connection failed: {}", err);
+                        Action::Reconnect
+                    }
+                },
+                State::Connected(ref mut socket) => {
+                    let mut buf = self.buf.borrow_mut();
+                    match buf.write_to(socket).and_then(|_| peer_closed(socket)) {
+                        Ok(false) =>
+                            return if closed && buf.chunks.is_empty() {
+                                Ok(Async::Ready(()))
+                            } else {
+                                Ok(Async::NotReady)
+                            },
+                        Err(ref err) if err.kind() == io::ErrorKind::WouldBlock =>
+                            return Ok(Async::NotReady),
+                        Ok(true) => {
+                            debug!("Log connection closed by peer");
+                            buf.disconnected();
+                            Action::Reconnect
+                        },
+                        Err(err) => {
+                            debug!("Log connection error: {}", err);
+                            buf.disconnected();
+                            Action::Reconnect
+                        },
+                    }
+                },
+                State::Backoff(ref mut timeout) => {
+                    if closed {
+                        return Ok(Async::Ready(()))
+                    }
+                    match timeout.poll() {
+                        Ok(Async::NotReady) => return Ok(Async::NotReady),
+                        _ => Action::Connect,
+                    }
+                },
+            };
+
+            self.state = match action {
+                Action::Connected(socket) => {
+                    self.delay = self.min_delay;
+                    self.buf.borrow_mut().connected = true;
+                    State::Connected(socket)
+                },
+                Action::Reconnect => self.backoff(),
+                Action::Connect => State::Connecting(self.connect()),
+            };
+        }
+    }
+}
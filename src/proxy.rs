@@ -0,0 +1,255 @@
+use std::io;
+use std::net;
+use pyo3::*;
+use futures::{future, Future};
+use tokio_core::net::TcpStream;
+use tokio_io::io::{read_exact, write_all};
+
+use http::percent_decode;
+use utils::{base64_encode, OperationError};
+
+type IoFuture<T> = Box<Future<Item=T, Error=io::Error>>;
+
+// maximum size of CONNECT response headers
+const MAX_RESPONSE_SIZE: usize = 8192;
+
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum ProxyKind {
+    Socks5,
+    Http,
+}
+
+///
+/// Proxy server from create_connection(proxy=...) url,
+/// socks5://[user:password@]host[:port] or http://[user:password@]host[:port]
+///
+#[derive(Clone, Debug)]
+pub struct Proxy {
+    pub kind: ProxyKind,
+    pub host: String,
+    pub port: u16,
+    pub auth: Option<(String, String)>,
+}
+
+impl Proxy {
+
+    pub fn parse(url: &str) -> PyResult<Proxy> {
+        let err = || exc::ValueError::new(format!("invalid proxy url: {:?}", url));
+
+        let (kind, rest) = match url.find("://") {
+            Some(pos) => match &url[..pos] {
+                "socks5" | "socks5h" => (ProxyKind::Socks5, &url[pos+3..]),
+                "http" => (ProxyKind::Http, &url[pos+3..]),
+                scheme => return Err(exc::ValueError::new(
+                    format!("unsupported proxy scheme: {:?}", scheme))),
+            },
+            None => return Err(err()),
+        };
+        let rest = rest.trim_right_matches('/');
+
+        let (auth, hostport) = match rest.rfind('@') {
+            Some(pos) => {
+                let userinfo = &rest[..pos];
+                let (user, password) = match userinfo.find(':') {
+                    Some(idx) => (&userinfo[..idx], &userinfo[idx+1..]),
+                    None => (userinfo, ""),
+                };
+                let user = percent_decode(user, false);
+                let password = percent_decode(password, false);
+                // socks5 username/password are limited to 255 bytes
+                if kind == ProxyKind::Socks5 && (user.len() > 255 || password.len() > 255) {
+                    return Err(err())
+                }
+                (Some((user, password)), &rest[pos+1..])
+            },
+            None => (None, rest),
+        };
+
+        let default_port = if kind == ProxyKind::Socks5 { 1080 } else { 8080 };
+        let (host, port) = if hostport.starts_with('[') {
+            // [ipv6]:port
+            let end = hostport.find(']').ok_or_else(&err)?;
+            let port = match &hostport[end+1..] {
+                "" => default_port,
+                p if p.starts_with(':') => p[1..].parse().map_err(|_| err())?,
+                _ => return Err(err()),
+            };
+            (&hostport[1..end], port)
+        } else {
+            match hostport.rfind(':') {
+                Some(pos) => (&hostport[..pos], hostport[pos+1..].parse().map_err(|_| err())?),
+                None => (hostport, default_port),
+            }
+        };
+        if host.is_empty() || host.contains('/') {
+            return Err(err())
+        }
+
+        Ok(Proxy {
+            kind: kind,
+            host: host.to_owned(),
+            port: port,
+            auth: auth,
+        })
+    }
+
+    ///
+    /// Establish tunnel to host:port through connected proxy stream
+    ///
+    pub fn handshake(self, stream: TcpStream, host: String, port: u16) -> IoFuture<TcpStream> {
+        let peer = stream.peer_addr().ok();
+        let fut = match self.kind {
+            ProxyKind::Socks5 => socks5_handshake(stream, self.auth, host, port),
+            ProxyKind::Http => connect_handshake(stream, self.auth, host, port),
+        };
+        Box::new(fut.map_err(move |err| OperationError::new("proxy", peer, err)))
+    }
+}
+
+fn proxy_error(kind: io::ErrorKind, msg: String) -> io::Error {
+    io::Error::new(kind, msg)
+}
+
+
+fn socks5_handshake(stream: TcpStream, auth: Option<(String, String)>,
+                    host: String, port: u16) -> IoFuture<TcpStream> {
+    if host.is_empty() || host.len() > 255 {
+        return Box::new(future::err(proxy_error(
+            io::ErrorKind::InvalidInput, format!("invalid host name: {:?}", host))))
+    }
+
+    // greeting, offer username/password auth if configured
+    let greeting = if auth.is_some() { vec![5, 2, 0, 2] } else { vec![5, 1, 0] };
+
+    let fut = write_all(stream, greeting)
+        .and_then(|(stream, _)| read_exact(stream, [0u8; 2]))
+        .and_then(move |(stream, reply)| -> IoFuture<TcpStream> {
+            match (reply, auth) {
+                ([5, 0], _) => Box::new(future::ok(stream)),
+                ([5, 2], Some((user, password))) => {
+                    let mut req = vec![1, user.len() as u8];
+                    req.extend_from_slice(user.as_bytes());
+                    req.push(password.len() as u8);
+                    req.extend_from_slice(password.as_bytes());
+                    Box::new(
+                        write_all(stream, req)
+                            .and_then(|(stream, _)| read_exact(stream, [0u8; 2]))
+                            .and_then(|(stream, reply)| if reply[1] == 0 {
+                                Ok(stream)
+                            } else {
+                                Err(proxy_error(io::ErrorKind::PermissionDenied,
+                                                "socks5 authentication failed".to_owned()))
+                            }))
+                },
+                _ => Box::new(future::err(proxy_error(
+                    io::ErrorKind::PermissionDenied,
+                    "socks5 proxy does not accept authentication method".to_owned()))),
+            }
+        })
+        .and_then(move |stream| {
+            let mut req = vec![5, 1, 0];
+            match host.parse::<net::IpAddr>() {
+                Ok(net::IpAddr::V4(ip)) => {
+                    req.push(1);
+                    req.extend_from_slice(&ip.octets());
+                },
+                Ok(net::IpAddr::V6(ip)) => {
+                    req.push(4);
+                    req.extend_from_slice(&ip.octets());
+                },
+                Err(_) => {
+                    // host name is resolved by proxy
+                    req.push(3);
+                    req.push(host.len() as u8);
+                    req.extend_from_slice(host.as_bytes());
+                },
+            }
+            req.extend_from_slice(&[(port >> 8) as u8, port as u8]);
+            write_all(stream, req)
+        })
+        .and_then(|(stream, _)| read_exact(stream, [0u8; 4]))
+        .and_then(|(stream, reply)| -> IoFuture<TcpStream> {
+            if reply[1] != 0 {
+                return Box::new(future::err(socks5_error(reply[1])))
+            }
+            // skip bound address
+            match reply[3] {
+                1 => Box::new(read_exact(stream, [0u8; 6]).map(|(stream, _)| stream)),
+                4 => Box::new(read_exact(stream, [0u8; 18]).map(|(stream, _)| stream)),
+                3 => Box::new(
+                    read_exact(stream, [0u8; 1])
+                        .and_then(|(stream, len)| read_exact(stream, vec![0; len[0] as usize + 2]))
+                        .map(|(stream, _)| stream)),
+                _ => Box::new(future::err(proxy_error(
+                    io::ErrorKind::InvalidData, "invalid socks5 reply".to_owned()))),
+            }
+        });
+    Box::new(fut)
+}
+
+fn socks5_error(code: u8) -> io::Error {
+    let (kind, msg) = match code {
+        2 => (io::ErrorKind::PermissionDenied, "connection not allowed by ruleset"),
+        3 => (io::ErrorKind::Other, "network unreachable"),
+        4 => (io::ErrorKind::Other, "host unreachable"),
+        5 => (io::ErrorKind::ConnectionRefused, "connection refused"),
+        6 => (io::ErrorKind::TimedOut, "TTL expired"),
+        7 => (io::ErrorKind::Other, "command not supported"),
+        8 => (io::ErrorKind::Other, "address type not supported"),
+        _ => (io::ErrorKind::Other, "general socks server failure"),
+    };
+    proxy_error(kind, format!("socks5 proxy: {}", msg))
+}
+
+
+fn connect_handshake(stream: TcpStream, auth: Option<(String, String)>,
+                     host: String, port: u16) -> IoFuture<TcpStream> {
+    let authority = if host.contains(':') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    };
+    let mut req = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n", authority, authority);
+    if let Some((user, password)) = auth {
+        req.push_str("Proxy-Authorization: Basic ");
+        req.push_str(&base64_encode(format!("{}:{}", user, password).as_bytes()));
+        req.push_str("\r\n");
+    }
+    req.push_str("\r\n");
+
+    Box::new(
+        write_all(stream, req.into_bytes())
+            .and_then(|(stream, _)| read_response(stream, Vec::new()))
+            .and_then(|(stream, response)| {
+                let response = String::from_utf8_lossy(&response);
+                let status_line = response.lines().next().unwrap_or("");
+                let status = status_line.split_whitespace().nth(1).unwrap_or("");
+                if status_line.starts_with("HTTP/1.") && status.starts_with('2') {
+                    Ok(stream)
+                } else {
+                    let kind = if status == "407" {
+                        io::ErrorKind::PermissionDenied
+                    } else {
+                        io::ErrorKind::ConnectionRefused
+                    };
+                    Err(proxy_error(kind, format!("CONNECT failed: {}", status_line)))
+                }
+            }))
+}
+
+// read byte by byte, data after response headers belongs to tunnel
+fn read_response(stream: TcpStream, mut buf: Vec<u8>) -> IoFuture<(TcpStream, Vec<u8>)> {
+    Box::new(
+        read_exact(stream, [0u8; 1]).and_then(move |(stream, byte)| -> IoFuture<_> {
+            buf.push(byte[0]);
+            if buf.ends_with(b"\r\n\r\n") {
+                Box::new(future::ok((stream, buf)))
+            } else if buf.len() >= MAX_RESPONSE_SIZE {
+                Box::new(future::err(proxy_error(
+                    io::ErrorKind::InvalidData, "CONNECT response is too large".to_owned())))
+            } else {
+                read_response(stream, buf)
+            }
+        }))
+}
@@ -1,21 +1,120 @@
 use std::io;
 use std::ptr;
+use std::cmp;
+use std::mem;
+use std::cell;
 use std::os::raw::{c_void, c_int};
 
 use twoway;
 use pyo3::{ffi, py};
 use pyo3::buffer::PyBuffer;
-use pyo3::{self, class, exc, Python, PyToken, Py, AsPyRef,
+use pyo3::{self, class, exc, Python, PyToken, Py, AsPyRef, PyRawObject,
            ObjectProtocol, ToPyPointer, PyResult, PyObject, PyObjectRef,
            PySlice, ToPyObject, PyObjectWithToken, PyTryFrom};
 use bytes::{Bytes, BytesMut, BufMut};
 
+pub const READ_BUFFER_SIZE: usize = 32 * 1024;
+const READ_BUFFERS_FREELIST: usize = 256;
+
+//
+// Pool of transport read buffers. Received chunks are split from
+// read buffer, buffer memory is reclaimed when last chunk is dropped
+//
+struct ReadBuffers {
+    free: Vec<BytesMut>,
+    allocated: u64,
+    reused: u64,
+}
+
+thread_local!(
+    static READ_BUFFERS: cell::RefCell<ReadBuffers> = cell::RefCell::new(
+        ReadBuffers { free: Vec::new(), allocated: 0, reused: 0 });
+);
+
+pub fn alloc_read_buffer() -> BytesMut {
+    READ_BUFFERS.with(|buffers| {
+        let mut buffers = buffers.borrow_mut();
+        match buffers.free.pop() {
+            Some(buf) => {
+                buffers.reused += 1;
+                buf
+            },
+            None => {
+                buffers.allocated += 1;
+                BytesMut::with_capacity(READ_BUFFER_SIZE)
+            }
+        }
+    })
+}
+
+fn release_read_buffer(bytes: Bytes) {
+    // other chunks of same buffer are still alive
+    let mut buf = match bytes.try_mut() {
+        Ok(buf) => buf,
+        Err(_) => return,
+    };
+    READ_BUFFERS.with(|buffers| {
+        let mut buffers = buffers.borrow_mut();
+        if buffers.free.len() < READ_BUFFERS_FREELIST {
+            // unique buffer is reclaimed from the beginning
+            buf.clear();
+            buf.reserve(READ_BUFFER_SIZE);
+            buffers.free.push(buf);
+        }
+    })
+}
+
+///
+/// Read buffer allocation counters of current thread:
+/// (allocated, reused, free)
+///
+pub fn read_buffers_stats() -> (u64, u64, usize) {
+    READ_BUFFERS.with(|buffers| {
+        let buffers = buffers.borrow();
+        (buffers.allocated, buffers.reused, buffers.free.len())
+    })
+}
+
+
+const WHITESPACE: &[u8] = b" \t\n\r\x0b\x0c";
+
+// bytes-like argument or single byte value
+fn to_bytes(py: Python, obj: &PyObjectRef) -> PyResult<Vec<u8>> {
+    if let Ok(bytes) = PyBytes::try_from(obj) {
+        return Ok(bytes.bytes.to_vec())
+    }
+    if let Ok(byte) = obj.extract::<u8>() {
+        return Ok(vec![byte])
+    }
+    PyBuffer::get(py, obj)
+        .and_then(|buf| buf.to_vec::<u8>(py))
+        .map_err(|_| exc::TypeError::new("a bytes-like object is required"))
+}
+
+fn matches_any<F>(py: Python, arg: &PyObjectRef, f: F) -> PyResult<bool>
+    where F: Fn(&[u8]) -> bool
+{
+    if let Ok(tuple) = pyo3::PyTuple::try_from(arg) {
+        for item in tuple.iter() {
+            if f(&to_bytes(py, item)?) {
+                return Ok(true)
+            }
+        }
+        Ok(false)
+    } else {
+        Ok(f(&to_bytes(py, arg)?))
+    }
+}
+
 #[py::class(weakref, freelist=100)]
 ///
-/// Buffer interface for Bytes
+/// Buffer interface for Bytes. Slices with step 1 and memoryview
+/// share memory with underlying buffer
 ///
 pub struct PyBytes {
     bytes: Bytes,
+    // chunk of pooled read buffer
+    pooled: bool,
     token: PyToken,
 }
 
@@ -23,32 +122,34 @@ pub struct PyBytes {
 #[py::methods]
 impl PyBytes {
 
-    fn find(&self, sub: &pyo3::PyBytes,
+    fn find(&self, sub: &PyObjectRef,
             start: Option<isize>, end: Option<isize>) -> PyResult<isize> {
-        let mut pre = 0;
-        let pos = match (&start, &end) {
-            (&None, &None) => {
-                twoway::find_bytes(self.bytes.as_ref(), sub.data())
-            }
-            _ => {
-                let start = if let Some(start) = start {start} else {0};
-                let end = if let Some(end) = end {end} else {-1};
-
-                let slice = PySlice::new(self.py(), start, end, 1);
-                let indices = slice.indices(self.bytes.len() as i64)?;
-                pre = indices.start as usize;
-                let end = (indices.stop+1) as usize;
-                twoway::find_bytes(&self.bytes[pre..end], sub.data())
-            }
-        };
+        let sub = to_bytes(self.py(), sub)?;
+        let (start, end) = self.range(start, end);
 
-        if let Some(pos) = pos {
-            Ok((pos+pre) as isize)
-        } else {
-            Ok(-1)
+        match twoway::find_bytes(&self.bytes[start..end], &sub) {
+            Some(pos) => Ok((start + pos) as isize),
+            None => Ok(-1),
         }
     }
 
+    ///
+    /// prefix is bytes-like object or tuple of them
+    ///
+    fn startswith(&self, prefix: &PyObjectRef,
+                  start: Option<isize>, end: Option<isize>) -> PyResult<bool> {
+        let (start, end) = self.range(start, end);
+        let data = &self.bytes[start..end];
+        matches_any(self.py(), prefix, |prefix| data.starts_with(prefix))
+    }
+
+    fn endswith(&self, suffix: &PyObjectRef,
+                start: Option<isize>, end: Option<isize>) -> PyResult<bool> {
+        let (start, end) = self.range(start, end);
+        let data = &self.bytes[start..end];
+        matches_any(self.py(), suffix, |suffix| data.ends_with(suffix))
+    }
+
     #[args(maxsplit="-1")]
     fn split(&self, sep: Option<&PyObjectRef>, maxsplit: i32) -> PyResult<&pyo3::PyList> {
         let py = self.py();
@@ -56,13 +157,16 @@ impl PyBytes {
         let remove_empty;
         let sep = if let Some(sep) = sep {
             remove_empty = false;
-            let v = PyBuffer::get(py, sep)?.to_vec::<u8>(py)?;
+            let v = to_bytes(py, sep)?;
+            if v.is_empty() {
+                return Err(exc::ValueError::new("empty separator"))
+            }
             sep_len = v.len();
             v
         } else {
             remove_empty = true;
             sep_len = 1;
-            vec![b' ', b'\t', b'\n', b'\r', b'\x0b', b'\x0c']
+            WHITESPACE.to_vec()
         };
 
         let length = self.bytes.len();
@@ -73,7 +177,7 @@ impl PyBytes {
         loop {
             if maxsplit >= 0 && result.len() == maxsplit as usize {
                 result.push(
-                    py.init(|t| PyBytes{bytes: self.bytes.slice_from(start), token: t})?);
+                    PyBytes::new(py, self.bytes.slice_from(start))?);
                 break
             }
 
@@ -96,7 +200,7 @@ impl PyBytes {
                 let pos = start + pos;
                 if ! (start == pos && remove_empty) {
                     result.push(
-                        py.init(|t| PyBytes{bytes: self.bytes.slice(start, pos), token: t})?);
+                        PyBytes::new(py, self.bytes.slice(start, pos))?);
                 }
 
                 start = pos + sep_len;
@@ -106,7 +210,7 @@ impl PyBytes {
             } else {
                 if ! (start == length && remove_empty) {
                     result.push(
-                        py.init(|t| PyBytes{bytes: self.bytes.slice_from(start), token: t})?);
+                        PyBytes::new(py, self.bytes.slice_from(start))?);
                 }
                 break
             }
@@ -115,38 +219,16 @@ impl PyBytes {
         Ok(pyo3::PyList::new(py, result.as_slice()))
     }
 
-    fn strip(&self, py: Python, sep: Option<&PyObjectRef>) -> PyResult<Py<PyBytes>> {
-        let sep = if let Some(sep) = sep {
-            PyBuffer::get(py, sep)?.to_vec::<u8>(py)?
-        } else {
-            vec![b' ', b'\t', b'\n', b'\r', b'\x0b', b'\x0c']
-        };
-
-        let mut start = 0;
-        let mut end = self.bytes.len();
+    fn strip(&self, py: Python, chars: Option<&PyObjectRef>) -> PyResult<Py<PyBytes>> {
+        self.strip_chars(py, chars, true, true)
+    }
 
-        loop {
-            if start == end {
-                break
-            }
-            if sep.contains(&self.bytes[start]) {
-                start += 1;
-            } else {
-                break
-            }
-        }
-        loop {
-            if end == start {
-                break
-            }
+    fn lstrip(&self, py: Python, chars: Option<&PyObjectRef>) -> PyResult<Py<PyBytes>> {
+        self.strip_chars(py, chars, true, false)
+    }
 
-            if sep.contains(&self.bytes[end - 1]) {
-                end -= 1;
-            } else {
-                break
-            }
-        }
-        py.init(|t| PyBytes{bytes: self.bytes.slice(start, end), token: t})
+    fn rstrip(&self, py: Python, chars: Option<&PyObjectRef>) -> PyResult<Py<PyBytes>> {
+        self.strip_chars(py, chars, false, true)
     }
 
     fn decode(&self, encoding: Option<&str>, errors: Option<&str>) -> PyResult<PyObject>
@@ -175,6 +257,18 @@ impl PyBytes {
     pub fn new(py: Python, bytes: Bytes) -> PyResult<Py<PyBytes>> {
         py.init(|t| PyBytes {
             bytes: bytes,
+            pooled: false,
+            token: t})
+    }
+
+    ///
+    /// Chunk of buffer allocated with alloc_read_buffer(), buffer
+    /// returns to pool when all its chunks are dropped
+    ///
+    pub fn read_chunk(py: Python, bytes: Bytes) -> PyResult<Py<PyBytes>> {
+        py.init(|t| PyBytes {
+            bytes: bytes,
+            pooled: true,
             token: t})
     }
 
@@ -190,6 +284,38 @@ impl PyBytes {
         }
     }
 
+    // python slice semantics for start and end arguments
+    fn range(&self, start: Option<isize>, end: Option<isize>) -> (usize, usize) {
+        let len = self.bytes.len() as isize;
+        let adjust = |idx: isize|
+            if idx < 0 { cmp::max(idx + len, 0) } else { cmp::min(idx, len) };
+        let start = start.map(&adjust).unwrap_or(0);
+        let end = end.map(&adjust).unwrap_or(len);
+        (start as usize, cmp::max(start, end) as usize)
+    }
+
+    fn strip_chars(&self, py: Python, chars: Option<&PyObjectRef>,
+                   left: bool, right: bool) -> PyResult<Py<PyBytes>> {
+        let chars = match chars {
+            Some(chars) => to_bytes(py, chars)?,
+            None => WHITESPACE.to_vec(),
+        };
+
+        let mut start = 0;
+        let mut end = self.bytes.len();
+        if left {
+            while start < end && chars.contains(&self.bytes[start]) {
+                start += 1;
+            }
+        }
+        if right {
+            while end > start && chars.contains(&self.bytes[end - 1]) {
+                end -= 1;
+            }
+        }
+        PyBytes::new(py, self.bytes.slice(start, end))
+    }
+
     pub fn extend_into(&self, dst: &mut BytesMut)  {
         dst.extend(self.bytes.as_ref())
     }
@@ -200,12 +326,20 @@ impl PyBytes {
 
     pub fn slice_to(&self, py: Python, end: usize) -> PyResult<Py<PyBytes>> {
         let bytes = self.bytes.slice_to(end);
-        py.init(|token| PyBytes {bytes: bytes, token: token})
+        py.init(|token| PyBytes {bytes: bytes, pooled: false, token: token})
     }
 
     pub fn slice_from(&self, py: Python, begin: usize) -> PyResult<Py<PyBytes>> {
         let bytes = self.bytes.slice_from(begin);
-        py.init(|token| PyBytes {bytes: bytes, token: token})
+        py.init(|token| PyBytes {bytes: bytes, pooled: false, token: token})
+    }
+}
+
+impl Drop for PyBytes {
+    fn drop(&mut self) {
+        if self.pooled {
+            release_read_buffer(mem::replace(&mut self.bytes, Bytes::new()));
+        }
     }
 }
 
@@ -214,22 +348,36 @@ impl<'p> pyo3::class::PyObjectProtocol<'p> for PyBytes {
 
     fn __richcmp__(&self, other: &PyObjectRef, op: pyo3::CompareOp) -> PyResult<PyObject> {
         let py = self.py();
-        match op {
-            pyo3::CompareOp::Eq => {
-                if let Ok(other) = PyBytes::try_from(other) {
-                    Ok((self.bytes.as_ref() == other.bytes.as_ref()).to_object(py))
-                }
-                else if let Ok(other) = pyo3::PyBytes::try_from_exact(other) {
-                    Ok((self.bytes.as_ref() == other.data().as_ref()).to_object(py))
-                }
-                else {
-                    Err(exc::TypeError::new(
-                        format!("Can not compare PyBytes and {:?}", &other)))
-                }
-            },
-            _ =>
-                Err(exc::TypeError::new("Can not complete this operation")),
-        }
+        let res = if let Ok(other) = PyBytes::try_from(other) {
+            compare(self.bytes.as_ref(), other.bytes.as_ref(), op)
+        } else if let Ok(other) = pyo3::PyBytes::try_from(other) {
+            compare(self.bytes.as_ref(), other.data(), op)
+        } else {
+            return Ok(py.NotImplemented())
+        };
+        Ok(res.to_object(py))
+    }
+
+    ///
+    /// same hash as bytes object with same content
+    ///
+    fn __hash__(&self) -> PyResult<isize> {
+        pyo3::PyBytes::new(self.py(), self.bytes.as_ref()).hash()
+    }
+
+    fn __bytes__(&self) -> PyResult<PyObject> {
+        Ok(pyo3::PyBytes::new(self.py(), self.bytes.as_ref()).into())
+    }
+}
+
+fn compare(lhs: &[u8], rhs: &[u8], op: pyo3::CompareOp) -> bool {
+    match op {
+        pyo3::CompareOp::Lt => lhs < rhs,
+        pyo3::CompareOp::Le => lhs <= rhs,
+        pyo3::CompareOp::Eq => lhs == rhs,
+        pyo3::CompareOp::Ne => lhs != rhs,
+        pyo3::CompareOp::Gt => lhs > rhs,
+        pyo3::CompareOp::Ge => lhs >= rhs,
     }
 }
 
@@ -280,16 +428,18 @@ impl pyo3::class::PyMappingProtocol for PyBytes {
         // access by slice
         if let Ok(slice) = PySlice::try_from(key) {
             let indices = slice.indices(self.bytes.len() as i64)?;
+            let length = indices.slicelength as usize;
 
             let s = if indices.step == 1 {
-                // continuous chunk of memory
-                self.bytes.slice(indices.start as usize, indices.stop as usize)
+                // continuous chunk of memory, shares buffer
+                let start = indices.start as usize;
+                self.bytes.slice(start, start + length)
             } else {
                 // copy every "step" byte
-                let mut buf = BytesMut::with_capacity(indices.slicelength as usize);
+                let mut buf = BytesMut::with_capacity(length);
 
                 let mut idx = indices.start;
-                while idx < indices.stop {
+                for _ in 0..length {
                     buf.put_u8(self.bytes[idx as usize]);
                     idx += indices.step;
                 }
@@ -299,16 +449,13 @@ impl pyo3::class::PyMappingProtocol for PyBytes {
         }
         // access by index
         else if let Ok(idx) = key.extract::<isize>() {
-            if idx < 0 {
-                Err(exc::IndexError::new("Index out of range"))
-            } else {
-                let idx = idx as usize;
+            let len = self.bytes.len() as isize;
+            let idx = if idx < 0 { idx + len } else { idx };
 
-                if idx < self.bytes.len() {
-                    Ok(self.bytes[idx].to_object(self.py()))
-                } else {
-                    Err(exc::IndexError::new("Index out of range"))
-                }
+            if idx >= 0 && idx < len {
+                Ok(self.bytes[idx as usize].to_object(self.py()))
+            } else {
+                Err(exc::IndexError::new("Index out of range"))
             }
         } else {
             Err(exc::TypeError::new("Index is not supported"))
@@ -316,6 +463,15 @@ impl pyo3::class::PyMappingProtocol for PyBytes {
     }
 }
 
+#[py::proto]
+impl<'p> pyo3::class::PySequenceProtocol<'p> for PyBytes {
+
+    fn __contains__(&self, item: &PyObjectRef) -> PyResult<bool> {
+        let item = to_bytes(self.py(), item)?;
+        Ok(twoway::find_bytes(self.bytes.as_ref(), &item).is_some())
+    }
+}
+
 #[py::proto]
 impl class::PyBufferProtocol for PyBytes {
 
@@ -333,6 +489,11 @@ impl class::PyBufferProtocol for PyBytes {
         }
 
         unsafe {
+            // view keeps object and its memory alive,
+            // reference is released by PyBuffer_Release
+            (*view).obj = self.as_ptr();
+            ffi::Py_INCREF((*view).obj);
+
             (*view).buf = self.bytes.as_ptr() as *mut c_void;
             (*view).len = self.bytes.len() as isize;
             (*view).readonly = 1;
@@ -397,3 +558,90 @@ impl pyo3::PyIterProtocol for PyBytesIter {
         }
     }
 }
+
+
+///
+/// Growable bytearray-like buffer. take() returns accumulated data
+/// as PyBytes without copy, writing buffer to transport or http payload
+/// writer takes its data the same way
+///
+#[py::class(weakref)]
+pub struct WriteBuffer {
+    buf: BytesMut,
+    token: PyToken,
+}
+
+#[py::methods]
+impl WriteBuffer {
+
+    #[new]
+    fn __new__(obj: &PyRawObject, capacity: Option<usize>) -> PyResult<()> {
+        obj.init(|token| WriteBuffer {
+            buf: BytesMut::with_capacity(capacity.unwrap_or(0)),
+            token: token})
+    }
+
+    fn append(&mut self, byte: u8) -> PyResult<()> {
+        self.buf.reserve(1);
+        self.buf.put_u8(byte);
+        Ok(())
+    }
+
+    fn extend(&mut self, py: Python, data: &PyObjectRef) -> PyResult<()> {
+        if let Ok(bytes) = PyBytes::try_from(data) {
+            self.buf.extend_from_slice(bytes.bytes.as_ref());
+        } else if let Ok(bytes) = pyo3::PyBytes::try_from(data) {
+            self.buf.extend_from_slice(bytes.data());
+        } else {
+            let buf = PyBuffer::get(py, data)
+                .map_err(|_| exc::TypeError::new("a bytes-like object is required"))?;
+            let len = buf.len_bytes();
+            self.buf.reserve(len);
+            unsafe {
+                buf.copy_to_slice(py, &mut self.buf.bytes_mut()[..len])?;
+                self.buf.advance_mut(len);
+            }
+        }
+        Ok(())
+    }
+
+    ///
+    /// return buffered data and reset buffer
+    ///
+    fn take(&mut self, py: Python) -> PyResult<Py<PyBytes>> {
+        PyBytes::new(py, self.take_bytes())
+    }
+
+    fn clear(&mut self) -> PyResult<()> {
+        self.buf.clear();
+        Ok(())
+    }
+
+    #[getter]
+    fn get_capacity(&self) -> PyResult<usize> {
+        Ok(self.buf.capacity())
+    }
+}
+
+impl WriteBuffer {
+
+    pub fn take_bytes(&mut self) -> Bytes {
+        self.buf.take().freeze()
+    }
+}
+
+#[py::proto]
+impl<'p> pyo3::class::PyObjectProtocol<'p> for WriteBuffer {
+
+    fn __bytes__(&self) -> PyResult<PyObject> {
+        Ok(pyo3::PyBytes::new(self.py(), self.buf.as_ref()).into())
+    }
+}
+
+#[py::proto]
+impl pyo3::class::PyMappingProtocol for WriteBuffer {
+
+    fn __len__(&self) -> PyResult<usize> {
+        Ok(self.buf.len())
+    }
+}
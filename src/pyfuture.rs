@@ -6,7 +6,8 @@ use pyo3::*;
 use futures::{future, unsync, Async, Poll};
 use boxfnonce::BoxFnOnce;
 
-use TokioEventLoop;
+use {PyTask, TokioEventLoop};
+use pytask;
 use utils::{Classes, PyLogger};
 use pyunsafe::{GIL, OneshotSender, OneshotReceiver};
 
@@ -19,6 +20,79 @@ pub enum State {
 
 pub type Callback = BoxFnOnce<(PyResult<PyObject>,)>;
 
+///
+/// Rust waiter of future completion. Task which awaits future is
+/// stored as is, other callbacks are boxed
+///
+pub enum Waiter {
+    // task and its coroutine
+    Task(Py<PyTask>, PyObject),
+    Callback(Callback),
+}
+
+impl Waiter {
+    fn call(self, result: PyResult<PyObject>) {
+        match self {
+            Waiter::Task(task, coro) => pytask::wakeup_task(task, coro, result),
+            Waiter::Callback(cb) => cb.call(result),
+        }
+    }
+}
+
+const WAITERS_FREELIST: usize = 256;
+
+//
+// Freelist of waiter vectors. Every await registers task waiter
+// on awaited future, vectors are reused after waiters run
+//
+struct Waiters {
+    free: Vec<Vec<Waiter>>,
+    allocated: u64,
+    reused: u64,
+}
+
+thread_local!(
+    static WAITERS: cell::RefCell<Waiters> = cell::RefCell::new(
+        Waiters { free: Vec::new(), allocated: 0, reused: 0 });
+);
+
+fn alloc_waiters() -> Vec<Waiter> {
+    WAITERS.with(|waiters| {
+        let mut waiters = waiters.borrow_mut();
+        match waiters.free.pop() {
+            Some(callbacks) => {
+                waiters.reused += 1;
+                callbacks
+            },
+            None => {
+                waiters.allocated += 1;
+                Vec::with_capacity(1)
+            }
+        }
+    })
+}
+
+fn release_waiters(mut callbacks: Vec<Waiter>) {
+    callbacks.clear();
+    WAITERS.with(|waiters| {
+        let mut waiters = waiters.borrow_mut();
+        if waiters.free.len() < WAITERS_FREELIST {
+            waiters.free.push(callbacks);
+        }
+    })
+}
+
+///
+/// Waiter allocation counters of current thread:
+/// (allocated, reused, free)
+///
+pub fn waiters_stats() -> (u64, u64, usize) {
+    WAITERS.with(|waiters| {
+        let waiters = waiters.borrow();
+        (waiters.allocated, waiters.reused, waiters.free.len())
+    })
+}
+
 pub struct _PyFuture {
     pub evloop: Py<TokioEventLoop>,
     state: State,
@@ -29,7 +103,7 @@ pub struct _PyFuture {
     pub callbacks: Option<Vec<PyObject>>,
 
     // rust callbacks
-    rcallbacks: Option<Vec<Callback>>,
+    rcallbacks: Option<Vec<Waiter>>,
 }
 
 unsafe impl Send for _PyFuture {}
@@ -374,18 +448,27 @@ impl _PyFuture {
     // Add future completion callback
     //
     pub fn add_callback(&mut self, py: Python, cb: Callback) {
+        self.add_waiter(py, Waiter::Callback(cb))
+    }
+
+    //
+    // Add rust waiter, it is called at once if future is done
+    //
+    pub fn add_waiter(&mut self, py: Python, waiter: Waiter) {
         match self.state {
             State::Pending => {
                 // add coro, create tasks vector if needed
                 if let Some(ref mut callbacks) = self.rcallbacks {
-                    callbacks.push(cb);
+                    callbacks.push(waiter);
                 } else {
-                    self.rcallbacks = Some(vec![cb]);
+                    let mut callbacks = alloc_waiters();
+                    callbacks.push(waiter);
+                    self.rcallbacks = Some(callbacks);
                 }
             },
             _ => {
                 // schedule callback
-                cb.call(self.result(py, false));
+                waiter.call(self.result(py, false));
             },
         }
     }
@@ -398,16 +481,17 @@ impl _PyFuture {
         self.state = state;
 
         // schedule rust callbacks
-        if let Some(rcallbacks) = self.rcallbacks.take() {
+        if let Some(mut rcallbacks) = self.rcallbacks.take() {
             let result = self.result(py, false);
             evloop.schedule_callback(BoxFnOnce::from(move || {
                 let py = GIL::python();
-                for cb in rcallbacks {
+                for cb in rcallbacks.drain(..) {
                     match result {
                         Ok(ref res) => cb.call(Ok(res.clone_ref(py))),
                         Err(ref err) => cb.call(Err(err.clone_ref(py))),
                     }
                 }
+                release_waiters(rcallbacks);
             }));
         }
 
@@ -436,6 +520,10 @@ impl _PyFuture {
 
 impl Drop for _PyFuture {
     fn drop(&mut self) {
+        if let Some(rcallbacks) = self.rcallbacks.take() {
+            release_waiters(rcallbacks);
+        }
+
         let py = GIL::python();
         if self.log_exc_tb.get() {
             let context = PyDict::new(py);
@@ -828,6 +916,13 @@ impl PyFuture {
         self.fut.add_callback(py, cb);
     }
 
+    //
+    // Add rust waiter, task waiter does not allocate callback
+    //
+    pub fn add_waiter(&mut self, py: Python, waiter: Waiter) {
+        self.fut.add_waiter(py, waiter);
+    }
+
     //
     // bloking
     //
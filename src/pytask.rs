@@ -1,6 +1,7 @@
 // Copyright (c) 2017-present PyO3 Project and Contributors
 
 use std;
+use std::time::Instant;
 use pyo3::*;
 use futures::{future, unsync, Async, Poll};
 use boxfnonce::BoxFnOnce;
@@ -8,7 +9,7 @@ use boxfnonce::BoxFnOnce;
 use TokioEventLoop;
 use utils::{Classes, PyLogger};
 use pyunsafe::{GIL, OneshotSender, OneshotReceiver};
-use pyfuture::{_PyFuture, PyFuture, Callback, State};
+use pyfuture::{_PyFuture, PyFuture, Callback, State, Waiter};
 
 
 #[py::class(weakref, freelist=250)]
@@ -244,6 +245,12 @@ impl PyTask {
         self.fut.add_callback(py, cb);
     }
 
+    /// Add rust waiter, task waiter does not allocate callback
+    ///
+    pub fn add_waiter(&mut self, py: Python, waiter: Waiter) {
+        self.fut.add_waiter(py, waiter);
+    }
+
     // blocking
     //
     pub fn is_blocking(&self) -> bool {
@@ -376,7 +383,8 @@ const INPLACE_RETRY: usize = 5;
 ///
 /// wakeup task from future
 ///
-fn wakeup_task(fut: Py<PyTask>, coro: PyObject, result: PyResult<PyObject>) {
+#[doc(hidden)]
+pub fn wakeup_task(fut: Py<PyTask>, coro: PyObject, result: PyResult<PyObject>) {
     let py = GIL::python();
     match result {
         Ok(_) => task_step(py, fut.as_mut(py), coro, None, 0),
@@ -412,12 +420,23 @@ fn task_step(py: Python, task: &mut PyTask, coro: PyObject, exc: Option<PyObject
     let task_ob = task.into();
     task.fut.evloop.as_mut(py).set_current_task(task_ob);
 
+    // measure step duration in debug mode
+    let started = if task.fut.evloop.as_ref(py).is_debug() {
+        Some(Instant::now())
+    } else {
+        None
+    };
+
     // call either coro.throw(exc) or coro.send(None).
     let res = match exc {
         None => coro.call_method1(py, "send", (py.None(),)),
         Some(exc) => coro.call_method1(py, "throw", (exc,)),
     };
 
+    if let Some(started) = started {
+        task.fut.evloop.as_ref(py).check_task_step(py, task, &coro, started.elapsed());
+    }
+
     // handle coroutine result
     match res {
         Err(err) => {
@@ -472,10 +491,7 @@ fn task_step(py: Python, task: &mut PyTask, coro: PyObject, exc: Option<PyObject
                 task.waiter = Some(fut.into());
 
                 // schedule wakeup on done
-                let waiter_task = task.into();
-                let _ = fut.add_callback(py, BoxFnOnce::from(move |result| {
-                    wakeup_task(waiter_task, coro, result);
-                }));
+                fut.add_waiter(py, Waiter::Task(task.into(), coro));
                 return
             }
 
@@ -493,10 +509,7 @@ fn task_step(py: Python, task: &mut PyTask, coro: PyObject, exc: Option<PyObject
                 task.waiter = Some(res.into());
 
                 // schedule wakeup on done
-                let waiter_task = task.into();
-                let _ = res.add_callback(py, BoxFnOnce::from(move |result| {
-                    wakeup_task(waiter_task, coro, result);
-                }));
+                res.add_waiter(py, Waiter::Task(task.into(), coro));
 
                 // cancel if needed
                 if task.must_cancel {
@@ -533,10 +546,7 @@ fn task_step(py: Python, task: &mut PyTask, coro: PyObject, exc: Option<PyObject
                 task.waiter = Some(fut.clone_ref(py).into());
 
                 // schedule wakeup on done
-                let waiter_task = task.into();
-                let _ = fut.as_mut(py).add_callback(py, BoxFnOnce::from(move |result| {
-                    wakeup_task(waiter_task, coro, result);
-                }));
+                fut.as_mut(py).add_waiter(py, Waiter::Task(task.into(), coro));
 
                 // cancel if needed
                 if task.must_cancel {
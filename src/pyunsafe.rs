@@ -1,8 +1,11 @@
 // UNSAFE code!
+use std::rc::Rc;
 use std::ops::Deref;
 use std::clone::Clone;
+use std::cell::{Cell, RefCell};
+use std::time::{Duration, Instant};
 use tokio_core::reactor;
-use futures::{Future, Poll};
+use futures::{Async, Future, Poll, Stream};
 use futures::unsync::{mpsc, oneshot};
 use pyo3::Python;
 
@@ -38,6 +41,39 @@ impl Core {
     pub fn into(self) -> reactor::Core {
         self.0
     }
+
+    /// Run future to completion. With busy_poll, reactor is polled
+    /// without blocking for the given time after every wakeup
+    /// before it parks thread again.
+    pub fn run_polling<F>(&mut self, fut: F, busy_poll: Option<Duration>)
+                          -> Result<F::Item, F::Error>
+        where F: Future + 'static, F::Item: 'static, F::Error: 'static
+    {
+        let busy_poll = match busy_poll {
+            Some(busy_poll) if busy_poll > Duration::new(0, 0) => busy_poll,
+            _ => return self.0.run(fut),
+        };
+
+        let result = Rc::new(RefCell::new(None));
+        let res = result.clone();
+        self.0.handle().spawn(fut.then(move |r| {
+            *res.borrow_mut() = Some(r);
+            Ok(())
+        }));
+
+        let mut woken = Instant::now();
+        loop {
+            if let Some(r) = result.borrow_mut().take() {
+                return r
+            }
+            if woken.elapsed() < busy_poll {
+                self.0.turn(Some(Duration::new(0, 0)));
+            } else {
+                self.0.turn(None);
+                woken = Instant::now();
+            }
+        }
+    }
 }
 
 impl Deref for Core {
@@ -105,6 +141,79 @@ impl<T> Sender<T> {
 }
 
 
+///
+/// Bounded channel, sender does not block. Capacity is counted in bytes
+/// of queued messages, message is returned back to caller once queued
+/// size reaches capacity. Capacity can be changed at any time
+///
+pub fn bounded<T>(capacity: usize) -> (BoundedSender<T>, BoundedReceiver<T>) {
+    let (tx, rx) = mpsc::unbounded();
+    let queued = Rc::new(Cell::new(0));
+    (BoundedSender { tx: tx, queued: queued.clone(), capacity: capacity },
+     BoundedReceiver { rx: rx, queued: queued })
+}
+
+#[doc(hidden)]
+pub struct BoundedSender<T> {
+    tx: mpsc::UnboundedSender<(usize, T)>,
+    queued: Rc<Cell<usize>>,
+    capacity: usize,
+}
+
+unsafe impl<T> Send for BoundedSender<T> {}
+
+impl<T> BoundedSender<T> {
+
+    // size of messages which are not received yet
+    pub fn len(&self) -> usize {
+        self.queued.get()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+    }
+
+    // message for closed receiver is dropped
+    pub fn send(&self, msg: T, size: usize) -> Result<(), T> {
+        if self.queued.get() >= self.capacity {
+            return Err(msg)
+        }
+        if self.tx.unbounded_send((size, msg)).is_ok() {
+            self.queued.set(self.queued.get() + size);
+        }
+        Ok(())
+    }
+}
+
+
+#[doc(hidden)]
+pub struct BoundedReceiver<T> {
+    rx: mpsc::UnboundedReceiver<(usize, T)>,
+    queued: Rc<Cell<usize>>,
+}
+
+impl<T> Stream for BoundedReceiver<T> {
+    type Item = T;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<T>, ()> {
+        match self.rx.poll() {
+            Ok(Async::Ready(Some((size, msg)))) => {
+                self.queued.set(self.queued.get() - size);
+                Ok(Async::Ready(Some(msg)))
+            },
+            Ok(Async::Ready(None)) => Ok(Async::Ready(None)),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+
 #[doc(hidden)]
 pub struct OneshotSender<T> (oneshot::Sender<T>);
 
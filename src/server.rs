@@ -1,40 +1,136 @@
 use std::io;
+use std::fs;
+use std::ffi;
 use std::net;
 use std::os::unix;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+use std::collections::HashMap;
+use libc;
 use pyo3::*;
-use futures::{unsync, Async, Stream, Future, Poll};
+use boxfnonce::BoxFnOnce;
+use futures::{task, unsync, Async, Stream, Future, Poll};
 use net2::TcpBuilder;
 use net2::unix::UnixTcpBuilderExt;
 use tokio_core::net::{TcpListener, Incoming};
+use tokio_core::reactor;
 use tokio_uds;
 use tokio_io::IoStream;
 
 use {PyFuture, TokioEventLoop};
+use activation;
 use addrinfo;
+use datagram;
 use pyunsafe;
-use socket::Socket;
-use transport::{TransportFactory, tcp_transport_factory};
+use sniff;
+use utils::{self, Classes};
+use socket::{Socket, sockaddr_object};
+use transport::{self, TransportFactory, TransportOptions, tcp_transport_factory};
+
+
+// SO_REUSEADDR is safe default on posix, listener can be restarted
+// while old connections are in TIME_WAIT
+pub const DEFAULT_REUSE_ADDRESS: bool = cfg!(unix);
+
+
+///
+/// Listen backlog, optionally overridden for some of bound addresses
+///
+#[derive(Clone, Debug)]
+pub struct Backlog {
+    pub default: i32,
+    addresses: Vec<(IpAddr, i32)>,
+}
+
+impl Backlog {
+
+    pub fn new(default: i32) -> Backlog {
+        Backlog { default: default, addresses: Vec::new() }
+    }
+
+    ///
+    /// backlogs maps ip address strings to backlog of listener bound to it
+    ///
+    pub fn parse(default: i32, backlogs: Option<&PyObjectRef>) -> PyResult<Backlog> {
+        let mut backlog = Backlog::new(default);
+        if let Some(backlogs) = backlogs {
+            let backlogs = PyDict::try_from(backlogs)
+                .map_err(|_| exc::TypeError::new("backlogs must be a dict"))?;
+            for (key, value) in backlogs.iter() {
+                let key: String = key.extract()?;
+                let ip = key.parse::<IpAddr>().map_err(|_| exc::ValueError::new(
+                    format!("invalid address in backlogs: {:?}", key)))?;
+                backlog.addresses.push((ip, value.extract()?));
+            }
+        }
+        Ok(backlog)
+    }
+
+    pub fn get(&self, ip: &IpAddr) -> i32 {
+        self.addresses.iter()
+            .find(|&&(addr, _)| addr == *ip)
+            .map(|&(_, backlog)| backlog)
+            .unwrap_or(self.default)
+    }
+}
+
+///
+/// Transport factory of tcp server, start of tls handshake
+/// is checked before ssl protocol is created
+///
+pub fn transport_factory(ssl: &Option<PyObject>) -> TransportFactory {
+    if ssl.is_some() {
+        sniff::tls_transport_factory
+    } else {
+        tcp_transport_factory
+    }
+}
 
 
 pub fn create_server(py: Python, evloop: &TokioEventLoop,
-                     addrs: Vec<addrinfo::AddrInfo>, backlog: i32,
-                     ssl: Option<PyObject>, reuse_address: bool, reuse_port: bool,
-                     proto_factory: PyObject, transport_factory: TransportFactory)
-                     -> PyResult<PyObject> {
+                     addrs: Vec<addrinfo::AddrInfo>, backlog: Backlog,
+                     ssl: Option<PyObject>, reuse_address: Option<bool>, reuse_port: bool,
+                     interface: Option<String>, dualstack: bool,
+                     proto_factory: PyObject, transport_factory: TransportFactory,
+                     opts: TransportOptions, start_serving: bool,
+                     on_connect: Option<PyObject>) -> PyResult<PyObject> {
 
     let handle = evloop.get_handle();
 
+    // dual-stack ipv6 wildcard socket serves ipv4 as well,
+    // bind it first and skip ipv4 wildcard address
+    let mut addrs = addrs;
+    if dualstack {
+        addrs.sort_by_key(|info| info.sockaddr.is_ipv4());
+    }
+    let mut dualstack_bound = false;
+
     // configure sockets
     let mut listeners = Vec::new();
     let mut sockets = Vec::new();
     for info in addrs {
+        let wildcard = info.sockaddr.ip().is_unspecified();
         let builder = match info.family {
-            addrinfo::Family::Inet =>
-                if let Ok(b) = TcpBuilder::new_v4() { b } else { continue },
+            addrinfo::Family::Inet => {
+                if dualstack_bound && wildcard {
+                    continue
+                }
+                if let Ok(b) = TcpBuilder::new_v4() { b } else { continue }
+            },
 
             addrinfo::Family::Inet6 => {
                 if let Ok(b) = TcpBuilder::new_v6() {
-                    let _ = b.only_v6(true);
+                    // fall back to separate sockets if dual-stack
+                    // is not supported
+                    if dualstack && wildcard && !dualstack_bound && b.only_v6(false).is_ok() {
+                        dualstack_bound = true;
+                    } else {
+                        let _ = b.only_v6(true);
+                    }
                     b
                 } else {
                     continue
@@ -43,21 +139,35 @@ pub fn create_server(py: Python, evloop: &TokioEventLoop,
             _ => continue
         };
 
-        let _ = builder.reuse_address(reuse_address);
-        let _ = builder.reuse_port(reuse_port);
+        builder.reuse_address(reuse_address.unwrap_or(DEFAULT_REUSE_ADDRESS))?;
+        if reuse_port {
+            builder.reuse_port(true).map_err(reuse_port_error)?;
+        }
+        if let Some(ref interface) = interface {
+            transport::bind_to_device(
+                builder.as_raw_fd(), interface, info.sockaddr.is_ipv6())?;
+        }
         builder.bind(info.sockaddr)?;
 
-        let listener = builder.listen(backlog)?;
+        let listener = builder.listen(backlog.get(&info.sockaddr.ip()))?;
+        if let Some(delay) = opts.defer_accept {
+            transport::set_defer_accept(listener.as_raw_fd(), delay)?;
+        }
         let lst = TcpListener::from_listener(listener, &info.sockaddr, &handle.h)?;
 
-        info!("Started listening on {:?}", info.sockaddr);
         let mut addr = info.clone();
         addr.sockaddr = lst.local_addr().expect("should not fail");
-        let s = Socket::new(py, &addr)?;
+        info!("Started listening on {:?}", addr.sockaddr);
+        let s = Socket::new_listener(py, &addr, lst.as_raw_fd())?;
         sockets.push(s);
         listeners.push((lst, addr));
     }
 
+    // server is registered once all sockets are listening
+    let id = evloop.register_server(start_serving, &opts, &ssl, on_connect);
+    let mut opts = opts;
+    opts.server = Some(id);
+
     // create tokio listeners
     let mut handles = Vec::new();
     for (listener, addr) in listeners {
@@ -72,71 +182,214 @@ pub fn create_server(py: Python, evloop: &TokioEventLoop,
         let (tx, rx) = unsync::oneshot::channel::<()>();
         handles.push(pyunsafe::OneshotSender::new(tx));
 
-        Server::serve(evloop, addr, listener.incoming(),
-                      transport_factory, proto_factory.clone_ref(py), s, rx);
+        let mut opts = opts;
+        opts.listener = Some(evloop.register_listener(id, ListenerAddr::Inet(addr.sockaddr)));
+
+        let fd = listener.as_raw_fd();
+        Server::serve(evloop, addr, fd, listener.incoming(),
+                      transport_factory, proto_factory.clone_ref(py), s, opts, rx);
     }
 
-    py.init(|token| TokioServer{
-        evloop: evloop.into(),
-        sockets: PyTuple::new(py, &sockets[..]),
-        stop_handle: Some(handles),
-        token: token}).map(|ptr| ptr.into())
+    new_server(py, evloop, id, PyTuple::new(py, &sockets[..]), handles, None)
 }
 
 
 pub fn create_sock_server(py: Python, evloop: &TokioEventLoop,
                           listener: net::TcpListener, info: addrinfo::AddrInfo,
                           ssl: Option<PyObject>, proto_factory: PyObject,
-                          transport_factory: TransportFactory) -> PyResult<PyObject> {
+                          transport_factory: TransportFactory,
+                          opts: TransportOptions, start_serving: bool,
+                          on_connect: Option<PyObject>) -> PyResult<PyObject> {
 
+    if let Some(delay) = opts.defer_accept {
+        transport::set_defer_accept(listener.as_raw_fd(), delay)?;
+    }
     let lst = TcpListener::from_listener(listener, &info.sockaddr, evloop.href())?;
 
-    info!("Started listening on {:?}", info.sockaddr);
     let mut addr = info.clone();
     addr.sockaddr = lst.local_addr().expect("should not fail");
-    let sock = Socket::new(py, &addr)?;
+    info!("Started listening on {:?}", addr.sockaddr);
+    let sock = Socket::new_listener(py, &addr, lst.as_raw_fd())?;
+
+    let id = evloop.register_server(start_serving, &opts, &ssl, on_connect);
+    let mut opts = opts;
+    opts.server = Some(id);
 
     let (tx, rx) = unsync::oneshot::channel::<()>();
     let handles = vec![pyunsafe::OneshotSender::new(tx)];
 
-    Server::serve(evloop, addr, lst.incoming(),
-                  transport_factory, proto_factory, ssl, rx);
+    opts.listener = Some(evloop.register_listener(id, ListenerAddr::Inet(addr.sockaddr)));
 
-    py.init(|token| TokioServer {
-        evloop: evloop.into(),
-        sockets: PyTuple::new(py, &[sock]),
-        stop_handle: Some(handles),
-        token: token}).map(|ptr| ptr.into())
+    let fd = lst.as_raw_fd();
+    Server::serve(evloop, addr, fd, lst.incoming(),
+                  transport_factory, proto_factory, ssl, opts, rx);
+
+    new_server(py, evloop, id, PyTuple::new(py, &[sock]), handles, None)
 }
 
 
 pub fn create_uds_server(py: Python, evloop: &TokioEventLoop,
                          listener: tokio_uds::UnixListener, ssl: Option<PyObject>,
-                         proto_factory: PyObject) -> PyResult<PyObject> {
-    info!("Started listening on {:?}", listener.local_addr().unwrap());
+                         proto_factory: PyObject, start_serving: bool,
+                         path_guard: Option<UnixPathGuard>) -> PyResult<PyObject> {
+    let local_addr = listener.local_addr()?;
+    info!("Started listening on {:?}", local_addr);
+
+    // python socket object for duplicated listener fd, closed with server
+    let fd = unsafe { libc::dup(listener.as_raw_fd()) };
+    if fd == -1 {
+        return Err(io::Error::last_os_error().into())
+    }
+    let sock = Classes.Socket.as_ref(py).call1(
+        "socket", (libc::AF_UNIX, libc::SOCK_STREAM, 0, fd))?;
 
+    let id = evloop.register_server(
+        start_serving, &TransportOptions::default(), &ssl, None);
     let (tx, rx) = unsync::oneshot::channel::<()>();
     let handles = vec![pyunsafe::OneshotSender::new(tx)];
 
-    UdsServer::serve(evloop, listener.incoming(), proto_factory, ssl, rx);
+    let path = local_addr.as_pathname()
+        .map(|path| path.to_string_lossy().into_owned()).unwrap_or_default();
+    let idx = evloop.register_listener(id, ListenerAddr::Unix(path));
+
+    let fd = listener.as_raw_fd();
+    UdsServer::serve(evloop, id, idx, fd, listener.incoming(), proto_factory, ssl, rx);
+
+    new_server(py, evloop, id, PyTuple::new(py, &[sock]), handles, path_guard)
+}
+
+///
+/// Datagram server, protocol and transport is created for each
+/// bound socket. Transports are counted as connections of server,
+/// wait_closed() waits until all of them are closed.
+///
+pub fn create_datagram_server(py: Python, evloop: &TokioEventLoop,
+                              addrs: Vec<addrinfo::AddrInfo>, proto_factory: PyObject,
+                              reuse_address: Option<bool>, reuse_port: bool,
+                              allow_broadcast: bool) -> PyResult<PyObject> {
+    let bound = datagram::bind(
+        py, addrs, reuse_address, reuse_port, allow_broadcast, evloop.href())?;
+    let id = evloop.register_server(true, &TransportOptions::default(), &None, None);
+
+    let mut sockets = Vec::new();
+    let mut handles = Vec::new();
+    for (sock, socket) in bound {
+        let (tx, rx) = unsync::oneshot::channel::<()>();
+        handles.push(pyunsafe::OneshotSender::new(tx));
+
+        let guard = evloop.server_connection(id, None);
+        let res = datagram::start_transport(
+            py, evloop, &proto_factory, datagram::DatagramSocket::Udp(socket),
+            sock.clone_ref(py).into(), None, Some(rx), Some(guard));
+        if let Err(err) = res {
+            // close transports of previous addresses, server is forgotten
+            // once they are done. sockets which are not started are dropped
+            for h in handles {
+                let _ = h.send(());
+            }
+            for sock in sockets {
+                sock.as_mut(py).forget_fd();
+            }
+            evloop.close_server(py, id);
+            return Err(err)
+        }
+        sockets.push(sock);
+    }
+
+    new_server(py, evloop, id, PyTuple::new(py, &sockets[..]), handles, None)
+}
+
+///
+/// SO_REUSEPORT is not available on every platform
+///
+pub fn reuse_port_error(err: io::Error) -> PyErr {
+    match err.raw_os_error() {
+        Some(libc::ENOPROTOOPT) | Some(libc::EINVAL) =>
+            exc::ValueError::new("reuse_port not supported by socket module"),
+        _ => err.into(),
+    }
+}
 
-    py.init(|token| TokioServer{
+fn new_server(py: Python, evloop: &TokioEventLoop, id: usize, sockets: Py<PyTuple>,
+              handles: Vec<pyunsafe::OneshotSender<()>>,
+              path_guard: Option<UnixPathGuard>) -> PyResult<PyObject> {
+    let srv: Py<TokioServer> = py.init(|token| TokioServer{
         evloop: evloop.into(),
-        sockets: PyTuple::empty(py),
+        id: id,
+        sockets: sockets,
         stop_handle: Some(handles),
-        token: token}).map(|ptr| ptr.into())
+        serving_forever: None,
+        path_guard: path_guard,
+        totals: ConnectionTotals::default(),
+        listeners: Vec::new(),
+        token: token})?;
+
+    // protocol factories may receive server in connection context
+    evloop.set_server_object(py, id, &srv)?;
+    Ok(srv.into())
 }
 
 
 #[py::class(weakref)]
 pub struct TokioServer {
     evloop: Py<TokioEventLoop>,
+    id: usize,
     sockets: Py<PyTuple>,
     stop_handle: Option<Vec<pyunsafe::OneshotSender<()>>>,
+    serving_forever: Option<Py<PyFuture>>,
+    // removes unix socket file on close
+    path_guard: Option<UnixPathGuard>,
+    // final counters, loop forgets server once it is done
+    totals: ConnectionTotals,
+    listeners: Vec<ListenerStats>,
     token: PyToken,
 }
 
 
+///
+/// Bind unix socket to path with optional file mode and owner,
+/// stale socket file left by previous process is removed if cleanup is set.
+/// Returned guard removes socket file when dropped.
+///
+pub fn bind_unix(path: &Path, mode: Option<u32>, owner: Option<(i32, i32)>, cleanup: bool,
+                 handle: &reactor::Handle)
+                 -> io::Result<(tokio_uds::UnixListener, Option<UnixPathGuard>)> {
+    if cleanup {
+        if let Ok(meta) = fs::symlink_metadata(path) {
+            if meta.file_type().is_socket() {
+                fs::remove_file(path)?;
+            }
+        }
+    }
+
+    let listener = tokio_uds::UnixListener::bind(path, handle)?;
+    let guard = if cleanup { Some(UnixPathGuard(path.to_path_buf())) } else { None };
+
+    if let Some(mode) = mode {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    }
+    if let Some((uid, gid)) = owner {
+        let cpath = ffi::CString::new(path.as_os_str().as_bytes())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid path"))?;
+        let res = unsafe {
+            libc::chown(cpath.as_ptr(), uid as libc::uid_t, gid as libc::gid_t)
+        };
+        if res == -1 {
+            return Err(io::Error::last_os_error())
+        }
+    }
+    Ok((listener, guard))
+}
+
+pub struct UnixPathGuard(PathBuf);
+
+impl Drop for UnixPathGuard {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+
 #[py::methods]
 impl TokioServer {
 
@@ -145,29 +398,570 @@ impl TokioServer {
         Ok(self.sockets.to_object(self.py()))
     }
 
+    ///
+    /// Actual bound addresses of listening sockets, in getsockname()
+    /// format, e.g. ports chosen by system for port 0
+    ///
+    #[getter]
+    fn addresses(&self) -> PyResult<PyObject> {
+        let py = self.py();
+        let mut addrs = Vec::new();
+        for sock in self.sockets.as_ref(py).iter() {
+            addrs.push(sock.call_method0("getsockname")?.to_object(py));
+        }
+        Ok(PyList::new(py, &addrs).into())
+    }
+
     fn close(&mut self, py: Python) -> PyResult<PyObject> {
         if let Some(handles) = self.stop_handle.take() {
             for h in handles {
                 let _ = h.send(());
             }
+
+            // listeners are closed, sockets are not usable anymore
+            for sock in self.sockets.as_ref(py).iter() {
+                if let Ok(sock) = Socket::try_from_mut(sock) {
+                    sock.forget_fd();
+                } else {
+                    sock.call_method0("close")?;
+                }
+            }
+            self.sockets = PyTuple::empty(py);
+            self.path_guard.take();
+            self.evloop.as_ref(py).close_server(py, self.id);
+        }
+        if let Some(fut) = self.serving_forever.take() {
+            fut.as_mut(py).cancel(py)?;
         }
         Ok(py.None())
     }
 
+    fn get_loop(&self, py: Python) -> PyResult<Py<TokioEventLoop>> {
+        Ok(self.evloop.clone_ref(py))
+    }
+
+    fn is_serving(&self, py: Python) -> PyResult<bool> {
+        Ok(self.stop_handle.is_some() && self.evloop.as_ref(py).is_server_serving(self.id))
+    }
+
+    ///
+    /// Start accepting connections of server created with start_serving=False
+    ///
+    fn start_serving(&self, py: Python) -> PyResult<Py<PyFuture>> {
+        if self.stop_handle.is_some() {
+            self.evloop.as_ref(py).start_server_serving(self.id);
+        }
+        PyFuture::done_fut(py, self.evloop.clone_ref(py), py.None())
+    }
+
+    ///
+    /// Start accepting connections until returned future is cancelled,
+    /// server is closed after it
+    ///
+    fn serve_forever(&mut self, py: Python) -> PyResult<Py<PyFuture>> {
+        if self.serving_forever.is_some() {
+            return Err(exc::RuntimeError::new(
+                format!("server {:?} is already being awaited on serve_forever()",
+                        self.sockets.as_ref(py))))
+        }
+        if self.stop_handle.is_none() {
+            return Err(exc::RuntimeError::new("server is closed"))
+        }
+
+        self.evloop.as_ref(py).start_server_serving(self.id);
+
+        let fut = PyFuture::new(py, self.evloop.clone_ref(py))?;
+        let srv: Py<TokioServer> = self.into();
+        fut.as_mut(py).add_callback(py, BoxFnOnce::from(move |_| {
+            let py = pyunsafe::GIL::python();
+            let _ = srv.as_mut(py).close(py);
+        }));
+        self.serving_forever = Some(fut.clone_ref(py));
+        Ok(fut)
+    }
+
+    ///
+    /// File descriptors of listening sockets, for passing to new process
+    /// which creates servers with loop.create_servers_from_fds().
+    /// inheritable clears FD_CLOEXEC, so fds survive exec().
+    ///
+    #[args(inheritable=true)]
+    fn export_fds(&self, py: Python, inheritable: bool) -> PyResult<Vec<i32>> {
+        if self.stop_handle.is_none() {
+            return Err(exc::RuntimeError::new("server is closed"))
+        }
+        let mut fds = Vec::new();
+        for sock in self.sockets.as_ref(py).iter() {
+            let fd: i32 = sock.call_method0("fileno")?.extract()?;
+            activation::set_cloexec(fd, !inheritable)?;
+            fds.push(fd);
+        }
+        Ok(fds)
+    }
+
+    ///
+    /// Wait until server is closed and all its connections are finished
+    ///
     fn wait_closed(&self, py: Python) -> PyResult<Py<PyFuture>> {
-        PyFuture::done_fut(py, self.evloop.clone_ref(py), true.to_object(py))
+        self.evloop.as_ref(py).wait_server_closed(py, self.id)
+    }
+
+    ///
+    /// Number of live connections
+    ///
+    #[getter]
+    fn connections(&self) -> PyResult<usize> {
+        Ok(self.counters(self.py()).0)
+    }
+
+    ///
+    /// Total number of accepted connections
+    ///
+    #[getter]
+    fn total_accepted(&self) -> PyResult<u64> {
+        Ok(self.counters(self.py()).1.accepted)
+    }
+
+    ///
+    /// Total number of finished connections
+    ///
+    #[getter]
+    fn total_closed(&self) -> PyResult<u64> {
+        Ok(self.counters(self.py()).1.closed)
+    }
+
+    ///
+    /// Total number of malformed or too large http requests
+    /// which were answered with error response
+    ///
+    #[getter]
+    fn total_bad_requests(&self) -> PyResult<u64> {
+        Ok(self.counters(self.py()).1.bad_requests)
+    }
+
+    ///
+    /// Accept statistics of each listener: list of dicts with address,
+    /// accepted, accepts_per_sec (during last full second), accept_errors
+    /// and handshake_failures (tls handshakes which failed or were
+    /// interrupted) keys
+    ///
+    fn stats(&self, py: Python) -> PyResult<PyObject> {
+        let mut listeners = self.evloop.as_ref(py).server_listeners(self.id)
+            .unwrap_or_else(|| self.listeners.clone());
+        let stats: Vec<PyObject> = listeners.iter_mut()
+            .map(|listener| listener.to_object(py))
+            .collect();
+        Ok(PyList::new(py, &stats).into())
+    }
+
+    ///
+    /// Replace tls configuration, either SSLContext or dict with
+    /// certfile, keyfile, sni and other configure_context() options.
+    /// Applies to new handshakes only, established connections are
+    /// not affected
+    ///
+    fn reload_tls(&self, py: Python, config: &PyObjectRef) -> PyResult<()> {
+        self.evloop.as_ref(py).reload_server_ssl(py, self.id, config)
+    }
+}
+
+impl TokioServer {
+
+    fn counters(&self, py: Python) -> (usize, ConnectionTotals) {
+        self.evloop.as_ref(py).server_counters(self.id).unwrap_or((0, self.totals))
+    }
+
+    pub fn set_final_stats(&mut self, totals: ConnectionTotals, listeners: Vec<ListenerStats>) {
+        self.totals = totals;
+        self.listeners = listeners;
+    }
+}
+
+
+#[derive(Clone, Debug)]
+pub enum ListenerAddr {
+    Inet(net::SocketAddr),
+    Unix(String),
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum ListenerEvent {
+    Accepted,
+    AcceptError,
+    HandshakeFailed,
+}
+
+///
+/// Accept counters of single listener
+///
+#[derive(Clone, Debug)]
+pub struct ListenerStats {
+    pub address: ListenerAddr,
+    pub accepted: u64,
+    pub accept_errors: u64,
+    pub handshake_failures: u64,
+    // accepts in current and previous second
+    window: Instant,
+    current: u64,
+    previous: u64,
+}
+
+impl ListenerStats {
+
+    pub fn new(address: ListenerAddr) -> ListenerStats {
+        ListenerStats { address: address, accepted: 0, accept_errors: 0,
+                        handshake_failures: 0,
+                        window: Instant::now(), current: 0, previous: 0 }
+    }
+
+    pub fn record(&mut self, event: ListenerEvent) {
+        match event {
+            ListenerEvent::Accepted => {
+                self.roll();
+                self.accepted += 1;
+                self.current += 1;
+            },
+            ListenerEvent::AcceptError => self.accept_errors += 1,
+            ListenerEvent::HandshakeFailed => self.handshake_failures += 1,
+        }
+    }
+
+    fn roll(&mut self) {
+        let elapsed = self.window.elapsed();
+        if elapsed >= Duration::from_secs(2) {
+            self.previous = 0;
+            self.current = 0;
+            self.window = Instant::now();
+        } else if elapsed >= Duration::from_secs(1) {
+            self.previous = self.current;
+            self.current = 0;
+            self.window += Duration::from_secs(1);
+        }
+    }
+
+    fn to_object(&mut self, py: Python) -> PyObject {
+        self.roll();
+        let stats = PyDict::new(py);
+        let _ = stats.set_item("address", match self.address {
+            ListenerAddr::Inet(ref addr) => sockaddr_object(py, addr),
+            ListenerAddr::Unix(ref path) => path.to_object(py),
+        });
+        let _ = stats.set_item("accepted", self.accepted);
+        let _ = stats.set_item("accepts_per_sec", self.previous);
+        let _ = stats.set_item("accept_errors", self.accept_errors);
+        let _ = stats.set_item("handshake_failures", self.handshake_failures);
+        stats.into()
     }
 }
 
 
+///
+/// Connection counters of server
+///
+#[derive(Copy, Clone, Debug, Default)]
+pub struct ConnectionTotals {
+    pub accepted: u64,
+    pub closed: u64,
+    // malformed http requests answered with error response
+    pub bad_requests: u64,
+}
+
+
+//
+// Live connections of server, closed server is done when last one finishes
+//
+pub struct ServerState {
+    pub connections: usize,
+    pub closed: bool,
+    pub waiters: Vec<Py<PyFuture>>,
+    pub serving: bool,
+    pub max_connections: Option<usize>,
+    // listeners wait for start_serving() or for connections to finish
+    pub parked: Vec<task::Task>,
+    pub peers: Option<PeerLimits>,
+    // tls handshakes in progress
+    pub handshakes: usize,
+    pub max_handshakes: Option<usize>,
+    // weak reference to TokioServer
+    pub server: Option<PyObject>,
+    // called with connection context, before protocol is created
+    pub on_connect: Option<PyObject>,
+    // tls context for new connections, replaced by reload_tls()
+    pub ssl: Option<PyObject>,
+    pub totals: ConnectionTotals,
+    pub listeners: Vec<ListenerStats>,
+}
+
+impl ServerState {
+    pub fn new(serving: bool, opts: &TransportOptions, ssl: Option<PyObject>,
+               on_connect: Option<PyObject>) -> ServerState {
+        ServerState { connections: 0, closed: false, waiters: Vec::new(),
+                      serving: serving, max_connections: opts.max_connections,
+                      parked: Vec::new(),
+                      peers: PeerLimits::new(opts.max_connections_per_ip,
+                                             opts.accept_rate_per_ip),
+                      handshakes: 0, max_handshakes: opts.max_handshakes,
+                      server: None, on_connect: on_connect, ssl: ssl,
+                      totals: ConnectionTotals::default(), listeners: Vec::new() }
+    }
+
+    pub fn can_accept(&self) -> bool {
+        self.serving && match self.max_connections {
+            Some(max) => self.connections < max,
+            None => true,
+        } && match self.max_handshakes {
+            Some(max) => self.handshakes < max,
+            None => true,
+        }
+    }
+
+    pub fn notify_parked(&mut self) {
+        if self.can_accept() {
+            for task in self.parked.drain(..) {
+                task.notify();
+            }
+        }
+    }
+}
+
+// number of tracked source addresses without live connections
+const MAX_IDLE_PEERS: usize = 1024;
+
+//
+// Per source address limits of accepted connections, concurrent
+// connections and token bucket of accept rate. Addresses without live
+// connections are evicted in least recently seen order.
+//
+pub struct PeerLimits {
+    max_connections: Option<usize>,
+    rate: Option<f64>,
+    peers: HashMap<IpAddr, PeerState>,
+}
+
+struct PeerState {
+    connections: usize,
+    tokens: f64,
+    refilled: Instant,
+    seen: Instant,
+}
+
+impl PeerLimits {
+
+    pub fn new(max_connections: Option<usize>, rate: Option<f64>) -> Option<PeerLimits> {
+        if max_connections.is_none() && rate.is_none() {
+            return None
+        }
+        Some(PeerLimits { max_connections: max_connections, rate: rate,
+                          peers: HashMap::new() })
+    }
+
+    //
+    // Check if new connection from address is allowed
+    //
+    pub fn admit(&mut self, ip: IpAddr) -> bool {
+        if !self.peers.contains_key(&ip) {
+            self.evict();
+        }
+
+        let now = Instant::now();
+        let burst = self.rate.map(|rate| rate.max(1.0)).unwrap_or(0.0);
+        let peer = self.peers.entry(ip).or_insert_with(|| PeerState {
+            connections: 0, tokens: burst, refilled: now, seen: now });
+        peer.seen = now;
+
+        if let Some(max) = self.max_connections {
+            if peer.connections >= max {
+                return false
+            }
+        }
+        if let Some(rate) = self.rate {
+            let elapsed = utils::duration_to_secs(now.duration_since(peer.refilled));
+            peer.tokens = (peer.tokens + elapsed * rate).min(burst);
+            peer.refilled = now;
+            if peer.tokens < 1.0 {
+                return false
+            }
+            peer.tokens -= 1.0;
+        }
+        true
+    }
+
+    pub fn connected(&mut self, ip: IpAddr) {
+        if let Some(peer) = self.peers.get_mut(&ip) {
+            peer.connections += 1;
+        }
+    }
+
+    pub fn disconnected(&mut self, ip: IpAddr) {
+        if let Some(peer) = self.peers.get_mut(&ip) {
+            peer.connections = peer.connections.saturating_sub(1);
+        }
+    }
+
+    fn evict(&mut self) {
+        let idle = self.peers.values().filter(|peer| peer.connections == 0).count();
+        if idle < MAX_IDLE_PEERS {
+            return
+        }
+        let oldest = self.peers.iter()
+            .filter(|&(_, peer)| peer.connections == 0)
+            .min_by_key(|&(_, peer)| peer.seen)
+            .map(|(ip, _)| *ip);
+        if let Some(ip) = oldest {
+            self.peers.remove(&ip);
+        }
+    }
+}
+
+
+//
+// Held by connection of server until protocol is notified
+// about connection lost, see TokioEventLoop::server_connection()
+//
+pub struct ConnectionGuard {
+    evloop: Py<TokioEventLoop>,
+    server: usize,
+    peer: Option<IpAddr>,
+}
+
+impl ConnectionGuard {
+    pub fn new(evloop: Py<TokioEventLoop>, server: usize, peer: Option<IpAddr>)
+               -> ConnectionGuard {
+        ConnectionGuard { evloop: evloop, server: server, peer: peer }
+    }
+
+    /// Connection received malformed http request
+    pub fn bad_request(&self) {
+        let py = pyunsafe::GIL::python();
+        self.evloop.as_ref(py).server_bad_request(self.server);
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let py = pyunsafe::GIL::python();
+        self.evloop.as_ref(py).server_connection_lost(py, self.server, self.peer);
+    }
+}
+
+
+//
+// accept() failed because of pending connection, not listener itself,
+// next connection can be accepted right away
+//
+fn is_transient_accept_error(err: &io::Error) -> bool {
+    match err.kind() {
+        io::ErrorKind::ConnectionAborted | io::ErrorKind::ConnectionReset |
+        io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock => true,
+        _ => match err.raw_os_error() {
+            Some(libc::EPROTO) | Some(libc::ENETDOWN) | Some(libc::ENOPROTOOPT) |
+            Some(libc::EHOSTDOWN) | Some(libc::EHOSTUNREACH) | Some(libc::EOPNOTSUPP) |
+            Some(libc::ENETUNREACH) => true,
+            _ => false,
+        },
+    }
+}
+
+//
+// accept() failed because process or system is out of resources,
+// retrying right away would spin
+//
+fn is_resource_accept_error(err: &io::Error) -> bool {
+    match err.raw_os_error() {
+        Some(libc::EMFILE) | Some(libc::ENFILE) |
+        Some(libc::ENOBUFS) | Some(libc::ENOMEM) => true,
+        _ => false,
+    }
+}
+
+//
+// Pause of listener after accept() ran out of resources. Optional
+// reserved fd is released to accept and close one pending connection,
+// so its client is refused instead of waiting in backlog.
+//
+struct AcceptBackoff {
+    timer: Option<reactor::Timeout>,
+    reserve: Option<RawFd>,
+}
+
+impl AcceptBackoff {
+
+    fn new(reserve_fd: bool) -> AcceptBackoff {
+        let reserve = if reserve_fd { open_reserve_fd() } else { None };
+        AcceptBackoff { timer: None, reserve: reserve }
+    }
+
+    // listener may accept, pause is over
+    fn poll_ready(&mut self) -> io::Result<bool> {
+        if let Some(mut timer) = self.timer.take() {
+            if timer.poll()?.is_not_ready() {
+                self.timer = Some(timer);
+                return Ok(false)
+            }
+        }
+        Ok(true)
+    }
+
+    fn pause(&mut self, listener: RawFd, delay: Duration, handle: &reactor::Handle)
+             -> io::Result<()> {
+        if let Some(fd) = self.reserve.take() {
+            unsafe {
+                libc::close(fd);
+                let conn = libc::accept(
+                    listener, ::std::ptr::null_mut(), ::std::ptr::null_mut());
+                if conn != -1 {
+                    libc::close(conn);
+                }
+            }
+            self.reserve = open_reserve_fd();
+        }
+
+        let mut timer = reactor::Timeout::new(delay, handle)?;
+        // register timer within current task
+        let _ = timer.poll()?;
+        self.timer = Some(timer);
+        Ok(())
+    }
+}
+
+impl Drop for AcceptBackoff {
+    fn drop(&mut self) {
+        if let Some(fd) = self.reserve.take() {
+            unsafe { libc::close(fd); }
+        }
+    }
+}
+
+fn open_reserve_fd() -> Option<RawFd> {
+    let fd = unsafe {
+        libc::open(b"/dev/null\0".as_ptr() as *const libc::c_char,
+                   libc::O_RDONLY | libc::O_CLOEXEC)
+    };
+    if fd == -1 { None } else { Some(fd) }
+}
+
+// listener keeps running, error is passed to loop exception handler
+fn report_accept_error(evloop: &Py<TokioEventLoop>, listener: &str, err: io::Error) {
+    let py = pyunsafe::GIL::python();
+    let context = PyDict::new(py);
+    let _ = context.set_item(
+        "message", format!("Error on accepting connection on {}", listener));
+    let _ = context.set_item("exception", PyErr::from(err));
+    let _ = evloop.as_ref(py).call_exception_handler(py, context);
+}
+
+
 struct Server {
     evloop: Py<TokioEventLoop>,
     addr: addrinfo::AddrInfo,
+    fd: RawFd,
     stream: Incoming,
     stop: unsync::oneshot::Receiver<()>,
     transport: TransportFactory,
     factory: PyObject,
     ssl: Option<PyObject>,
+    opts: TransportOptions,
+    backoff: AcceptBackoff,
 }
 
 impl Server {
@@ -175,12 +969,14 @@ impl Server {
     //
     // Start accepting incoming connections
     //
-    fn serve(evloop: &TokioEventLoop, addr: addrinfo::AddrInfo,
+    fn serve(evloop: &TokioEventLoop, addr: addrinfo::AddrInfo, fd: RawFd,
              stream: Incoming, transport: TransportFactory,
-             factory: PyObject, ssl: Option<PyObject>, stop: unsync::oneshot::Receiver<()>) {
+             factory: PyObject, ssl: Option<PyObject>, opts: TransportOptions,
+             stop: unsync::oneshot::Receiver<()>) {
 
-        let srv = Server { evloop: evloop.into(), addr: addr, stop: stop, stream: stream,
-                           transport: transport, factory: factory, ssl: ssl};
+        let srv = Server { evloop: evloop.into(), addr: addr, fd: fd, stop: stop, stream: stream,
+                           transport: transport, factory: factory, ssl: ssl, opts: opts,
+                           backoff: AcceptBackoff::new(opts.reserve_fd) };
 
         evloop.get_handle().spawn(
             srv.map_err(|e| {
@@ -188,6 +984,11 @@ impl Server {
             })
         );
     }
+
+    fn record(&self, event: ListenerEvent) {
+        self.evloop.as_ref(pyunsafe::GIL::python()).server_listener_event(
+            self.opts.server, self.opts.listener, event);
+    }
 }
 
 
@@ -203,24 +1004,86 @@ impl Future for Server
             Ok(Async::NotReady) => (),
         }
 
-        let option = self.stream.poll()?;
-        match option {
-            Async::Ready(Some((socket, peer))) => {
-                (self.transport)(
-                    self.evloop.clone_ref(pyunsafe::GIL::python()),
-                    true, &self.factory, &self.ssl,
-                    None, socket, Some(&self.addr), Some(peer), None)?;
+        // accepting is paused after running out of fds
+        if !self.backoff.poll_ready()? {
+            return Ok(Async::NotReady)
+        }
 
-                // we can not just return Async::NotReady here,
-                // because self.stream is not registered within mio anymore
-                // next stream.poll() will re-register io object
-                self.poll()
-            },
-            Async::Ready(None) =>
-                Ok(Async::Ready(())),
-            Async::NotReady =>
-                Ok(Async::NotReady),
+        // accept until stream is drained, but no more than batch
+        // of connections per wakeup
+        for _ in 0..self.opts.accept_batch() {
+            // server is not serving yet or has too many connections,
+            // pending connections wait in listen backlog
+            if !self.evloop.as_ref(pyunsafe::GIL::python()).poll_server_accept(self.opts.server) {
+                return Ok(Async::NotReady)
+            }
+
+            let item = match self.stream.poll() {
+                Ok(item) => item,
+                Err(ref err) if is_transient_accept_error(err) => {
+                    debug!("Accept error on {}: {}", self.addr.sockaddr, err);
+                    self.record(ListenerEvent::AcceptError);
+                    continue
+                },
+                Err(err) => {
+                    self.record(ListenerEvent::AcceptError);
+                    let resources = is_resource_accept_error(&err);
+                    report_accept_error(&self.evloop, &format!("{}", self.addr.sockaddr), err);
+
+                    // keep listening, retry after delay if out of fds
+                    // or on next turn otherwise
+                    if resources {
+                        let ev = self.evloop.as_ref(pyunsafe::GIL::python());
+                        self.backoff.pause(self.fd, self.opts.accept_retry_delay(), ev.href())?;
+                    } else {
+                        task::current().notify();
+                    }
+                    return Ok(Async::NotReady)
+                },
+            };
+
+            match item {
+                Async::Ready(Some((socket, peer))) => {
+                    self.record(ListenerEvent::Accepted);
+
+                    // connection is closed before any python code runs
+                    let ev = self.evloop.as_ref(pyunsafe::GIL::python());
+                    if !ev.server_admit(self.opts.server, peer.ip()) {
+                        debug!("Connection from {} is rejected by per-ip limits", peer);
+                        continue
+                    }
+                    if self.ssl.is_some() {
+                        self.ssl = ev.server_ssl(self.opts.server).or(self.ssl.take());
+                    }
+                    if !ev.server_on_connect(self.opts.server, &self.ssl,
+                                             peer, socket.local_addr().ok()) {
+                        debug!("Connection from {} is rejected by on_connect", peer);
+                        continue
+                    }
+
+                    // disable nagle algorithm, same as asyncio
+                    let _ = socket.set_nodelay(true);
+
+                    if let Err(err) = (self.transport)(
+                        self.evloop.clone_ref(pyunsafe::GIL::python()),
+                        true, &self.factory, &self.ssl,
+                        None, socket, Some(&self.addr), Some(peer), None, self.opts)
+                    {
+                        error!("Can not create transport for {}: {}", peer, err);
+                    }
+                },
+                Async::Ready(None) =>
+                    return Ok(Async::Ready(())),
+                // stream is registered within mio again
+                Async::NotReady =>
+                    return Ok(Async::NotReady),
+            }
         }
+
+        // there may be more pending connections, but self.stream is not
+        // registered within mio anymore, let other tasks run and poll again
+        task::current().notify();
+        Ok(Async::NotReady)
     }
 }
 
@@ -229,10 +1092,13 @@ type UdsIncoming = IoStream<(tokio_uds::UnixStream, unix::net::SocketAddr)>;
 
 struct UdsServer {
     evloop: Py<TokioEventLoop>,
+    opts: TransportOptions,
+    fd: RawFd,
     stream: UdsIncoming,
     stop: unsync::oneshot::Receiver<()>,
     factory: PyObject,
     ssl: Option<PyObject>,
+    backoff: AcceptBackoff,
 }
 
 impl UdsServer {
@@ -240,11 +1106,16 @@ impl UdsServer {
     //
     // Start accepting incoming connections
     //
-    fn serve(evloop: &TokioEventLoop, stream: UdsIncoming,
-             factory: PyObject, ssl: Option<PyObject>, stop: unsync::oneshot::Receiver<()>) {
+    fn serve(evloop: &TokioEventLoop, id: usize, listener: usize, fd: RawFd,
+             stream: UdsIncoming, factory: PyObject, ssl: Option<PyObject>,
+             stop: unsync::oneshot::Receiver<()>) {
 
-        let srv = UdsServer { evloop: evloop.into(), stop: stop,
-                              stream: stream, factory: factory, ssl: ssl};
+        let mut opts = TransportOptions::default();
+        opts.server = Some(id);
+        opts.listener = Some(listener);
+        let srv = UdsServer { evloop: evloop.into(), opts: opts, fd: fd, stop: stop,
+                              stream: stream, factory: factory, ssl: ssl,
+                              backoff: AcceptBackoff::new(false) };
 
         evloop.get_handle().spawn(
             srv.map_err(|e| {
@@ -252,6 +1123,11 @@ impl UdsServer {
             })
         );
     }
+
+    fn record(&self, event: ListenerEvent) {
+        self.evloop.as_ref(pyunsafe::GIL::python()).server_listener_event(
+            self.opts.server, self.opts.listener, event);
+    }
 }
 
 
@@ -269,22 +1145,62 @@ impl Future for UdsServer
             Ok(Async::NotReady) => (),
         }
 
-        let option = self.stream.poll()?;
-        match option {
-            Async::Ready(Some((socket, _peer))) => {
-                tcp_transport_factory(
-                    self.evloop.clone_ref(pyunsafe::GIL::python()),
-                    true, &self.factory, &self.ssl, None, socket, None, None, None)?;
+        if !self.backoff.poll_ready()? {
+            return Ok(Async::NotReady)
+        }
 
-                // we can not just return Async::NotReady here,
-                // because self.stream is not registered within mio anymore
-                // next stream.poll() will re-register io object
-                self.poll()
-            },
-            Async::Ready(None) =>
-                Ok(Async::Ready(())),
-            Async::NotReady =>
-                Ok(Async::NotReady),
+        for _ in 0..self.opts.accept_batch() {
+            // server is not serving yet or has too many connections,
+            // pending connections wait in listen backlog
+            if !self.evloop.as_ref(pyunsafe::GIL::python()).poll_server_accept(self.opts.server) {
+                return Ok(Async::NotReady)
+            }
+
+            let item = match self.stream.poll() {
+                Ok(item) => item,
+                Err(ref err) if is_transient_accept_error(err) => {
+                    debug!("Accept error on unix socket: {}", err);
+                    self.record(ListenerEvent::AcceptError);
+                    continue
+                },
+                Err(err) => {
+                    self.record(ListenerEvent::AcceptError);
+                    let resources = is_resource_accept_error(&err);
+                    report_accept_error(&self.evloop, "unix socket", err);
+                    if resources {
+                        let ev = self.evloop.as_ref(pyunsafe::GIL::python());
+                        self.backoff.pause(self.fd, self.opts.accept_retry_delay(), ev.href())?;
+                    } else {
+                        task::current().notify();
+                    }
+                    return Ok(Async::NotReady)
+                },
+            };
+
+            match item {
+                Async::Ready(Some((socket, _peer))) => {
+                    self.record(ListenerEvent::Accepted);
+                    if self.ssl.is_some() {
+                        let ev = self.evloop.as_ref(pyunsafe::GIL::python());
+                        self.ssl = ev.server_ssl(self.opts.server).or(self.ssl.take());
+                    }
+                    if let Err(err) = tcp_transport_factory(
+                        self.evloop.clone_ref(pyunsafe::GIL::python()),
+                        true, &self.factory, &self.ssl, None, socket, None, None, None,
+                        self.opts)
+                    {
+                        error!("Can not create transport: {}", err);
+                    }
+                },
+                Async::Ready(None) =>
+                    return Ok(Async::Ready(())),
+                Async::NotReady =>
+                    return Ok(Async::NotReady),
+            }
         }
+
+        // batch is exhausted, poll again on next turn
+        task::current().notify();
+        Ok(Async::NotReady)
     }
 }
@@ -0,0 +1,496 @@
+use std::io::{self, Read};
+use std::cmp;
+use std::mem;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use pyo3::*;
+use futures::{Async, Future, Poll};
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::Timeout;
+
+use {PyFuture, TokioEventLoop};
+use addrinfo::AddrInfo;
+use http::http_transport_factory;
+use transport::{InitializedTransport, TransportOptions, tcp_transport_factory};
+
+
+pub const DEFAULT_SNIFF_TIMEOUT: u64 = 5;
+
+// longest custom magic prefix
+const MAX_MAGIC: usize = 64;
+
+const HTTP_METHODS: &'static [&'static [u8]] = &[
+    b"GET ", b"HEAD ", b"POST ", b"PUT ", b"DELETE ",
+    b"CONNECT ", b"OPTIONS ", b"TRACE ", b"PATCH "];
+
+
+#[derive(Debug)]
+enum Pattern {
+    // TLS handshake record, ClientHello
+    Tls,
+    // HTTP request line
+    Http,
+    // custom prefix
+    Magic(Vec<u8>),
+    // fallback
+    Any,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum Match {
+    Yes,
+    No,
+    More,
+}
+
+impl Pattern {
+
+    fn new(py: Python, pattern: &PyObjectRef) -> PyResult<Pattern> {
+        if pattern.is_none() {
+            return Ok(Pattern::Any)
+        }
+        if let Ok(s) = PyString::try_from(pattern) {
+            return match s.to_string()?.as_ref() {
+                "tls" => Ok(Pattern::Tls),
+                "http" => Ok(Pattern::Http),
+                name => Err(exc::ValueError::new(
+                    format!("Unknown sniff pattern: {}", name))),
+            }
+        }
+        let magic = buffer::PyBuffer::get(py, pattern)
+            .and_then(|buf| buf.to_vec::<u8>(py))
+            .map_err(|_| exc::TypeError::new(
+                "pattern must be 'tls', 'http', bytes or None"))?;
+        if magic.is_empty() || magic.len() > MAX_MAGIC {
+            return Err(exc::ValueError::new(
+                format!("magic pattern length must be between 1 and {}", MAX_MAGIC)))
+        }
+        Ok(Pattern::Magic(magic))
+    }
+
+    fn check(&self, data: &[u8]) -> Match {
+        match *self {
+            Pattern::Tls => match data.len() {
+                0 => Match::More,
+                1 => if data[0] == 0x16 { Match::More } else { Match::No },
+                _ => if data[0] == 0x16 && data[1] == 0x03 { Match::Yes } else { Match::No },
+            },
+            Pattern::Http => {
+                let mut result = Match::No;
+                for method in HTTP_METHODS {
+                    match prefix(method, data) {
+                        Match::Yes => return Match::Yes,
+                        Match::More => result = Match::More,
+                        Match::No => (),
+                    }
+                }
+                result
+            },
+            Pattern::Magic(ref magic) => prefix(magic, data),
+            Pattern::Any => Match::Yes,
+        }
+    }
+}
+
+fn prefix(expected: &[u8], data: &[u8]) -> Match {
+    let len = cmp::min(expected.len(), data.len());
+    if expected[..len] != data[..len] {
+        Match::No
+    } else if len < expected.len() {
+        Match::More
+    } else {
+        Match::Yes
+    }
+}
+
+
+struct Route {
+    pattern: Pattern,
+    factory: PyObject,
+    ssl: Option<PyObject>,
+}
+
+enum Selection {
+    Route(usize),
+    More,
+    Unmatched,
+}
+
+///
+/// Routes of sniffing server, passed to sniff_transport_factory
+/// in place of protocol factory
+///
+#[py::class]
+pub struct SniffRoutes {
+    routes: Vec<Route>,
+    timeout: Duration,
+    token: PyToken,
+}
+
+impl SniffRoutes {
+
+    pub fn new(py: Python, routes: &PyObjectRef, timeout: Duration)
+               -> PyResult<Py<SniffRoutes>> {
+        let mut result = Vec::new();
+
+        for item in routes.iter()? {
+            let item = PyTuple::try_from(item?)
+                .map_err(|_| exc::TypeError::new(
+                    "route must be (pattern, protocol_factory[, ssl]) tuple"))?;
+            let ssl = match item.len() {
+                2 => None,
+                3 => {
+                    let ssl = item.get_item(2);
+                    if ssl.is_none() { None } else { Some(ssl.into()) }
+                },
+                _ => return Err(exc::TypeError::new(
+                    "route must be (pattern, protocol_factory[, ssl]) tuple")),
+            };
+            let pattern = Pattern::new(py, item.get_item(0))?;
+            if let (&Pattern::Http, &Some(_)) = (&pattern, &ssl) {
+                return Err(exc::ValueError::new("ssl is not supported for http routes"))
+            }
+            result.push(Route {
+                pattern: pattern, factory: item.get_item(1).into(), ssl: ssl});
+        }
+
+        if result.is_empty() {
+            return Err(exc::ValueError::new("At least one route is required"))
+        }
+
+        py.init(|token| SniffRoutes {routes: result, timeout: timeout, token: token})
+    }
+
+    //
+    // routes are checked in order, first matching route wins,
+    // route that needs more data blocks routes after it
+    //
+    fn select(&self, data: &[u8]) -> Selection {
+        for (idx, route) in self.routes.iter().enumerate() {
+            match route.pattern.check(data) {
+                Match::Yes => return Selection::Route(idx),
+                Match::More => return Selection::More,
+                Match::No => (),
+            }
+        }
+        Selection::Unmatched
+    }
+
+    fn fallback(&self) -> Option<usize> {
+        self.routes.iter().position(|route| match route.pattern {
+            Pattern::Any => true,
+            _ => false,
+        })
+    }
+}
+
+
+///
+/// Transport factory for sniffing server, factory is SniffRoutes object.
+/// Reads first bytes of connection and dispatches it to matching route
+///
+pub fn sniff_transport_factory(
+    evloop: Py<TokioEventLoop>, _server: bool, factory: &PyObject,
+    _ssl: &Option<PyObject>, _server_hostname: Option<PyObject>,
+    socket: TcpStream, addr: Option<&AddrInfo>,
+    peer: Option<SocketAddr>, _waiter: Option<Py<PyFuture>>,
+    opts: TransportOptions) -> io::Result<InitializedTransport>
+{
+    let gil = Python::acquire_gil();
+    let py = gil.python();
+
+    let timeout = SniffRoutes::try_from(factory.as_ref(py))
+        .map_err(|_| io::Error::new(io::ErrorKind::Other, "SniffRoutes is required"))?
+        .timeout;
+    let handle = evloop.as_ref(py).get_handle();
+    let timeout = Timeout::new(timeout, &handle)?;
+
+    handle.spawn(
+        Sniffer {
+            evloop: evloop,
+            routes: factory.clone_ref(py),
+            socket: Some(socket),
+            addr: addr.cloned(),
+            peer: peer,
+            opts: opts,
+            buf: Vec::new(),
+            timeout: timeout,
+        }.map_err(|err| debug!("Sniffing failed: {}", err)));
+
+    Ok(InitializedTransport::new(py.None(), py.None()))
+}
+
+
+struct Sniffer {
+    evloop: Py<TokioEventLoop>,
+    routes: PyObject,
+    socket: Option<TcpStream>,
+    addr: Option<AddrInfo>,
+    peer: Option<SocketAddr>,
+    opts: TransportOptions,
+    buf: Vec<u8>,
+    timeout: Timeout,
+}
+
+impl Sniffer {
+
+    fn routes<'p>(&'p self, py: Python<'p>) -> &'p SniffRoutes {
+        SniffRoutes::try_from(self.routes.as_ref(py)).expect("checked in factory")
+    }
+
+    fn dispatch(&mut self, py: Python, idx: usize) -> io::Result<()> {
+        let socket = PrefixedStream::new(
+            mem::replace(&mut self.buf, Vec::new()), self.socket.take().expect("socket"));
+        let route = &self.routes(py).routes[idx];
+        let evloop = self.evloop.clone_ref(py);
+
+        match route.pattern {
+            Pattern::Http => http_transport_factory(
+                evloop, true, &route.factory, &None, None,
+                socket, self.addr.as_ref(), self.peer, None, self.opts)?,
+            _ => tcp_transport_factory(
+                evloop, true, &route.factory, &route.ssl, None,
+                socket, self.addr.as_ref(), self.peer, None, self.opts)?,
+        };
+        Ok(())
+    }
+}
+
+impl Future for Sniffer {
+    type Item = ();
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let gil = Python::acquire_gil();
+        let py = gil.python();
+
+        loop {
+            match self.routes(py).select(&self.buf) {
+                Selection::Route(idx) => {
+                    self.dispatch(py, idx)?;
+                    return Ok(Async::Ready(()))
+                },
+                Selection::Unmatched => {
+                    debug!("Connection does not match any route, closing");
+                    return Ok(Async::Ready(()))
+                },
+                Selection::More => (),
+            }
+
+            // not enough data in time, use fallback route if any
+            if let Async::Ready(_) = self.timeout.poll()? {
+                if let Some(idx) = self.routes(py).fallback() {
+                    self.dispatch(py, idx)?;
+                } else {
+                    debug!("Sniffing timeout, closing connection");
+                }
+                return Ok(Async::Ready(()))
+            }
+
+            let mut chunk = [0; MAX_MAGIC];
+            match self.socket.as_mut().expect("socket").read(&mut chunk) {
+                Ok(0) => return Ok(Async::Ready(())),
+                Ok(size) => self.buf.extend_from_slice(&chunk[..size]),
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock =>
+                    return Ok(Async::NotReady),
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+
+///
+/// Transport factory of tls server. Start of tls handshake is checked
+/// without involving python, connections which send something else or
+/// do not start handshake in time are closed and counted as failed
+/// handshakes. Rest of handshake is limited by ssl_handshake_timeout
+/// of SSLProtocol.
+///
+pub fn tls_transport_factory(
+    evloop: Py<TokioEventLoop>, _server: bool, factory: &PyObject,
+    ssl: &Option<PyObject>, _server_hostname: Option<PyObject>,
+    socket: TcpStream, addr: Option<&AddrInfo>,
+    peer: Option<SocketAddr>, waiter: Option<Py<PyFuture>>,
+    opts: TransportOptions) -> io::Result<InitializedTransport>
+{
+    let gil = Python::acquire_gil();
+    let py = gil.python();
+
+    let ev = evloop.as_ref(py);
+    let handle = ev.get_handle();
+    let timeout = Timeout::new(opts.ssl_handshake_timeout(), &handle)?;
+
+    // handshake starts right after accept
+    let waiter = match (waiter, opts.server) {
+        (None, Some(id)) => Some(ev.server_handshake(py, id, opts.listener)?),
+        (waiter, _) => waiter,
+    };
+
+    handle.spawn(
+        TlsGate {
+            evloop: evloop.clone_ref(py),
+            factory: factory.clone_ref(py),
+            ssl: ssl.as_ref().map(|ssl| ssl.clone_ref(py)),
+            socket: Some(socket),
+            addr: addr.cloned(),
+            peer: peer,
+            waiter: waiter,
+            opts: opts,
+            buf: Vec::new(),
+            started: Instant::now(),
+            timeout: timeout,
+        }.map_err(move |err| match peer {
+            Some(peer) => debug!("TLS handshake from {} failed: {}", peer, err),
+            None => debug!("TLS handshake failed: {}", err),
+        }));
+
+    Ok(InitializedTransport::new(py.None(), py.None()))
+}
+
+
+struct TlsGate {
+    evloop: Py<TokioEventLoop>,
+    factory: PyObject,
+    ssl: Option<PyObject>,
+    socket: Option<TcpStream>,
+    addr: Option<AddrInfo>,
+    peer: Option<SocketAddr>,
+    waiter: Option<Py<PyFuture>>,
+    opts: TransportOptions,
+    buf: Vec<u8>,
+    started: Instant,
+    timeout: Timeout,
+}
+
+impl TlsGate {
+
+    // handshake waiter fails, server counts failed handshake
+    fn fail(&mut self, py: Python, err: io::Error) -> io::Error {
+        if let Some(waiter) = self.waiter.take() {
+            let exc = io::Error::new(err.kind(), err.to_string());
+            waiter.as_mut(py).set(py, Err(exc.into()));
+        }
+        err
+    }
+
+    fn start(&mut self, py: Python) -> io::Result<()> {
+        // ssl protocol gets rest of timeout
+        let timeout = self.opts.ssl_handshake_timeout();
+        let mut opts = self.opts;
+        opts.ssl_handshake_timeout = Some(cmp::max(
+            timeout.checked_sub(self.started.elapsed()).unwrap_or(Duration::new(0, 0)),
+            Duration::from_millis(1)));
+
+        let socket = PrefixedStream::new(
+            mem::replace(&mut self.buf, Vec::new()), self.socket.take().expect("socket"));
+        let res = tcp_transport_factory(
+            self.evloop.clone_ref(py), true, &self.factory, &self.ssl, None,
+            socket, self.addr.as_ref(), self.peer,
+            self.waiter.as_ref().map(|waiter| waiter.clone_ref(py)), opts);
+        match res {
+            Ok(_) => {
+                self.waiter.take();
+                Ok(())
+            },
+            Err(err) => Err(self.fail(py, err)),
+        }
+    }
+}
+
+impl Future for TlsGate {
+    type Item = ();
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let gil = Python::acquire_gil();
+        let py = gil.python();
+
+        loop {
+            match Pattern::Tls.check(&self.buf) {
+                Match::Yes => {
+                    self.start(py)?;
+                    return Ok(Async::Ready(()))
+                },
+                Match::No => return Err(self.fail(py, io::Error::new(
+                    io::ErrorKind::InvalidData, "client did not start TLS handshake"))),
+                Match::More => (),
+            }
+
+            if let Async::Ready(_) = self.timeout.poll()? {
+                return Err(self.fail(py, io::Error::new(
+                    io::ErrorKind::TimedOut, "TLS handshake timeout")))
+            }
+
+            let mut chunk = [0; 2];
+            match self.socket.as_mut().expect("socket").read(&mut chunk) {
+                Ok(0) => return Err(self.fail(py, io::Error::new(
+                    io::ErrorKind::UnexpectedEof, "connection closed before TLS handshake"))),
+                Ok(size) => self.buf.extend_from_slice(&chunk[..size]),
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock =>
+                    return Ok(Async::NotReady),
+                Err(err) => return Err(self.fail(py, err)),
+            }
+        }
+    }
+}
+
+
+//
+// Stream that returns already consumed bytes before reading from socket
+//
+pub struct PrefixedStream<T> {
+    prefix: Vec<u8>,
+    pos: usize,
+    stream: T,
+}
+
+impl<T> PrefixedStream<T> {
+    pub fn new(prefix: Vec<u8>, stream: T) -> PrefixedStream<T> {
+        PrefixedStream { prefix: prefix, pos: 0, stream: stream }
+    }
+}
+
+impl<T: io::Read> io::Read for PrefixedStream<T> {
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos < self.prefix.len() {
+            let len = cmp::min(buf.len(), self.prefix.len() - self.pos);
+            buf[..len].copy_from_slice(&self.prefix[self.pos..self.pos + len]);
+            self.pos += len;
+            Ok(len)
+        } else {
+            self.stream.read(buf)
+        }
+    }
+}
+
+impl<T: io::Write> io::Write for PrefixedStream<T> {
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl<T: AsyncRead> AsyncRead for PrefixedStream<T> {}
+
+impl<T: AsyncWrite> AsyncWrite for PrefixedStream<T> {
+
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.stream.shutdown()
+    }
+}
+
+impl<T: AsRawFd> AsRawFd for PrefixedStream<T> {
+
+    fn as_raw_fd(&self) -> RawFd {
+        self.stream.as_raw_fd()
+    }
+}
@@ -36,6 +36,26 @@ impl Socket {
                 token: token})
     }
 
+    pub fn new_listener(py: Python, addr: &AddrInfo, fd: RawFd) -> PyResult<Py<Socket>> {
+        py.init(
+            |token| Socket{
+                fd: Some(fd),
+                family: addr.family.to_int() as i32,
+                socktype: addr.socktype.to_int() as i32,
+                proto: addr.protocol.to_int() as i32,
+                sockaddr: addr.sockaddr.clone(),
+                peername: None,
+                socket: None,
+                token: token})
+    }
+
+    ///
+    /// Underlying socket is closed, fileno() returns -1
+    ///
+    pub fn forget_fd(&mut self) {
+        self.fd = None;
+    }
+
     pub fn new_peer(py: Python, addr: &AddrInfo,
                     peer: SocketAddr, fd: Option<RawFd>) -> PyResult<Py<Socket>> {
         py.init(
@@ -112,7 +132,7 @@ impl Socket {
     }
 
     fn fileno(&self, py: Python) -> PyResult<i32> {
-        Ok(-1)
+        Ok(self.fd.unwrap_or(-1))
     }
 
     fn get_inheritable(&self, py: Python) -> PyResult<()> {
@@ -122,28 +142,12 @@ impl Socket {
     pub fn getpeername(&self, py: Python) -> PyResult<PyObject> {
         match self.peername {
             None => Err(PyErr::new::<exc::OSError, _>("Socket is not connected")),
-            Some(ref addr) => match addr {
-                &SocketAddr::V4(addr) => {
-                    Ok((format!("{}", addr.ip()), addr.port()).into_object(py))
-                }
-                &SocketAddr::V6(addr) => {
-                    Ok((format!("{}", addr.ip()),
-                        addr.port(), addr.flowinfo(), addr.scope_id(),).into_object(py))
-                },
-            }
+            Some(ref addr) => Ok(sockaddr_object(py, addr)),
         }
     }
 
     pub fn getsockname(&self, py: Python) -> PyResult<PyObject> {
-        match self.sockaddr {
-            SocketAddr::V4(ref addr) => {
-                Ok((format!("{}", addr.ip()), addr.port()).into_object(py))
-            }
-            SocketAddr::V6(ref addr) => {
-                Ok((format!("{}", addr.ip()),
-                    addr.port(), addr.flowinfo(), addr.scope_id(),).into_object(py))
-            },
-        }
+        Ok(sockaddr_object(py, &self.sockaddr))
     }
 
     fn getsockopt(&self, py: Python) -> PyResult<()> {
@@ -241,3 +245,19 @@ impl Socket {
         Err(PyErr::new::<exc::RuntimeError, _>("share method is not supported."))
     }
 }
+
+
+///
+/// Socket address in python socket module format
+///
+pub fn sockaddr_object(py: Python, addr: &SocketAddr) -> PyObject {
+    match *addr {
+        SocketAddr::V4(ref addr) => {
+            (format!("{}", addr.ip()), addr.port()).into_object(py)
+        }
+        SocketAddr::V6(ref addr) => {
+            (format!("{}", addr.ip()),
+             addr.port(), addr.flowinfo(), addr.scope_id(),).into_object(py)
+        },
+    }
+}
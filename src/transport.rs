@@ -1,10 +1,14 @@
 // Copyright (c) 2017-present PyO3 Project and Contributors
 
 use std::io;
+use std::cmp;
 use std::net::SocketAddr;
 use std::collections::HashMap;
-use std::os::unix::io::AsRawFd;
+use std::mem;
+use std::time::{Duration, Instant};
+use std::os::unix::io::{AsRawFd, RawFd};
 
+use libc;
 use pyo3::*;
 use futures::unsync::mpsc;
 use futures::{unsync, Async, AsyncSink, Stream, Future, Poll, Sink};
@@ -12,12 +16,16 @@ use bytes::{Bytes, BytesMut, BufMut};
 use tokio_io::{AsyncRead, AsyncWrite};
 use tokio_io::codec::{Encoder, Decoder, Framed};
 use tokio_core::net::TcpStream;
+use tokio_core::reactor::{Handle, Timeout};
 
 use {PyFuture, TokioEventLoop};
-use utils::{Classes, PyLogger};
+use utils::{self, Classes, OperationError, PyLogger};
 use addrinfo::AddrInfo;
+use http::HeaderEncoding;
 use pybytes;
-use pyunsafe::{GIL, Sender};
+use faults::{FaultConfig, FaultyStream};
+use pyunsafe::{self, BoundedReceiver, BoundedSender, GIL, Sender};
+use server::ConnectionGuard;
 use socket::Socket;
 
 #[derive(Debug)]
@@ -42,23 +50,400 @@ impl IntoPyTuple for InitializedTransport {
 }
 
 
+// Per-connection transport settings
+#[derive(Copy, Clone, Debug, Default)]
+pub struct TransportOptions {
+    pub idle_timeout: Option<Duration>,
+    pub linger: Option<Duration>,
+    pub read_rate: Option<u64>,
+    pub write_rate: Option<u64>,
+    pub header_encoding: HeaderEncoding,
+    // http connection is closed after this number of requests
+    pub max_requests: Option<usize>,
+    // pipelined http requests handled at once
+    pub concurrency: Option<usize>,
+    // idle http connection is closed if no new request starts in time
+    pub keepalive_timeout: Option<Duration>,
+    // http request head has to be received within header_timeout, body
+    // has to progress within body_timeout or at body_min_rate bytes/sec
+    // larger http request body is rejected with 413 response
+    pub max_body_size: Option<u64>,
+    pub header_timeout: Option<Duration>,
+    pub body_timeout: Option<Duration>,
+    pub body_min_rate: Option<u64>,
+    pub ssl_shutdown_timeout: Option<Duration>,
+    // server side, time for client to finish tls handshake
+    pub ssl_handshake_timeout: Option<Duration>,
+    pub tos: Option<u8>,
+    pub read_batch: Option<usize>,
+    pub faults: Option<FaultConfig>,
+    pub user_timeout: Option<Duration>,
+    // accepting server, connections are counted for wait_closed()
+    pub server: Option<usize>,
+    // index of accepting listener in server stats
+    pub listener: Option<usize>,
+    // accepting server stops accepting at this number of live connections
+    pub max_connections: Option<usize>,
+    // connections accepted per listener wakeup
+    pub accept_batch: Option<usize>,
+    // limits of accepted connections per source address
+    pub max_connections_per_ip: Option<usize>,
+    pub accept_rate_per_ip: Option<f64>,
+    // accepting server stops accepting at this number of tls handshakes
+    pub max_handshakes: Option<usize>,
+    // accepting is paused for this delay when process runs out of fds
+    pub accept_retry_delay: Option<Duration>,
+    // spare fd is used to close pending connection when out of fds
+    pub reserve_fd: bool,
+    // listener wakes up only when accepted connection has data
+    pub defer_accept: Option<Duration>,
+    // protocol factory is called with connection details
+    pub factory_context: bool,
+    // set by client, connect duration is reported in transport stats
+    pub connect_started: Option<Instant>,
+}
+
+impl TransportOptions {
+    pub fn new(idle_timeout: Option<&PyObjectRef>, linger: Option<&PyObjectRef>,
+               read_rate: Option<u64>, write_rate: Option<u64>)
+               -> PyResult<TransportOptions> {
+        if read_rate == Some(0) || write_rate == Some(0) {
+            return Err(exc::ValueError::new("rate limit must be positive"))
+        }
+        let idle_timeout = match idle_timeout {
+            Some(val) => utils::parse_seconds("idle_timeout", val)?,
+            None => None,
+        };
+        let linger = match linger {
+            Some(val) => Some(parse_linger(val)?),
+            None => None,
+        };
+        Ok(TransportOptions {
+            idle_timeout: idle_timeout,
+            linger: linger,
+            read_rate: read_rate,
+            write_rate: write_rate,
+            header_encoding: HeaderEncoding::default(),
+            max_requests: None,
+            concurrency: None,
+            keepalive_timeout: None,
+            max_body_size: None,
+            header_timeout: None,
+            body_timeout: None,
+            body_min_rate: None,
+            ssl_shutdown_timeout: None,
+            ssl_handshake_timeout: None,
+            tos: None,
+            read_batch: None,
+            faults: None,
+            user_timeout: None,
+            server: None,
+            listener: None,
+            max_connections: None,
+            accept_batch: None,
+            max_connections_per_ip: None,
+            accept_rate_per_ip: None,
+            max_handshakes: None,
+            accept_retry_delay: None,
+            reserve_fd: false,
+            defer_accept: None,
+            factory_context: false,
+            connect_started: None,
+        })
+    }
+
+    pub fn set_ssl_shutdown_timeout(&mut self, timeout: Option<&PyObjectRef>) -> PyResult<()> {
+        if let Some(val) = timeout {
+            self.ssl_shutdown_timeout = Some(
+                utils::parse_seconds("ssl_shutdown_timeout", val)?.ok_or_else(
+                    || exc::ValueError::new("ssl_shutdown_timeout must be non-negative"))?);
+        }
+        Ok(())
+    }
+
+    pub fn set_ssl_handshake_timeout(&mut self, timeout: Option<&PyObjectRef>) -> PyResult<()> {
+        if let Some(val) = timeout {
+            match utils::parse_seconds("ssl_handshake_timeout", val)? {
+                Some(timeout) if timeout > Duration::new(0, 0) =>
+                    self.ssl_handshake_timeout = Some(timeout),
+                _ => return Err(exc::ValueError::new("ssl_handshake_timeout must be positive")),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn ssl_handshake_timeout(&self) -> Duration {
+        self.ssl_handshake_timeout
+            .unwrap_or(Duration::from_secs(DEFAULT_SSL_HANDSHAKE_TIMEOUT))
+    }
+
+    pub fn set_read_batch(&mut self, read_batch: Option<usize>) {
+        if read_batch.is_some() {
+            self.read_batch = read_batch;
+        }
+    }
+
+    pub fn set_max_connections(&mut self, max_connections: Option<usize>) -> PyResult<()> {
+        if max_connections == Some(0) {
+            return Err(exc::ValueError::new("max_connections must be positive"))
+        }
+        self.max_connections = max_connections;
+        Ok(())
+    }
+
+    pub fn set_max_requests(&mut self, max_requests: Option<usize>) -> PyResult<()> {
+        if max_requests == Some(0) {
+            return Err(exc::ValueError::new("max_requests must be positive"))
+        }
+        self.max_requests = max_requests;
+        Ok(())
+    }
+
+    pub fn set_concurrency(&mut self, concurrency: Option<usize>) -> PyResult<()> {
+        if concurrency == Some(0) {
+            return Err(exc::ValueError::new("concurrency must be positive"))
+        }
+        self.concurrency = concurrency;
+        Ok(())
+    }
+
+    pub fn set_body_min_rate(&mut self, body_min_rate: Option<u64>) -> PyResult<()> {
+        if body_min_rate == Some(0) {
+            return Err(exc::ValueError::new("body_min_rate must be positive"))
+        }
+        if body_min_rate.is_some() && self.body_timeout.is_none() {
+            return Err(exc::ValueError::new("body_min_rate requires body_timeout"))
+        }
+        self.body_min_rate = body_min_rate;
+        Ok(())
+    }
+
+    pub fn set_accept_batch(&mut self, accept_batch: Option<usize>) -> PyResult<()> {
+        if accept_batch == Some(0) {
+            return Err(exc::ValueError::new("accept_batch must be positive"))
+        }
+        self.accept_batch = accept_batch;
+        Ok(())
+    }
+
+    pub fn set_peer_limits(&mut self, max_connections: Option<usize>, accept_rate: Option<f64>)
+                           -> PyResult<()> {
+        if max_connections == Some(0) {
+            return Err(exc::ValueError::new("max_connections_per_ip must be positive"))
+        }
+        if let Some(rate) = accept_rate {
+            if !(rate > 0.0) {
+                return Err(exc::ValueError::new("accept_rate_per_ip must be positive"))
+            }
+        }
+        self.max_connections_per_ip = max_connections;
+        self.accept_rate_per_ip = accept_rate;
+        Ok(())
+    }
+
+    pub fn set_max_handshakes(&mut self, max_handshakes: Option<usize>) -> PyResult<()> {
+        if max_handshakes == Some(0) {
+            return Err(exc::ValueError::new("ssl_max_handshakes must be positive"))
+        }
+        self.max_handshakes = max_handshakes;
+        Ok(())
+    }
+
+    pub fn accept_batch(&self) -> usize {
+        self.accept_batch.unwrap_or(DEFAULT_ACCEPT_BATCH)
+    }
+
+    pub fn set_accept_retry_delay(&mut self, delay: Option<&PyObjectRef>) -> PyResult<()> {
+        if let Some(val) = delay {
+            self.accept_retry_delay = Some(
+                utils::parse_seconds("accept_retry_delay", val)?.ok_or_else(
+                    || exc::ValueError::new("accept_retry_delay must be non-negative"))?);
+        }
+        Ok(())
+    }
+
+    pub fn accept_retry_delay(&self) -> Duration {
+        self.accept_retry_delay.unwrap_or(Duration::from_secs(DEFAULT_ACCEPT_RETRY_DELAY))
+    }
+
+    pub fn set_defer_accept(&mut self, delay: Option<&PyObjectRef>) -> PyResult<()> {
+        if let Some(val) = delay {
+            if cfg!(not(any(target_os = "linux", target_os = "freebsd"))) {
+                return Err(exc::ValueError::new(
+                    "defer_accept is supported on linux and freebsd only"))
+            }
+            self.defer_accept = Some(
+                utils::parse_seconds("defer_accept", val)?.ok_or_else(
+                    || exc::ValueError::new("defer_accept must be non-negative"))?);
+        }
+        Ok(())
+    }
+
+    pub fn set_faults(&mut self, faults: Option<&PyObjectRef>) -> PyResult<()> {
+        if let Some(faults) = faults {
+            self.faults = Some(FaultConfig::parse(faults)?);
+        }
+        Ok(())
+    }
+
+    pub fn set_user_timeout(&mut self, timeout: Option<&PyObjectRef>) -> PyResult<()> {
+        if let Some(val) = timeout {
+            if cfg!(not(target_os = "linux")) {
+                return Err(exc::ValueError::new("user_timeout is supported on linux only"))
+            }
+            self.user_timeout = Some(
+                utils::parse_seconds("user_timeout", val)?.ok_or_else(
+                    || exc::ValueError::new("user_timeout must be non-negative"))?);
+        }
+        Ok(())
+    }
+
+    pub fn set_tos(&mut self, tos: Option<i32>) -> PyResult<()> {
+        if let Some(tos) = tos {
+            self.tos = Some(parse_tos(tos)?);
+        }
+        Ok(())
+    }
+}
+
+///
+/// Details of accepted connection for protocol factory and on_connect hook
+///
+pub fn connection_context<'p>(py: Python<'p>, ev: &TokioEventLoop, server: Option<usize>,
+                              ssl: &Option<PyObject>, peername: Option<PyObject>,
+                              sockname: Option<PyObject>) -> &'p PyDict {
+    let context = PyDict::new(py);
+    let _ = context.set_item("peername", peername.unwrap_or_else(|| py.None()));
+    let _ = context.set_item("sockname", sockname.unwrap_or_else(|| py.None()));
+    let _ = context.set_item("server", match server {
+        Some(id) => ev.server_object(py, id),
+        None => py.None(),
+    });
+    let _ = context.set_item("ssl", match *ssl {
+        Some(ref ssl) => ssl.clone_ref(py),
+        None => py.None(),
+    });
+    context
+}
+
+///
+/// Validate network interface name for bind_to_device()
+///
+pub fn parse_interface(interface: Option<String>) -> PyResult<Option<String>> {
+    match interface {
+        Some(ref name) if name.is_empty() || name.len() >= libc::IF_NAMESIZE
+            || name.contains('\0') =>
+            Err(exc::ValueError::new(format!("invalid interface name: {:?}", name))),
+        interface => Ok(interface),
+    }
+}
+
+fn parse_tos(tos: i32) -> PyResult<u8> {
+    if tos < 0 || tos > 255 {
+        Err(exc::ValueError::new("tos must be in range 0-255"))
+    } else {
+        Ok(tos as u8)
+    }
+}
+
+fn parse_linger(value: &PyObjectRef) -> PyResult<Duration> {
+    match utils::parse_seconds("linger", value)? {
+        Some(linger) => Ok(linger),
+        None => Err(exc::ValueError::new("linger must be non-negative")),
+    }
+}
+
+///
+/// Apply explicit ssl_* options (TLS versions, ciphers, certificates)
+/// to ssl context, see tokio.sslproto.configure_context
+///
+pub fn configure_ssl(py: Python, ssl: Option<PyObject>, server_side: bool,
+                     opts: &[(&str, Option<PyObject>)]) -> PyResult<Option<PyObject>> {
+    let kwargs = PyDict::new(py);
+    for &(name, ref value) in opts {
+        if let Some(ref value) = *value {
+            kwargs.set_item(name, value.clone_ref(py))?;
+        }
+    }
+    if kwargs.len() == 0 {
+        return Ok(ssl)
+    }
+    match ssl {
+        Some(ssl) => Ok(Some(
+            Classes.SSLConfigure.as_ref(py).call((ssl, server_side), kwargs)?.into())),
+        None => Err(exc::ValueError::new("ssl_* options are only meaningful with ssl")),
+    }
+}
+
+
+// Totals of closed and live transports, see loop.stats()
+#[derive(Copy, Clone, Debug, Default)]
+pub struct TransportTotals {
+    pub transports: u64,
+    pub connects: u64,
+    pub connect_time: Duration,
+    pub bytes_received: u64,
+    pub bytes_sent: u64,
+}
+
+
 // Transport factory
 pub type TransportFactory = fn(
     Py<TokioEventLoop>, bool, &PyObject, &Option<PyObject>, Option<PyObject>,
     TcpStream, Option<&AddrInfo>, Option<SocketAddr>,
-    Option<Py<PyFuture>>) -> io::Result<InitializedTransport>;
+    Option<Py<PyFuture>>, TransportOptions) -> io::Result<InitializedTransport>;
 
 pub struct BytesMsg {
     pub buf: buffer::PyBuffer,
     pub len: usize,
 }
 
+impl BytesMsg {
+
+    pub fn new(py: Python, data: &PyObjectRef) -> PyResult<BytesMsg> {
+        // write buffer content is taken without copy
+        if let Ok(wbuf) = pybytes::WriteBuffer::try_from_mut(data) {
+            let bytes: PyObject = pybytes::PyBytes::new(py, wbuf.take_bytes())?.into();
+            let buf = buffer::PyBuffer::get(py, bytes.as_ref(py))?;
+            let len = buf.len_bytes();
+            return Ok(BytesMsg{buf: buf, len: len})
+        }
+
+        let buf = buffer::PyBuffer::get(py, data)
+            .map_err(|_| exc::TypeError::new("data argument must be a bytes-like object"))?;
+
+        // immutable byte buffers are sent as is
+        if buf.readonly() && buf.as_slice::<u8>(py).is_some() {
+            let len = buf.len_bytes();
+            return Ok(BytesMsg{buf: buf, len: len})
+        }
+
+        // mutable, non-contiguous or non-byte buffers get copied
+        let data = Classes.MemoryView.call1(py, (data,))?.call_method0(py, "tobytes")?;
+        let buf = buffer::PyBuffer::get(py, data.as_ref(py))?;
+        let len = buf.len_bytes();
+        Ok(BytesMsg{buf: buf, len: len})
+    }
+}
+
+// default write buffer watermarks, same as asyncio
+pub const DEFAULT_HIGH_WATER: usize = 64 * 1024;
+pub const DEFAULT_LOW_WATER: usize = 16 * 1024;
+pub const DEFAULT_READ_BATCH: usize = 256 * 1024;
+pub const DEFAULT_ACCEPT_BATCH: usize = 64;
+// same as asyncio
+pub const DEFAULT_ACCEPT_RETRY_DELAY: u64 = 1;
+pub const DEFAULT_SSL_HANDSHAKE_TIMEOUT: u64 = 60;
+pub const MIN_WRITE_QUEUE: usize = 256 * 1024;
+
+// control messages, written data goes through bounded data channel
 pub enum TcpTransportMessage {
-    Bytes(BytesMsg),
     Pause,
     Resume,
     Close,
     Shutdown,
+    // write queue overflow, connection_lost() gets error
+    Abort(io::Error),
 }
 
 
@@ -66,7 +451,8 @@ pub fn tcp_transport_factory<T>(
     evloop: Py<TokioEventLoop>, server: bool,
     factory: &PyObject, ssl: &Option<PyObject>, server_hostname: Option<PyObject>,
     socket: T, addr: Option<&AddrInfo>,
-    peer: Option<SocketAddr>, waiter: Option<Py<PyFuture>>) -> io::Result<InitializedTransport>
+    peer: Option<SocketAddr>, waiter: Option<Py<PyFuture>>,
+    opts: TransportOptions) -> io::Result<InitializedTransport>
 
     where T: AsyncRead + AsyncWrite + AsRawFd + 'static
 {
@@ -74,8 +460,19 @@ pub fn tcp_transport_factory<T>(
     let py = gil.python();
 
     let ev = evloop.as_ref(py);
+    let fd = socket.as_raw_fd();
     let mut info: HashMap<&'static str, PyObject> = HashMap::new();
 
+    if let Some(linger) = opts.linger {
+        set_linger(fd, Some(linger))?;
+    }
+    if let Some(tos) = opts.tos {
+        set_tos(fd, tos)?;
+    }
+    if let Some(timeout) = opts.user_timeout {
+        set_user_timeout(fd, timeout)?;
+    }
+
     if let (Some(ref addr), Some(peer)) = (addr, peer) {
         let sock = Socket::new_peer(py, addr, peer, Some(socket.as_raw_fd()))?;
         let sock_ref = sock.as_ref(py);
@@ -84,25 +481,69 @@ pub fn tcp_transport_factory<T>(
         info.insert("socket", sock.clone_ref(py).into());
     }
 
+    // unix domain socket, paths and (pid, uid, gid) of connected peer
+    if addr.is_none() {
+        if let Some(name) = unix_socket_name(fd, false) {
+            info.insert("sockname", name.to_object(py));
+        }
+        if let Some(name) = unix_socket_name(fd, true) {
+            info.insert("peername", name.to_object(py));
+        }
+        if let Some((pid, uid, gid)) = peer_credentials(fd) {
+            info.insert("peercred", (pid, uid, gid).to_object(py));
+        }
+    }
+
     // create protocol
-    let proto = factory.as_ref(py).call0()
-        .log_error(py, "Protocol factory failure")?;
+    let proto = if opts.factory_context {
+        let context = connection_context(
+            py, ev, opts.server, ssl,
+            info.get("peername").map(|val| val.clone_ref(py)),
+            info.get("sockname").map(|val| val.clone_ref(py)));
+        factory.as_ref(py).call1((context,))
+    } else {
+        factory.as_ref(py).call0()
+    }.log_error(py, "Protocol factory failure")?;
 
     // create py transport
     let (tx, rx) = mpsc::unbounded();
+    let (data_tx, data_rx) = pyunsafe::bounded(write_queue_limit(DEFAULT_HIGH_WATER));
+    let connect_time = opts.connect_started.map(|started| started.elapsed());
 
     let (tr, wrp_tr): (_, PyObject) = if let Some(ref ssl) = *ssl {
         // create SSLProtocol and wrpped transport
         let kwargs = PyDict::new(py);
         info.insert("sslcontext", ssl.clone_ref(py));
         let _ = kwargs.set_item("server_side", server);
+        if let Some(timeout) = opts.ssl_shutdown_timeout {
+            let _ = kwargs.set_item("shutdown_timeout", utils::duration_to_secs(timeout));
+        }
+        if let (true, Some(timeout)) = (server, opts.ssl_handshake_timeout) {
+            let _ = kwargs.set_item("handshake_timeout", utils::duration_to_secs(timeout));
+        }
+        if let (false, Some(peer)) = (server, peer) {
+            // client tls session is resumed per (host, port)
+            let host = match server_hostname {
+                Some(ref hostname) if hostname.is_true(py).unwrap_or(false) =>
+                    hostname.clone_ref(py),
+                _ => format!("{}", peer.ip()).to_object(py),
+            };
+            let _ = kwargs.set_item("session_key", (host, peer.port()));
+        }
         if let Some(hostname) = server_hostname {
             let _ = kwargs.set_item("server_hostname", hostname);
         }
+        // handshake is counted by server until waiter is resolved
+        let waiter = match opts.server {
+            Some(id) if server && waiter.is_none() =>
+                Some(ev.server_handshake(py, id, opts.listener)?),
+            _ => waiter,
+        };
         let ssl_proto = Classes.SSLProto.as_ref(py).call(
             (evloop.clone_ref(py), proto, ssl.clone_ref(py), waiter), kwargs)?;
 
-        let tr = PyTcpTransportPtr::new(py, ev, Sender::new(tx), &ssl_proto, info)?;
+        let tr = PyTcpTransportPtr::new(
+            py, ev, Sender::new(tx), data_tx, &ssl_proto, info, fd, connect_time)?;
         let wrp_tr = ssl_proto.getattr("_app_transport")?;
         (tr, wrp_tr.into())
     } else {
@@ -110,41 +551,73 @@ pub fn tcp_transport_factory<T>(
         if let Some(waiter) = waiter {
             waiter.as_mut(py).set(py, Ok(py.None()));
         }
-        let tr = PyTcpTransportPtr::new(py, ev, Sender::new(tx), proto, info)?;
+        let tr = PyTcpTransportPtr::new(
+            py, ev, Sender::new(tx), data_tx, proto, info, fd, connect_time)?;
         let wrp_tr = tr.0.clone_ref(py).into();
         (tr, wrp_tr)
     };
 
     // create transport and then call connection_made on protocol
-    let transport = TcpTransport::new(socket, rx, tr.clone_ref(py));
+    let guard = opts.server.map(|id| ev.server_connection(id, peer));
+    if let Some(faults) = opts.faults {
+        let socket = FaultyStream::new(socket, faults, ev.href())?;
+        spawn_transport(
+            py, ev.href(), TcpTransport::new(socket, rx, data_rx, tr.clone_ref(py), ev.href(), opts)?,
+            &tr, guard);
+    } else {
+        spawn_transport(
+            py, ev.href(), TcpTransport::new(socket, rx, data_rx, tr.clone_ref(py), ev.href(), opts)?,
+            &tr, guard);
+    }
 
+    Ok(InitializedTransport::new(wrp_tr.into(), proto.into()))
+}
+
+fn spawn_transport<T>(py: Python, handle: &Handle,
+                      transport: TcpTransport<T>, tr: &PyTcpTransportPtr,
+                      guard: Option<ConnectionGuard>)
+    where T: AsyncRead + AsyncWrite + 'static
+{
     // handle connection lost
-    let conn_err = tr.clone_ref(py);
-    let conn_lost = tr.clone_ref(py);
-
-    ev.href().spawn(
-        transport.map(move |_| {
-            conn_lost.connection_lost()
-        }).map_err(move |err| {
-            conn_err.connection_error(err)
+    let tr = tr.clone_ref(py);
+
+    handle.spawn(
+        transport.then(move |res| {
+            match res {
+                Ok(_) => tr.connection_lost(),
+                Err(err) => tr.connection_error(err),
+            }
+            // server connection is done after protocol is notified
+            drop(guard);
+            Ok(())
         })
     );
-
-    Ok(InitializedTransport::new(wrp_tr.into(), proto.into()))
 }
 
 
 #[py::class(weakref, freelist=100)]
 pub struct PyTcpTransport {
     evloop: Py<TokioEventLoop>,
+    protocol: PyObject,
     connection_lost: PyObject,
     data_received: PyObject,
+    eof_received: Option<PyObject>,
     transport: Sender<TcpTransportMessage>,
+    data: BoundedSender<BytesMsg>,
     drain: Option<Py<PyFuture>>,
-    drained: bool,
+    buffer_size: usize,
+    low_water: usize,
+    high_water: usize,
+    writing_paused: bool,
     closing: bool,
     info: HashMap<&'static str, PyObject>,
     paused: bool,
+    frozen: Option<Vec<BytesMsg>>,
+    fd: RawFd,
+    bytes_received: u64,
+    bytes_sent: u64,
+    created: Instant,
+    connect_time: Option<Duration>,
     token: PyToken,
 }
 
@@ -160,6 +633,9 @@ impl PyTcpTransport {
 
     fn get_extra_info(&self, py: Python, name: &str, default: Option<PyObject>)
                       -> PyResult<PyObject> {
+        if name == "stats" {
+            return self.stats(py)
+        }
         if self.closing {
             return match default {
                 Some(val) => Ok(val),
@@ -181,16 +657,33 @@ impl PyTcpTransport {
     /// write bytes to transport
     ///
     fn write(&mut self, py: Python, data: &PyObjectRef) -> PyResult<()> {
-        let data = buffer::PyBuffer::get(py, data)?;
-        let len = if let Some(slice) = data.as_slice::<u8>(py) {
-            slice.len() as usize
-        } else {
-            return Err(exc::TypeError::new("data argument must be a bytes-like object"))
-        };
+        let msg = BytesMsg::new(py, data)?;
 
-        self.drained = false;
-        let _ = self.transport.send(
-            TcpTransportMessage::Bytes(BytesMsg{buf:data, len:len}));
+        // closing transport does not accept new data
+        if self.closing || msg.len == 0 {
+            return Ok(())
+        }
+
+        // protocol which does not respect pause_writing() can not
+        // grow write buffer without limit, transport is aborted
+        let held = self.frozen.as_ref().map_or(
+            0, |pending| pending.iter().map(|msg| msg.len).sum());
+        if self.data.len() + held >= self.data.capacity() {
+            self.closing = true;
+            let _ = self.transport.send(TcpTransportMessage::Abort(write_queue_overflow()));
+            return Ok(())
+        }
+
+        // transport is frozen, keep data until restore()
+        if let Some(ref mut pending) = self.frozen {
+            pending.push(msg);
+            return Ok(())
+        }
+
+        self.buffer_size += msg.len;
+        let len = msg.len;
+        let _ = self.data.send(msg, len);
+        self.maybe_pause_writing(py);
         Ok(())
     }
 
@@ -215,10 +708,10 @@ impl PyTcpTransport {
     }
 
     ///
-    /// write all data to socket
+    /// wait until write buffer size falls below low watermark
     ///
     fn drain(&mut self, py: Python) -> PyResult<Py<PyFuture>> {
-        if self.drained {
+        if self.buffer_size <= self.low_water {
             Ok(PyFuture::done_fut(py, self.evloop.clone_ref(py), py.None())?)
         } else {
             if let Some(ref fut) = self.drain {
@@ -231,6 +724,162 @@ impl PyTcpTransport {
         }
     }
 
+    fn get_write_buffer_size(&self) -> PyResult<usize> {
+        Ok(self.buffer_size)
+    }
+
+    fn get_write_buffer_limits(&self) -> PyResult<(usize, usize)> {
+        Ok((self.low_water, self.high_water))
+    }
+
+    ///
+    /// set high and low watermarks for write buffer. protocol's
+    /// pause_writing() is called when buffer size goes over high watermark,
+    /// resume_writing() and drain() waiters when it falls below low watermark
+    ///
+    #[args(high="None", low="None")]
+    fn set_write_buffer_limits(&mut self, py: Python,
+                               high: Option<usize>, low: Option<usize>) -> PyResult<()> {
+        let (high, low) = write_buffer_limits(high, low)?;
+        self.high_water = high;
+        self.low_water = low;
+        self.data.set_capacity(write_queue_limit(high));
+        self.maybe_pause_writing(py);
+        self.wakeup_drain(py);
+        Ok(())
+    }
+
+    ///
+    /// set SO_LINGER timeout in seconds, 0 resets connection on close.
+    /// None restores platform default behavior
+    ///
+    fn set_linger(&self, linger: &PyObjectRef) -> PyResult<()> {
+        let fd = self.socket_fd()?;
+        let linger = if linger.is_none() { None } else { Some(parse_linger(linger)?) };
+        set_linger(fd, linger)?;
+        Ok(())
+    }
+
+    ///
+    /// set IP_TOS (IPV6_TCLASS for ipv6) value, e.g. DSCP class
+    /// shifted by 2 bits
+    ///
+    fn set_tos(&self, tos: i32) -> PyResult<()> {
+        let fd = self.socket_fd()?;
+        set_tos(fd, parse_tos(tos)?)?;
+        Ok(())
+    }
+
+    ///
+    /// enable or disable TCP_NODELAY socket option
+    ///
+    fn set_nodelay(&self, flag: bool) -> PyResult<()> {
+        let fd = self.socket_fd()?;
+        setsockopt(fd, libc::IPPROTO_TCP, libc::TCP_NODELAY, flag as libc::c_int)?;
+        Ok(())
+    }
+
+    ///
+    /// set socket option on underlying socket, value is int or
+    /// bytes-like object, same as socket.setsockopt()
+    ///
+    fn set_socket_option(&self, py: Python, level: i32, optname: i32,
+                         value: &PyObjectRef) -> PyResult<()> {
+        let fd = self.socket_fd()?;
+        if let Ok(val) = value.extract::<libc::c_int>() {
+            setsockopt(fd, level, optname, val)?;
+            return Ok(())
+        }
+        let buf = buffer::PyBuffer::get(py, value)
+            .map_err(|_| exc::TypeError::new("value must be int or bytes-like object"))?;
+        let data = buf.to_vec::<u8>(py)?;
+        if data.len() > MAX_SOCKOPT_LEN {
+            return Err(exc::ValueError::new("socket option value is too large"))
+        }
+        setsockopt_buf(fd, level, optname, &data)?;
+        Ok(())
+    }
+
+    ///
+    /// get socket option of underlying socket, int if buflen is 0
+    /// otherwise bytes of at most buflen, same as socket.getsockopt()
+    ///
+    #[args(buflen=0)]
+    fn get_socket_option(&self, py: Python, level: i32, optname: i32,
+                         buflen: usize) -> PyResult<PyObject> {
+        let fd = self.socket_fd()?;
+        if buflen == 0 {
+            let val: libc::c_int = getsockopt(fd, level, optname)?;
+            return Ok(val.to_object(py))
+        }
+        if buflen > MAX_SOCKOPT_LEN {
+            return Err(exc::ValueError::new("buflen is too large"))
+        }
+        let data = getsockopt_buf(fd, level, optname, buflen)?;
+        Ok(PyBytes::new(py, &data).into())
+    }
+
+    fn get_protocol(&self, py: Python) -> PyResult<PyObject> {
+        Ok(self.protocol.clone_ref(py))
+    }
+
+    fn set_protocol(&mut self, protocol: &PyObjectRef) -> PyResult<()> {
+        self.connection_lost = protocol.getattr("connection_lost")?.into();
+        self.data_received = protocol.getattr("data_received")?.into();
+        self.eof_received = protocol.getattr("eof_received").ok().map(|h| h.into());
+        self.protocol = protocol.into();
+        Ok(())
+    }
+
+    ///
+    /// freeze transport for protocol upgrade, stop reading and hold
+    /// new writes. data already written is sent to socket as usual,
+    /// unconsumed inbound data stays in transport until restore()
+    ///
+    fn freeze(&mut self) -> PyResult<()> {
+        if self.closing {
+            return Err(exc::RuntimeError::new("Transport is closing"))
+        }
+        if self.frozen.is_none() {
+            self.frozen = Some(Vec::new());
+            let _ = self.transport.send(TcpTransportMessage::Pause);
+        }
+        Ok(())
+    }
+
+    ///
+    /// resume frozen transport. if new protocol is provided, it receives
+    /// all following data, writes made during freeze are returned as
+    /// list of bytes for new framing layer. otherwise held writes
+    /// are sent to socket in order
+    ///
+    fn restore(&mut self, py: Python, protocol: Option<&PyObjectRef>) -> PyResult<PyObject> {
+        let pending = match self.frozen.take() {
+            Some(pending) => pending,
+            None => return Err(exc::RuntimeError::new("Transport is not frozen")),
+        };
+
+        let result = PyList::empty(py);
+        if let Some(protocol) = protocol {
+            self.set_protocol(protocol)?;
+            for msg in pending {
+                result.append(PyBytes::new(py, &msg.buf.to_vec::<u8>(py)?))?;
+            }
+        } else {
+            for msg in pending {
+                self.buffer_size += msg.len;
+                let len = msg.len;
+                let _ = self.data.send(msg, len);
+            }
+            self.maybe_pause_writing(py);
+        }
+
+        if !self.paused {
+            let _ = self.transport.send(TcpTransportMessage::Resume);
+        }
+        Ok(result.to_object(py))
+    }
+
     fn pause_reading(&mut self) -> PyResult<()> {
         self.paused = true;
         let _ = self.transport.send(TcpTransportMessage::Pause);
@@ -239,6 +888,9 @@ impl PyTcpTransport {
 
     fn resume_reading(&mut self) -> PyResult<()> {
         self.paused = false;
+        if self.frozen.is_some() {
+            return Ok(())
+        }
         let _ = self.transport.send(TcpTransportMessage::Resume);
         Ok(())
     }
@@ -264,29 +916,149 @@ impl PyTcpTransport {
     }
 }
 
+impl PyTcpTransport {
+
+    // socket extra info reports closed fd after connection is lost,
+    // fd may be reused by new socket so options are not set anymore
+    fn forget_socket(&mut self, py: Python) {
+        self.closing = true;
+        self.fd = -1;
+        if let Some(sock) = self.info.get("socket") {
+            if let Ok(sock) = Socket::try_from_mut(sock.as_ref(py)) {
+                sock.forget_fd();
+            }
+        }
+    }
+
+    // drain() waiter does not wait for lost connection
+    fn drain_lost(&mut self, py: Python, err: Option<PyErr>) {
+        if let Some(fut) = self.drain.take() {
+            let res = match err {
+                Some(err) => Err(err),
+                None => Ok(py.None()),
+            };
+            let _ = fut.as_mut(py).set(py, res);
+        }
+    }
+
+    // fd of open connection for socket options
+    fn socket_fd(&self) -> PyResult<RawFd> {
+        if self.closing || self.fd == -1 {
+            return Err(exc::RuntimeError::new("Transport is closing"))
+        }
+        Ok(self.fd)
+    }
+
+    // (bytes_received, bytes_sent)
+    pub fn traffic(&self) -> (u64, u64) {
+        (self.bytes_received, self.bytes_sent)
+    }
+
+    ///
+    /// Traffic and timing of transport, get_extra_info('stats')
+    ///
+    pub fn stats(&self, py: Python) -> PyResult<PyObject> {
+        let dict = PyDict::new(py);
+        dict.set_item("bytes_received", self.bytes_received)?;
+        dict.set_item("bytes_sent", self.bytes_sent)?;
+        dict.set_item("connect_time", self.connect_time.map(utils::duration_to_secs))?;
+        dict.set_item("lifetime", utils::duration_to_secs(self.created.elapsed()))?;
+        Ok(dict.into())
+    }
+
+    ///
+    /// Diagnostic snapshot for loop.transports()
+    ///
+    pub fn descriptor(&self, py: Python) -> PyResult<PyObject> {
+        let state = if self.closing {
+            "closing"
+        } else if self.frozen.is_some() {
+            "frozen"
+        } else if self.paused {
+            "paused"
+        } else {
+            "open"
+        };
+
+        let transport: Py<PyTcpTransport> = self.into();
+        let dict = PyDict::new(py);
+        dict.set_item("transport", transport)?;
+        dict.set_item("peername", self.info.get("peername").map(|p| p.clone_ref(py)))?;
+        dict.set_item("sockname", self.info.get("sockname").map(|s| s.clone_ref(py)))?;
+        dict.set_item("bytes_received", self.bytes_received)?;
+        dict.set_item("bytes_sent", self.bytes_sent)?;
+        dict.set_item("write_buffer_size", self.buffer_size)?;
+        dict.set_item("age", utils::duration_to_secs(self.created.elapsed()))?;
+        dict.set_item("state", state)?;
+        Ok(dict.into())
+    }
+
+    fn maybe_pause_writing(&mut self, py: Python) {
+        if !self.writing_paused && self.buffer_size > self.high_water {
+            self.writing_paused = true;
+            self.call_protocol(py, "pause_writing");
+        }
+    }
+
+    fn wakeup_drain(&mut self, py: Python) {
+        if self.buffer_size <= self.low_water {
+            if let Some(fut) = self.drain.take() {
+                let _ = fut.as_mut(py).set(py, Ok(py.None()));
+            }
+            if self.writing_paused {
+                self.writing_paused = false;
+                self.call_protocol(py, "resume_writing");
+            }
+        }
+    }
+
+    // flow control callbacks are optional
+    fn call_protocol(&self, py: Python, name: &str) {
+        if let Ok(cb) = self.protocol.getattr(py, name) {
+            trace!("Protocol.{}()", name);
+            self.evloop.as_ref(py).with(
+                &format!("protocol.{}() failed", name), || cb.call0(py));
+        }
+    }
+}
+
 impl PyTcpTransportPtr {
 
     pub fn new(py: Python, evloop: &TokioEventLoop,
-               sender: Sender<TcpTransportMessage>,
-               protocol: &PyObjectRef, info: HashMap<&'static str, PyObject>)
-               -> PyResult<PyTcpTransportPtr>
+               sender: Sender<TcpTransportMessage>, data: BoundedSender<BytesMsg>,
+               protocol: &PyObjectRef, info: HashMap<&'static str, PyObject>, fd: RawFd,
+               connect_time: Option<Duration>) -> PyResult<PyTcpTransportPtr>
     {
         // get protocol callbacks
         let connection_made = protocol.getattr("connection_made")?;
         let connection_lost = protocol.getattr("connection_lost")?;
         let data_received = protocol.getattr("data_received")?;
+        let eof_received = protocol.getattr("eof_received").ok().map(|h| h.into());
 
         let transport = py.init(|token| PyTcpTransport {
             evloop: evloop.into(),
+            protocol: protocol.into(),
             connection_lost: connection_lost.into(),
             data_received: data_received.into(),
+            eof_received: eof_received,
             transport: sender,
+            data: data,
             drain: None,
-            drained: true,
+            buffer_size: 0,
+            low_water: DEFAULT_LOW_WATER,
+            high_water: DEFAULT_HIGH_WATER,
+            writing_paused: false,
             closing: false,
             info: info,
             paused: false,
+            frozen: None,
+            fd: fd,
+            bytes_received: 0,
+            bytes_sent: 0,
+            created: Instant::now(),
+            connect_time: connect_time,
             token: token})?;
+        evloop.register_transport(&transport, connect_time);
 
         // connection made
         let _ = connection_made.call1((transport.clone_ref(py),))
@@ -305,7 +1077,11 @@ impl PyTcpTransportPtr {
 
     pub fn connection_lost(&self) {
         trace!("Protocol.connection_lost(None)");
-        self.0.with(|py, transport| {
+        self.0.with_mut(|py, transport| {
+            transport.forget_socket(py);
+            transport.drain_lost(py, None);
+            transport.evloop.as_ref(py).unregister_transport(
+                &self.0, transport.bytes_received, transport.bytes_sent);
             transport.evloop.as_ref(py).with(
                 "Protocol.connection_made error",
                 || transport.connection_lost.call1(py, (py.None(),)))});
@@ -314,48 +1090,358 @@ impl PyTcpTransportPtr {
     pub fn connection_error(&self, err: io::Error) {
         trace!("Protocol.connection_lost({:?})", err);
         self.0.with_mut(|py, tr| {
-            match err.kind() {
+            tr.forget_socket(py);
+            tr.evloop.as_ref(py).unregister_transport(
+                &self.0, tr.bytes_received, tr.bytes_sent);
+            let e: PyErr = match err.kind() {
                 io::ErrorKind::TimedOut => {
                     trace!("socket.timeout");
-                    let e: PyErr = exc::socket::timeout.into();
-
-                    tr.connection_lost.call1(py, (e,))
-                        .into_log(py, "connection_lost error");
+                    exc::socket::timeout.into()
                 },
                 _ => {
                     trace!("Protocol.connection_lost(err): {:?}", err);
-                    let e: PyErr = err.into();
-                    tr.connection_lost.call1(py, (e,))
-                        .into_log(py, "connection_lost error");
+                    utils::to_pyerr(py, err)
                 }
-            }
+            };
+            tr.drain_lost(py, Some(e.clone_ref(py)));
+            tr.connection_lost.call1(py, (e,))
+                .into_log(py, "connection_lost error");
         });
     }
 
-    pub fn data_received(&self, bytes: Bytes) -> bool {
-        self.0.with(|py, tr| {
+    //
+    // pooled bytes is chunk of pybytes::alloc_read_buffer() buffer
+    //
+    pub fn data_received(&self, bytes: Bytes, pooled: bool) -> bool {
+        self.0.with_mut(|py, tr| {
+            tr.bytes_received += bytes.len() as u64;
             tr.evloop.as_ref(py).with(
                 "data_received error", || {
-                    let bytes = pybytes::PyBytes::new(py, bytes)?;
+                    let bytes = if pooled {
+                        pybytes::PyBytes::read_chunk(py, bytes)?
+                    } else {
+                        pybytes::PyBytes::new(py, bytes)?
+                    };
                     // let bytes = PyBytes::new(py, bytes.as_ref());
                     tr.data_received.call1(py, (bytes,))
                         .log_error(py, "data_received error")
                 });
-            !tr.paused
+            !tr.paused && tr.frozen.is_none()
         })
     }
 
-    pub fn drained(&self) {
-        self.0.with_mut(|py, tr| {
-            tr.drained = true;
-            match tr.drain.take() {
-                Some(fut) => {
-                    let _ = fut.as_mut(py).set(py, Ok(py.None()));
-                },
-                None => (),
+    //
+    // returns true if protocol wants to keep transport open
+    //
+    pub fn eof_received(&self) -> bool {
+        self.0.with(|py, tr| {
+            if let Some(ref eof_received) = tr.eof_received {
+                trace!("Protocol.eof_received()");
+                match eof_received.call0(py).and_then(|res| res.is_true(py)) {
+                    Ok(keep_open) => keep_open,
+                    Err(err) => {
+                        let _ = tr.evloop.as_ref(py).log_error(err, "eof_received error");
+                        false
+                    }
+                }
+            } else {
+                false
             }
         })
     }
+
+    pub fn written(&self, len: usize) {
+        self.0.with_mut(|py, tr| {
+            tr.bytes_sent += len as u64;
+            tr.buffer_size = tr.buffer_size.saturating_sub(len);
+            tr.wakeup_drain(py);
+        })
+    }
+}
+
+
+//
+// resolve write buffer watermarks, missing value is derived from other one
+//
+pub fn write_buffer_limits(high: Option<usize>, low: Option<usize>)
+                           -> PyResult<(usize, usize)> {
+    let (high, low) = match (high, low) {
+        (Some(high), Some(low)) => (high, low),
+        (Some(high), None) => (high, high / 4),
+        (None, Some(low)) => (low * 4, low),
+        (None, None) => (DEFAULT_HIGH_WATER, DEFAULT_LOW_WATER),
+    };
+    if high < low {
+        return Err(exc::ValueError::new(
+            format!("high ({}) must be >= low ({}) must be >= 0", high, low)))
+    }
+    Ok((high, low))
+}
+
+//
+// size of queued writes in bytes, derived from high watermark. protocol
+// which respects pause_writing() does not reach it
+//
+pub fn write_queue_limit(high: usize) -> usize {
+    cmp::max(high.saturating_mul(4), MIN_WRITE_QUEUE)
+}
+
+pub fn write_queue_overflow() -> io::Error {
+    io::Error::new(io::ErrorKind::WouldBlock,
+                   "Write queue limit is exceeded, flow control is ignored")
+}
+
+
+pub fn setsockopt<T>(fd: RawFd, level: libc::c_int, name: libc::c_int, val: T) -> io::Result<()> {
+    let res = unsafe {
+        libc::setsockopt(fd, level, name,
+                         &val as *const T as *const libc::c_void,
+                         mem::size_of::<T>() as libc::socklen_t)
+    };
+    if res == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+
+// same limit as socket.getsockopt()
+const MAX_SOCKOPT_LEN: usize = 1024;
+
+fn setsockopt_buf(fd: RawFd, level: libc::c_int, name: libc::c_int, val: &[u8])
+                  -> io::Result<()> {
+    let res = unsafe {
+        libc::setsockopt(fd, level, name,
+                         val.as_ptr() as *const libc::c_void,
+                         val.len() as libc::socklen_t)
+    };
+    if res == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+pub fn getsockopt(fd: RawFd, level: libc::c_int, name: libc::c_int)
+                  -> io::Result<libc::c_int> {
+    let mut val: libc::c_int = 0;
+    let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let res = unsafe {
+        libc::getsockopt(fd, level, name, &mut val as *mut _ as *mut libc::c_void, &mut len)
+    };
+    if res == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(val)
+    }
+}
+
+fn getsockopt_buf(fd: RawFd, level: libc::c_int, name: libc::c_int, buflen: usize)
+                  -> io::Result<Vec<u8>> {
+    let mut buf = vec![0u8; buflen];
+    let mut len = buflen as libc::socklen_t;
+    let res = unsafe {
+        libc::getsockopt(fd, level, name, buf.as_mut_ptr() as *mut libc::c_void, &mut len)
+    };
+    if res == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        buf.truncate(len as usize);
+        Ok(buf)
+    }
+}
+
+
+///
+/// Bind socket to network interface, has to be called before bind() or
+/// connect(). SO_BINDTODEVICE on linux, IP_BOUND_IF / IPV6_BOUND_IF on macos
+///
+#[cfg(target_os = "linux")]
+pub fn bind_to_device(fd: RawFd, interface: &str, _v6: bool) -> io::Result<()> {
+    let res = unsafe {
+        libc::setsockopt(fd, libc::SOL_SOCKET, libc::SO_BINDTODEVICE,
+                         interface.as_ptr() as *const libc::c_void,
+                         interface.len() as libc::socklen_t)
+    };
+    if res == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "macos")]
+pub fn bind_to_device(fd: RawFd, interface: &str, v6: bool) -> io::Result<()> {
+    const IP_BOUND_IF: libc::c_int = 25;
+    const IPV6_BOUND_IF: libc::c_int = 125;
+
+    let name = ::std::ffi::CString::new(interface)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid interface name"))?;
+    let idx = unsafe { libc::if_nametoindex(name.as_ptr()) };
+    if idx == 0 {
+        return Err(io::Error::last_os_error())
+    }
+    if v6 {
+        setsockopt(fd, libc::IPPROTO_IPV6, IPV6_BOUND_IF, idx as libc::c_int)
+    } else {
+        setsockopt(fd, libc::IPPROTO_IP, IP_BOUND_IF, idx as libc::c_int)
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub fn bind_to_device(_fd: RawFd, _interface: &str, _v6: bool) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other, "binding to interface is not supported on this platform"))
+}
+
+pub fn socket_family(fd: RawFd) -> Option<libc::c_int> {
+    let mut addr: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    let res = unsafe {
+        libc::getsockname(fd, &mut addr as *mut _ as *mut libc::sockaddr, &mut len)
+    };
+    if res == 0 { Some(addr.ss_family as libc::c_int) } else { None }
+}
+
+// peer credentials are available for AF_UNIX sockets only
+fn is_unix_socket(fd: RawFd) -> bool {
+    socket_family(fd) == Some(libc::AF_UNIX)
+}
+
+#[cfg(target_os = "linux")]
+const IP_TOS: libc::c_int = 1;
+#[cfg(target_os = "linux")]
+const IPV6_TCLASS: libc::c_int = 67;
+#[cfg(not(target_os = "linux"))]
+const IP_TOS: libc::c_int = 3;
+#[cfg(not(target_os = "linux"))]
+const IPV6_TCLASS: libc::c_int = 36;
+
+///
+/// Set IP_TOS for ipv4 or IPV6_TCLASS for ipv6 socket
+///
+fn set_tos(fd: RawFd, tos: u8) -> io::Result<()> {
+    match socket_family(fd) {
+        Some(libc::AF_INET) => setsockopt(fd, libc::IPPROTO_IP, IP_TOS, tos as libc::c_int),
+        Some(libc::AF_INET6) =>
+            setsockopt(fd, libc::IPPROTO_IPV6, IPV6_TCLASS, tos as libc::c_int),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput, "tos is supported for ip sockets only")),
+    }
+}
+
+///
+/// Set TCP_USER_TIMEOUT, max time transmitted data may remain
+/// unacknowledged before connection is closed
+///
+#[cfg(target_os = "linux")]
+fn set_user_timeout(fd: RawFd, timeout: Duration) -> io::Result<()> {
+    let millis = timeout.as_secs() * 1000 + (timeout.subsec_nanos() / 1_000_000) as u64;
+    setsockopt(fd, libc::IPPROTO_TCP, libc::TCP_USER_TIMEOUT,
+               cmp::min(millis, libc::c_uint::max_value() as u64) as libc::c_uint)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_user_timeout(_fd: RawFd, _timeout: Duration) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other, "TCP_USER_TIMEOUT is not supported on this platform"))
+}
+
+///
+/// Wake up listener only when accepted connection has data to read,
+/// TCP_DEFER_ACCEPT on linux, "dataready" accept filter on freebsd
+///
+#[cfg(target_os = "linux")]
+pub fn set_defer_accept(fd: RawFd, delay: Duration) -> io::Result<()> {
+    // kernel rounds seconds to number of syn-ack retransmits
+    let secs = delay.as_secs() + if delay.subsec_nanos() > 0 { 1 } else { 0 };
+    setsockopt(fd, libc::IPPROTO_TCP, libc::TCP_DEFER_ACCEPT,
+               cmp::min(secs, libc::c_int::max_value() as u64) as libc::c_int)
+}
+
+#[cfg(target_os = "freebsd")]
+pub fn set_defer_accept(fd: RawFd, _delay: Duration) -> io::Result<()> {
+    // struct accept_filter_arg
+    let mut arg = [0u8; 256];
+    arg[..9].copy_from_slice(b"dataready");
+    setsockopt_buf(fd, libc::SOL_SOCKET, libc::SO_ACCEPTFILTER, &arg)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "freebsd")))]
+pub fn set_defer_accept(_fd: RawFd, _delay: Duration) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other, "deferred accept is not supported on this platform"))
+}
+
+#[cfg(target_os = "linux")]
+fn peer_credentials(fd: RawFd) -> Option<(Option<i32>, u32, u32)> {
+    if !is_unix_socket(fd) {
+        return None
+    }
+    let mut cred: libc::ucred = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let res = unsafe {
+        libc::getsockopt(fd, libc::SOL_SOCKET, libc::SO_PEERCRED,
+                         &mut cred as *mut _ as *mut libc::c_void, &mut len)
+    };
+    if res == 0 { Some((Some(cred.pid), cred.uid, cred.gid)) } else { None }
+}
+
+// pid is not available on bsd systems
+#[cfg(not(target_os = "linux"))]
+fn peer_credentials(fd: RawFd) -> Option<(Option<i32>, u32, u32)> {
+    if !is_unix_socket(fd) {
+        return None
+    }
+    let mut uid: libc::uid_t = 0;
+    let mut gid: libc::gid_t = 0;
+    if unsafe { libc::getpeereid(fd, &mut uid, &mut gid) } == 0 {
+        Some((None, uid, gid))
+    } else {
+        None
+    }
+}
+
+// path of unix socket, empty string for unnamed socket
+fn unix_socket_name(fd: RawFd, peer: bool) -> Option<String> {
+    if !is_unix_socket(fd) {
+        return None
+    }
+    let mut addr: libc::sockaddr_un = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::sockaddr_un>() as libc::socklen_t;
+    let res = unsafe {
+        let ptr = &mut addr as *mut _ as *mut libc::sockaddr;
+        if peer {
+            libc::getpeername(fd, ptr, &mut len)
+        } else {
+            libc::getsockname(fd, ptr, &mut len)
+        }
+    };
+    if res == -1 {
+        return None
+    }
+
+    let offset = &addr.sun_path as *const _ as usize - &addr as *const _ as usize;
+    let size = cmp::min((len as usize).saturating_sub(offset), addr.sun_path.len());
+    let path: Vec<u8> = addr.sun_path[..size].iter().map(|c| *c as u8).collect();
+
+    // abstract socket name starts with nul byte
+    let path = if path.first() == Some(&0) {
+        &path[..]
+    } else {
+        path.split(|c| *c == 0).next().unwrap_or(&[])
+    };
+    Some(String::from_utf8_lossy(path).into_owned())
+}
+
+fn set_linger(fd: RawFd, linger: Option<Duration>) -> io::Result<()> {
+    let val = match linger {
+        Some(linger) => libc::linger {
+            l_onoff: 1, l_linger: linger.as_secs() as libc::c_int },
+        None => libc::linger { l_onoff: 0, l_linger: 0 },
+    };
+    setsockopt(fd, libc::SOL_SOCKET, libc::SO_LINGER, val)
 }
 
 
@@ -370,12 +1456,25 @@ enum TransportState {
 struct TcpTransport<T> {
     framed: Framed<T, TcpTransportCodec>,
     intake: unsync::mpsc::UnboundedReceiver<TcpTransportMessage>,
+    data: BoundedReceiver<BytesMsg>,
     transport: PyTcpTransportPtr,
 
     buf: Option<BytesMsg>,
+    written: usize,
     incoming_eof: bool,
+    keep_open: bool,
     flushed: bool,
     state: TransportState,
+
+    idle: Option<(Duration, Timeout)>,
+    active: bool,
+
+    read_limit: Option<RateLimit>,
+    write_limit: Option<RateLimit>,
+
+    // chunks read within one poll are delivered with single
+    // data_received() call, up to read_batch bytes
+    read_batch: usize,
 }
 
 impl<T> TcpTransport<T>
@@ -384,18 +1483,43 @@ impl<T> TcpTransport<T>
 
     fn new(socket: T,
            intake: mpsc::UnboundedReceiver<TcpTransportMessage>,
-           transport: PyTcpTransportPtr) -> TcpTransport<T> {
+           data: BoundedReceiver<BytesMsg>,
+           transport: PyTcpTransportPtr,
+           handle: &Handle, opts: TransportOptions) -> io::Result<TcpTransport<T>> {
 
-        TcpTransport {
+        let idle = match opts.idle_timeout {
+            Some(timeout) => Some((timeout, Timeout::new(timeout, handle)?)),
+            None => None,
+        };
+        let read_limit = match opts.read_rate {
+            Some(rate) => Some(RateLimit::new(rate, handle)?),
+            None => None,
+        };
+        let write_limit = match opts.write_rate {
+            Some(rate) => Some(RateLimit::new(rate, handle)?),
+            None => None,
+        };
+
+        Ok(TcpTransport {
             framed: socket.framed(TcpTransportCodec),
             intake: intake,
+            data: data,
             transport: transport,
 
             buf: None,
+            written: 0,
             incoming_eof: false,
+            keep_open: false,
             flushed: true,
             state: TransportState::Normal,
-        }
+
+            idle: idle,
+            active: false,
+
+            read_limit: read_limit,
+            write_limit: write_limit,
+            read_batch: opts.read_batch.unwrap_or(DEFAULT_READ_BATCH),
+        })
     }
 }
 
@@ -407,49 +1531,60 @@ impl<T> Future for TcpTransport<T>
     type Error = io::Error;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        // commands from transport
         loop {
-            let bytes = if let Some(bytes) = self.buf.take() {
-                Some(bytes)
-            } else {
-                match self.intake.poll() {
-                    Ok(Async::Ready(Some(msg))) => {
-                        match msg {
-                            TcpTransportMessage::Bytes(bytes) => {
-                                Some(bytes)
-                            },
-                            TcpTransportMessage::Pause => {
-                                match self.state {
-                                    TransportState::Normal => {
-                                        self.state = TransportState::Paused;
-                                    }
-                                    _ => (),
-                                }
-                                return self.poll()
-                            },
-                            TcpTransportMessage::Resume => {
-                                match self.state {
-                                    TransportState::Paused => {
-                                        self.state = TransportState::Normal;
-                                    }
-                                    _ => (),
+            match self.intake.poll() {
+                Ok(Async::Ready(Some(msg))) => {
+                    match msg {
+                        TcpTransportMessage::Pause => {
+                            match self.state {
+                                TransportState::Normal => {
+                                    self.state = TransportState::Paused;
                                 }
-                                return self.poll()
-                            },
-                            TcpTransportMessage::Close => {
-                                match self.state {
-                                    TransportState::Normal | TransportState::Paused =>
-                                        self.state = TransportState::Closing,
-                                    _ => (),
+                                _ => (),
+                            }
+                        },
+                        TcpTransportMessage::Resume => {
+                            match self.state {
+                                TransportState::Paused => {
+                                    self.state = TransportState::Normal;
                                 }
-                                None
+                                _ => (),
                             }
-                            TcpTransportMessage::Shutdown => {
-                                self.state = TransportState::Closed;
-                                let _ = self.framed.get_mut().shutdown();
-                                return Ok(Async::Ready(()))
+                        },
+                        // data written before close is still sent
+                        TcpTransportMessage::Close => {
+                            match self.state {
+                                TransportState::Normal | TransportState::Paused =>
+                                    self.state = TransportState::Closing,
+                                _ => (),
                             }
                         }
+                        TcpTransportMessage::Shutdown => {
+                            self.state = TransportState::Closed;
+                            let _ = self.framed.get_mut().shutdown();
+                            return Ok(Async::Ready(()))
+                        }
+                        TcpTransportMessage::Abort(err) => {
+                            self.state = TransportState::Closed;
+                            let _ = self.framed.get_mut().shutdown();
+                            return Err(err)
+                        }
                     }
+                }
+                Ok(_) => break,
+                Err(_) => {
+                    return Err(io::Error::new(io::ErrorKind::Other, "Closed"));
+                }
+            }
+        }
+
+        loop {
+            let bytes = if let Some(bytes) = self.buf.take() {
+                Some(bytes)
+            } else {
+                match self.data.poll() {
+                    Ok(Async::Ready(Some(bytes))) => Some(bytes),
                     Ok(_) => None,
                     Err(_) => {
                         return Err(io::Error::new(io::ErrorKind::Other, "Closed"));
@@ -458,14 +1593,30 @@ impl<T> Future for TcpTransport<T>
             };
 
             if let Some(bytes) = bytes {
+                // write rate limit, wait for tokens
+                if let Some(ref mut limit) = self.write_limit {
+                    if !limit.poll_ready()? {
+                        self.buf = Some(bytes);
+                        break
+                    }
+                }
+
                 self.flushed = false;
+                let len = bytes.len;
 
                 match self.framed.start_send(bytes) {
                     Ok(AsyncSink::NotReady(bytes)) => {
                         self.buf = Some(bytes);
                         break
                     }
-                    Ok(AsyncSink::Ready) => continue,
+                    Ok(AsyncSink::Ready) => {
+                        if let Some(ref mut limit) = self.write_limit {
+                            limit.consume(len);
+                        }
+                        self.written += len;
+                        self.active = true;
+                        continue
+                    },
                     Err(_) => return Err(io::Error::new(io::ErrorKind::Other, "Closed")),
                 }
             } else {
@@ -475,40 +1626,86 @@ impl<T> Future for TcpTransport<T>
 
         // flush sink
         if !self.flushed {
-            self.flushed = self.framed.poll_complete()?.is_ready();
+            self.flushed = self.framed.poll_complete()
+                .map_err(|err| OperationError::new("write", None, err))?.is_ready();
             if self.flushed {
-                self.transport.drained();
+                self.transport.written(mem::replace(&mut self.written, 0));
             }
         }
 
         // poll for incoming data
-        if !self.incoming_eof && self.state != TransportState::Paused {
+        if !self.incoming_eof && self.state == TransportState::Normal {
+            let mut batch = ReadBatch::Empty;
             loop {
+                // read rate limit, wait for tokens
+                if let Some(ref mut limit) = self.read_limit {
+                    if !limit.poll_ready()? {
+                        break
+                    }
+                }
+
                 match self.framed.poll() {
                     Ok(Async::Ready(Some(bytes))) => {
-                        if ! self.transport.data_received(bytes) {
+                        if let Some(ref mut limit) = self.read_limit {
+                            limit.consume(bytes.len());
+                        }
+                        self.active = true;
+                        batch.push(bytes);
+                        if batch.len() < self.read_batch {
+                            continue
+                        }
+                        if ! self.deliver(&mut batch) {
                             self.state = TransportState::Paused;
                             break
                         }
                         continue
                     },
-                    Ok(Async::Ready(None)) => self.incoming_eof = true,
+                    Ok(Async::Ready(None)) => {
+                        // eof is polled again after resume
+                        if !batch.is_empty() && !self.deliver(&mut batch) {
+                            self.state = TransportState::Paused;
+                            break
+                        }
+                        self.incoming_eof = true;
+                        // half-closed connection stays writable
+                        self.keep_open = self.transport.eof_received();
+                    },
                     Ok(Async::NotReady) => (),
-                    Err(err) => return Err(err.into()),
+                    Err(err) => {
+                        // deliver data received before error
+                        if !batch.is_empty() {
+                            self.deliver(&mut batch);
+                        }
+                        return Err(OperationError::new("read", None, err))
+                    },
                 }
                 break
             }
+
+            if !batch.is_empty() && !self.deliver(&mut batch) {
+                self.state = TransportState::Paused;
+            }
+        }
+
+        // close connection without read/write activity
+        if let Some((timeout, ref mut idle)) = self.idle {
+            if mem::replace(&mut self.active, false) {
+                idle.reset(Instant::now() + timeout);
+            }
+            if idle.poll()?.is_ready() {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "Connection idle timeout"))
+            }
         }
 
-        // close
+        // close, flush queued data first then shutdown write side
         if self.state == TransportState::Closing {
-            if self.incoming_eof {
-                return Ok(Async::Ready(()))
+            if self.buf.is_some() {
+                return Ok(Async::NotReady)
             }
             return self.framed.close();
         }
 
-        if self.flushed && self.incoming_eof {
+        if self.flushed && self.incoming_eof && !self.keep_open {
             Ok(Async::Ready(()))
         } else {
             Ok(Async::NotReady)
@@ -517,6 +1714,122 @@ impl<T> Future for TcpTransport<T>
 }
 
 
+impl<T> TcpTransport<T> {
+
+    // returns false if protocol paused reading
+    fn deliver(&self, batch: &mut ReadBatch) -> bool {
+        let (bytes, pooled) = batch.take();
+        self.transport.data_received(bytes, pooled)
+    }
+}
+
+
+//
+// Token bucket rate limiter, allows bursts up to one second of traffic.
+// Transfer may overdraw bucket, next one waits until it refills.
+//
+pub struct RateLimit {
+    rate: u64,
+    tokens: i64,
+    updated: Instant,
+    timer: Timeout,
+}
+
+impl RateLimit {
+
+    pub fn new(rate: u64, handle: &Handle) -> io::Result<RateLimit> {
+        Ok(RateLimit {
+            rate: rate,
+            tokens: rate as i64,
+            updated: Instant::now(),
+            timer: Timeout::new(Duration::new(0, 0), handle)?,
+        })
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = utils::duration_to_secs(now - self.updated);
+        let tokens = self.tokens + (elapsed * self.rate as f64) as i64;
+        self.tokens = cmp::min(tokens, self.rate as i64);
+        self.updated = now;
+    }
+
+    //
+    // returns false and schedules wakeup if bucket is empty
+    //
+    pub fn poll_ready(&mut self) -> io::Result<bool> {
+        loop {
+            self.refill();
+            if self.tokens > 0 {
+                return Ok(true)
+            }
+            let wait = (1 - self.tokens) as f64 / self.rate as f64;
+            self.timer.reset(
+                Instant::now() + Duration::new(
+                    wait as u64, (wait.fract() * 1_000_000_000.0) as u32));
+            if self.timer.poll()?.is_not_ready() {
+                return Ok(false)
+            }
+        }
+    }
+
+    pub fn consume(&mut self, len: usize) {
+        self.tokens -= len as i64;
+    }
+}
+
+
+//
+// Chunks coalesced within single poll, single chunk is not copied
+// and stays backed by pooled read buffer
+//
+enum ReadBatch {
+    Empty,
+    Chunk(Bytes),
+    Buffer(BytesMut),
+}
+
+impl ReadBatch {
+
+    fn push(&mut self, bytes: Bytes) {
+        *self = match mem::replace(self, ReadBatch::Empty) {
+            ReadBatch::Empty => ReadBatch::Chunk(bytes),
+            ReadBatch::Chunk(first) => {
+                let mut buf = BytesMut::with_capacity(first.len() + bytes.len());
+                buf.put_slice(&first);
+                buf.put_slice(&bytes);
+                ReadBatch::Buffer(buf)
+            },
+            ReadBatch::Buffer(mut buf) => {
+                buf.reserve(bytes.len());
+                buf.put_slice(&bytes);
+                ReadBatch::Buffer(buf)
+            },
+        }
+    }
+
+    fn len(&self) -> usize {
+        match *self {
+            ReadBatch::Empty => 0,
+            ReadBatch::Chunk(ref bytes) => bytes.len(),
+            ReadBatch::Buffer(ref buf) => buf.len(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn take(&mut self) -> (Bytes, bool) {
+        match mem::replace(self, ReadBatch::Empty) {
+            ReadBatch::Empty => (Bytes::new(), false),
+            ReadBatch::Chunk(bytes) => (bytes, true),
+            ReadBatch::Buffer(buf) => (buf.freeze(), false),
+        }
+    }
+}
+
+
 struct TcpTransportCodec;
 
 impl Decoder for TcpTransportCodec {
@@ -530,7 +1843,7 @@ impl Decoder for TcpTransportCodec {
             Ok(None)
         };
         if src.capacity() <= 1024 {
-            src.reserve(32768);
+            *src = pybytes::alloc_read_buffer();
         }
         res
     }
@@ -1,6 +1,10 @@
 #![allow(non_upper_case_globals)]
 
 use std;
+use std::io;
+use std::fmt;
+use std::error::Error;
+use std::net::SocketAddr;
 use pyo3;
 use pyo3::*;
 use std::os::raw::c_long;
@@ -17,6 +21,8 @@ pub struct WorkingClasses {
 
     pub Asyncio: Py<PyModule>,
     pub SSLProto: Py<PyType>,
+    pub SSLConfigure: PyObject,
+    pub SSLReload: PyObject,
     pub Coroutines: Py<PyModule>,
     pub UnixEvents: Py<PyModule>,
 
@@ -26,6 +32,9 @@ pub struct WorkingClasses {
     pub GetNameInfo: PyObject,
 
     pub Sys: Py<PyModule>,
+    pub MemoryView: PyObject,
+    pub JsonLoads: PyObject,
+    pub JsonDumps: PyObject,
     pub Traceback: Py<PyModule>,
     pub ExtractStack: PyObject,
 }
@@ -43,7 +52,7 @@ lazy_static! {
         let socket = py.import("socket").unwrap();
         let tb = py.import("traceback").unwrap();
         let asyncio = py.import("asyncio").unwrap();
-        let sslproto = py.import("asyncio.sslproto").unwrap();
+        let sslproto = py.import("tokio.sslproto").unwrap();
 
         WorkingClasses {
             // asyncio types
@@ -52,6 +61,8 @@ lazy_static! {
             Asyncio: asyncio.into(),
             SSLProto: PyType::try_from(
                 &sslproto.get("SSLProtocol").unwrap()).unwrap().into(),
+            SSLConfigure: sslproto.get("configure_context").unwrap().into(),
+            SSLReload: sslproto.get("reload_context").unwrap().into(),
             Coroutines: py.import("asyncio.coroutines").unwrap().into(),
             UnixEvents: py.import("asyncio.unix_events").unwrap().into(),
 
@@ -62,6 +73,9 @@ lazy_static! {
             Socket: socket.into(),
 
             Sys: py.import("sys").unwrap().into(),
+            MemoryView: py.import("builtins").unwrap().get("memoryview").unwrap().into(),
+            JsonLoads: py.import("json").unwrap().get("loads").unwrap().into(),
+            JsonDumps: py.import("json").unwrap().get("dumps").unwrap().into(),
             Traceback: tb.into(),
             ExtractStack: tb.get("extract_stack").unwrap().into(),
         }
@@ -186,6 +200,131 @@ impl std::convert::From<LookupError> for PyErr {
 }
 
 
+//
+// io::Error with context: failed operation, address and optional cause.
+// carried inside io::Error, so it passes through io futures unchanged
+//
+#[derive(Debug)]
+pub struct OperationError {
+    pub operation: &'static str,
+    pub address: Option<SocketAddr>,
+    pub error: io::Error,
+    pub cause: Option<io::Error>,
+    pub errors: Vec<io::Error>,
+}
+
+impl OperationError {
+
+    pub fn new(operation: &'static str, address: Option<SocketAddr>, error: io::Error)
+               -> io::Error {
+        OperationError::with_cause(operation, address, error, None)
+    }
+
+    pub fn with_cause(operation: &'static str, address: Option<SocketAddr>,
+                      error: io::Error, cause: Option<io::Error>) -> io::Error {
+        io::Error::new(error.kind(), OperationError {
+            operation: operation,
+            address: address,
+            error: error,
+            cause: cause,
+            errors: Vec::new(),
+        })
+    }
+
+    ///
+    /// Error of several attempts, exposed as `errors` attribute
+    /// of python exception, error of single attempt is the cause
+    ///
+    pub fn with_errors(operation: &'static str, address: Option<SocketAddr>,
+                       error: io::Error, errors: Vec<io::Error>) -> io::Error {
+        io::Error::new(error.kind(), OperationError {
+            operation: operation,
+            address: address,
+            error: error,
+            cause: None,
+            errors: errors,
+        })
+    }
+}
+
+impl fmt::Display for OperationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.address {
+            Some(ref addr) => write!(f, "{} {}: {}", self.operation, addr, self.error),
+            None => write!(f, "{}: {}", self.operation, self.error),
+        }
+    }
+}
+
+impl Error for OperationError {
+    fn description(&self) -> &str {
+        self.error.description()
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        match self.cause.as_ref().or(self.errors.last()) {
+            Some(err) => Some(err),
+            None => None,
+        }
+    }
+}
+
+//
+// Convert io::Error into python exception. for OperationError
+// `operation` and `address` attributes are set on exception
+// and cause is chained as `__cause__`
+//
+pub fn to_pyerr(py: Python, err: io::Error) -> PyErr {
+    let is_op = err.get_ref().map(|e| e.is::<OperationError>()).unwrap_or(false);
+    if !is_op {
+        return err.into()
+    }
+    let op = match err.into_inner().map(|e| e.downcast::<OperationError>()) {
+        Some(Ok(op)) => *op,
+        _ => unreachable!(),
+    };
+
+    let mut pyerr = to_pyerr(py, op.error);
+    pyerr.normalize(py);
+
+    if let PyErrValue::Value(ref exc) = pyerr.pvalue {
+        let exc = exc.as_ref(py);
+        let address = match op.address {
+            Some(addr) => (addr.ip().to_string(), addr.port()).to_object(py),
+            None => py.None(),
+        };
+        let _ = exc.setattr("operation", op.operation);
+        let _ = exc.setattr("address", address);
+
+        // errors of all attempts, single attempt error is the cause as well
+        let mut cause = op.cause.map(|cause| to_pyerr(py, cause));
+        if !op.errors.is_empty() {
+            let single = op.errors.len() == 1;
+            let errors = PyList::empty(py);
+            for err in op.errors {
+                let mut err = to_pyerr(py, err);
+                err.normalize(py);
+                if let PyErrValue::Value(ref value) = err.pvalue {
+                    let _ = errors.append(value.clone_ref(py));
+                }
+                if single && cause.is_none() {
+                    cause = Some(err);
+                }
+            }
+            let _ = exc.setattr("errors", errors);
+        }
+
+        if let Some(mut cause) = cause {
+            cause.normalize(py);
+            if let PyErrValue::Value(ref cause) = cause.pvalue {
+                let _ = exc.setattr("__cause__", cause.clone_ref(py));
+            }
+        }
+    }
+    pyerr
+}
+
+
 //
 // Format exception
 //
@@ -224,6 +363,14 @@ pub fn parse_seconds(name: &str, value: &PyObjectRef) -> PyResult<Option<Duratio
 }
 
 
+//
+// convert Duration into float seconds
+//
+pub fn duration_to_secs(dur: Duration) -> f64 {
+    dur.as_secs() as f64 + (dur.subsec_nanos() as f64 / 1_000_000_000.0)
+}
+
+
 //
 // convert PyFloat or PyInt into u64 (milliseconds)
 //
@@ -250,3 +397,26 @@ pub fn parse_millis(name: &str, value: &PyObjectRef) -> PyResult<u64> {
             format!("'{}' must be int of float type: {:?}", name, value.get_type())))
     }
 }
+
+
+pub const BASE64: &'static [u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+//
+// standard base64 encoding with padding
+//
+pub fn base64_encode(data: &[u8]) -> String {
+    let mut out = String::with_capacity((data.len() + 2) / 3 * 4);
+    for chunk in data.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for idx in 0..4 {
+            if idx <= chunk.len() {
+                out.push(BASE64[(n >> (18 - idx * 6)) as usize & 0x3F] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
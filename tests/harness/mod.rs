@@ -0,0 +1,81 @@
+//! Integration test harness: embedded interpreter with `tokio` package
+//! importable from the source tree, extension module is registered
+//! from this crate instead of built shared library.
+#![allow(dead_code)]
+
+use std::sync::{Once, ONCE_INIT};
+
+use async_tokio::*;
+
+
+static INIT: Once = ONCE_INIT;
+
+const PRELUDE: &str = "
+import asyncio
+import socket
+import tokio
+
+loop = tokio.new_event_loop()
+
+def run(coro, timeout=5.0):
+    return loop.run_until_complete(asyncio.wait_for(coro, timeout, loop=loop))
+";
+
+fn init(py: Python) {
+    INIT.call_once(|| {
+        let module = PyModule::new(py, "_tokio").unwrap();
+        register_classes(py, module).unwrap();
+
+        let d = PyDict::new(py);
+        d.set_item("module", module).unwrap();
+        d.set_item("root", env!("CARGO_MANIFEST_DIR")).unwrap();
+        py.run("import sys\n\
+                sys.path.insert(0, root)\n\
+                sys.modules['tokio._tokio'] = module\n\
+                import tokio", None, Some(d))
+            .log_error(py, "can not initialize tokio package").unwrap();
+    });
+}
+
+///
+/// Python test scope, code runs with fresh event loop available as `loop`
+/// and `run(coro)` helper. Loop is closed at the end of scope.
+///
+pub struct Scope<'p> {
+    py: Python<'p>,
+    globals: &'p PyDict,
+}
+
+impl<'p> Scope<'p> {
+
+    pub fn new(py: Python<'p>) -> Scope<'p> {
+        init(py);
+
+        let globals = PyDict::new(py);
+        py.run(PRELUDE, Some(globals), None)
+            .log_error(py, "can not create event loop").unwrap();
+
+        Scope { py: py, globals: globals }
+    }
+
+    /// Execute code, panics with python traceback on failure
+    pub fn run(&self, code: &str) {
+        self.py.run(code, Some(self.globals), None)
+            .log_error(self.py, "python code failure")
+            .expect(code);
+    }
+
+    /// Evaluate expression
+    pub fn eval<T>(&self, expr: &str) -> T where for<'a> T: FromPyObject<'a> {
+        self.py.eval(expr, Some(self.globals), None)
+            .log_error(self.py, "python code failure")
+            .expect(expr)
+            .extract().expect(expr)
+    }
+}
+
+impl<'p> Drop for Scope<'p> {
+    fn drop(&mut self) {
+        let _ = self.py.run("loop.close()", Some(self.globals), None);
+    }
+}
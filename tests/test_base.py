@@ -526,3 +526,100 @@ def test_set_task_factory(loop):
     assert isinstance(task, asyncio.Task)
     assert not isinstance(task, MyTask)
     loop.run_until_complete(task)
+
+
+def test_busy_poll_wakeup_latency(loop):
+    if not hasattr(loop, 'busy_poll'):
+        pytest.skip('loop does not support busy polling')
+
+    assert loop.busy_poll is None
+    assert loop.wakeup_latency is None
+    with pytest.raises(ValueError):
+        loop.busy_poll = -1
+
+    calls = []
+    loop.busy_poll = 0.001
+    assert loop.busy_poll == 0.001
+
+    loop.call_later(0.01, calls.append, 1)
+    loop.call_later(0.02, loop.stop)
+    loop.run_forever()
+
+    assert calls == [1]
+    assert 0 <= loop.wakeup_latency < 1
+
+    loop.reset_wakeup_latency()
+    assert loop.wakeup_latency is None
+
+    loop.busy_poll = None
+    loop.run_until_complete(asyncio.sleep(0.01, loop=loop))
+    assert loop.wakeup_latency is not None
+
+
+def test_debug_slow_task_step(loop):
+    if not hasattr(loop, 'slow_task_step_duration'):
+        pytest.skip('loop does not support task step warnings')
+
+    logger = logging.getLogger('asyncio')
+    loop.slow_task_step_duration = 0.05
+    assert loop.slow_task_step_duration == 0.05
+
+    async def busy():
+        time.sleep(0.1)
+
+    with mock.patch.object(logger, 'warning') as log:
+        loop.run_until_complete(busy())
+    assert log.call_count == 0
+
+    loop.set_debug(True)
+    with mock.patch.object(logger, 'warning') as log:
+        loop.run_until_complete(busy())
+
+    assert log.call_count == 1
+    msg = log.call_args[0][0] % log.call_args[0][1:]
+    assert 'Executing step of <Task' in msg
+    assert 'busy()' in msg
+    assert 'test_base.py' in msg
+
+
+def test_add_service(loop):
+    if not hasattr(loop, 'add_service'):
+        pytest.skip('loop does not support background services')
+
+    events = []
+
+    class Service:
+
+        def __init__(self, name, delay=0):
+            self.name = name
+            self.delay = delay
+
+        async def __aenter__(self):
+            events.append(('enter', self.name))
+
+        async def __aexit__(self, *exc):
+            await asyncio.sleep(self.delay, loop=loop)
+            events.append(('exit', self.name))
+
+    with pytest.raises(TypeError):
+        loop.add_service(object())
+
+    loop.add_service(Service('db'))
+    loop.add_service(Service('slow', 1.0), timeout=0.05)
+    assert events == []
+
+    async def main():
+        assert events == [('enter', 'db'), ('enter', 'slow')]
+        loop.add_service(Service('cache'))
+        await asyncio.sleep(0.01, loop=loop)
+        assert events[-1] == ('enter', 'cache')
+
+    loop.run_until_complete(main())
+
+    handler = mock.Mock()
+    loop.set_exception_handler(handler)
+    loop.close()
+
+    assert events[3:] == [('exit', 'cache'), ('exit', 'db')]
+    assert handler.call_count == 1
+    assert 'Timeout exiting service' in handler.call_args[0][1]['message']
@@ -3,6 +3,7 @@
 #
 # Portions copyright (c) 2015-present MagicStack Inc.  http://magic.io
 
+import asyncio
 import socket
 
 import pytest
@@ -104,3 +105,23 @@ def test_getnameinfo(loop, args):
             raise err
 
         assert a1 == a2
+
+
+@pytest.mark.parametrize('lookup', ['getaddrinfo', 'getnameinfo'])
+def test_lookup_cancel(loop, lookup):
+    def start():
+        if lookup == 'getaddrinfo':
+            return loop.getaddrinfo('localhost', 80)
+        else:
+            return loop.getnameinfo(('127.0.0.1', 80), 0)
+
+    futs = [start() for _ in range(10000)]
+    for fut in futs:
+        fut.cancel()
+        assert fut.cancelled()
+
+    # cancelled lookups are skipped, pool is not busy
+    async def check():
+        return await asyncio.wait_for(start(), 5.0, loop=loop)
+
+    assert loop.run_until_complete(check())
@@ -13,6 +13,8 @@ from test import support
 from unittest import mock
 
 import pytest
+import tokio
+from tokio import _tokio
 
 
 @pytest.fixture
@@ -448,3 +450,27 @@ def test_future_remove_done_callback(
 
     assert bag == [2]
     assert f.result() == 'foo'
+
+
+def test_future_waiters_reuse(loop):
+    if not isinstance(loop, tokio.Loop):
+        pytest.skip('waiter freelist is tokio specific')
+
+    async def worker(queue):
+        for _ in range(1000):
+            await queue.get()
+
+    async def producer(queue):
+        for i in range(1000):
+            await queue.put(i)
+            await asyncio.sleep(0, loop=loop)
+
+    queue = asyncio.Queue(loop=loop)
+    allocated, reused, _ = _tokio.waiters_stats()
+    loop.run_until_complete(asyncio.gather(
+        worker(queue), producer(queue), loop=loop))
+
+    new_allocated, new_reused, free = _tokio.waiters_stats()
+    assert new_reused - reused >= 1000
+    assert new_allocated - allocated < 100
+    assert free > 0
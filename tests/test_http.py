@@ -0,0 +1,1241 @@
+import asyncio
+import json
+import pathlib
+import ssl
+import tempfile
+import zlib
+
+import pytest
+
+import tokio
+from tokio import _tokio
+from tokio.test_utils import loop_context
+
+
+@pytest.fixture
+def loop():
+    with loop_context(tokio.EventLoopPolicy, fast=False) as _loop:
+        yield _loop
+
+
+class HttpProto:
+
+    def __init__(self, loop):
+        self.loop = loop
+        self.transport = None
+        self.lost = False
+
+    def connection_made(self, transport):
+        self.transport = transport
+
+    def data_received(self, req):
+        self.loop.create_task(self.handle(req))
+
+    def connection_lost(self, exc):
+        self.lost = True
+
+    async def handle(self, req):
+        body = await req.content.read()
+        req.writer.write_headers(
+            'HTTP/1.1 200 OK\r\n',
+            {'Content-Length': str(len(body) + 1)})
+        await req.writer.write_eof(body + b'!')
+
+
+def run_briefly(loop):
+    loop.run_until_complete(asyncio.sleep(0.05, loop=loop))
+
+
+def test_http_capture(loop):
+    cap = loop._http_capture(lambda: HttpProto(loop))
+    cap.feed_data(b'GET /test HTTP/1.1\r\nHost: example.com\r\n\r\n')
+    run_briefly(loop)
+
+    assert len(cap.requests) == 1
+    req = cap.requests[0]
+    assert req.method == 'GET'
+    assert req.path == '/test'
+    assert req.version == (1, 1)
+    assert req.headers['host'] == 'example.com'
+
+    assert cap.responses == [
+        b'HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\n!']
+
+
+def test_http_request_url(loop):
+    cap = loop._http_capture(lambda: HttpProto(loop))
+    cap.feed_data(b'GET /caf%C3%A9/a%20b?x=1&y=%20 HTTP/1.1\r\n\r\n'
+                  b'GET http://example.com:8080/p?q HTTP/1.1\r\n\r\n')
+    run_briefly(loop)
+
+    req = cap.requests[0]
+    assert req.path == '/caf\xe9/a b'
+    assert req.raw_path == '/caf%C3%A9/a%20b?x=1&y=%20'
+    assert req.query_string == 'x=1&y=%20'
+    assert req.rel_url.path == req.path
+    assert req.rel_url.raw_path == req.raw_path
+    assert req.rel_url.query_string == req.query_string
+
+    req = cap.requests[1]
+    assert req.path == '/p'
+    assert req.raw_path == '/p?q'
+    assert req.query_string == 'q'
+
+
+def test_http_request_query(loop):
+    cap = loop._http_capture(lambda: HttpProto(loop))
+    cap.feed_data(b'GET /?a=1&B=x+y&a=%32&flag HTTP/1.1\r\n\r\n'
+                  b'GET / HTTP/1.1\r\n\r\n')
+    run_briefly(loop)
+
+    query = cap.requests[0].query
+    assert query is cap.requests[0].query
+    assert len(query) == 4
+    assert query['a'] == '1'
+    assert query.get('B') == 'x y'
+    assert query.get('b') is None
+    assert query.get('b', 'default') == 'default'
+    assert query.getall('a') == ['1', '2']
+    assert query['flag'] == ''
+    assert 'a' in query and 'b' not in query
+    assert list(query) == query.keys() == ['a', 'B', 'a', 'flag']
+    assert query.values() == ['1', 'x y', '2', '']
+    assert query.items() == [('a', '1'), ('B', 'x y'), ('a', '2'), ('flag', '')]
+    with pytest.raises(KeyError):
+        query['b']
+    with pytest.raises(KeyError):
+        query.getall('b')
+    assert query.getall('b', []) == []
+
+    assert len(cap.requests[1].query) == 0
+
+
+def test_http_capture_body(loop):
+    proto = None
+
+    def factory():
+        nonlocal proto
+        proto = HttpProto(loop)
+        return proto
+
+    cap = loop._http_capture(factory)
+    cap.feed_data(b'POST / HTTP/1.1\r\nContent-Length: 4\r\n\r\nda')
+    run_briefly(loop)
+    cap.feed_data(b'ta')
+    run_briefly(loop)
+    cap.feed_eof()
+    run_briefly(loop)
+
+    assert [r.method for r in cap.requests] == ['POST']
+    assert cap.responses == [
+        b'HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\ndata!']
+    assert proto.lost
+
+
+def test_http_capture_chunked_body(loop):
+    cap = loop._http_capture(lambda: HttpProto(loop))
+    cap.feed_data(b'POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n'
+                  b'4;ext=1\r\nda')
+    run_briefly(loop)
+    cap.feed_data(b'ta\r\n')
+    run_briefly(loop)
+    cap.feed_data(b'A\r\n0123456789\r\n0\r\nTrailer: 1\r\n\r\n')
+    run_briefly(loop)
+
+    assert [r.method for r in cap.requests] == ['POST']
+    assert cap.responses == [
+        b'HTTP/1.1 200 OK\r\nContent-Length: 15\r\n\r\ndata0123456789!']
+
+
+class StreamProto(HttpProto):
+
+    async def handle(self, req):
+        req.writer.write_headers('HTTP/1.1 200 OK\r\n', {})
+        await req.writer.write(b'data')
+        await req.writer.write(b'')
+        await req.writer.write(bytearray(b'0123456789'))
+        await req.writer.write_eof(b'!')
+
+
+def test_http_chunked_response(loop):
+    cap = loop._http_capture(lambda: StreamProto(loop))
+    cap.feed_data(b'GET / HTTP/1.1\r\n\r\n')
+    cap.feed_data(b'GET / HTTP/1.0\r\n\r\n')
+    run_briefly(loop)
+
+    assert cap.responses == [
+        b'HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n'
+        b'4\r\ndata\r\na\r\n0123456789\r\n1\r\n!\r\n0\r\n\r\n',
+        b'HTTP/1.1 200 OK\r\n\r\ndata0123456789!']
+
+
+class PipelineProto(HttpProto):
+
+    def __init__(self, loop):
+        super().__init__(loop)
+        self.active = 0
+        self.max_active = 0
+
+    async def handle_request(self, req):
+        self.active += 1
+        self.max_active = max(self.max_active, self.active)
+        # first request is slowest one
+        await asyncio.sleep(0.03 if req.path == '/1' else 0.01, loop=self.loop)
+        self.active -= 1
+
+        body = req.path.encode()
+        req.writer.write_headers(
+            'HTTP/1.1 200 OK\r\n', {'Content-Length': str(len(body))})
+        await req.writer.write_eof(body)
+
+
+def pipeline(cap):
+    cap.feed_data(b'GET /1 HTTP/1.1\r\n\r\n'
+                  b'GET /2 HTTP/1.1\r\n\r\n'
+                  b'POST /3 HTTP/1.1\r\nContent-Length: 1\r\n\r\n!')
+    return [b'HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\n/%d' % i
+            for i in (1, 2, 3)]
+
+
+@pytest.mark.parametrize('concurrency', [None, 1, 2])
+def test_http_pipelining(loop, concurrency):
+    proto = None
+
+    def factory():
+        nonlocal proto
+        proto = PipelineProto(loop)
+        return proto
+
+    cap = loop._http_capture(factory, concurrency=concurrency)
+    expected = pipeline(cap)
+    loop.run_until_complete(asyncio.sleep(0.2, loop=loop))
+
+    assert [r.path for r in cap.requests] == ['/1', '/2', '/3']
+    assert cap.responses == expected
+    assert proto.max_active == (concurrency or 3)
+
+
+def test_http_pipelining_unfinished_response(loop):
+    class Proto(HttpProto):
+        async def handle_request(self, req):
+            req.writer.write_headers('HTTP/1.1 200 OK\r\n', {})
+
+    proto = None
+
+    def factory():
+        nonlocal proto
+        proto = Proto(loop)
+        return proto
+
+    with pytest.raises(ValueError):
+        loop._http_capture(factory, concurrency=0)
+
+    cap = loop._http_capture(factory, concurrency=1)
+    cap.feed_data(b'GET / HTTP/1.1\r\n\r\n')
+    run_briefly(loop)
+    assert proto.lost
+
+
+def test_http_request_body_flow_control(loop):
+    size = 1024 * 1024
+    consume = asyncio.Event(loop=loop)
+
+    class Proto(HttpProto):
+        async def handle(self, req):
+            await consume.wait()
+            received = 0
+            while True:
+                chunk = await req.content.readany()
+                if not chunk:
+                    break
+                received += len(chunk)
+            body = str(received).encode()
+            req.writer.write_headers(
+                'HTTP/1.1 200 OK\r\n', {'Content-Length': str(len(body))})
+            await req.writer.write_eof(body)
+
+    cap = loop._http_capture(lambda: Proto(loop))
+    cap.feed_data(b'POST / HTTP/1.1\r\nContent-Length: %d\r\n\r\n' % size)
+    for _ in range(16):
+        cap.feed_data(b'x' * (size // 16))
+    run_briefly(loop)
+
+    # body is not consumed, connection stops reading
+    req = cap.requests[0]
+    assert req.content.total_bytes < size // 2
+
+    consume.set()
+    loop.run_until_complete(asyncio.sleep(0.2, loop=loop))
+    assert req.content.total_bytes == size
+    assert cap.responses == [
+        b'HTTP/1.1 200 OK\r\nContent-Length: 7\r\n\r\n1048576']
+
+
+def test_http_errors(loop):
+    assert issubclass(tokio.HttpParseError, tokio.HttpError)
+    assert issubclass(tokio.PayloadError, tokio.HttpError)
+    assert issubclass(tokio.ServerTimeoutError, tokio.HttpError)
+
+    class Proto(HttpProto):
+        exc = payload_exc = None
+
+        def connection_lost(self, exc):
+            self.exc = exc
+
+        async def handle(self, req):
+            try:
+                await req.content.read()
+            except tokio.HttpError as exc:
+                self.payload_exc = exc
+
+    proto = Proto(loop)
+    cap = loop._http_capture(lambda: proto)
+    cap.feed_data(b'GET / HTTP/3.0\r\n\r\n')
+    run_briefly(loop)
+    assert isinstance(proto.exc, tokio.HttpParseError)
+
+    # connection closed before payload is completed
+    proto = Proto(loop)
+    cap = loop._http_capture(lambda: proto)
+    cap.feed_data(b'POST / HTTP/1.1\r\nContent-Length: 4\r\n\r\nda')
+    run_briefly(loop)
+    cap.feed_eof()
+    run_briefly(loop)
+    assert isinstance(proto.exc, tokio.PayloadError)
+    assert isinstance(proto.payload_exc, tokio.PayloadError)
+
+
+def test_http_response_queue_limit(loop):
+    protos = []
+
+    class Proto(HttpProto):
+        exc = None
+
+        def connection_lost(self, exc):
+            super().connection_lost(exc)
+            self.exc = exc
+
+        async def handle(self, req):
+            req.writer.write_headers('HTTP/1.1 200 OK\r\n', {})
+            # response writer does not wait for drain()
+            for _ in range(8):
+                req.writer.write(b'x' * 65536, False)
+
+    def factory():
+        protos.append(Proto(loop))
+        return protos[-1]
+
+    cap = loop._http_capture(factory)
+    cap.feed_data(b'GET / HTTP/1.1\r\n\r\n')
+    run_briefly(loop)
+
+    # connection is aborted instead of raising from write()
+    assert protos[0].lost
+    assert isinstance(protos[0].exc, BlockingIOError)
+
+
+def test_http_server_ssl(loop):
+    # http transport does not terminate tls
+    ctx = ssl.create_default_context(ssl.Purpose.CLIENT_AUTH)
+    with pytest.raises(ValueError):
+        loop.create_http_server(
+            lambda: HttpProto(loop), '127.0.0.1', 0, ssl=ctx)
+
+
+def test_http_interned_strings(loop):
+    cap = loop._http_capture(lambda: HttpProto(loop))
+    cap.feed_data(b'GET /1 HTTP/1.1\r\nHost: a\r\n\r\n'
+                  b'GET /2 HTTP/1.1\r\nHost: b\r\n\r\n')
+    run_briefly(loop)
+
+    first, second = cap.requests
+    assert first.method is second.method
+    assert first.headers.items()[0][0] is second.headers.items()[0][0]
+
+    assert _tokio.status_line(200) == 'HTTP/1.1 200 OK\r\n'
+    assert _tokio.status_line(404) is _tokio.status_line(404)
+    assert _tokio.reason_phrase(503) == 'Service Unavailable'
+    assert _tokio.status_line(299) is None
+
+
+def test_http_header_encoding(loop):
+    data = (b'GET / HTTP/1.1\r\nX-Name: caf\xc3\xa9\r\n'
+            b'X-Raw: a\xffb\r\n\r\n')
+
+    cap = loop._http_capture(lambda: HttpProto(loop))
+    cap.feed_data(data)
+    run_briefly(loop)
+    headers = cap.requests[0].headers
+    assert headers.encoding == 'utf-8'
+    assert headers['x-name'] == 'café'
+    assert headers['x-raw'] == 'a\udcffb'
+    assert headers.getraw('x-raw') == b'a\xffb'
+    assert headers.getraw('x-missing') is None
+    assert sorted(headers.raw_items()) == [
+        (b'X-Name', b'caf\xc3\xa9'), (b'X-Raw', b'a\xffb')]
+
+    cap = loop._http_capture(
+        lambda: HttpProto(loop), header_encoding='latin-1')
+    cap.feed_data(data)
+    run_briefly(loop)
+    headers = cap.requests[0].headers
+    assert headers['x-name'] == 'cafÃ©'
+    assert headers.get('x-raw') == 'a\xffb'
+
+    cap = loop._http_capture(
+        lambda: HttpProto(loop), header_encoding='bytes')
+    cap.feed_data(data)
+    run_briefly(loop)
+    headers = cap.requests[0].headers
+    assert headers['x-raw'] == b'a\xffb'
+    assert sorted(headers.items())[0] == (b'X-Name', b'caf\xc3\xa9')
+
+    with pytest.raises(ValueError):
+        loop._http_capture(lambda: HttpProto(loop), header_encoding='koi8')
+
+
+def test_http_headers_multidict(loop):
+    cap = loop._http_capture(lambda: HttpProto(loop))
+    cap.feed_data(b'GET / HTTP/1.1\r\nAccept: a\r\nHost: h\r\n'
+                  b'accept: b\r\n\r\n')
+    run_briefly(loop)
+
+    headers = cap.requests[0].headers
+    assert len(headers) == 3
+    assert headers['ACCEPT'] == 'a'
+    assert headers.getall('Accept') == ['a', 'b']
+    assert headers.getone('host') == 'h'
+    assert headers.getall('x-missing', []) == []
+    with pytest.raises(KeyError):
+        headers.getall('x-missing')
+    with pytest.raises(KeyError):
+        headers.getone('x-missing')
+    assert 'HOST' in headers
+    assert list(headers) == headers.keys() == ['Accept', 'Host', 'accept']
+    assert headers.values() == ['a', 'h', 'b']
+    assert headers.items()[2] == ('accept', 'b')
+
+
+def test_http_response_headers(loop):
+    headers = tokio.ResponseHeaders({'Content-Type': 'text/plain'})
+    headers.add('Set-Cookie', 'a=1')
+    headers.add('set-cookie', 'b=2')
+    headers.extend([('X-Count', 1)])
+    assert len(headers) == 4
+    assert headers['content-type'] == 'text/plain'
+    assert headers.getall('SET-COOKIE') == ['a=1', 'b=2']
+    assert headers['x-count'] == '1'
+    assert 'X-COUNT' in headers and 'x-other' not in headers
+    assert list(headers) == [
+        'Content-Type', 'Set-Cookie', 'set-cookie', 'X-Count']
+
+    headers['Content-Type'] = 'text/html'
+    headers['SET-COOKIE'] = 'c=3'
+    assert headers.items() == [
+        ('Content-Type', 'text/html'), ('SET-COOKIE', 'c=3'),
+        ('X-Count', '1')]
+    assert headers.pop('x-count') == '1'
+    assert headers.pop('x-count', None) is None
+    del headers['set-cookie']
+    with pytest.raises(KeyError):
+        del headers['set-cookie']
+    with pytest.raises(KeyError):
+        headers['set-cookie']
+    headers.add('Set-Cookie', 'd=4')
+    headers.add('Set-Cookie', 'e=5')
+
+    class Proto(HttpProto):
+        async def handle(self, req):
+            req.writer.write_headers('HTTP/1.1 200 OK\r\n', headers)
+            await req.writer.write_eof(b'ok')
+
+    cap = loop._http_capture(lambda: Proto(loop))
+    cap.feed_data(b'GET / HTTP/1.0\r\n\r\n')
+    run_briefly(loop)
+    assert cap.responses[0] == (
+        b'HTTP/1.1 200 OK\r\nContent-Type: text/html\r\n'
+        b'Set-Cookie: d=4\r\nSet-Cookie: e=5\r\n\r\nok')
+
+
+def test_http_send_file(loop):
+    with tempfile.TemporaryDirectory() as td:
+        _test_http_send_file(loop, pathlib.Path(td))
+
+
+def _test_http_send_file(loop, root):
+    small = root / 'index.html'
+    small.write_bytes(b'<html></html>')
+    large = root / 'data.bin'
+    large.write_bytes(bytes(range(256)) * 1024)
+    errors = []
+
+    class Proto(HttpProto):
+        async def handle(self, req):
+            if req.path == '/missing':
+                try:
+                    await req.send_file(str(root / 'missing'))
+                except FileNotFoundError as exc:
+                    errors.append(exc)
+                    await req.writer.send_json({}, status=404)
+            elif req.path == '/large':
+                await req.send_file(large, content_type='x/data')
+            else:
+                await req.send_file(str(small))
+
+    cap = loop._http_capture(lambda: Proto(loop))
+    cap.feed_data(b'GET / HTTP/1.1\r\n\r\n'
+                  b'HEAD / HTTP/1.1\r\n\r\n'
+                  b'GET /missing HTTP/1.1\r\n\r\n')
+    run_briefly(loop)
+
+    assert cap.responses[:2] == [
+        b'HTTP/1.1 200 OK\r\nContent-Type: text/html; charset=utf-8\r\n'
+        b'Content-Length: 13\r\n\r\n<html></html>',
+        b'HTTP/1.1 200 OK\r\nContent-Type: text/html; charset=utf-8\r\n'
+        b'Content-Length: 13\r\n\r\n']
+    assert cap.responses[2].startswith(b'HTTP/1.1 404 Not Found\r\n')
+    assert isinstance(errors[0], FileNotFoundError)
+
+    srv = loop.run_until_complete(
+        loop.create_http_server(lambda: Proto(loop), '127.0.0.1', 0))
+    port = srv.sockets[0].getsockname()[1]
+
+    async def client():
+        reader, writer = await asyncio.open_connection(
+            '127.0.0.1', port, loop=loop)
+        writer.write(b'GET /large HTTP/1.1\r\n\r\n')
+        head = await asyncio.wait_for(
+            reader.readuntil(b'\r\n\r\n'), 5, loop=loop)
+        body = await asyncio.wait_for(
+            reader.readexactly(256 * 1024), 5, loop=loop)
+        writer.close()
+        return head, body
+
+    head, body = loop.run_until_complete(client())
+    assert head == (b'HTTP/1.1 200 OK\r\nContent-Type: x/data\r\n'
+                    b'Content-Length: 262144\r\n\r\n')
+    assert body == bytes(range(256)) * 1024
+
+    srv.close()
+
+
+class JsonProto(HttpProto):
+
+    max_size = 1024
+
+    async def handle(self, req):
+        try:
+            data = await req.json(max_size=self.max_size)
+            body = str(data).encode()
+        except ValueError as exc:
+            body = type(exc).__name__.encode()
+        req.writer.write_headers(
+            'HTTP/1.1 200 OK\r\n', {'Content-Length': str(len(body))})
+        await req.writer.write_eof(body)
+
+
+def test_http_request_json(loop):
+    cap = loop._http_capture(lambda: JsonProto(loop))
+    cap.feed_data(b'POST / HTTP/1.1\r\nContent-Length: 11\r\n\r\n{"a": ')
+    run_briefly(loop)
+    cap.feed_data(b'[1]}')
+    run_briefly(loop)
+    cap.feed_data(b'\n')
+    run_briefly(loop)
+
+    assert cap.responses == [
+        b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\n{'a': [1]}"]
+
+
+def test_http_request_json_errors(loop):
+    proto = JsonProto(loop)
+    proto.max_size = 4
+    cap = loop._http_capture(lambda: proto)
+    cap.feed_data(b'POST / HTTP/1.1\r\nContent-Length: 6\r\n\r\n"long"')
+    cap.feed_data(b'POST / HTTP/1.1\r\nContent-Length: 2\r\n\r\n\xff\xfe')
+    run_briefly(loop)
+
+    assert cap.responses == [
+        b'HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nValueError',
+        b'HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nValueError']
+
+
+class SendJsonProto(HttpProto):
+
+    def __init__(self, loop, obj, **kwargs):
+        super().__init__(loop)
+        self.obj = obj
+        self.kwargs = kwargs
+
+    async def handle(self, req):
+        await req.writer.send_json(self.obj, **self.kwargs)
+
+
+def json_response(loop, obj, **kwargs):
+    cap = loop._http_capture(lambda: SendJsonProto(loop, obj, **kwargs))
+    cap.feed_data(b'GET / HTTP/1.1\r\n\r\n')
+    run_briefly(loop)
+
+    head, body = cap.responses[0].split(b'\r\n\r\n', 1)
+    return head.split(b'\r\n'), body
+
+
+def test_http_send_json(loop):
+    obj = {'a': [1, 2.5, None, True, False], 'b': 'q"\né',
+           'c': (10 ** 30, -1)}
+    head, body = json_response(loop, obj)
+
+    assert head == [
+        b'HTTP/1.1 200 OK',
+        b'Content-Type: application/json; charset=utf-8',
+        b'Content-Length: %d' % len(body)]
+    assert json.loads(body.decode()) == {
+        'a': [1, 2.5, None, True, False], 'b': 'q"\né',
+        'c': [10 ** 30, -1]}
+
+
+def test_http_send_json_fallback(loop):
+    head, body = json_response(
+        loop, {1: float('nan')}, status=201,
+        dumps=lambda obj: 'custom')
+
+    assert head[0] == b'HTTP/1.1 201 Created'
+    assert body == b'custom'
+
+
+class EchoProto(asyncio.Protocol):
+
+    def __init__(self, prefix):
+        self.prefix = prefix
+
+    def connection_made(self, transport):
+        self.transport = transport
+
+    def data_received(self, data):
+        self.transport.write(self.prefix + data)
+
+
+def test_sniffing_server(loop):
+    srv = loop.run_until_complete(loop.create_sniffing_server(
+        [('http', lambda: HttpProto(loop)),
+         (b'PING', lambda: EchoProto(b'magic:')),
+         (None, lambda: EchoProto(b'raw:'))],
+        '127.0.0.1', 0, sniff_timeout=0.2))
+    port = srv.sockets[0].getsockname()[1]
+
+    async def request(data, size):
+        reader, writer = await asyncio.open_connection(
+            '127.0.0.1', port, loop=loop)
+        writer.write(data)
+        resp = await asyncio.wait_for(reader.readexactly(size), 5, loop=loop)
+        writer.close()
+        return resp
+
+    resp = b'HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\n!'
+    assert loop.run_until_complete(
+        request(b'GET / HTTP/1.1\r\n\r\n', len(resp))) == resp
+    assert loop.run_until_complete(request(b'PING', 10)) == b'magic:PING'
+    assert loop.run_until_complete(request(b'hello', 9)) == b'raw:hello'
+
+    # incomplete magic, fallback after timeout receives sniffed bytes
+    assert loop.run_until_complete(request(b'PI', 6)) == b'raw:PI'
+
+    srv.close()
+
+
+def test_http_request_remote(loop):
+    requests = []
+
+    class Proto(HttpProto):
+        async def handle(self, req):
+            requests.append(req)
+            await super().handle(req)
+
+    srv = loop.run_until_complete(
+        loop.create_http_server(lambda: Proto(loop), '127.0.0.1', 0))
+    port = srv.sockets[0].getsockname()[1]
+
+    async def request():
+        reader, writer = await asyncio.open_connection(
+            '127.0.0.1', port, loop=loop)
+        writer.write(b'GET / HTTP/1.1\r\n\r\n')
+        await asyncio.wait_for(reader.readexactly(39), 5, loop=loop)
+        local = writer.get_extra_info('sockname')
+        writer.close()
+        return local
+
+    local = loop.run_until_complete(request())
+    assert requests[0].peername == local
+    assert requests[0].remote == '127.0.0.1'
+    srv.close()
+
+    # no socket
+    cap = loop._http_capture(lambda: HttpProto(loop))
+    cap.feed_data(b'GET / HTTP/1.1\r\n\r\n')
+    run_briefly(loop)
+    assert cap.requests[0].peername is None
+    assert cap.requests[0].remote is None
+
+
+def test_http_max_requests(loop):
+    requests = []
+
+    class Proto(HttpProto):
+        async def handle(self, req):
+            requests.append(req)
+            await super().handle(req)
+
+    with pytest.raises(ValueError):
+        loop.run_until_complete(loop.create_http_server(
+            lambda: Proto(loop), '127.0.0.1', 0, max_requests=0))
+
+    srv = loop.run_until_complete(loop.create_http_server(
+        lambda: Proto(loop), '127.0.0.1', 0, max_requests=2))
+    port = srv.sockets[0].getsockname()[1]
+
+    async def pipeline():
+        reader, writer = await asyncio.open_connection(
+            '127.0.0.1', port, loop=loop)
+        writer.write(b'GET /1 HTTP/1.1\r\n\r\n' * 3)
+        data = await asyncio.wait_for(reader.read(), 5, loop=loop)
+        writer.close()
+        return data
+
+    # third request is not processed, connection is closed
+    ok = b'HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\n!'
+    last = b'HTTP/1.1 200 OK\r\nContent-Length: 1\r\nConnection: close\r\n\r\n!'
+    assert loop.run_until_complete(pipeline()) == ok + last
+    assert len(requests) == 2
+    assert requests[0].keep_alive
+    assert not requests[1].keep_alive
+
+    srv.close()
+
+
+def test_http_keepalive_timeout(loop):
+    class Proto(HttpProto):
+        async def handle(self, req):
+            # request in progress is not limited by keep-alive timeout
+            await asyncio.sleep(0.3, loop=self.loop)
+            await super().handle(req)
+
+    srv = loop.run_until_complete(loop.create_http_server(
+        lambda: Proto(loop), '127.0.0.1', 0, keepalive_timeout=0.1))
+    port = srv.sockets[0].getsockname()[1]
+
+    async def client(request, *parts):
+        reader, writer = await asyncio.open_connection(
+            '127.0.0.1', port, loop=loop)
+        started = loop.time()
+        writer.write(request)
+        for part in parts:
+            await asyncio.sleep(0.15, loop=loop)
+            writer.write(part)
+        data = await asyncio.wait_for(reader.read(), 5, loop=loop)
+        writer.close()
+        return data, loop.time() - started
+
+    # idle connection is closed after response
+    data, elapsed = loop.run_until_complete(
+        client(b'GET / HTTP/1.1\r\n\r\n'))
+    assert data == b'HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\n!'
+    assert 0.3 < elapsed < 2
+
+    # request head which is being received is not idle time
+    data, elapsed = loop.run_until_complete(
+        client(b'GET / HTTP/1.1\r\n', b'Host: example.com\r\n', b'\r\n'))
+    assert data == b'HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\n!'
+
+    # connection without request
+    data, elapsed = loop.run_until_complete(client(b''))
+    assert data == b''
+    assert elapsed < 2
+
+    srv.close()
+
+
+def test_http_request_timeouts(loop):
+    protos = []
+
+    class Proto(HttpProto):
+        exc = payload_exc = None
+
+        def connection_lost(self, exc):
+            self.exc = exc
+
+        async def handle(self, req):
+            try:
+                await super().handle(req)
+            except tokio.HttpError as exc:
+                self.payload_exc = exc
+
+    def factory():
+        protos.append(Proto(loop))
+        return protos[-1]
+
+    srv = loop.run_until_complete(loop.create_http_server(
+        factory, '127.0.0.1', 0,
+        header_timeout=0.1, body_timeout=0.2, body_min_rate=100))
+    port = srv.sockets[0].getsockname()[1]
+
+    async def client(*chunks, size=None):
+        reader, writer = await asyncio.open_connection(
+            '127.0.0.1', port, loop=loop)
+        started = loop.time()
+        for chunk in chunks:
+            writer.write(chunk)
+            await asyncio.sleep(0.05, loop=loop)
+        read = reader.read() if size is None else reader.readexactly(size)
+        data = await asyncio.wait_for(read, 5, loop=loop)
+        writer.close()
+        return data, loop.time() - started
+
+    timeout = (b'HTTP/1.1 408 Request Timeout\r\n'
+               b'Connection: close\r\nContent-Length: 0\r\n\r\n')
+
+    # headers are not completed
+    data, elapsed = loop.run_until_complete(
+        client(b'GET / HTTP/1.1\r\n', b'Host: example.com\r\n'))
+    assert data == timeout
+    assert elapsed < 2
+    assert isinstance(protos[-1].exc, tokio.ServerTimeoutError)
+
+    # body is not completed
+    data, elapsed = loop.run_until_complete(
+        client(b'POST / HTTP/1.1\r\nContent-Length: 100\r\n\r\n', b'data'))
+    assert data == timeout
+    assert elapsed < 2
+    assert isinstance(protos[-1].exc, tokio.ServerTimeoutError)
+    assert protos[-1].payload_exc is not None
+
+    # body which is sent at allowed rate
+    response = (b'HTTP/1.1 200 OK\r\nContent-Length: 61\r\n\r\n' +
+                b'0123456789' * 6 + b'!')
+    data, elapsed = loop.run_until_complete(
+        client(b'POST / HTTP/1.1\r\nContent-Length: 60\r\n\r\n',
+               *[b'0123456789'] * 6, size=len(response)))
+    assert data == response
+
+    with pytest.raises(ValueError):
+        loop.run_until_complete(loop.create_http_server(
+            factory, '127.0.0.1', 0, body_min_rate=100))
+
+    srv.close()
+
+
+def test_http_bad_requests(loop):
+    protos = []
+
+    class Proto(HttpProto):
+        exc = None
+
+        def connection_lost(self, exc):
+            self.exc = exc
+
+    def factory():
+        protos.append(Proto(loop))
+        return protos[-1]
+
+    srv = loop.run_until_complete(loop.create_http_server(
+        factory, '127.0.0.1', 0, max_body_size=10))
+    port = srv.sockets[0].getsockname()[1]
+
+    async def client(request, size=None):
+        reader, writer = await asyncio.open_connection(
+            '127.0.0.1', port, loop=loop)
+        writer.write(request)
+        read = reader.read() if size is None else reader.readexactly(size)
+        data = await asyncio.wait_for(read, 5, loop=loop)
+        writer.close()
+        return data
+
+    def response(status):
+        return ('HTTP/1.1 {}\r\nConnection: close\r\n'
+                'Content-Length: 0\r\n\r\n'.format(status)).encode()
+
+    data = loop.run_until_complete(client(b'GET / HTTP/3.0\r\n\r\n'))
+    assert data == response('400 Bad Request')
+    assert isinstance(protos[-1].exc, tokio.HttpParseError)
+
+    data = loop.run_until_complete(
+        client(b'GET / HTTP/1.1\r\nx-test: ' + b'x' * 10000 + b'\r\n\r\n'))
+    assert data == response('431 Request Header Fields Too Large')
+
+    data = loop.run_until_complete(
+        client(b'POST / HTTP/1.1\r\nContent-Length: 11\r\n\r\n'))
+    assert data == response('413 Payload Too Large')
+
+    # body within limit
+    ok = b'HTTP/1.1 200 OK\r\nContent-Length: 11\r\n\r\n0123456789!'
+    data = loop.run_until_complete(client(
+        b'POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\n0123456789',
+        size=len(ok)))
+    assert data == ok
+
+    # pipelined request is answered before error response
+    data = loop.run_until_complete(
+        client(b'GET / HTTP/1.1\r\n\r\nGET / HTTP/3.0\r\n\r\n'))
+    assert data == (b'HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\n!' +
+                    response('400 Bad Request'))
+
+    assert srv.total_bad_requests == 4
+    srv.close()
+
+
+def test_http_connect_detach(loop):
+    requests = []
+    detached = []
+
+    class Proto(HttpProto):
+        async def handle(self, req):
+            requests.append(req)
+            req.writer.write_headers(
+                'HTTP/1.1 200 Connection Established\r\n', {})
+            await req.writer.write_eof()
+            detached.append(await req.detach(lambda: EchoProto(b'tunnel:')))
+
+    srv = loop.run_until_complete(
+        loop.create_http_server(lambda: Proto(loop), '127.0.0.1', 0))
+    port = srv.sockets[0].getsockname()[1]
+
+    async def request():
+        reader, writer = await asyncio.open_connection(
+            '127.0.0.1', port, loop=loop)
+        writer.write(b'CONNECT example.com:80 HTTP/1.1\r\n\r\nearly')
+        head = await asyncio.wait_for(
+            reader.readuntil(b'\r\n\r\n'), 5, loop=loop)
+        # data sent with request is passed to new protocol
+        early = await asyncio.wait_for(reader.readexactly(12), 5, loop=loop)
+        writer.write(b'late')
+        late = await asyncio.wait_for(reader.readexactly(11), 5, loop=loop)
+        writer.close()
+        return head, early, late
+
+    head, early, late = loop.run_until_complete(request())
+    assert head == b'HTTP/1.1 200 Connection Established\r\n\r\n'
+    assert early == b'tunnel:early'
+    assert late == b'tunnel:late'
+
+    assert requests[0].upgrade
+    transport, proto = detached[0]
+    assert isinstance(proto, EchoProto)
+    assert proto.transport is transport
+
+    srv.close()
+
+    # regular request can not be detached
+    cap = loop._http_capture(lambda: HttpProto(loop))
+    cap.feed_data(b'GET / HTTP/1.1\r\n\r\n')
+    run_briefly(loop)
+    assert not cap.requests[0].upgrade
+    with pytest.raises(RuntimeError):
+        cap.requests[0].detach(lambda: EchoProto(b''))
+
+
+def test_http_upgrade_declined(loop):
+    # upgrade request is answered without "101 Switching Protocols",
+    # its body is delivered and pipelined request is parsed
+    cap = loop._http_capture(lambda: HttpProto(loop))
+    cap.feed_data(b'POST / HTTP/1.1\r\n'
+                  b'Connection: Upgrade\r\n'
+                  b'Upgrade: h2c\r\n'
+                  b'Content-Length: 4\r\n\r\nbody'
+                  b'GET /next HTTP/1.1\r\n\r\n')
+    run_briefly(loop)
+
+    assert len(cap.requests) == 2
+    assert cap.requests[0].upgrade
+    assert not cap.requests[1].upgrade
+    assert cap.requests[1].path == '/next'
+    assert cap.responses == [
+        b'HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nbody!',
+        b'HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\n!']
+
+
+WS_HANDSHAKE = (b'GET /ws HTTP/1.1\r\n'
+                b'Connection: Upgrade\r\n'
+                b'Upgrade: websocket\r\n'
+                b'Sec-WebSocket-Version: 13\r\n'
+                b'Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n'
+                b'Sec-WebSocket-Protocol: chat, echo\r\n\r\n')
+
+
+def ws_frame(opcode, data, fin=True):
+    mask = b'\x37\xfa\x21\x3d'
+    head = bytes([(0x80 if fin else 0) | opcode, 0x80 | len(data)])
+    return head + mask + bytes(b ^ mask[i % 4] for i, b in enumerate(data))
+
+
+async def ws_read_frame(reader):
+    head = await reader.readexactly(2)
+    return head[0], await reader.readexactly(head[1] & 0x7f)
+
+
+def test_http_websocket(loop):
+    sockets = []
+
+    class Proto(HttpProto):
+        async def handle(self, req):
+            ws = await req.accept_websocket(protocols=('echo',))
+            sockets.append(ws)
+            while True:
+                msg = await ws.receive()
+                if msg is None:
+                    break
+                if isinstance(msg, str):
+                    ws.send_text('text:' + msg)
+                else:
+                    ws.send_bytes(b'bytes:' + msg)
+
+    srv = loop.run_until_complete(
+        loop.create_http_server(lambda: Proto(loop), '127.0.0.1', 0))
+    port = srv.sockets[0].getsockname()[1]
+
+    async def client():
+        reader, writer = await asyncio.open_connection(
+            '127.0.0.1', port, loop=loop)
+        writer.write(WS_HANDSHAKE + ws_frame(0x1, b'early'))
+        head = await asyncio.wait_for(
+            reader.readuntil(b'\r\n\r\n'), 5, loop=loop)
+
+        frames = [await asyncio.wait_for(ws_read_frame(reader), 5, loop=loop)]
+        # fragmented message, ping between fragments
+        writer.write(ws_frame(0x2, b'da', fin=False) + ws_frame(0x9, b'p') +
+                     ws_frame(0x0, b'ta'))
+        frames.append(await ws_read_frame(reader))
+        frames.append(await ws_read_frame(reader))
+
+        writer.write(ws_frame(0x8, b'\x03\xe8'))
+        frames.append(await ws_read_frame(reader))
+        rest = await asyncio.wait_for(reader.read(), 5, loop=loop)
+        writer.close()
+        return head, frames, rest
+
+    head, frames, rest = loop.run_until_complete(client())
+    assert head == (b'HTTP/1.1 101 Switching Protocols\r\n'
+                    b'Upgrade: websocket\r\n'
+                    b'Connection: Upgrade\r\n'
+                    b'Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n'
+                    b'Sec-WebSocket-Protocol: echo\r\n\r\n')
+    assert frames == [(0x81, b'text:early'), (0x8a, b'p'),
+                      (0x82, b'bytes:data'), (0x88, b'\x03\xe8')]
+    assert rest == b''
+
+    ws = sockets[0]
+    assert ws.protocol == 'echo'
+    assert ws.close_code == 1000
+    assert ws.closed
+
+    srv.close()
+
+
+def test_http_websocket_errors(loop):
+    errors = []
+
+    class Proto(HttpProto):
+        async def handle(self, req):
+            try:
+                ws = await req.accept_websocket()
+            except tokio.WebSocketError as exc:
+                errors.append(exc)
+                req.writer.write_headers(
+                    'HTTP/1.1 400 Bad Request\r\n', {'Content-Length': '0'})
+                await req.writer.write_eof()
+                return
+            try:
+                await ws.receive()
+            except tokio.WebSocketError as exc:
+                errors.append(exc)
+
+    # handshake without websocket key
+    cap = loop._http_capture(lambda: Proto(loop))
+    cap.feed_data(WS_HANDSHAKE.replace(b'Sec-WebSocket-Key', b'X-Key'))
+    run_briefly(loop)
+    assert isinstance(errors[0], tokio.WebSocketError)
+    assert cap.responses[0].startswith(b'HTTP/1.1 400 Bad Request\r\n')
+
+    srv = loop.run_until_complete(
+        loop.create_http_server(lambda: Proto(loop), '127.0.0.1', 0))
+    port = srv.sockets[0].getsockname()[1]
+
+    async def client():
+        reader, writer = await asyncio.open_connection(
+            '127.0.0.1', port, loop=loop)
+        # unmasked frame is protocol error
+        writer.write(WS_HANDSHAKE + b'\x81\x02ok')
+        await asyncio.wait_for(reader.readuntil(b'\r\n\r\n'), 5, loop=loop)
+        frame = await asyncio.wait_for(ws_read_frame(reader), 5, loop=loop)
+        # connection is closed after close frame
+        assert await asyncio.wait_for(reader.read(), 5, loop=loop) == b''
+        writer.close()
+        return frame
+
+    assert loop.run_until_complete(client()) == (0x88, b'\x03\xea')
+    assert isinstance(errors[1], tokio.WebSocketError)
+
+    srv.close()
+
+
+def test_http_websocket_deflate(loop):
+    class Proto(HttpProto):
+        async def handle(self, req):
+            ws = await req.accept_websocket()
+            msg = await ws.receive()
+            ws.send_text(msg * 2)
+            await ws.close()
+
+    srv = loop.run_until_complete(
+        loop.create_http_server(lambda: Proto(loop), '127.0.0.1', 0))
+    port = srv.sockets[0].getsockname()[1]
+
+    comp = zlib.compressobj(wbits=-15)
+    data = comp.compress(b'hello hello') + comp.flush(zlib.Z_SYNC_FLUSH)
+    handshake = WS_HANDSHAKE.replace(
+        b'\r\n\r\n', b'\r\nSec-WebSocket-Extensions: '
+        b'permessage-deflate; client_max_window_bits\r\n\r\n')
+
+    async def client():
+        reader, writer = await asyncio.open_connection(
+            '127.0.0.1', port, loop=loop)
+        writer.write(handshake + ws_frame(0x41, data[:-4]))
+        head = await asyncio.wait_for(
+            reader.readuntil(b'\r\n\r\n'), 5, loop=loop)
+        frame = await asyncio.wait_for(ws_read_frame(reader), 5, loop=loop)
+        writer.close()
+        return head, frame
+
+    head, (first, payload) = loop.run_until_complete(client())
+    assert (b'Sec-WebSocket-Extensions: permessage-deflate\r\n'
+            in head)
+    assert first == 0xc1
+    decomp = zlib.decompressobj(-15)
+    assert decomp.decompress(payload + b'\x00\x00\xff\xff') == (
+        b'hello hellohello hello')
+
+    srv.close()
+
+
+def test_sniffing_server_routes(loop):
+    with pytest.raises(ValueError):
+        loop.create_sniffing_server([('ftp', None)], '127.0.0.1', 0)
+    with pytest.raises(ValueError):
+        loop.create_sniffing_server([], '127.0.0.1', 0)
+    with pytest.raises(TypeError):
+        loop.create_sniffing_server([(1, None)], '127.0.0.1', 0)
+
+
+class SpanProto(HttpProto):
+
+    async def handle(self, req):
+        req.span.annotate('user', 'bob')
+        child = req.span.start_child('db')
+        child.annotate('rows', 3)
+        child.finish()
+        req.span.start_child('render')
+        await super().handle(req)
+
+
+def test_http_request_span(loop):
+    spans = []
+    assert loop.get_span_exporter() is None
+    loop.set_span_exporter(spans.append)
+    with pytest.raises(TypeError):
+        loop.set_span_exporter(1)
+
+    cap = loop._http_capture(lambda: SpanProto(loop))
+    cap.feed_data(b'GET /test HTTP/1.1\r\n\r\n')
+    run_briefly(loop)
+
+    assert len(spans) == 1
+    span = spans[0]
+    assert span is cap.requests[0].span
+    assert span.name == 'GET /test'
+    assert span.annotations == {'user': 'bob'}
+    assert [c.name for c in span.children] == ['db', 'render']
+    assert span.duration is not None
+    assert all(c.duration is not None for c in span.children)
+
+    data = span.to_dict()
+    assert data['name'] == 'GET /test'
+    assert data['children'][0]['annotations'] == {'rows': 3}
+
+    loop.set_span_exporter(None)
+    cap.feed_data(b'GET /test HTTP/1.1\r\n\r\n')
+    run_briefly(loop)
+    assert len(spans) == 1
+
+
+class ParserProto:
+
+    def __init__(self):
+        self.events = []
+
+    def on_message_begin(self):
+        self.events.append('begin')
+
+    def on_url(self, url):
+        self.events.append(('url', url))
+
+    def on_status(self, reason):
+        self.events.append(('status', reason))
+
+    def on_header(self, name, value):
+        self.events.append((name.lower(), value))
+
+    def on_headers_complete(self):
+        self.events.append('headers')
+
+    def on_body(self, body):
+        self.events.append(body)
+
+    def on_message_complete(self):
+        self.events.append('complete')
+
+
+def test_http_request_parser():
+    proto = ParserProto()
+    parser = tokio.HttpRequestParser(proto)
+    parser.feed_data(b'POST /path?q=1 HTTP/1.0\r\nContent-Length: 4\r\n\r\nda')
+    parser.feed_data(b'ta')
+
+    assert proto.events == [
+        'begin', ('url', b'/path?q=1'), (b'content-length', b'4'),
+        'headers', b'da', b'ta', 'complete']
+    assert parser.get_method() == 'POST'
+    assert parser.get_http_version() == (1, 0)
+    assert not parser.should_keep_alive()
+
+    # callbacks are optional
+    parser = tokio.HttpRequestParser(object())
+    parser.feed_data(b'GET / HTTP/1.1\r\n\r\n')
+    assert parser.should_keep_alive()
+
+    with pytest.raises(tokio.HttpParseError):
+        parser.feed_data(b'GET / HTTP/3.0\r\n\r\n')
+
+
+def test_http_response_parser():
+    proto = ParserProto()
+    parser = tokio.HttpResponseParser(proto)
+    parser.feed_data(b'HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n'
+                     b'4\r\ndata\r\n0\r\n\r\n')
+
+    assert proto.events == [
+        'begin', ('status', b'OK'), (b'transfer-encoding', b'chunked'),
+        'headers', b'data', 'complete']
+    assert parser.get_status_code() == 200
+    assert parser.get_http_version() == (1, 1)
+
+    # body delimited by connection close
+    proto = ParserProto()
+    parser = tokio.HttpResponseParser(proto)
+    parser.feed_data(b'HTTP/1.1 404 Not Found\r\nConnection: close\r\n\r\nbody')
+    assert not parser.should_keep_alive()
+    parser.feed_eof()
+    assert proto.events[-2:] == [b'body', 'complete']
+    assert parser.get_status_code() == 404
+
+    parser = tokio.HttpResponseParser(proto)
+    parser.feed_data(b'HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nbody')
+    with pytest.raises(tokio.PayloadError):
+        parser.feed_eof()
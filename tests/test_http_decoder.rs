@@ -468,21 +468,21 @@ test! { test_max_header_name_size,
         "GET /test HTTP/1.1\r\n" => |codec, buf| {
             buf.extend([b't'; 10 * 1024][..].as_ref());
             buf.extend(b":data\r\n\r\n");
-            expect_error!(codec(buf): Error::LineTooLong);
+            expect_error!(codec(buf): Error::HeaderTooLarge);
         }}
 
 test! { test_max_header_value_size,
         "GET /test HTTP/1.1\r\n" => |codec, buf| {
             buf.extend(b"header:");
             buf.extend([b't'; 10 * 1024][..].as_ref());
-            expect_error!(codec(buf): Error::LineTooLong);
+            expect_error!(codec(buf): Error::HeaderTooLarge);
         }}
 
 test! { test_max_header_value_size_continuation,
         "GET /test HTTP/1.1\r\n" => |codec, buf| {
             buf.extend(b"header: test\r\n ");
             buf.extend([b't'; 10 * 1024][..].as_ref());
-            expect_error!(codec(buf): Error::LineTooLong);
+            expect_error!(codec(buf): Error::HeaderTooLarge);
         }}
 
 test! { test_http_request_bad_status_line,
@@ -558,6 +558,32 @@ test! { test_http_request_chunked_payload,
         }}
 
 
+test! { test_http_request_max_body_size,
+        "POST /test HTTP/1.1\r\n",
+        "content-length: 4\r\n\r\n" => |codec, buf| {
+            codec.set_max_body_size(Some(3));
+            expect_error!(codec(buf): Error::BodyTooLarge);
+        }}
+
+test! { test_http_request_max_body_size_chunked,
+        "POST /test HTTP/1.1\r\n",
+        "transfer-encoding: chunked\r\n\r\n",
+        "2\r\nda\r\n2\r\nta\r\n" => |codec, buf| {
+            codec.set_max_body_size(Some(3));
+            expect_status!(msg => codec(buf) => "POST", "/test", Version::Http11);
+            expect_body!(codec(buf): "da");
+            expect_error!(codec(buf): Error::BodyTooLarge);
+        }}
+
+#[test]
+fn test_error_status() {
+    assert_eq!(Error::BadStatusLine.status(), Some("400 Bad Request"));
+    assert_eq!(Error::LineTooLong.status(), Some("400 Bad Request"));
+    assert_eq!(Error::HeaderTooLarge.status(), Some("431 Request Header Fields Too Large"));
+    assert_eq!(Error::BodyTooLarge.status(), Some("413 Payload Too Large"));
+    assert_eq!(Error::PayloadNotCompleted.status(), None);
+}
+
 test! { test_http_request_chunked_payload_and_next_message,
         "GET /test HTTP/1.1\r\n",
         "transfer-encoding: chunked\r\n\r\n" => |codec, buf| {
@@ -618,6 +644,81 @@ test! { test_parse_chunked_payload_chunk_extension,
             expect_completed!(codec(buf));
         }}
 
+test! { test_parse_chunked_payload_max_trailer_line_size,
+        "POST /test HTTP/1.1\r\n",
+        "transfer-encoding: chunked\r\n\r\n",
+        "4\r\ndata\r\n0\r\n" => |codec, buf| {
+            expect_status!(msg => codec(buf) => "POST", "/test", Version::Http11);
+            expect_headers!(msg => chunked:true, ("transfer-encoding", "chunked"));
+            expect_body!(codec(buf): "data");
+
+            buf.extend(b"trailer: ");
+            buf.extend([b't'; 10 * 1024][..].as_ref());
+            expect_error!(codec(buf): Error::HeaderTooLarge);
+        }}
+
+test! { test_parse_chunked_payload_max_trailers_size,
+        "POST /test HTTP/1.1\r\n",
+        "transfer-encoding: chunked\r\n\r\n",
+        "4\r\ndata\r\n0\r\n" => |codec, buf| {
+            expect_status!(msg => codec(buf) => "POST", "/test", Version::Http11);
+            expect_headers!(msg => chunked:true, ("transfer-encoding", "chunked"));
+            expect_body!(codec(buf): "data");
+
+            for _ in 0..64 {
+                buf.extend(b"trailer: ");
+                buf.extend([b't'; 1024][..].as_ref());
+                buf.extend(b"\r\n");
+            }
+            expect_error!(codec(buf): Error::HeaderTooLarge);
+        }}
+
+test! { test_parse_chunked_payload_split_crlf,
+        "POST /test HTTP/1.1\r\n",
+        "transfer-encoding: chunked\r\n\r\n" => |codec, buf| {
+            expect_status!(msg => codec(buf) => "POST", "/test", Version::Http11);
+            expect_headers!(msg => chunked:true, ("transfer-encoding", "chunked"));
+
+            buf.extend(b"4\r\ndata");
+            expect_body!(codec(buf): "data");
+            expect_none!(codec(buf));
+
+            buf.extend(b"\r\n");
+            expect_none!(codec(buf));
+
+            buf.extend(b"0\r\n\r\n");
+            expect_completed!(codec(buf));
+        }}
+
+test! { test_parse_chunked_payload_uppercase_size,
+        "POST /test HTTP/1.1\r\n",
+        "transfer-encoding: chunked\r\n\r\n",
+        "A ; name=value\r\n0123456789\r\n0\r\n\r\n" => |codec, buf| {
+            expect_status!(msg => codec(buf) => "POST", "/test", Version::Http11);
+            expect_headers!(msg => chunked:true, ("transfer-encoding", "chunked"));
+            expect_body!(codec(buf): "0123456789");
+            expect_completed!(codec(buf));
+        }}
+
+test! { test_parse_chunked_payload_size_overflow,
+        "POST /test HTTP/1.1\r\n",
+        "transfer-encoding: chunked\r\n\r\n",
+        "ffffffffffffffffff" => |codec, buf| {
+            expect_status!(msg => codec(buf) => "POST", "/test", Version::Http11);
+            expect_headers!(msg => chunked:true, ("transfer-encoding", "chunked"));
+            expect_error!(codec(buf): Error::TransferEncoding);
+        }}
+
+test! { test_parse_chunked_payload_eof,
+        "POST /test HTTP/1.1\r\n",
+        "transfer-encoding: chunked\r\n\r\n",
+        "4\r\nda" => |codec, buf| {
+            expect_status!(msg => codec(buf) => "POST", "/test", Version::Http11);
+            expect_headers!(msg => chunked:true, ("transfer-encoding", "chunked"));
+            expect_body!(codec(buf): "da");
+            expect_eof_error!(codec(buf): Error::PayloadNotCompleted);
+        }}
+
 test! { test_parse_length_payload,
         "GET /path HTTP/1.1\r\n",
         "content-length: 4\r\n\r\n" => |codec, buf| {
@@ -691,6 +792,67 @@ test! { test_http_request_websocket,
             expect_completed!(codec(buf));
         }}
 
+test! { test_http_request_upgrade,
+        "GET /path HTTP/1.1\r\n",
+        "Connection: Upgrade\r\n",
+        "Upgrade: tunnel\r\n\r\n",
+        "raw data" => |codec, buf| {
+            expect_status!(msg => codec(buf) => "GET", "/path", Version::Http11);
+            expect_headers!(msg => conn:ConnectionType::Upgrade,
+                            ("connection", "Upgrade"), ("upgrade", "tunnel"));
+            assert_eq!(msg.upgrade, true);
+            expect_completed!(codec(buf));
+            expect_none!(codec(buf));
+            assert_eq!(buf[..], b"raw data"[..]);
+        }}
+
+
+test! { test_http_request_connect,
+        "CONNECT example.com:443 HTTP/1.1\r\n",
+        "Content-Length: 4\r\n\r\n",
+        "bodyraw data" => |codec, buf| {
+            expect_status!(msg => codec(buf) => "CONNECT", "example.com:443", Version::Http11);
+            expect_headers!(msg => ("content-length", "4"));
+            assert_eq!(msg.upgrade, true);
+            expect_body!(codec(buf): "body");
+            expect_completed!(codec(buf));
+            expect_none!(codec(buf));
+            assert_eq!(buf[..], b"raw data"[..]);
+        }}
+
+test! { test_http_request_upgrade_resume,
+        "GET /path HTTP/1.1\r\n",
+        "Connection: Upgrade\r\n",
+        "Upgrade: h2c\r\n\r\n",
+        "GET /next HTTP/1.1\r\n\r\n" => |codec, buf| {
+            expect_status!(msg => codec(buf) => "GET", "/path", Version::Http11);
+            assert_eq!(msg.upgrade, true);
+            expect_completed!(codec(buf));
+            expect_none!(codec(buf));
+            assert!(codec.tunnel());
+
+            // declined upgrade, next request is parsed
+            codec.resume();
+            expect_status!(msg => codec(buf) => "GET", "/next", Version::Http11);
+            assert_eq!(msg.upgrade, false);
+            expect_completed!(codec(buf));
+            assert!(!codec.tunnel());
+        }}
+
+test! { test_http_request_repeated_headers,
+        "GET / HTTP/1.1\r\n",
+        "Set-Cookie: a=1\r\n",
+        "Host: example.com\r\n",
+        "set-cookie: b=2\r\n\r\n" => |codec, buf| {
+            expect_status!(msg => codec(buf) => "GET", "/", Version::Http11);
+            assert_eq!(msg.headers.len(), 3);
+            assert_eq!(msg.headers.get("SET-COOKIE"), Some("a=1"));
+            assert_eq!(msg.headers.get_all_raw("Set-Cookie"), vec![&b"a=1"[..], &b"b=2"[..]]);
+            assert!(msg.headers.get_all_raw("cookie").is_empty());
+            let names: Vec<_> = msg.headers.raw_headers().into_iter().map(|(n, _)| n).collect();
+            assert_eq!(names, vec![&b"Set-Cookie"[..], &b"Host"[..], &b"set-cookie"[..]]);
+        }}
+
 //_comp = zlib.compressobj(wbits=-zlib.MAX_WBITS)
 //_COMPRESSED = b''.join([_comp.compress(b'data'), _comp.flush()])
 
@@ -0,0 +1,111 @@
+extern crate bytes;
+extern crate tokio_io;
+extern crate async_tokio;
+
+use bytes::BytesMut;
+use tokio_io::codec::Decoder;
+use async_tokio::http::{
+    ConnectionType, Error, ResponseDecoder, ResponseMessage, Response, Version};
+
+
+fn expect_response(codec: &mut ResponseDecoder, buf: &mut BytesMut) -> Response {
+    match codec.decode(buf) {
+        Ok(Some(ResponseMessage::Message(resp))) => resp,
+        res => panic!("Response is expected, got {:?}", res),
+    }
+}
+
+fn expect_body(codec: &mut ResponseDecoder, buf: &mut BytesMut, body: &[u8]) {
+    match codec.decode(buf) {
+        Ok(Some(ResponseMessage::Body(data))) => assert_eq!(&data[..], body),
+        res => panic!("Body is expected, got {:?}", res),
+    }
+}
+
+fn expect_completed(codec: &mut ResponseDecoder, buf: &mut BytesMut) {
+    match codec.decode(buf) {
+        Ok(Some(ResponseMessage::Completed)) => (),
+        res => panic!("Completed is expected, got {:?}", res),
+    }
+}
+
+
+#[test]
+fn test_parse_response() {
+    let mut codec = ResponseDecoder::new();
+    let mut buf = BytesMut::from(
+        "HTTP/1.1 200 OK\r\nContent-Length: 4\r\nX-Test:  value \r\n\r\nda");
+
+    let resp = expect_response(&mut codec, &mut buf);
+    assert_eq!(resp.version, Version::Http11);
+    assert_eq!(resp.status, 200);
+    assert_eq!(&resp.reason[..], b"OK");
+    assert_eq!(resp.connection, ConnectionType::KeepAlive);
+    assert_eq!(resp.headers.len(), 2);
+    assert_eq!(&resp.headers[1].0[..], b"X-Test");
+    assert_eq!(&resp.headers[1].1[..], b"value");
+
+    expect_body(&mut codec, &mut buf, b"da");
+    assert!(codec.decode(&mut buf).unwrap().is_none());
+
+    buf.extend(b"taHTTP/1.1 204 No Content\r\n\r\n");
+    expect_body(&mut codec, &mut buf, b"ta");
+    expect_completed(&mut codec, &mut buf);
+
+    let resp = expect_response(&mut codec, &mut buf);
+    assert_eq!(resp.status, 204);
+    expect_completed(&mut codec, &mut buf);
+    assert!(buf.is_empty());
+}
+
+#[test]
+fn test_parse_response_chunked() {
+    let mut codec = ResponseDecoder::new();
+    let mut buf = BytesMut::from(
+        "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n4;ext=1\r\ndata\r\n");
+
+    let resp = expect_response(&mut codec, &mut buf);
+    assert!(resp.chunked);
+    expect_body(&mut codec, &mut buf, b"data");
+    assert!(codec.decode(&mut buf).unwrap().is_none());
+
+    buf.extend(b"0\r\nTrailer: 1\r\n\r\n");
+    expect_completed(&mut codec, &mut buf);
+    assert!(buf.is_empty());
+}
+
+#[test]
+fn test_parse_response_eof() {
+    let mut codec = ResponseDecoder::new();
+    let mut buf = BytesMut::from("HTTP/1.0 200 OK\r\n\r\nbody");
+
+    let resp = expect_response(&mut codec, &mut buf);
+    assert_eq!(resp.version, Version::Http10);
+    assert_eq!(resp.connection, ConnectionType::Close);
+    expect_body(&mut codec, &mut buf, b"body");
+
+    match codec.decode_eof(&mut buf) {
+        Ok(Some(ResponseMessage::Completed)) => (),
+        res => panic!("Completed is expected, got {:?}", res),
+    }
+    assert!(codec.decode_eof(&mut buf).unwrap().is_none());
+}
+
+#[test]
+fn test_parse_response_errors() {
+    let mut codec = ResponseDecoder::new();
+    let mut buf = BytesMut::from("HTTP/2.0 200 OK\r\n\r\n");
+    match codec.decode(&mut buf) {
+        Err(Error::BadStatusLine) => (),
+        res => panic!("BadStatusLine is expected, got {:?}", res),
+    }
+
+    let mut codec = ResponseDecoder::new();
+    let mut buf = BytesMut::from("HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\nda");
+    expect_response(&mut codec, &mut buf);
+    expect_body(&mut codec, &mut buf, b"da");
+    match codec.decode_eof(&mut buf) {
+        Err(Error::PayloadNotCompleted) => (),
+        res => panic!("PayloadNotCompleted is expected, got {:?}", res),
+    }
+}
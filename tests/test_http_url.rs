@@ -0,0 +1,51 @@
+extern crate async_tokio;
+
+use async_tokio::http::{RequestTarget, parse_query, percent_decode};
+
+
+#[test]
+fn test_origin_form() {
+    let target = RequestTarget::parse("/a%20b/c?x=1&y=%2B#top");
+    assert_eq!(target.raw_path, "/a%20b/c?x=1&y=%2B");
+    assert_eq!(target.path, "/a%20b/c");
+    assert_eq!(target.query, "x=1&y=%2B");
+    assert_eq!(target.fragment, "top");
+    assert_eq!(target.decoded_path(), "/a b/c");
+}
+
+#[test]
+fn test_absolute_form() {
+    let target = RequestTarget::parse("http://example.com:8080/path?q=1");
+    assert_eq!(target.raw_path, "/path?q=1");
+    assert_eq!(target.path, "/path");
+    assert_eq!(target.query, "q=1");
+
+    let target = RequestTarget::parse("https://example.com");
+    assert_eq!(target.raw_path, "/");
+    assert_eq!(target.path, "/");
+
+    // authority and asterisk forms
+    assert_eq!(RequestTarget::parse("example.com:443").path, "example.com:443");
+    assert_eq!(RequestTarget::parse("*").path, "*");
+}
+
+#[test]
+fn test_percent_decode() {
+    assert_eq!(percent_decode("a+b%2Fc", false), "a+b/c");
+    assert_eq!(percent_decode("a+b%2Fc", true), "a b/c");
+    assert_eq!(percent_decode("%e2%82%ac", false), "\u{20ac}");
+    // invalid escapes are kept
+    assert_eq!(percent_decode("%2x%+1%", false), "%2x%+1%");
+    assert_eq!(percent_decode("%ff", false), "\u{fffd}");
+}
+
+#[test]
+fn test_parse_query() {
+    let pairs = |items: &[(&str, &str)]| -> Vec<(String, String)> {
+        items.iter().map(|&(n, v)| (n.to_owned(), v.to_owned())).collect()
+    };
+    assert_eq!(parse_query("a=1&b=x+y&a=%32"), pairs(&[("a", "1"), ("b", "x y"), ("a", "2")]));
+    assert_eq!(parse_query("flag&&empty=&=v"), pairs(&[("flag", ""), ("empty", ""), ("", "v")]));
+    assert_eq!(parse_query("x=a=b"), pairs(&[("x", "a=b")]));
+    assert!(parse_query("").is_empty());
+}
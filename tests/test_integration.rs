@@ -0,0 +1,116 @@
+extern crate pyo3;
+extern crate async_tokio;
+
+mod harness;
+
+use pyo3::*;
+use harness::Scope;
+
+
+#[test]
+fn test_tcp_protocol_callbacks() {
+    let gil = Python::acquire_gil();
+    let scope = Scope::new(gil.python());
+
+    scope.run("
+events = []
+
+class Echo(asyncio.Protocol):
+    def connection_made(self, transport):
+        self.transport = transport
+        events.append('connection_made')
+
+    def data_received(self, data):
+        events.append(('data_received', data))
+        self.transport.write(data)
+
+    def eof_received(self):
+        events.append('eof_received')
+
+    def connection_lost(self, exc):
+        events.append(('connection_lost', exc))
+
+srv = run(loop.create_server(Echo, '127.0.0.1', 0))
+addr = srv.sockets[0].getsockname()
+
+async def client():
+    reader, writer = await asyncio.open_connection(*addr, loop=loop)
+    writer.write(b'ping')
+    data = await reader.readexactly(4)
+    writer.write_eof()
+    assert await reader.read() == b''
+    writer.close()
+    return data
+
+data = run(client())
+run(asyncio.sleep(0.05, loop=loop))
+srv.close()
+");
+
+    assert!(scope.eval::<bool>("data == b'ping'"));
+    assert!(scope.eval::<bool>(
+        "events == ['connection_made', ('data_received', b'ping'), \
+                    'eof_received', ('connection_lost', None)]"));
+}
+
+
+#[test]
+fn test_http_server_roundtrip() {
+    let gil = Python::acquire_gil();
+    let scope = Scope::new(gil.python());
+
+    scope.run("
+class Http:
+    def connection_made(self, transport):
+        self.transport = transport
+
+    def data_received(self, req):
+        loop.create_task(self.handle(req))
+
+    def connection_lost(self, exc):
+        pass
+
+    async def handle(self, req):
+        body = await req.content.read()
+        resp = req.method.encode() + b' ' + req.path.encode() + b' ' + body
+        req.writer.write_headers(
+            'HTTP/1.1 200 OK\\r\\n', {'Content-Length': str(len(resp))})
+        await req.writer.write_eof(resp)
+
+srv = run(loop.create_http_server(Http, '127.0.0.1', 0))
+addr = srv.sockets[0].getsockname()
+
+async def client():
+    reader, writer = await asyncio.open_connection(*addr, loop=loop)
+    writer.write(b'POST /echo HTTP/1.1\\r\\nContent-Length: 4\\r\\n\\r\\ndata')
+    head = await reader.readuntil(b'\\r\\n\\r\\n')
+    body = await reader.readexactly(15)
+    writer.close()
+    return head, body
+
+head, body = run(client())
+srv.close()
+");
+
+    assert!(scope.eval::<bool>("head.startswith(b'HTTP/1.1 200 OK\\r\\n')"));
+    assert!(scope.eval::<bool>("body == b'POST /echo data'"));
+}
+
+
+#[test]
+fn test_timers_order() {
+    let gil = Python::acquire_gil();
+    let scope = Scope::new(gil.python());
+
+    scope.run("
+events = []
+loop.call_later(0.03, events.append, 'later')
+loop.call_at(loop.time() + 0.01, events.append, 'at')
+loop.call_soon(events.append, 'soon')
+handle = loop.call_later(0.02, events.append, 'cancelled')
+handle.cancel()
+run(asyncio.sleep(0.05, loop=loop))
+");
+
+    assert!(scope.eval::<bool>("events == ['soon', 'at', 'later']"));
+}
@@ -114,3 +114,75 @@ fn test_pybytes_strip() {
     let _ = py.run("assert pb.strip() == b'1   2   3'", None, Some(&d)).map_err(|e| e.print(py));
     let _ = py.run("assert pb.strip(b' 1') == b'2   3'", None, Some(&d)).unwrap();
 }
+
+#[test]
+fn test_pybytes_slice_buffer() {
+    let gil = Python::acquire_gil();
+    let py = gil.python();
+
+    let pb = PyBytes::new(py, Bytes::from("0123456789")).unwrap();
+
+    py_assert!(py, pb, "pb[:3] == b'012'");
+    py_assert!(py, pb, "pb[-3:] == b'789'");
+    py_assert!(py, pb, "pb[7:2] == b''");
+    py_assert!(py, pb, "pb[::-3] == b'9630'");
+    py_assert!(py, pb, "pb[-1] == ord('9')");
+    py_run!(py, pb, "try:\n    pb[10]\nexcept IndexError:\n    pass\nelse:\n    assert False");
+
+    py_run!(py, pb, "view = memoryview(pb)\n\
+                     assert view.readonly\n\
+                     assert view[2:4].tobytes() == b'23'\n\
+                     try:\n    view[0] = 0\nexcept TypeError:\n    pass\nelse:\n    assert False");
+
+    // view keeps data alive
+    let d = PyDict::new(py);
+    d.set_item("pb", pb).unwrap();
+    py.run("view = memoryview(pb)\n\
+            del pb\n\
+            assert bytes(view) == b'0123456789'\n\
+            view.release()", None, Some(&d)).unwrap();
+}
+
+#[test]
+fn test_pybytes_bytes_api() {
+    let gil = Python::acquire_gil();
+    let py = gil.python();
+
+    let pb = PyBytes::new(py, Bytes::from("  GET /path HTTP/1.1\r\n")).unwrap();
+    let d = PyDict::new(py);
+    d.set_item("pb", pb.clone_ref(py)).unwrap();
+    d.set_item("payload", pyo3::PyBytes::new(py, b"  GET /path HTTP/1.1\r\n")).unwrap();
+
+    let run = |code: &str| py.run(code, None, Some(&d)).map_err(|e| e.print(py)).unwrap();
+
+    // search and strip
+    run("assert pb.find(b'/') == payload.find(b'/')");
+    run("assert pb.find(b'/', 8) == payload.find(b'/', 8)");
+    run("assert pb.find(b'GET', -5) == -1");
+    run("assert pb.find(ord('H')) == payload.find(b'H')");
+    run("assert pb.startswith(b'  GET')");
+    run("assert pb.startswith((b'POST', b'GET'), 2)");
+    run("assert pb.endswith(b'\\r\\n')");
+    run("assert pb.endswith(b'1.1', 0, -2)");
+    run("assert pb.strip() == payload.strip()");
+    run("assert pb.lstrip() == payload.lstrip()");
+    run("assert pb.rstrip(b'\\r\\n') == payload.rstrip(b'\\r\\n')");
+    run("assert pb.split() == payload.split()");
+    run("assert pb.strip().split(b' ', 1) == payload.strip().split(b' ', 1)");
+    run("try:\n    pb.split(b'')\nexcept ValueError:\n    pass\nelse:\n    assert False");
+
+    // containment and iteration
+    run("assert b'/path' in pb");
+    run("assert ord('G') in pb");
+    run("assert list(pb) == list(payload)");
+
+    // comparison and hashing
+    run("assert pb == payload");
+    run("assert pb != b'other'");
+    run("assert pb < payload + b'x'");
+    run("assert pb >= payload");
+    run("assert pb != 'str'");
+    run("assert hash(pb) == hash(payload)");
+    run("assert {payload: 1}[pb] == 1");
+    run("assert bytes(pb) == payload");
+}
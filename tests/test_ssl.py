@@ -6,11 +6,19 @@
 import asyncio
 import socket
 import ssl
+import time
+
+import pytest
+
+import tokio
 
 import _testbase as tb
 
 ONLYCERT = tb._cert_fullname('ssl_cert.pem')
 ONLYKEY = tb._cert_fullname('ssl_key.pem')
+CA_CERT = tb._cert_fullname('ca_cert.pem')
+CLIENT_CERT = tb._cert_fullname('client_cert.pem')
+CLIENT_KEY = tb._cert_fullname('client_key.pem')
 
 
 def create_server_ssl_context(certfile, keyfile=None):
@@ -206,3 +214,533 @@ def test_create_connection_ssl_1(loop):
 
     run(client)
     run(client_sock)
+
+
+def test_ssl_peercert(loop):
+    if not isinstance(loop, tokio.Loop):
+        pytest.skip('peercert details are tokio specific')
+
+    sslctx = create_server_ssl_context(ONLYCERT, ONLYKEY)
+    client_sslctx = create_client_ssl_context()
+
+    with open(ONLYCERT) as f:
+        der = ssl.PEM_cert_to_DER_cert(f.read())
+
+    srv = loop.run_until_complete(
+        loop.create_server(asyncio.Protocol, '127.0.0.1', 0, ssl=sslctx))
+    addr = srv.sockets[0].getsockname()
+
+    tr, _ = loop.run_until_complete(loop.create_connection(
+        asyncio.Protocol, *addr, ssl=client_sslctx, server_hostname=''))
+
+    assert tr.get_extra_info('peercert_der') == der
+    info = tr.get_extra_info('peercert_info')
+    assert set(info) == {'subject', 'issuer', 'san'}
+    assert tr.get_extra_info('unknown') is None
+
+    tr.close()
+    srv.close()
+
+
+def test_ssl_versions_ciphers(loop):
+    if not isinstance(loop, tokio.Loop):
+        pytest.skip('ssl version kwargs are tokio specific')
+
+    sslctx = create_server_ssl_context(ONLYCERT, ONLYKEY)
+    srv = loop.run_until_complete(loop.create_server(
+        asyncio.Protocol, '127.0.0.1', 0, ssl=sslctx,
+        ssl_min_version='TLSv1.2', ssl_max_version=ssl.TLSVersion.TLSv1_2,
+        ssl_ciphers='ECDHE+AESGCM'))
+    assert sslctx.minimum_version == ssl.TLSVersion.TLSv1_2
+    assert sslctx.maximum_version == ssl.TLSVersion.TLSv1_2
+    addr = srv.sockets[0].getsockname()
+
+    tr, _ = loop.run_until_complete(loop.create_connection(
+        asyncio.Protocol, *addr, ssl=create_client_ssl_context(),
+        server_hostname='', ssl_min_version='TLSv1.2'))
+    sslobj = tr.get_extra_info('ssl_object')
+    assert sslobj.version() == 'TLSv1.2'
+    assert 'GCM' in sslobj.cipher()[0]
+    tr.close()
+
+    # client requires newer protocol than server supports
+    with pytest.raises(ssl.SSLError):
+        loop.run_until_complete(loop.create_connection(
+            asyncio.Protocol, *addr, ssl=create_client_ssl_context(),
+            server_hostname='', ssl_min_version='TLSv1.3'))
+
+    srv.close()
+
+    with pytest.raises(ValueError):
+        loop.create_connection(
+            asyncio.Protocol, '127.0.0.1', 1, ssl_ciphers='ECDHE+AESGCM')
+    with pytest.raises(ValueError):
+        loop.create_server(
+            asyncio.Protocol, '127.0.0.1', 0, ssl=sslctx,
+            ssl_min_version='SSLv1')
+
+
+def test_ssl_client_certificate(loop):
+    if not isinstance(loop, tokio.Loop):
+        pytest.skip('ssl client certificate kwargs are tokio specific')
+
+    identities = []
+
+    class Proto(asyncio.Protocol):
+        def connection_made(self, transport):
+            identities.append(transport.get_extra_info('peer_identity'))
+
+    srv = loop.run_until_complete(loop.create_server(
+        Proto, '127.0.0.1', 0,
+        ssl=create_server_ssl_context(ONLYCERT, ONLYKEY),
+        ssl_client_ca=CA_CERT))
+    addr = srv.sockets[0].getsockname()
+
+    tr, _ = loop.run_until_complete(loop.create_connection(
+        asyncio.Protocol, *addr, ssl=create_client_ssl_context(),
+        server_hostname='', ssl_certfile=CLIENT_CERT, ssl_keyfile=CLIENT_KEY))
+    loop.run_until_complete(asyncio.sleep(0.1, loop=loop))
+    assert identities == ['client.example']
+    tr.close()
+
+    # server rejects client without certificate
+    try:
+        tr, _ = loop.run_until_complete(loop.create_connection(
+            asyncio.Protocol, *addr, ssl=create_client_ssl_context(),
+            server_hostname=''))
+        tr.close()
+    except (ssl.SSLError, ConnectionError):
+        pass
+    loop.run_until_complete(asyncio.sleep(0.1, loop=loop))
+    assert identities == ['client.example']
+
+    srv.close()
+
+    with pytest.raises(ValueError):
+        loop.create_connection(
+            asyncio.Protocol, '127.0.0.1', 1, ssl_certfile=CLIENT_CERT)
+
+
+def test_ssl_alpn(loop):
+    if not isinstance(loop, tokio.Loop):
+        pytest.skip('ssl alpn kwargs are tokio specific')
+
+    protocols = []
+
+    class Proto(asyncio.Protocol):
+        def connection_made(self, transport):
+            protocols.append(transport.get_extra_info('alpn_protocol'))
+
+    srv = loop.run_until_complete(loop.create_server(
+        Proto, '127.0.0.1', 0,
+        ssl=create_server_ssl_context(ONLYCERT, ONLYKEY),
+        ssl_alpn_protocols=['h2', 'http/1.1']))
+    addr = srv.sockets[0].getsockname()
+
+    tr, _ = loop.run_until_complete(loop.create_connection(
+        asyncio.Protocol, *addr, ssl=create_client_ssl_context(),
+        server_hostname='', ssl_alpn_protocols=['http/1.1']))
+    assert tr.get_extra_info('alpn_protocol') == 'http/1.1'
+    assert tr.get_extra_info('ssl_object').selected_alpn_protocol() == 'http/1.1'
+    tr.close()
+
+    # no alpn offered by client
+    tr, _ = loop.run_until_complete(loop.create_connection(
+        asyncio.Protocol, *addr, ssl=create_client_ssl_context(),
+        server_hostname=''))
+    assert tr.get_extra_info('alpn_protocol') is None
+    tr.close()
+
+    loop.run_until_complete(asyncio.sleep(0.1, loop=loop))
+    assert protocols == ['http/1.1', None]
+    srv.close()
+
+    with pytest.raises(TypeError):
+        loop.create_connection(
+            asyncio.Protocol, '127.0.0.1', 1, ssl=create_client_ssl_context(),
+            server_hostname='', ssl_alpn_protocols='h2')
+
+
+def test_ssl_sni(loop):
+    if not isinstance(loop, tokio.Loop):
+        pytest.skip('ssl sni kwargs are tokio specific')
+
+    with open(CLIENT_CERT) as f:
+        client_der = ssl.PEM_cert_to_DER_cert(f.read())
+
+    def peercert(addr, server_hostname):
+        tr, _ = loop.run_until_complete(loop.create_connection(
+            asyncio.Protocol, *addr, ssl=create_client_ssl_context(),
+            server_hostname=server_hostname))
+        der = tr.get_extra_info('peercert_der')
+        tr.close()
+        return der
+
+    srv = loop.run_until_complete(loop.create_server(
+        asyncio.Protocol, '127.0.0.1', 0,
+        ssl=create_server_ssl_context(ONLYCERT, ONLYKEY),
+        ssl_sni={'*.example': (CLIENT_CERT, CLIENT_KEY)}))
+    addr = srv.sockets[0].getsockname()
+
+    assert peercert(addr, 'client.example') == client_der
+    assert peercert(addr, 'localhost') != client_der
+    srv.close()
+
+    # callback
+    names = []
+
+    def select(server_name):
+        names.append(server_name)
+        if server_name == 'client.example':
+            return create_server_ssl_context(CLIENT_CERT, CLIENT_KEY)
+
+    srv = loop.run_until_complete(loop.create_server(
+        asyncio.Protocol, '127.0.0.1', 0,
+        ssl=create_server_ssl_context(ONLYCERT, ONLYKEY), ssl_sni=select))
+    addr = srv.sockets[0].getsockname()
+
+    assert peercert(addr, 'client.example') == client_der
+    assert peercert(addr, 'other.example') != client_der
+    assert names == ['client.example', 'other.example']
+    srv.close()
+    loop.run_until_complete(asyncio.sleep(0.1, loop=loop))
+
+
+
+def test_ssl_sni_options(loop):
+    if not isinstance(loop, tokio.Loop):
+        pytest.skip('ssl sni kwargs are tokio specific')
+
+    connections = []
+
+    class Proto(asyncio.Protocol):
+        def connection_made(self, transport):
+            connections.append((transport.get_extra_info('alpn_protocol'),
+                                transport.get_extra_info('peer_identity')))
+
+    srv = loop.run_until_complete(loop.create_server(
+        Proto, '127.0.0.1', 0,
+        ssl=create_server_ssl_context(ONLYCERT, ONLYKEY),
+        ssl_client_ca=CA_CERT, ssl_alpn_protocols=['h2', 'http/1.1'],
+        ssl_sni={'client.example': (CLIENT_CERT, CLIENT_KEY)}))
+    addr = srv.sockets[0].getsockname()
+
+    tr, _ = loop.run_until_complete(loop.create_connection(
+        asyncio.Protocol, *addr, ssl=create_client_ssl_context(),
+        server_hostname='client.example', ssl_certfile=CLIENT_CERT,
+        ssl_keyfile=CLIENT_KEY, ssl_alpn_protocols=['http/1.1']))
+    assert tr.get_extra_info('alpn_protocol') == 'http/1.1'
+    tr.close()
+
+    # sni context requires client certificate as well
+    try:
+        tr, _ = loop.run_until_complete(loop.create_connection(
+            asyncio.Protocol, *addr, ssl=create_client_ssl_context(),
+            server_hostname='client.example'))
+        tr.close()
+    except (ssl.SSLError, ConnectionError):
+        pass
+    loop.run_until_complete(asyncio.sleep(0.1, loop=loop))
+    assert connections == [('http/1.1', 'client.example')]
+    srv.close()
+
+def test_ssl_session_resumption(loop):
+    if not isinstance(loop, tokio.Loop):
+        pytest.skip('ssl session cache is tokio specific')
+
+    class Echo(asyncio.Protocol):
+        def connection_made(self, transport):
+            self.transport = transport
+
+        def data_received(self, data):
+            self.transport.write(data)
+            self.transport.close()
+
+    class Client(asyncio.Protocol):
+        def __init__(self):
+            self.done = asyncio.Future(loop=loop)
+
+        def connection_lost(self, exc):
+            self.done.set_result(None)
+
+    srv = loop.run_until_complete(loop.create_server(
+        Echo, '127.0.0.1', 0, ssl=create_server_ssl_context(ONLYCERT, ONLYKEY)))
+    addr = srv.sockets[0].getsockname()
+
+    def connect(sslcontext):
+        tr, _ = loop.run_until_complete(loop.create_connection(
+            Client, *addr, ssl=sslcontext, server_hostname=''))
+        reused = tr.get_extra_info('session_reused')
+        tr.write(b'ping')
+        loop.run_until_complete(proto.done)
+        return reused
+
+    sslcontext = create_client_ssl_context()
+    assert connect(sslcontext) is False
+    assert connect(sslcontext) is True
+    assert connect(sslcontext) is True
+
+    # sessions are not shared between contexts
+    assert connect(create_client_ssl_context()) is False
+    srv.close()
+
+
+def test_ssl_close_notify(loop):
+    if not isinstance(loop, tokio.Loop):
+        pytest.skip('ssl_shutdown_timeout is tokio specific')
+
+    lost = []
+
+    class Proto(asyncio.Protocol):
+        def connection_made(self, transport):
+            transport.write(b'bye')
+            transport.close()
+
+        def connection_lost(self, exc):
+            lost.append(loop.time())
+
+    srv = loop.run_until_complete(loop.create_server(
+        Proto, '127.0.0.1', 0,
+        ssl=create_server_ssl_context(ONLYCERT, ONLYKEY),
+        ssl_shutdown_timeout=0.3))
+    addr = srv.sockets[0].getsockname()
+
+    def client(answer):
+        sslcontext = create_client_ssl_context()
+        with socket.create_connection(addr) as sock:
+            sslsock = sslcontext.wrap_socket(sock)
+            data = b''
+            while True:
+                chunk = sslsock.recv(1024)
+                if not chunk:
+                    # close_notify received
+                    break
+                data += chunk
+            if answer:
+                sslsock.unwrap()
+            else:
+                time.sleep(1.0)
+        return data
+
+    # peer answers close_notify
+    assert loop.run_until_complete(
+        loop.run_in_executor(None, client, True)) == b'bye'
+    loop.run_until_complete(asyncio.sleep(0.1, loop=loop))
+    assert len(lost) == 1
+
+    # peer keeps connection open, server gives up after timeout
+    start = loop.time()
+    assert loop.run_until_complete(
+        loop.run_in_executor(None, client, False)) == b'bye'
+    assert len(lost) == 2
+    assert lost[1] - start < 0.9
+    srv.close()
+
+
+def test_ssl_max_handshakes(loop):
+    if not isinstance(loop, tokio.Loop):
+        pytest.skip('ssl_max_handshakes is tokio specific')
+
+    made = []
+
+    class Proto(asyncio.Protocol):
+        def connection_made(self, transport):
+            made.append(transport)
+            transport.write(b'hello')
+
+    with pytest.raises(ValueError):
+        loop.run_until_complete(loop.create_server(
+            Proto, '127.0.0.1', 0,
+            ssl=create_server_ssl_context(ONLYCERT, ONLYKEY),
+            ssl_max_handshakes=0))
+
+    srv = loop.run_until_complete(loop.create_server(
+        Proto, '127.0.0.1', 0,
+        ssl=create_server_ssl_context(ONLYCERT, ONLYKEY),
+        ssl_max_handshakes=1))
+    addr = srv.sockets[0].getsockname()
+
+    def client():
+        sslcontext = create_client_ssl_context()
+        with socket.create_connection(addr) as sock:
+            sslsock = sslcontext.wrap_socket(sock)
+            return sslsock.recv(1024)
+
+    # plain tcp client never starts handshake and holds the only slot
+    stalled = socket.create_connection(addr)
+    loop.run_until_complete(asyncio.sleep(0.1, loop=loop))
+
+    fut = loop.run_in_executor(None, client)
+    loop.run_until_complete(asyncio.sleep(0.3, loop=loop))
+    assert not fut.done()
+    assert made == []
+
+    # handshake slot is released when stalled connection is closed
+    stalled.close()
+    assert loop.run_until_complete(
+        asyncio.wait_for(fut, 5, loop=loop)) == b'hello'
+    assert len(made) == 1
+
+    for tr in made:
+        tr.close()
+    srv.close()
+    loop.run_until_complete(asyncio.sleep(0.1, loop=loop))
+
+
+def test_ssl_server_stats(loop):
+    if not isinstance(loop, tokio.Loop):
+        pytest.skip('server.stats() is tokio specific')
+
+    class Proto(asyncio.Protocol):
+        def connection_made(self, transport):
+            transport.write(b'hello')
+            transport.close()
+
+    srv = loop.run_until_complete(loop.create_server(
+        Proto, '127.0.0.1', 0,
+        ssl=create_server_ssl_context(ONLYCERT, ONLYKEY)))
+    addr = srv.sockets[0].getsockname()
+
+    def client():
+        sslcontext = create_client_ssl_context()
+        with socket.create_connection(addr) as sock:
+            sslsock = sslcontext.wrap_socket(sock)
+            return sslsock.recv(1024)
+
+    def scanner(data):
+        # port scanner sends garbage or closes connection right away
+        with socket.create_connection(addr) as sock:
+            if not data:
+                return
+            sock.settimeout(5)
+            sock.sendall(data)
+            try:
+                while sock.recv(1024):
+                    pass
+            except ConnectionResetError:
+                pass
+
+    assert loop.run_until_complete(
+        loop.run_in_executor(None, client)) == b'hello'
+    loop.run_until_complete(loop.run_in_executor(
+        None, scanner, b'GET / HTTP/1.0\r\n\r\n'))
+    loop.run_until_complete(loop.run_in_executor(None, scanner, b''))
+    loop.run_until_complete(asyncio.sleep(0.1, loop=loop))
+
+    stats = srv.stats()
+    assert len(stats) == 1
+    assert stats[0]['address'] == addr
+    assert stats[0]['accepted'] == 3
+    assert stats[0]['accept_errors'] == 0
+    assert stats[0]['handshake_failures'] == 2
+
+    srv.close()
+    loop.run_until_complete(srv.wait_closed())
+    assert srv.stats()[0]['accepted'] == 3
+
+
+def test_ssl_handshake_timeout(loop):
+    if not isinstance(loop, tokio.Loop):
+        pytest.skip('ssl_handshake_timeout is tokio specific')
+
+    made = []
+
+    class Proto(asyncio.Protocol):
+        def connection_made(self, transport):
+            made.append(transport)
+
+    with pytest.raises(ValueError):
+        loop.run_until_complete(loop.create_server(
+            Proto, '127.0.0.1', 0,
+            ssl=create_server_ssl_context(ONLYCERT, ONLYKEY),
+            ssl_handshake_timeout=0))
+
+    srv = loop.run_until_complete(loop.create_server(
+        Proto, '127.0.0.1', 0,
+        ssl=create_server_ssl_context(ONLYCERT, ONLYKEY),
+        ssl_handshake_timeout=0.3))
+    addr = srv.sockets[0].getsockname()
+
+    def client(data):
+        # returns time until server closed connection
+        with socket.create_connection(addr) as sock:
+            sock.settimeout(5)
+            start = time.monotonic()
+            if data:
+                sock.sendall(data)
+            try:
+                while sock.recv(1024):
+                    pass
+            except ConnectionResetError:
+                pass
+            return time.monotonic() - start
+
+    # client which never starts handshake is closed after timeout
+    elapsed = loop.run_until_complete(
+        loop.run_in_executor(None, client, b''))
+    assert 0.2 < elapsed < 2
+
+    # garbage is rejected right away
+    elapsed = loop.run_until_complete(
+        loop.run_in_executor(None, client, b'SSH-2.0-OpenSSH\r\n'))
+    assert elapsed < 0.2
+    loop.run_until_complete(asyncio.sleep(0.1, loop=loop))
+
+    assert made == []
+    assert srv.stats()[0]['handshake_failures'] == 2
+
+    srv.close()
+    loop.run_until_complete(srv.wait_closed())
+
+
+def test_ssl_reload_tls(loop):
+    if not isinstance(loop, tokio.Loop):
+        pytest.skip('reload_tls is tokio specific')
+
+    with open(ONLYCERT) as f:
+        only_der = ssl.PEM_cert_to_DER_cert(f.read())
+    with open(CLIENT_CERT) as f:
+        client_der = ssl.PEM_cert_to_DER_cert(f.read())
+
+    def connect(addr):
+        tr, _ = loop.run_until_complete(loop.create_connection(
+            asyncio.Protocol, *addr, ssl=create_client_ssl_context()))
+        return tr
+
+    srv = loop.run_until_complete(loop.create_server(
+        asyncio.Protocol, '127.0.0.1', 0,
+        ssl=create_server_ssl_context(ONLYCERT, ONLYKEY)))
+    addr = srv.sockets[0].getsockname()
+
+    before = connect(addr)
+    assert before.get_extra_info('peercert_der') == only_der
+
+    # dict updates current context
+    srv.reload_tls({'certfile': CLIENT_CERT, 'keyfile': CLIENT_KEY})
+    after = connect(addr)
+    assert after.get_extra_info('peercert_der') == client_der
+
+    # established connection is not affected
+    assert not before.is_closing()
+    assert before.get_extra_info('peercert_der') == only_der
+
+    # context is replaced
+    srv.reload_tls(create_server_ssl_context(ONLYCERT, ONLYKEY))
+    replaced = connect(addr)
+    assert replaced.get_extra_info('peercert_der') == only_der
+
+    with pytest.raises(TypeError):
+        srv.reload_tls('cert.pem')
+
+    for tr in (before, after, replaced):
+        tr.close()
+    srv.close()
+    loop.run_until_complete(srv.wait_closed())
+
+    # server without tls
+    srv = loop.run_until_complete(loop.create_server(
+        asyncio.Protocol, '127.0.0.1', 0))
+    with pytest.raises(RuntimeError):
+        srv.reload_tls({'certfile': ONLYCERT, 'keyfile': ONLYKEY})
+    srv.close()
+    loop.run_until_complete(srv.wait_closed())
@@ -3,14 +3,20 @@
 #
 # Portions copyright (c) 2015-present MagicStack Inc.  http://magic.io
 
+import array
 import asyncio
+import os
 import socket
+import struct
+import subprocess
 import sys
 import threading
 
 import pytest
 import uvloop
 
+import tokio
+
 import _testbase as tb
 
 
@@ -173,6 +179,638 @@ def test_create_server_1(loop):
     assert CNT == TOTAL_CNT
 
 
+def test_server_sockets(loop):
+    srv = loop.run_until_complete(
+        loop.create_server(asyncio.Protocol, '127.0.0.1', 0))
+    srv_socks = srv.sockets
+    assert len(srv_socks) == 1
+    assert srv_socks[0].family == socket.AF_INET
+    assert srv_socks[0].fileno() >= 0
+
+    host, port = srv_socks[0].getsockname()
+    assert port != 0
+    tr, _ = loop.run_until_complete(
+        loop.create_connection(asyncio.Protocol, host, port))
+    assert tr.get_extra_info('peername') == (host, port)
+    tr.close()
+
+    srv.close()
+    loop.run_until_complete(srv.wait_closed())
+    assert not srv.sockets
+    assert srv_socks[0].fileno() == -1
+
+
+@pytest.mark.skipif(not socket.has_ipv6, reason='ipv6 is not supported')
+def test_server_addresses(loop):
+    if not isinstance(loop, tokio.Loop):
+        pytest.skip('server.addresses is tokio specific')
+
+    srv = loop.run_until_complete(
+        loop.create_server(asyncio.Protocol, ['127.0.0.1', '::1'], 0))
+    addrs = srv.addresses
+    assert len(addrs) == len(srv.sockets)
+    assert addrs == [sock.getsockname() for sock in srv.sockets]
+    assert all(addr[1] != 0 for addr in addrs)
+
+    tr, _ = loop.run_until_complete(
+        loop.create_connection(asyncio.Protocol, *addrs[0][:2]))
+    tr.close()
+
+    srv.close()
+    assert srv.addresses == []
+
+
+def test_server_wait_closed(loop):
+    if not isinstance(loop, tokio.Loop):
+        pytest.skip('wait_closed waits for connections in tokio only')
+
+    connected = asyncio.Future(loop=loop)
+
+    class Proto(asyncio.Protocol):
+        def connection_made(self, tr):
+            connected.set_result(tr)
+
+    srv = loop.run_until_complete(
+        loop.create_server(Proto, '127.0.0.1', 0))
+    addr = srv.sockets[0].getsockname()
+    tr, _ = loop.run_until_complete(
+        loop.create_connection(asyncio.Protocol, *addr))
+    loop.run_until_complete(asyncio.wait_for(connected, 5, loop=loop))
+
+    waiter = asyncio.ensure_future(srv.wait_closed(), loop=loop)
+    loop.run_until_complete(asyncio.sleep(0.05, loop=loop))
+    assert not waiter.done()
+
+    # server is closed, but connection is still alive
+    srv.close()
+    loop.run_until_complete(asyncio.sleep(0.05, loop=loop))
+    assert not waiter.done()
+
+    tr.close()
+    loop.run_until_complete(asyncio.wait_for(waiter, 5, loop=loop))
+
+    # closed server without connections is done immediately
+    loop.run_until_complete(asyncio.wait_for(srv.wait_closed(), 1, loop=loop))
+
+
+def test_server_start_serving(loop):
+    if not isinstance(loop, tokio.Loop):
+        pytest.skip('start_serving is tokio specific')
+
+    connected = asyncio.Future(loop=loop)
+
+    class Proto(asyncio.Protocol):
+        def connection_made(self, tr):
+            connected.set_result(tr)
+
+    srv = loop.run_until_complete(
+        loop.create_server(Proto, '127.0.0.1', 0, start_serving=False))
+    assert not srv.is_serving()
+    assert srv.get_loop() is loop
+
+    # connection waits in backlog
+    addr = srv.sockets[0].getsockname()
+    tr, _ = loop.run_until_complete(
+        loop.create_connection(asyncio.Protocol, *addr))
+    loop.run_until_complete(asyncio.sleep(0.05, loop=loop))
+    assert not connected.done()
+
+    loop.run_until_complete(srv.start_serving())
+    assert srv.is_serving()
+    loop.run_until_complete(asyncio.wait_for(connected, 5, loop=loop))
+
+    tr.close()
+    srv.close()
+    assert not srv.is_serving()
+
+
+def test_server_serve_forever(loop):
+    if not isinstance(loop, tokio.Loop):
+        pytest.skip('serve_forever is tokio specific')
+
+    srv = loop.run_until_complete(
+        loop.create_server(asyncio.Protocol, '127.0.0.1', 0, start_serving=False))
+
+    fut = asyncio.ensure_future(srv.serve_forever(), loop=loop)
+    loop.run_until_complete(asyncio.sleep(0.05, loop=loop))
+    assert srv.is_serving()
+    assert not fut.done()
+
+    with pytest.raises(RuntimeError):
+        srv.serve_forever()
+
+    # close() cancels serve_forever()
+    srv.close()
+    with pytest.raises(asyncio.CancelledError):
+        loop.run_until_complete(fut)
+    assert not srv.is_serving()
+
+    # cancelled serve_forever() closes server
+    srv = loop.run_until_complete(
+        loop.create_server(asyncio.Protocol, '127.0.0.1', 0))
+    fut = asyncio.ensure_future(srv.serve_forever(), loop=loop)
+    loop.run_until_complete(asyncio.sleep(0.05, loop=loop))
+    fut.cancel()
+    loop.run_until_complete(asyncio.sleep(0.05, loop=loop))
+    assert not srv.is_serving()
+    assert srv.sockets == ()
+
+
+def test_server_max_connections(loop):
+    if not isinstance(loop, tokio.Loop):
+        pytest.skip('max_connections is tokio specific')
+
+    made = []
+
+    class Proto(asyncio.Protocol):
+        def connection_made(self, tr):
+            made.append(tr)
+
+    srv = loop.run_until_complete(
+        loop.create_server(Proto, '127.0.0.1', 0, max_connections=1))
+    addr = srv.sockets[0].getsockname()
+
+    tr1, _ = loop.run_until_complete(
+        loop.create_connection(asyncio.Protocol, *addr))
+    tr2, _ = loop.run_until_complete(
+        loop.create_connection(asyncio.Protocol, *addr))
+    loop.run_until_complete(asyncio.sleep(0.1, loop=loop))
+    assert len(made) == 1
+
+    # second connection is accepted after first one is closed
+    made[0].close()
+    loop.run_until_complete(asyncio.sleep(0.1, loop=loop))
+    assert len(made) == 2
+
+    tr1.close()
+    tr2.close()
+    srv.close()
+
+    with pytest.raises(ValueError):
+        loop.run_until_complete(
+            loop.create_server(Proto, '127.0.0.1', 0, max_connections=0))
+
+
+def test_server_connection_counts(loop):
+    if not isinstance(loop, tokio.Loop):
+        pytest.skip('connection counts are tokio specific')
+
+    made = []
+
+    class Proto(asyncio.Protocol):
+        def connection_made(self, tr):
+            made.append(tr)
+
+    srv = loop.run_until_complete(
+        loop.create_server(Proto, '127.0.0.1', 0))
+    addr = srv.sockets[0].getsockname()
+    assert (srv.connections, srv.total_accepted, srv.total_closed) == (0, 0, 0)
+
+    clients = [socket.create_connection(addr) for _ in range(3)]
+    loop.run_until_complete(asyncio.sleep(0.1, loop=loop))
+    assert (srv.connections, srv.total_accepted, srv.total_closed) == (3, 3, 0)
+
+    made[0].close()
+    loop.run_until_complete(asyncio.sleep(0.1, loop=loop))
+    assert (srv.connections, srv.total_accepted, srv.total_closed) == (2, 3, 1)
+
+    # totals are kept after server is done
+    srv.close()
+    for tr in made[1:]:
+        tr.close()
+    loop.run_until_complete(srv.wait_closed())
+    assert (srv.connections, srv.total_accepted, srv.total_closed) == (0, 3, 3)
+
+    for client in clients:
+        client.close()
+
+
+@pytest.mark.parametrize('accept_batch', [1, 3, None])
+def test_server_accept_batch(loop, accept_batch):
+    if not isinstance(loop, tokio.Loop):
+        pytest.skip('accept_batch is tokio specific')
+
+    made = []
+
+    class Proto(asyncio.Protocol):
+        def connection_made(self, tr):
+            made.append(tr)
+
+    srv = loop.run_until_complete(
+        loop.create_server(Proto, '127.0.0.1', 0, accept_batch=accept_batch))
+    addr = srv.sockets[0].getsockname()
+
+    # connections are queued in backlog before server wakes up
+    clients = [socket.create_connection(addr) for _ in range(10)]
+    loop.run_until_complete(asyncio.sleep(0.1, loop=loop))
+    assert len(made) == 10
+
+    for s in clients:
+        s.close()
+    srv.close()
+
+    with pytest.raises(ValueError):
+        loop.run_until_complete(
+            loop.create_server(Proto, '127.0.0.1', 0, accept_batch=0))
+
+
+@pytest.mark.skipif(not socket.has_ipv6, reason='ipv6 is not supported')
+def test_server_dualstack(loop):
+    if not isinstance(loop, tokio.Loop):
+        pytest.skip('dualstack is tokio specific')
+
+    peers = []
+
+    class Proto(asyncio.Protocol):
+        def connection_made(self, tr):
+            peers.append(tr.get_extra_info('peername'))
+
+    srv = loop.run_until_complete(
+        loop.create_server(Proto, None, 0, dualstack=True))
+    assert len(srv.sockets) == 1
+    sock = srv.sockets[0]
+    assert sock.family == socket.AF_INET6
+    port = sock.getsockname()[1]
+
+    for host in ('127.0.0.1', '::1'):
+        tr, _ = loop.run_until_complete(
+            loop.create_connection(asyncio.Protocol, host, port))
+        loop.run_until_complete(asyncio.sleep(0.05, loop=loop))
+        tr.close()
+
+    assert [peer[0] for peer in peers] == ['::ffff:127.0.0.1', '::1']
+    srv.close()
+
+    with pytest.raises(ValueError):
+        loop.run_until_complete(
+            loop.create_server(Proto, sock=socket.socket(), dualstack=True))
+
+
+ACTIVATED_SERVER = """
+import asyncio, os, tokio
+
+loop = tokio.new_event_loop()
+
+class Proto(asyncio.Protocol):
+    def connection_made(self, tr):
+        tr.write(b'activated')
+        tr.close()
+        loop.call_later(0.1, loop.stop)
+
+servers = loop.run_until_complete(loop.create_activated_servers(Proto))
+print(len(servers), servers[0].sockets[0].getsockname()[1],
+      'LISTEN_FDS' in os.environ, flush=True)
+loop.run_forever()
+"""
+
+
+def test_create_activated_servers(loop):
+    if not isinstance(loop, tokio.Loop):
+        pytest.skip('socket activation is tokio specific')
+
+    # not activated
+    servers = loop.run_until_complete(
+        loop.create_activated_servers(asyncio.Protocol))
+    assert servers == []
+
+    sock = socket.socket()
+    sock.bind(('127.0.0.1', 0))
+    sock.listen(10)
+    port = sock.getsockname()[1]
+
+    # listening socket is passed as fd 3, LISTEN_PID is pid of shell
+    # which is replaced by python interpreter
+    proc = subprocess.Popen(
+        ['/bin/sh', '-c', 'LISTEN_PID=$$ LISTEN_FDS=1 exec "$0" -c "$1"',
+         sys.executable, ACTIVATED_SERVER],
+        preexec_fn=lambda: os.dup2(sock.fileno(), 3),
+        stdout=subprocess.PIPE)
+    try:
+        line = proc.stdout.readline().decode().split()
+        assert line == ['1', str(port), 'False']
+
+        with socket.create_connection(('127.0.0.1', port)) as s:
+            assert s.recv(9) == b'activated'
+        assert proc.wait(5) == 0
+    finally:
+        proc.kill()
+        proc.stdout.close()
+        sock.close()
+
+
+INHERITED_SERVER = """
+import asyncio, sys, tokio
+
+loop = tokio.new_event_loop()
+
+class Proto(asyncio.Protocol):
+    def connection_made(self, tr):
+        tr.write(b'child')
+        tr.close()
+        loop.call_later(0.1, loop.stop)
+
+fds = [int(fd) for fd in sys.argv[1:]]
+servers = loop.run_until_complete(loop.create_servers_from_fds(Proto, fds))
+print(len(servers), flush=True)
+loop.run_forever()
+"""
+
+
+def test_server_export_fds(loop):
+    if not isinstance(loop, tokio.Loop):
+        pytest.skip('export_fds is tokio specific')
+
+    srv = loop.run_until_complete(
+        loop.create_server(asyncio.Protocol, '127.0.0.1', 0))
+    port = srv.sockets[0].getsockname()[1]
+    fds = srv.export_fds()
+    assert len(fds) == 1
+
+    proc = subprocess.Popen(
+        [sys.executable, '-c', INHERITED_SERVER] + [str(fd) for fd in fds],
+        pass_fds=fds, stdout=subprocess.PIPE)
+    try:
+        assert proc.stdout.readline() == b'1\n'
+
+        # old server is closed, new process keeps listening
+        srv.close()
+        with pytest.raises(RuntimeError):
+            srv.export_fds()
+
+        with socket.create_connection(('127.0.0.1', port)) as s:
+            assert s.recv(5) == b'child'
+        assert proc.wait(5) == 0
+    finally:
+        proc.kill()
+        proc.stdout.close()
+
+
+def test_create_server_fd(loop):
+    if not isinstance(loop, tokio.Loop):
+        pytest.skip('create_server(fd=...) is tokio specific')
+
+    class Proto(asyncio.Protocol):
+        def connection_made(self, tr):
+            tr.write(b'hello')
+            tr.close()
+
+    # socket is not listening
+    with socket.socket() as sock:
+        with pytest.raises(ValueError):
+            loop.run_until_complete(
+                loop.create_server(Proto, fd=sock.fileno()))
+
+    sock = socket.socket()
+    sock.bind(('127.0.0.1', 0))
+    sock.listen(10)
+    addr = sock.getsockname()
+
+    with pytest.raises(ValueError):
+        loop.run_until_complete(
+            loop.create_server(Proto, '127.0.0.1', 0, fd=sock.fileno()))
+
+    srv = loop.run_until_complete(
+        loop.create_server(Proto, fd=sock.detach()))
+    assert srv.addresses == [addr]
+
+    with socket.create_connection(addr) as client:
+        client.settimeout(5)
+        assert client.recv(5) == b'hello'
+
+    srv.close()
+    loop.run_until_complete(srv.wait_closed())
+
+
+def test_server_factory_context(loop):
+    if not isinstance(loop, tokio.Loop):
+        pytest.skip('factory_context is tokio specific')
+
+    contexts = []
+
+    def factory(context):
+        contexts.append(context)
+        return asyncio.Protocol()
+
+    srv = loop.run_until_complete(loop.create_server(
+        factory, '127.0.0.1', 0, factory_context=True))
+    addrs = srv.addresses
+
+    for addr in addrs:
+        tr, _ = loop.run_until_complete(
+            loop.create_connection(asyncio.Protocol, *addr))
+        loop.run_until_complete(asyncio.sleep(0.05, loop=loop))
+        contexts[-1]['local'] = tr.get_extra_info('sockname')
+        tr.close()
+
+    assert len(contexts) == 1
+    for context, addr in zip(contexts, addrs):
+        assert context['sockname'] == addr
+        assert context['peername'] == context['local']
+        assert context['server'] is srv
+        assert context['ssl'] is None
+
+    srv.close()
+
+
+def test_server_on_connect(loop):
+    if not isinstance(loop, tokio.Loop):
+        pytest.skip('on_connect is tokio specific')
+
+    made = []
+    seen = []
+    errors = []
+
+    class Proto(asyncio.Protocol):
+        def connection_made(self, tr):
+            made.append(tr)
+
+    def on_connect(context):
+        seen.append(context)
+        if len(seen) == 2:
+            return False
+        if len(seen) == 3:
+            raise RuntimeError('hook failure')
+
+    loop.set_exception_handler(lambda loop, ctx: errors.append(ctx))
+    srv = loop.run_until_complete(loop.create_server(
+        Proto, '127.0.0.1', 0, on_connect=on_connect))
+    addr = srv.addresses[0]
+
+    def connect():
+        client = socket.create_connection(addr)
+        client.settimeout(5)
+        loop.run_until_complete(asyncio.sleep(0.05, loop=loop))
+        return client
+
+    # accepted
+    with connect() as client:
+        assert len(made) == 1
+        assert seen[0]['sockname'] == addr
+        assert seen[0]['peername'] == client.getsockname()
+        assert seen[0]['server'] is srv
+
+    # rejected, connection is closed before protocol is created
+    with connect() as client:
+        assert client.recv(1) == b''
+        assert len(made) == 1
+
+    # failed hook rejects connection as well
+    with connect() as client:
+        assert client.recv(1) == b''
+        assert len(made) == 1
+    assert errors[0]['message'] == 'on_connect hook failure'
+    assert isinstance(errors[0]['exception'], RuntimeError)
+
+    for tr in made:
+        tr.close()
+    srv.close()
+
+
+@pytest.mark.parametrize('limits', [
+    {'max_connections_per_ip': 2},
+    {'accept_rate_per_ip': 2},
+])
+def test_server_per_ip_limits(loop, limits):
+    if not isinstance(loop, tokio.Loop):
+        pytest.skip('per-ip limits are tokio specific')
+
+    made = []
+
+    class Proto(asyncio.Protocol):
+        def connection_made(self, tr):
+            made.append(tr)
+
+    srv = loop.run_until_complete(
+        loop.create_server(Proto, '127.0.0.1', 0, **limits))
+    addr = srv.sockets[0].getsockname()
+
+    clients = [socket.create_connection(addr) for _ in range(3)]
+    loop.run_until_complete(asyncio.sleep(0.1, loop=loop))
+    assert len(made) == 2
+
+    # rejected connection is closed
+    clients[2].settimeout(5)
+    assert clients[2].recv(1) == b''
+
+    for s in clients:
+        s.close()
+    srv.close()
+
+    with pytest.raises(ValueError):
+        loop.run_until_complete(
+            loop.create_server(Proto, '127.0.0.1', 0, accept_rate_per_ip=0))
+
+
+@pytest.mark.skipif(not sys.platform.startswith('linux'),
+                    reason='relies on fd allocation of linux')
+def test_server_accept_error(loop):
+    if not isinstance(loop, tokio.Loop):
+        pytest.skip('accept error handling is tokio specific')
+    import errno
+    import resource
+
+    made = []
+    errors = []
+
+    class Proto(asyncio.Protocol):
+        def connection_made(self, tr):
+            made.append(tr)
+
+    def exhaust_fds(delay):
+        # exhaust fds, so accept() fails with EMFILE
+        limits = resource.getrlimit(resource.RLIMIT_NOFILE)
+        max_fd = max(int(fd) for fd in os.listdir('/proc/self/fd'))
+        resource.setrlimit(resource.RLIMIT_NOFILE, (max_fd + 1, limits[1]))
+        dummy = []
+        try:
+            while True:
+                dummy.append(os.dup(0))
+        except OSError:
+            pass
+
+        try:
+            loop.run_until_complete(asyncio.sleep(delay, loop=loop))
+        finally:
+            for fd in dummy:
+                os.close(fd)
+            resource.setrlimit(resource.RLIMIT_NOFILE, limits)
+
+    loop.set_exception_handler(lambda loop, ctx: errors.append(ctx))
+    srv = loop.run_until_complete(
+        loop.create_server(Proto, '127.0.0.1', 0, accept_retry_delay=0.3))
+    client = socket.create_connection(srv.sockets[0].getsockname())
+
+    exhaust_fds(0.05)
+
+    # accepting is paused instead of spinning on EMFILE
+    assert len(errors) == 1
+    assert errors[0]['message'].startswith('Error on accepting connection')
+    assert errors[0]['exception'].errno == errno.EMFILE
+    assert not made
+
+    # listener keeps running after delay
+    loop.run_until_complete(asyncio.sleep(0.05, loop=loop))
+    assert not made
+    loop.run_until_complete(asyncio.sleep(0.4, loop=loop))
+    assert len(made) == 1
+
+    client.close()
+    srv.close()
+
+    # reserved fd is used to refuse pending connection
+    srv = loop.run_until_complete(
+        loop.create_server(Proto, '127.0.0.1', 0, accept_retry_delay=0.3,
+                           reserve_fd=True))
+    client = socket.create_connection(srv.sockets[0].getsockname())
+    client.settimeout(5)
+
+    exhaust_fds(0.05)
+    assert client.recv(1) == b''
+    loop.run_until_complete(asyncio.sleep(0.4, loop=loop))
+    assert len(made) == 1
+
+    client.close()
+    srv.close()
+
+
+@pytest.mark.skipif(not sys.platform.startswith('linux'),
+                    reason='TCP_DEFER_ACCEPT is linux specific')
+def test_server_defer_accept(loop):
+    if not isinstance(loop, tokio.Loop):
+        pytest.skip('defer_accept is tokio specific')
+
+    made = []
+
+    class Proto(asyncio.Protocol):
+        def connection_made(self, tr):
+            made.append(tr)
+
+    with pytest.raises(ValueError):
+        loop.run_until_complete(loop.create_server(
+            Proto, '127.0.0.1', 0, backlogs={'localhost': 10}))
+    with pytest.raises(TypeError):
+        loop.run_until_complete(loop.create_server(
+            Proto, '127.0.0.1', 0, backlogs=[10]))
+
+    srv = loop.run_until_complete(loop.create_server(
+        Proto, '127.0.0.1', 0, defer_accept=5, backlogs={'127.0.0.1': 10}))
+    addr = srv.sockets[0].getsockname()
+
+    # idle client does not wake up listener
+    client = socket.create_connection(addr)
+    loop.run_until_complete(asyncio.sleep(0.1, loop=loop))
+    assert not made
+
+    client.sendall(b'GET / HTTP/1.0\r\n\r\n')
+    loop.run_until_complete(asyncio.sleep(0.1, loop=loop))
+    assert len(made) == 1
+
+    client.close()
+    for tr in made:
+        tr.close()
+    srv.close()
+
+
 def test_create_server_2(loop):
     with pytest.raises(ValueError) as excinfo:
         loop.run_until_complete(loop.create_server(object))
@@ -259,6 +897,69 @@ def test_create_server_6(loop, port):
     loop.run_until_complete(runner())
 
 
+def test_create_server_reuse_defaults(loop, port):
+    if not isinstance(loop, tokio.Loop):
+        pytest.skip('asyncio defaults differ between python versions')
+
+    def sockopt(srv, opt):
+        fd = srv.sockets[0].fileno()
+        with socket.fromfd(fd, socket.AF_INET, socket.SOCK_STREAM) as sock:
+            return sock.getsockopt(socket.SOL_SOCKET, opt)
+
+    srv = loop.run_until_complete(
+        loop.create_server(asyncio.Protocol, '127.0.0.1', port))
+    assert sockopt(srv, socket.SO_REUSEADDR)
+    if hasattr(socket, 'SO_REUSEPORT'):
+        assert not sockopt(srv, socket.SO_REUSEPORT)
+
+    # reuse_port is opt-in, port is taken
+    with pytest.raises(OSError):
+        loop.run_until_complete(
+            loop.create_server(asyncio.Protocol, '127.0.0.1', port))
+    srv.close()
+    loop.run_until_complete(srv.wait_closed())
+
+    srv = loop.run_until_complete(loop.create_server(
+        asyncio.Protocol, '127.0.0.1', 0, reuse_address=False))
+    assert not sockopt(srv, socket.SO_REUSEADDR)
+    srv.close()
+    loop.run_until_complete(srv.wait_closed())
+
+
+@pytest.mark.skipif(not hasattr(socket, 'SO_REUSEPORT'),
+                    reason='The system does not support SO_REUSEPORT')
+def test_sharded_server():
+    lock = threading.Lock()
+    threads = set()
+
+    class Proto(asyncio.Protocol):
+        def connection_made(self, tr):
+            with lock:
+                threads.add(threading.current_thread().name)
+            tr.write(b'hello')
+            tr.close()
+
+    srv = tokio.start_sharded_server(Proto, '127.0.0.1', 0, shards=2)
+    try:
+        ports = {sock.getsockname()[1] for sock in srv.sockets}
+        assert len(srv.sockets) == 2
+        assert len(ports) == 1
+        assert all(isinstance(l, tokio.Loop) for l in srv.loops)
+
+        for _ in range(20):
+            with socket.create_connection(('127.0.0.1', ports.pop())) as s:
+                ports.add(s.getpeername()[1])
+                assert s.recv(5) == b'hello'
+    finally:
+        srv.close(timeout=5)
+
+    assert threads <= {'tokio-shard-0', 'tokio-shard-1'}
+    assert all(l.is_closed() for l in srv.loops)
+
+    with pytest.raises(ValueError):
+        tokio.start_sharded_server(Proto, '127.0.0.1', 0, shards=0)
+
+
 def test_create_connection_1(loop):
     CNT = 0
     TOTAL_CNT = 100
@@ -404,6 +1105,1137 @@ def test_create_connection_4(loop):
     loop.run_until_complete(runner())
 
 
+def test_create_connection_nodelay(loop):
+    lsock = socket.socket()
+    lsock.bind(('127.0.0.1', 0))
+    lsock.listen(1)
+
+    sock = socket.socket()
+    sock.connect(lsock.getsockname())
+    conn, _ = lsock.accept()
+
+    def nodelay():
+        probe = socket.fromfd(
+            sock.fileno(), socket.AF_INET, socket.SOCK_STREAM)
+        with probe:
+            return probe.getsockopt(socket.IPPROTO_TCP, socket.TCP_NODELAY)
+
+    tr, pr = loop.run_until_complete(
+        loop.create_connection(MyBaseProto, sock=sock))
+    assert nodelay()
+
+    if hasattr(tr, 'set_nodelay'):
+        tr.set_nodelay(False)
+        assert not nodelay()
+        tr.set_nodelay(True)
+        assert nodelay()
+
+    tr.close()
+    conn.close()
+    lsock.close()
+
+
+def test_create_connection_refused(loop):
+    sock = socket.socket()
+    sock.bind(('127.0.0.1', 0))
+    addr = sock.getsockname()
+    sock.close()
+
+    with pytest.raises(ConnectionRefusedError) as excinfo:
+        loop.run_until_complete(
+            loop.create_connection(MyBaseProto, *addr))
+
+    exc = excinfo.value
+    if hasattr(exc, 'operation'):
+        assert exc.operation == 'connect'
+        cause = exc.__cause__
+        assert isinstance(cause, ConnectionRefusedError)
+        assert cause.operation == 'connect'
+        assert cause.address == addr
+        assert exc.errors == [cause]
+
+
+
+def test_create_connection_refused_multiple(loop):
+    if not isinstance(loop, tokio.Loop):
+        pytest.skip('errors attribute is tokio specific')
+
+    sock = socket.socket()
+    sock.bind(('127.0.0.1', 0))
+    port = sock.getsockname()[1]
+    sock.close()
+
+    infos = loop.run_until_complete(
+        loop.getaddrinfo('localhost', port, type=socket.SOCK_STREAM))
+    if len(set(info[4][:2] for info in infos)) < 2:
+        pytest.skip('localhost resolves to single address')
+
+    with pytest.raises(OSError) as excinfo:
+        loop.run_until_complete(
+            loop.create_connection(MyBaseProto, 'localhost', port))
+
+    exc = excinfo.value
+    assert exc.operation == 'connect'
+    assert exc.__cause__ is None
+    assert len(exc.errors) > 1
+    for err in exc.errors:
+        assert isinstance(err, OSError)
+        assert err.operation == 'connect'
+        assert err.__cause__ is None
+
+def test_create_connection_timeout(loop):
+    if not isinstance(loop, tokio.Loop):
+        pytest.skip('connect_timeout is tokio specific')
+
+    # non-routable address, connect hangs or fails quickly
+    started = loop.time()
+    with pytest.raises(OSError) as excinfo:
+        loop.run_until_complete(
+            loop.create_connection(
+                MyBaseProto, '10.255.255.1', 80, connect_timeout=0.2))
+    assert loop.time() - started < 5
+
+    exc = excinfo.value
+    assert exc.operation == 'connect'
+    assert len(exc.errors) == 1
+    assert exc.errors[0].address == ('10.255.255.1', 80)
+
+
+def test_transport_freeze_restore(loop):
+    lsock = socket.socket()
+    lsock.bind(('127.0.0.1', 0))
+    lsock.listen(1)
+
+    sock = socket.socket()
+    sock.connect(lsock.getsockname())
+    conn, _ = lsock.accept()
+    conn.settimeout(1.0)
+
+    class Proto(MyBaseProto):
+        def __init__(self):
+            super().__init__()
+            self.data = b''
+
+        def data_received(self, data):
+            self.data += data
+
+    def run_briefly():
+        loop.run_until_complete(asyncio.sleep(0.05, loop=loop))
+
+    tr, pr = loop.run_until_complete(
+        loop.create_connection(Proto, sock=sock))
+    if not hasattr(tr, 'freeze'):
+        tr.close()
+        conn.close()
+        lsock.close()
+        pytest.skip('transport does not support freeze()')
+
+    tr.write(b'before')
+    tr.freeze()
+    tr.write(b'held')
+    conn.sendall(b'inbound')
+    run_briefly()
+
+    assert conn.recv(100) == b'before'
+    assert pr.data == b''
+
+    # writes are sent in order on restore
+    assert tr.restore() == []
+    run_briefly()
+    assert conn.recv(100) == b'held'
+    assert pr.data == b'inbound'
+
+    # new protocol gets unconsumed data and held writes
+    tr.freeze()
+    tr.write(b'upgrade')
+    conn.sendall(b'next')
+    run_briefly()
+
+    new_pr = Proto()
+    new_pr.state = 'CONNECTED'
+    assert tr.restore(new_pr) == [b'upgrade']
+    assert tr.get_protocol() is new_pr
+    run_briefly()
+    assert new_pr.data == b'next'
+    assert pr.data == b'inbound'
+
+    tr.close()
+    conn.close()
+    lsock.close()
+
+
+def test_transport_write_buffers(loop):
+    lsock = socket.socket()
+    lsock.bind(('127.0.0.1', 0))
+    lsock.listen(1)
+
+    sock = socket.socket()
+    sock.connect(lsock.getsockname())
+    conn, _ = lsock.accept()
+    conn.settimeout(1.0)
+
+    tr, pr = loop.run_until_complete(
+        loop.create_connection(MyBaseProto, sock=sock))
+
+    ints = array.array('i', [1, 2])
+    buf = bytearray(b'ba')
+    tr.write(buf)
+    buf[:] = b'XX'
+    tr.write(memoryview(b'xmvx')[1:3])
+    tr.write(memoryview(ints))
+    expected = b'ba' + b'mv' + ints.tobytes()
+
+    with pytest.raises(TypeError):
+        tr.write('str')
+
+    loop.run_until_complete(asyncio.sleep(0.05, loop=loop))
+    data = b''
+    while len(data) < len(expected):
+        data += conn.recv(100)
+    assert data == expected
+
+    tr.close()
+    conn.close()
+    lsock.close()
+
+
+def test_transport_drain(loop):
+    lsock = socket.socket()
+    lsock.bind(('127.0.0.1', 0))
+    lsock.listen(1)
+
+    sock = socket.socket()
+    sock.connect(lsock.getsockname())
+    conn, _ = lsock.accept()
+
+    tr, pr = loop.run_until_complete(
+        loop.create_connection(MyBaseProto, sock=sock))
+    if not hasattr(tr, 'drain'):
+        tr.close()
+        conn.close()
+        lsock.close()
+        pytest.skip('transport does not support drain()')
+
+    assert tr.get_write_buffer_limits() == (16 * 1024, 64 * 1024)
+    tr.set_write_buffer_limits(high=1024)
+    assert tr.get_write_buffer_limits() == (256, 1024)
+    with pytest.raises(ValueError):
+        tr.set_write_buffer_limits(high=1, low=2)
+
+    # peer does not read, data stays in write buffer
+    data = b'x' * (16 * 1024 * 1024)
+    tr.write(data)
+    waiter = tr.drain()
+    loop.run_until_complete(asyncio.sleep(0.05, loop=loop))
+    assert not waiter.done()
+    assert tr.get_write_buffer_size() > 256
+
+    def reader():
+        received = 0
+        while received < len(data):
+            received += len(conn.recv(65536))
+
+    thread = threading.Thread(target=reader)
+    thread.start()
+    loop.run_until_complete(asyncio.wait_for(waiter, 10, loop=loop))
+    thread.join()
+    assert tr.get_write_buffer_size() == 0
+
+    tr.close()
+    conn.close()
+    lsock.close()
+
+
+def test_transport_pause_writing(loop):
+    lsock = socket.socket()
+    lsock.bind(('127.0.0.1', 0))
+    lsock.listen(1)
+
+    sock = socket.socket()
+    sock.connect(lsock.getsockname())
+    conn, _ = lsock.accept()
+
+    class Proto(MyBaseProto):
+        def __init__(self):
+            super().__init__()
+            self.calls = []
+
+        def pause_writing(self):
+            self.calls.append('pause')
+
+        def resume_writing(self):
+            self.calls.append('resume')
+
+    tr, pr = loop.run_until_complete(
+        loop.create_connection(Proto, sock=sock))
+    tr.set_write_buffer_limits(high=1024)
+
+    # peer does not read, buffer grows over high watermark
+    data = b'x' * (16 * 1024 * 1024)
+    tr.write(data)
+    loop.run_until_complete(asyncio.sleep(0.05, loop=loop))
+    assert pr.calls == ['pause']
+
+    def reader():
+        received = 0
+        while received < len(data):
+            received += len(conn.recv(65536))
+
+    thread = threading.Thread(target=reader)
+    thread.start()
+    for _ in range(1000):
+        if not tr.get_write_buffer_size():
+            break
+        loop.run_until_complete(asyncio.sleep(0.01, loop=loop))
+    thread.join()
+    assert pr.calls == ['pause', 'resume']
+
+    tr.close()
+    conn.close()
+    lsock.close()
+
+
+def test_transport_linger(loop):
+    lsock = socket.socket()
+    lsock.bind(('127.0.0.1', 0))
+    lsock.listen(1)
+
+    sock = socket.socket()
+    sock.connect(lsock.getsockname())
+    conn, _ = lsock.accept()
+
+    if not isinstance(loop, tokio.Loop):
+        sock.close()
+        conn.close()
+        lsock.close()
+        pytest.skip('linger is tokio specific')
+
+    def linger():
+        val = sock.getsockopt(socket.SOL_SOCKET, socket.SO_LINGER, 8)
+        return struct.unpack('ii', val)
+
+    with pytest.raises(ValueError):
+        loop.run_until_complete(
+            loop.create_connection(MyBaseProto, sock=sock, linger=-1))
+
+    tr, pr = loop.run_until_complete(
+        loop.create_connection(MyBaseProto, sock=sock, linger=0))
+    assert linger() == (1, 0)
+
+    tr.set_linger(5)
+    assert linger() == (1, 5)
+    tr.set_linger(None)
+    assert linger()[0] == 0
+
+    tr.close()
+    conn.close()
+    lsock.close()
+
+
+def test_transport_options_after_connection_lost(loop):
+    if not isinstance(loop, tokio.Loop):
+        pytest.skip('socket options are tokio specific')
+
+    lsock = socket.socket()
+    lsock.bind(('127.0.0.1', 0))
+    lsock.listen(1)
+
+    sock = socket.socket()
+    sock.connect(lsock.getsockname())
+    conn, _ = lsock.accept()
+
+    tr, pr = loop.run_until_complete(
+        loop.create_connection(lambda: MyBaseProto(loop=loop), sock=sock))
+    conn.close()
+    loop.run_until_complete(pr.done)
+
+    # fd of lost connection may belong to other socket already
+    with pytest.raises(RuntimeError):
+        tr.set_nodelay(True)
+    with pytest.raises(RuntimeError):
+        tr.set_linger(0)
+    with pytest.raises(RuntimeError):
+        tr.get_socket_option(socket.SOL_SOCKET, socket.SO_KEEPALIVE)
+
+    lsock.close()
+
+
+def test_transport_drain_connection_lost(loop):
+    if not isinstance(loop, tokio.Loop):
+        pytest.skip('drain is tokio specific')
+
+    lsock = socket.socket()
+    lsock.bind(('127.0.0.1', 0))
+    lsock.listen(1)
+
+    sock = socket.socket()
+    sock.connect(lsock.getsockname())
+    conn, _ = lsock.accept()
+
+    tr, pr = loop.run_until_complete(
+        loop.create_connection(lambda: MyBaseProto(loop=loop), sock=sock))
+    tr.write(b'x' * 64 * 1024 * 1024)
+    waiter = tr.drain()
+    assert not waiter.done()
+
+    # peer does not read, pending drain() is resolved with connection loss
+    conn.close()
+    loop.run_until_complete(pr.done)
+    assert waiter.done()
+    exc = waiter.exception()
+    assert exc is None or isinstance(exc, ConnectionError)
+
+    lsock.close()
+
+
+def test_transport_write_queue_limit(loop):
+    if not isinstance(loop, tokio.Loop):
+        pytest.skip('write queue limit is tokio specific')
+
+    class Proto(MyBaseProto):
+        exc = None
+
+        def connection_lost(self, exc):
+            self.exc = exc
+            super().connection_lost(exc)
+
+    lsock = socket.socket()
+    lsock.bind(('127.0.0.1', 0))
+    lsock.listen(1)
+
+    sock = socket.socket()
+    sock.connect(lsock.getsockname())
+    conn, _ = lsock.accept()
+
+    tr, pr = loop.run_until_complete(
+        loop.create_connection(lambda: Proto(loop), sock=sock))
+    tr.set_write_buffer_limits(high=1)
+
+    # queued data below limit is accepted
+    tr.write(b'x' * 65536)
+    assert not tr.is_closing()
+
+    # protocol ignores pause_writing(), write() does not raise but
+    # transport is aborted once queued bytes reach the limit
+    for _ in range(8):
+        tr.write(b'x' * 65536)
+    assert tr.is_closing()
+
+    loop.run_until_complete(asyncio.wait_for(pr.done, 5, loop=loop))
+    assert isinstance(pr.exc, BlockingIOError)
+
+    conn.close()
+    lsock.close()
+
+
+def test_transport_tos(loop):
+    if not isinstance(loop, tokio.Loop):
+        pytest.skip('tos is tokio specific')
+
+    with pytest.raises(ValueError):
+        loop.create_server(asyncio.Protocol, '127.0.0.1', 0, tos=256)
+
+    accepted = asyncio.Future(loop=loop)
+
+    class Proto(asyncio.Protocol):
+        def connection_made(self, tr):
+            fd = tr.get_extra_info('socket').fileno()
+            with socket.fromfd(fd, socket.AF_INET, socket.SOCK_STREAM) as sock:
+                accepted.set_result(sock.getsockopt(socket.IPPROTO_IP, socket.IP_TOS))
+
+    srv = loop.run_until_complete(
+        loop.create_server(Proto, '127.0.0.1', 0, tos=0x20))
+    addr = srv.sockets[0].getsockname()
+
+    sock = socket.socket()
+    sock.connect(addr)
+    tr, _ = loop.run_until_complete(
+        loop.create_connection(MyBaseProto, sock=sock, tos=0xb8))
+    assert sock.getsockopt(socket.IPPROTO_IP, socket.IP_TOS) == 0xb8
+
+    tr.set_tos(0x10)
+    assert sock.getsockopt(socket.IPPROTO_IP, socket.IP_TOS) == 0x10
+    with pytest.raises(ValueError):
+        tr.set_tos(-1)
+
+    assert loop.run_until_complete(accepted) == 0x20
+
+    tr.close()
+    srv.close()
+
+
+@pytest.mark.parametrize('read_batch', [None, 0])
+def test_transport_read_batch(loop, read_batch):
+    if not isinstance(loop, tokio.Loop):
+        pytest.skip('read_batch is tokio specific')
+
+    SIZE = 96 * 1024
+    chunks = []
+    done = asyncio.Future(loop=loop)
+
+    class Proto(asyncio.Protocol):
+        def data_received(self, data):
+            chunks.append(len(data))
+            if sum(chunks) == SIZE:
+                done.set_result(None)
+
+    srv = loop.run_until_complete(
+        loop.create_server(Proto, '127.0.0.1', 0, read_batch=read_batch))
+    addr = srv.sockets[0].getsockname()
+
+    # data is queued in socket buffers before transport polls socket
+    with socket.create_connection(addr) as sock:
+        sock.sendall(b'x' * SIZE)
+        loop.run_until_complete(asyncio.wait_for(done, 5, loop=loop))
+
+    if read_batch is None:
+        assert chunks == [SIZE]
+    else:
+        assert len(chunks) > 1
+    srv.close()
+
+
+def test_transport_read_buffers_reuse(loop):
+    if not isinstance(loop, tokio.Loop):
+        pytest.skip('read buffer pool is tokio specific')
+
+    class Echo(asyncio.Protocol):
+        def connection_made(self, tr):
+            self.transport = tr
+
+        def data_received(self, data):
+            self.transport.write(data)
+
+    srv = loop.run_until_complete(
+        loop.create_server(Echo, '127.0.0.1', 0))
+    addr = srv.sockets[0].getsockname()
+
+    async def client():
+        reader, writer = await asyncio.open_connection(*addr, loop=loop)
+        for _ in range(2000):
+            writer.write(b'x' * 1000)
+            await reader.readexactly(1000)
+        writer.close()
+
+    allocated, reused, _ = tokio._tokio.read_buffers_stats()
+    loop.run_until_complete(client())
+
+    new_allocated, new_reused, free = tokio._tokio.read_buffers_stats()
+    assert new_reused - reused >= 100
+    assert new_allocated - allocated < 20
+    assert free > 0
+    srv.close()
+
+
+def test_write_buffer(loop):
+    if not isinstance(loop, tokio.Loop):
+        pytest.skip('WriteBuffer is tokio specific')
+
+    buf = tokio.WriteBuffer(64)
+    assert buf.capacity >= 64
+    buf.extend(b'GET ')
+    buf.extend(memoryview(b'/path'))
+    buf.extend(bytearray(b' HTTP/1.1'))
+    buf.append(ord('\n'))
+    assert len(buf) == 19
+    assert bytes(buf) == b'GET /path HTTP/1.1\n'
+    with pytest.raises(TypeError):
+        buf.extend('str')
+
+    data = buf.take()
+    assert data == b'GET /path HTTP/1.1\n'
+    assert len(buf) == 0
+
+    received = asyncio.Future(loop=loop)
+
+    class Proto(asyncio.Protocol):
+        def data_received(self, data):
+            received.set_result(bytes(data))
+
+    srv = loop.run_until_complete(
+        loop.create_server(Proto, '127.0.0.1', 0))
+    addr = srv.sockets[0].getsockname()
+    tr, _ = loop.run_until_complete(
+        loop.create_connection(asyncio.Protocol, *addr))
+
+    # transport takes buffered data
+    buf.extend(b'ping')
+    tr.write(buf)
+    assert len(buf) == 0
+    buf.extend(b'pong')
+    assert loop.run_until_complete(
+        asyncio.wait_for(received, 5, loop=loop)) == b'ping'
+
+    tr.close()
+    srv.close()
+
+
+def test_loop_transports(loop):
+    if not isinstance(loop, tokio.Loop):
+        pytest.skip('loop.transports() is tokio specific')
+
+    received = asyncio.Future(loop=loop)
+
+    class Proto(asyncio.Protocol):
+        def data_received(self, data):
+            received.set_result(bytes(data))
+
+    srv = loop.run_until_complete(
+        loop.create_server(Proto, '127.0.0.1', 0))
+    addr = srv.sockets[0].getsockname()
+    tr, _ = loop.run_until_complete(
+        loop.create_connection(asyncio.Protocol, *addr))
+
+    tr.write(b'ping')
+    loop.run_until_complete(asyncio.wait_for(received, 5, loop=loop))
+
+    transports = {d['transport']: d for d in loop.transports()}
+    assert len(transports) == 2
+
+    desc = transports[tr]
+    assert desc['peername'][1] == addr[1]
+    assert desc['bytes_sent'] == 4
+    assert desc['bytes_received'] == 0
+    assert desc['state'] == 'open'
+    assert desc['age'] >= 0
+
+    server_desc = [d for t, d in transports.items() if t is not tr][0]
+    assert server_desc['bytes_received'] == 4
+
+    tr.close()
+    srv.close()
+    loop.run_until_complete(asyncio.sleep(0.1, loop=loop))
+    assert loop.transports() == []
+
+
+def test_transport_stats(loop):
+    if not isinstance(loop, tokio.Loop):
+        pytest.skip('transport stats are tokio specific')
+
+    received = asyncio.Future(loop=loop)
+    srv_transports = []
+
+    class Proto(asyncio.Protocol):
+        def connection_made(self, tr):
+            srv_transports.append(tr)
+
+        def data_received(self, data):
+            received.set_result(bytes(data))
+
+    before = loop.stats()
+
+    srv = loop.run_until_complete(
+        loop.create_server(Proto, '127.0.0.1', 0))
+    addr = srv.sockets[0].getsockname()
+    tr, _ = loop.run_until_complete(
+        loop.create_connection(asyncio.Protocol, *addr))
+    tr.write(b'ping')
+    loop.run_until_complete(asyncio.wait_for(received, 5, loop=loop))
+
+    stats = tr.get_extra_info('stats')
+    assert stats['bytes_sent'] == 4
+    assert stats['bytes_received'] == 0
+    assert stats['connect_time'] >= 0
+    assert stats['lifetime'] >= 0
+
+    stats = srv_transports[0].get_extra_info('stats')
+    assert stats['bytes_received'] == 4
+    assert stats['connect_time'] is None
+
+    tr.close()
+    srv.close()
+    loop.run_until_complete(asyncio.sleep(0.1, loop=loop))
+
+    after = loop.stats()
+    assert after['transports'] == 0
+    assert after['transports_total'] - before['transports_total'] == 2
+    assert after['bytes_sent'] - before['bytes_sent'] == 4
+    assert after['bytes_received'] - before['bytes_received'] == 4
+    assert after['connect_time'] >= 0
+
+
+def test_transport_socket_option(loop):
+    if not isinstance(loop, tokio.Loop):
+        pytest.skip('set_socket_option is tokio specific')
+
+    srv = loop.run_until_complete(
+        loop.create_server(asyncio.Protocol, '127.0.0.1', 0))
+    addr = srv.sockets[0].getsockname()
+    tr, _ = loop.run_until_complete(
+        loop.create_connection(asyncio.Protocol, *addr))
+
+    tr.set_socket_option(socket.SOL_SOCKET, socket.SO_KEEPALIVE, 1)
+    assert tr.get_socket_option(socket.SOL_SOCKET, socket.SO_KEEPALIVE) == 1
+    tr.set_socket_option(socket.SOL_SOCKET, socket.SO_KEEPALIVE, False)
+    assert tr.get_socket_option(socket.SOL_SOCKET, socket.SO_KEEPALIVE) == 0
+
+    linger = struct.pack('ii', 1, 5)
+    tr.set_socket_option(socket.SOL_SOCKET, socket.SO_LINGER, linger)
+    assert tr.get_socket_option(
+        socket.SOL_SOCKET, socket.SO_LINGER, len(linger)) == linger
+
+    with pytest.raises(TypeError):
+        tr.set_socket_option(socket.SOL_SOCKET, socket.SO_KEEPALIVE, 'on')
+    with pytest.raises(OSError):
+        tr.get_socket_option(socket.SOL_SOCKET, -1)
+
+    tr.close()
+    with pytest.raises(RuntimeError):
+        tr.get_socket_option(socket.SOL_SOCKET, socket.SO_KEEPALIVE)
+    srv.close()
+
+
+@pytest.mark.skipif(not sys.platform.startswith('linux'),
+                    reason='TCP_USER_TIMEOUT is linux specific')
+def test_user_timeout(loop):
+    if not isinstance(loop, tokio.Loop):
+        pytest.skip('user_timeout is tokio specific')
+
+    TCP_USER_TIMEOUT = 18
+
+    with pytest.raises(ValueError):
+        loop.run_until_complete(loop.create_server(
+            asyncio.Protocol, '127.0.0.1', 0, user_timeout=-1))
+
+    srv_transports = []
+
+    class Proto(asyncio.Protocol):
+        def connection_made(self, tr):
+            srv_transports.append(tr)
+
+    srv = loop.run_until_complete(loop.create_server(
+        Proto, '127.0.0.1', 0, user_timeout=2.5))
+    addr = srv.sockets[0].getsockname()
+    tr, _ = loop.run_until_complete(loop.create_connection(
+        asyncio.Protocol, *addr, user_timeout=10))
+    loop.run_until_complete(asyncio.sleep(0.05, loop=loop))
+
+    assert tr.get_socket_option(
+        socket.IPPROTO_TCP, TCP_USER_TIMEOUT) == 10000
+    assert srv_transports[0].get_socket_option(
+        socket.IPPROTO_TCP, TCP_USER_TIMEOUT) == 2500
+
+    tr.close()
+    srv.close()
+
+
+def test_transport_faults(loop):
+    if not isinstance(loop, tokio.Loop):
+        pytest.skip('fault injection is tokio specific')
+
+    with pytest.raises(ValueError):
+        loop.run_until_complete(loop.create_server(
+            asyncio.Protocol, '127.0.0.1', 0, faults={'drop_rate': 2.0}))
+    with pytest.raises(ValueError):
+        loop.run_until_complete(loop.create_server(
+            asyncio.Protocol, '127.0.0.1', 0, faults={'unknown': 1}))
+
+    class Proto(asyncio.Protocol):
+        def __init__(self):
+            self.data = bytearray()
+            self.lost = asyncio.Future(loop=loop)
+
+        def data_received(self, data):
+            self.data.extend(data)
+
+        def connection_lost(self, exc):
+            self.lost.set_result(exc)
+
+    # partial writes and latency, data arrives intact
+    srv_proto = Proto()
+    srv = loop.run_until_complete(loop.create_server(
+        lambda: srv_proto, '127.0.0.1', 0, faults={'latency': 0.05}))
+    addr = srv.sockets[0].getsockname()
+    tr, _ = loop.run_until_complete(loop.create_connection(
+        asyncio.Protocol, *addr, faults={'partial_writes': True, 'seed': 1}))
+
+    data = b'x' * (256 * 1024)
+    started = loop.time()
+    tr.write(data)
+    tr.close()
+    loop.run_until_complete(asyncio.wait_for(srv_proto.lost, 10, loop=loop))
+    assert srv_proto.data == data
+    assert loop.time() - started >= 0.05
+    srv.close()
+
+    # injected reset
+    srv = loop.run_until_complete(loop.create_server(
+        asyncio.Protocol, '127.0.0.1', 0))
+    addr = srv.sockets[0].getsockname()
+    pr = Proto()
+    tr, _ = loop.run_until_complete(loop.create_connection(
+        lambda: pr, *addr, faults={'reset_rate': 1.0}))
+    tr.write(b'data')
+    exc = loop.run_until_complete(asyncio.wait_for(pr.lost, 5, loop=loop))
+    assert isinstance(exc, ConnectionResetError)
+    srv.close()
+
+
+@pytest.mark.parametrize('version', [1, 2])
+def test_create_connection_proxy_protocol(loop, version):
+    if not isinstance(loop, tokio.Loop):
+        pytest.skip('proxy_protocol is tokio specific')
+
+    with pytest.raises(ValueError):
+        loop.run_until_complete(loop.create_connection(
+            asyncio.Protocol, '127.0.0.1', 1, proxy_protocol=3))
+    with pytest.raises(ValueError):
+        loop.run_until_complete(loop.create_connection(
+            asyncio.Protocol, '127.0.0.1', 1, proxy_source=('10.0.0.1', 80)))
+
+    received = bytearray()
+    done = asyncio.Future(loop=loop)
+
+    class Proto(asyncio.Protocol):
+        def data_received(self, data):
+            received.extend(data)
+            if received.endswith(b'ping'):
+                done.set_result(None)
+
+    srv = loop.run_until_complete(
+        loop.create_server(Proto, '127.0.0.1', 0))
+    addr = srv.sockets[0].getsockname()
+    tr, _ = loop.run_until_complete(
+        loop.create_connection(
+            asyncio.Protocol, *addr, proxy_protocol=version,
+            proxy_source=('192.0.2.1', 4242)))
+    tr.write(b'ping')
+    loop.run_until_complete(asyncio.wait_for(done, 5, loop=loop))
+
+    if version == 1:
+        assert bytes(received) == (
+            'PROXY TCP4 192.0.2.1 127.0.0.1 4242 {}\r\nping'.format(
+                addr[1]).encode())
+    else:
+        assert bytes(received) == (
+            b'\r\n\r\n\x00\r\nQUIT\n\x21\x11\x00\x0c' +
+            socket.inet_aton('192.0.2.1') + socket.inet_aton('127.0.0.1') +
+            struct.pack('!HH', 4242, addr[1]) + b'ping')
+
+    tr.close()
+    srv.close()
+
+
+def _recv_exact(conn, size):
+    data = b''
+    while len(data) < size:
+        chunk = conn.recv(size - len(data))
+        if not chunk:
+            break
+        data += chunk
+    return data
+
+
+def _fake_proxy(handshake):
+    # accepts one connection, performs handshake and echoes data back
+    lsock = socket.socket()
+    lsock.bind(('127.0.0.1', 0))
+    lsock.listen(1)
+    requests = []
+
+    def serve():
+        conn, _ = lsock.accept()
+        conn.settimeout(5.0)
+        try:
+            requests.append(handshake(conn))
+            conn.sendall(conn.recv(1024))
+        finally:
+            conn.close()
+            lsock.close()
+
+    thread = threading.Thread(target=serve)
+    thread.start()
+    return lsock.getsockname(), requests, thread
+
+
+def _socks5_handshake(conn):
+    assert _recv_exact(conn, 4) == b'\x05\x02\x00\x02'
+    conn.sendall(b'\x05\x02')
+    ver, ulen = _recv_exact(conn, 2)
+    user = _recv_exact(conn, ulen)
+    plen, = _recv_exact(conn, 1)
+    password = _recv_exact(conn, plen)
+    conn.sendall(b'\x01\x00')
+    header = _recv_exact(conn, 5)
+    host = _recv_exact(conn, header[4])
+    port, = struct.unpack('!H', _recv_exact(conn, 2))
+    conn.sendall(b'\x05\x00\x00\x01\x7f\x00\x00\x01\x00\x50')
+    return user, password, host, port
+
+
+def _http_connect_handshake(conn):
+    request = b''
+    while not request.endswith(b'\r\n\r\n'):
+        request += conn.recv(1)
+    conn.sendall(b'HTTP/1.1 200 Connection established\r\n\r\n')
+    return request
+
+
+@pytest.mark.parametrize('scheme', ['socks5', 'http'])
+def test_create_connection_proxy(loop, scheme):
+    if not isinstance(loop, tokio.Loop):
+        pytest.skip('proxy is tokio specific')
+
+    with pytest.raises(ValueError):
+        loop.run_until_complete(loop.create_connection(
+            asyncio.Protocol, 'example.com', 80, proxy='ftp://proxy'))
+
+    handshake = (_socks5_handshake if scheme == 'socks5'
+                 else _http_connect_handshake)
+    addr, requests, thread = _fake_proxy(handshake)
+
+    received = asyncio.Future(loop=loop)
+
+    class Proto(asyncio.Protocol):
+        def data_received(self, data):
+            received.set_result(bytes(data))
+
+    url = '{}://us%40er:secret@{}:{}'.format(scheme, *addr)
+    tr, _ = loop.run_until_complete(loop.create_connection(
+        Proto, 'example.com', 443, proxy=url))
+    tr.write(b'ping')
+    assert loop.run_until_complete(
+        asyncio.wait_for(received, 5, loop=loop)) == b'ping'
+    tr.close()
+    thread.join()
+
+    if scheme == 'socks5':
+        assert requests == [(b'us@er', b'secret', b'example.com', 443)]
+    else:
+        assert requests[0].startswith(
+            b'CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n')
+        assert b'Proxy-Authorization: Basic dXNAZXI6c2VjcmV0\r\n' in requests[0]
+
+
+def test_transport_close_flushes(loop):
+    lsock = socket.socket()
+    lsock.bind(('127.0.0.1', 0))
+    lsock.listen(1)
+
+    sock = socket.socket()
+    sock.connect(lsock.getsockname())
+    conn, _ = lsock.accept()
+    conn.settimeout(5.0)
+
+    tr, pr = loop.run_until_complete(
+        loop.create_connection(
+            lambda: MyBaseProto(loop=loop), sock=sock))
+
+    data = b'x' * (4 * 1024 * 1024)
+    tr.write(data)
+    tr.close()
+
+    received = bytearray()
+
+    def reader():
+        while True:
+            chunk = conn.recv(65536)
+            if not chunk:
+                break
+            received.extend(chunk)
+
+    thread = threading.Thread(target=reader)
+    thread.start()
+    loop.run_until_complete(asyncio.wait_for(pr.done, 10, loop=loop))
+    thread.join()
+
+    assert received == data
+    assert pr.state == 'CLOSED'
+
+    conn.close()
+    lsock.close()
+
+
+def test_create_server_idle_timeout(loop):
+    if not isinstance(loop, tokio.Loop):
+        pytest.skip('idle_timeout is tokio specific')
+
+    lost = asyncio.Future(loop=loop)
+
+    class Proto(asyncio.Protocol):
+        def data_received(self, data):
+            pass
+
+        def connection_lost(self, exc):
+            lost.set_result(exc)
+
+    srv = loop.run_until_complete(
+        loop.create_server(Proto, '127.0.0.1', 0, idle_timeout=0.2))
+    addr = srv.sockets[0].getsockname()
+
+    async def client():
+        reader, writer = await asyncio.open_connection(*addr, loop=loop)
+        # activity postpones timeout
+        for _ in range(3):
+            writer.write(b'ping')
+            await asyncio.sleep(0.1, loop=loop)
+        assert not lost.done()
+
+        exc = await asyncio.wait_for(lost, 5, loop=loop)
+        assert isinstance(exc, socket.timeout)
+        assert await reader.read() == b''
+        writer.close()
+
+    loop.run_until_complete(client())
+    srv.close()
+
+
+def test_create_server_rate_limit(loop):
+    if not isinstance(loop, tokio.Loop):
+        pytest.skip('rate limits are tokio specific')
+
+    with pytest.raises(ValueError):
+        loop.create_server(asyncio.Protocol, '127.0.0.1', 0, read_rate=0)
+
+    class Proto(asyncio.Protocol):
+        def connection_made(self, transport):
+            # one second burst is sent immediately, the rest is throttled
+            for _ in range(40):
+                transport.write(b'x' * 1000)
+            transport.close()
+
+    srv = loop.run_until_complete(
+        loop.create_server(Proto, '127.0.0.1', 0, write_rate=20000))
+    addr = srv.sockets[0].getsockname()
+
+    async def client():
+        reader, writer = await asyncio.open_connection(*addr, loop=loop)
+        started = loop.time()
+        data = await asyncio.wait_for(reader.read(), 10, loop=loop)
+        writer.close()
+        return len(data), loop.time() - started
+
+    size, elapsed = loop.run_until_complete(client())
+    assert size == 40000
+    assert elapsed >= 0.8
+    srv.close()
+
+
+def test_bind_interface(loop):
+    if not isinstance(loop, tokio.Loop):
+        pytest.skip('interface is tokio specific')
+    if not sys.platform.startswith('linux'):
+        pytest.skip('loopback interface name is platform specific')
+
+    with pytest.raises(ValueError):
+        loop.create_server(asyncio.Protocol, '127.0.0.1', 0, interface='')
+    with pytest.raises(ValueError):
+        loop.create_connection(asyncio.Protocol, '127.0.0.1', 1, interface='x' * 16)
+
+    with pytest.raises(OSError):
+        loop.run_until_complete(loop.create_server(
+            asyncio.Protocol, '127.0.0.1', 0, interface='no-such-if0'))
+
+    try:
+        srv = loop.run_until_complete(
+            loop.create_server(asyncio.Protocol, '127.0.0.1', 0, interface='lo'))
+    except PermissionError:
+        pytest.skip('SO_BINDTODEVICE requires CAP_NET_RAW')
+
+    addr = srv.sockets[0].getsockname()
+    tr, _ = loop.run_until_complete(
+        loop.create_connection(asyncio.Protocol, *addr, interface='lo'))
+    assert tr.get_extra_info('peername') == addr
+
+    tr.close()
+    srv.close()
+
+
+def test_create_log_connection(loop):
+    if not isinstance(loop, tokio.Loop):
+        pytest.skip('log connection is tokio specific')
+
+    with pytest.raises(ValueError):
+        loop.create_log_connection('127.0.0.1', 1, buffer_size=0)
+    with pytest.raises(ValueError):
+        loop.create_log_connection('127.0.0.1', 1, reconnect_delay=0)
+
+    received = []
+    lines = asyncio.Future(loop=loop)
+
+    class Proto(asyncio.Protocol):
+        def data_received(self, data):
+            received.append(data)
+            if b''.join(received).count(b'\n') == 3 and not lines.done():
+                lines.set_result(b''.join(received))
+
+    # reserve port, nothing listens on it yet
+    sock = socket.socket()
+    sock.bind(('127.0.0.1', 0))
+    port = sock.getsockname()[1]
+    sock.close()
+
+    tr = loop.create_log_connection(
+        '127.0.0.1', port, buffer_size=12,
+        reconnect_delay=0.01, max_reconnect_delay=0.05)
+    for line in (b'one\n', b'two\n', b'six\n', b'ten\n'):
+        tr.write(line)
+
+    # oldest line is dropped
+    assert tr.dropped == 1
+    assert tr.dropped_bytes == 4
+    assert tr.buffered == 12
+    assert not tr.connected
+
+    srv = loop.run_until_complete(
+        loop.create_server(Proto, '127.0.0.1', port))
+    data = loop.run_until_complete(asyncio.wait_for(lines, 5, loop=loop))
+
+    assert data == b'two\nsix\nten\n'
+    assert tr.connected
+    assert tr.sent_bytes == 12
+    assert tr.buffered == 0
+
+    tr.close()
+    assert tr.is_closing()
+    with pytest.raises(RuntimeError):
+        tr.write(b'data')
+    srv.close()
+
+
+def test_transport_eof_received(loop):
+    lost = asyncio.Future(loop=loop)
+
+    class Proto(asyncio.Protocol):
+        def connection_made(self, transport):
+            self.transport = transport
+            self.data = b''
+
+        def data_received(self, data):
+            self.data += data
+
+        def eof_received(self):
+            # peer half-closed, reply and keep write side open
+            self.transport.write(self.data.upper())
+            loop.call_later(0.05, self.transport.close)
+            return True
+
+        def connection_lost(self, exc):
+            lost.set_result(exc)
+
+    srv = loop.run_until_complete(
+        loop.create_server(Proto, '127.0.0.1', 0))
+    addr = srv.sockets[0].getsockname()
+
+    def client():
+        with socket.create_connection(addr) as sock:
+            sock.settimeout(5.0)
+            sock.sendall(b'request')
+            sock.shutdown(socket.SHUT_WR)
+            data = b''
+            while True:
+                chunk = sock.recv(100)
+                if not chunk:
+                    return data
+                data += chunk
+
+    data = loop.run_until_complete(loop.run_in_executor(None, client))
+    assert data == b'REQUEST'
+    assert loop.run_until_complete(lost) is None
+
+    srv.close()
+
+
 def test_transport_shutdown(loop):
     CNT = 0           # number of clients that were successful
     TOTAL_CNT = 100   # total number of clients that test will create
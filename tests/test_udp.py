@@ -0,0 +1,367 @@
+import asyncio
+import os
+import socket
+import tempfile
+
+import pytest
+
+import tokio
+
+
+class EchoProto(asyncio.DatagramProtocol):
+
+    def __init__(self, made):
+        self.made = made
+        self.transport = None
+        self.lost = None
+
+    def connection_made(self, transport):
+        self.transport = transport
+        self.made.append(self)
+
+    def datagram_received(self, data, addr):
+        self.transport.sendto(b'echo:' + data, addr)
+
+    def connection_lost(self, exc):
+        self.lost = exc
+
+
+def test_create_datagram_server(loop):
+    if not isinstance(loop, tokio.Loop):
+        pytest.skip('create_datagram_server is tokio specific')
+
+    made = []
+    srv = loop.run_until_complete(loop.create_datagram_server(
+        lambda: EchoProto(made), '127.0.0.1', 0))
+    addr = srv.sockets[0].getsockname()
+    assert len(made) == 1
+    assert made[0].transport.get_extra_info('sockname') == addr
+
+    def client():
+        with socket.socket(socket.AF_INET, socket.SOCK_DGRAM) as sock:
+            sock.settimeout(5)
+            sock.sendto(b'ping', addr)
+            return sock.recvfrom(1024)
+
+    data, peer = loop.run_until_complete(
+        loop.run_in_executor(None, client))
+    assert data == b'echo:ping'
+    assert peer == addr
+
+    with pytest.raises(ValueError):
+        made[0].transport.sendto(b'data', ('localhost', 1))
+
+    srv.close()
+    loop.run_until_complete(
+        asyncio.wait_for(srv.wait_closed(), 5, loop=loop))
+    assert made[0].transport.is_closing()
+    assert made[0].lost is None
+
+
+@pytest.mark.skipif(not hasattr(socket, 'SO_REUSEPORT'),
+                    reason='The system does not support SO_REUSEPORT')
+def test_create_datagram_server_reuse_port(loop):
+    if not isinstance(loop, tokio.Loop):
+        pytest.skip('create_datagram_server is tokio specific')
+
+    made = []
+    srv1 = loop.run_until_complete(loop.create_datagram_server(
+        lambda: EchoProto(made), '127.0.0.1', 0, reuse_port=True))
+    port = srv1.sockets[0].getsockname()[1]
+    srv2 = loop.run_until_complete(loop.create_datagram_server(
+        lambda: EchoProto(made), '127.0.0.1', port, reuse_port=True))
+    assert len(made) == 2
+
+    for srv in (srv1, srv2):
+        srv.close()
+        loop.run_until_complete(srv.wait_closed())
+
+
+def test_create_datagram_server_address_in_use(loop):
+    if not isinstance(loop, tokio.Loop):
+        pytest.skip('create_datagram_server is tokio specific')
+    if not socket.has_ipv6:
+        pytest.skip('server needs ipv4 and ipv6 addresses')
+
+    # port is free on ipv4 and in use on ipv6
+    with socket.socket(socket.AF_INET, socket.SOCK_DGRAM) as sock:
+        sock.bind(('0.0.0.0', 0))
+        port = sock.getsockname()[1]
+    busy = socket.socket(socket.AF_INET6, socket.SOCK_DGRAM)
+    busy.setsockopt(socket.IPPROTO_IPV6, socket.IPV6_V6ONLY, 1)
+    try:
+        busy.bind(('::', port))
+    except OSError:
+        busy.close()
+        pytest.skip('ipv6 is not available')
+
+    made = []
+    with pytest.raises(OSError):
+        loop.run_until_complete(loop.create_datagram_server(
+            lambda: EchoProto(made), None, port))
+    loop.run_until_complete(asyncio.sleep(0.05, loop=loop))
+    busy.close()
+
+    # socket of free address is released
+    assert all(proto.transport.is_closing() for proto in made)
+    with socket.socket(socket.AF_INET, socket.SOCK_DGRAM) as sock:
+        sock.bind(('0.0.0.0', port))
+
+
+def test_create_datagram_server_factory_error(loop):
+    if not isinstance(loop, tokio.Loop):
+        pytest.skip('create_datagram_server is tokio specific')
+
+    made = []
+
+    def factory():
+        if made:
+            raise RuntimeError('factory error')
+        return EchoProto(made)
+
+    addrs = socket.getaddrinfo(
+        None, 0, type=socket.SOCK_DGRAM, flags=socket.AI_PASSIVE)
+    if len(addrs) < 2:
+        pytest.skip('server needs several addresses')
+
+    with pytest.raises(RuntimeError):
+        loop.run_until_complete(
+            loop.create_datagram_server(factory, None, 0))
+    loop.run_until_complete(asyncio.sleep(0.05, loop=loop))
+
+    # transport of first address is closed
+    assert len(made) == 1
+    assert made[0].transport.is_closing()
+    addr = made[0].transport.get_extra_info('sockname')
+    with socket.socket(socket.AF_INET6 if ':' in addr[0] else socket.AF_INET,
+                       socket.SOCK_DGRAM) as sock:
+        sock.bind(addr[:2])
+
+
+class RecvProto(asyncio.DatagramProtocol):
+
+    def __init__(self, loop):
+        self.transport = None
+        self.received = asyncio.Queue(loop=loop)
+        self.errors = []
+
+    def connection_made(self, transport):
+        self.transport = transport
+
+    def datagram_received(self, data, addr):
+        self.received.put_nowait((data, addr))
+
+    def error_received(self, exc):
+        self.errors.append(exc)
+
+
+def test_create_datagram_endpoint_unix_socketpair(loop):
+    if not isinstance(loop, tokio.Loop):
+        pytest.skip('address of unnamed socket is tokio specific')
+
+    sock, peer = socket.socketpair(socket.AF_UNIX, socket.SOCK_DGRAM)
+    peer.settimeout(5)
+    tr, proto = loop.run_until_complete(loop.create_datagram_endpoint(
+        lambda: RecvProto(loop), sock=sock))
+    assert tr.get_extra_info('socket') is sock
+
+    peer.send(b'ping')
+    data, addr = loop.run_until_complete(
+        asyncio.wait_for(proto.received.get(), 5, loop=loop))
+    assert data == b'ping'
+    assert addr is None
+
+    tr.sendto(b'pong')
+    loop.run_until_complete(asyncio.sleep(0.01, loop=loop))
+    assert peer.recv(1024) == b'pong'
+
+    # remote address of socketpair is not known
+    with pytest.raises(ValueError):
+        tr.sendto(b'data', '/tmp/other.sock')
+
+    tr.close()
+    loop.run_until_complete(asyncio.sleep(0.01, loop=loop))
+    assert sock.fileno() == -1
+    peer.close()
+
+
+def test_create_datagram_endpoint_unix_path(loop):
+    if not isinstance(loop, tokio.Loop):
+        pytest.skip('address of unnamed socket is tokio specific')
+
+    with tempfile.TemporaryDirectory() as td:
+        path = os.path.join(td, 'server.sock')
+        client_path = os.path.join(td, 'client.sock')
+
+        # stale socket file is removed
+        with socket.socket(socket.AF_UNIX, socket.SOCK_DGRAM) as stale:
+            stale.bind(path)
+
+        tr, proto = loop.run_until_complete(loop.create_datagram_endpoint(
+            lambda: RecvProto(loop), local_addr=path, family=socket.AF_UNIX))
+        assert tr.get_extra_info('sockname') == path
+
+        with socket.socket(socket.AF_UNIX, socket.SOCK_DGRAM) as client:
+            client.settimeout(5)
+            client.bind(client_path)
+            client.sendto(b'ping', path)
+            data, addr = loop.run_until_complete(
+                asyncio.wait_for(proto.received.get(), 5, loop=loop))
+            assert (data, addr) == (b'ping', client_path)
+
+            tr.sendto(b'pong', addr)
+            loop.run_until_complete(asyncio.sleep(0.01, loop=loop))
+            assert client.recvfrom(1024) == (b'pong', path)
+
+            # connected to client
+            tr2, proto2 = loop.run_until_complete(loop.create_datagram_endpoint(
+                lambda: RecvProto(loop), remote_addr=client_path,
+                family=socket.AF_UNIX))
+            assert tr2.get_extra_info('peername') == client_path
+            tr2.sendto(b'hello')
+            loop.run_until_complete(asyncio.sleep(0.01, loop=loop))
+            assert client.recv(1024) == b'hello'
+
+            with pytest.raises(ValueError):
+                tr2.sendto(b'data', path)
+
+        tr.close()
+        tr2.close()
+        loop.run_until_complete(asyncio.sleep(0.01, loop=loop))
+
+
+def test_datagram_transport_multicast(loop):
+    if not isinstance(loop, tokio.Loop):
+        pytest.skip('multicast options of transport are tokio specific')
+
+    group = '239.255.0.1'
+    made = []
+
+    def factory():
+        proto = RecvProto(loop)
+        made.append(proto)
+        return proto
+
+    srv = loop.run_until_complete(loop.create_datagram_server(
+        factory, '0.0.0.0', 0))
+    port = srv.sockets[0].getsockname()[1]
+    tr = made[0].transport
+
+    with pytest.raises(ValueError):
+        tr.join_multicast_group('127.0.0.1')
+    with pytest.raises(ValueError):
+        tr.join_multicast_group('ff02::1')
+    with pytest.raises(ValueError):
+        tr.set_multicast_ttl(256)
+
+    # receive datagram sent to group on loopback interface
+    tr.join_multicast_group(group, '127.0.0.1')
+    with socket.socket(socket.AF_INET, socket.SOCK_DGRAM) as sock:
+        sock.setsockopt(socket.IPPROTO_IP, socket.IP_MULTICAST_IF,
+                        socket.inet_aton('127.0.0.1'))
+        sock.sendto(b'ping', (group, port))
+        data, addr = loop.run_until_complete(
+            asyncio.wait_for(made[0].received.get(), 5, loop=loop))
+        assert data == b'ping'
+
+    # send to group on loopback interface
+    with socket.socket(socket.AF_INET, socket.SOCK_DGRAM) as sock:
+        sock.settimeout(5)
+        sock.bind(('0.0.0.0', 0))
+        sock.setsockopt(socket.IPPROTO_IP, socket.IP_ADD_MEMBERSHIP,
+                        socket.inet_aton(group) + socket.inet_aton('127.0.0.1'))
+        tr.set_multicast_interface('127.0.0.1')
+        tr.set_multicast_ttl(1)
+        tr.set_multicast_loop(True)
+        tr.sendto(b'pong', (group, sock.getsockname()[1]))
+        data, _ = loop.run_until_complete(
+            loop.run_in_executor(None, sock.recvfrom, 1024))
+        assert data == b'pong'
+
+    tr.leave_multicast_group(group, '127.0.0.1')
+
+    srv.close()
+    loop.run_until_complete(srv.wait_closed())
+    with pytest.raises(RuntimeError):
+        tr.set_multicast_ttl(1)
+
+
+def test_create_datagram_endpoint_connected(loop):
+    made = []
+    srv = loop.run_until_complete(loop.create_datagram_endpoint(
+        lambda: EchoProto(made), local_addr=('127.0.0.1', 0)))[0]
+    addr = srv.get_extra_info('sockname')
+
+    tr, proto = loop.run_until_complete(loop.create_datagram_endpoint(
+        lambda: RecvProto(loop), remote_addr=addr))
+    assert tr.get_extra_info('peername') == addr
+
+    tr.sendto(b'ping')
+    data, peer = loop.run_until_complete(
+        asyncio.wait_for(proto.received.get(), 5, loop=loop))
+    assert data == b'echo:ping'
+    assert peer == addr
+
+    # remote address is accepted too
+    tr.sendto(b'pong', addr)
+    data, peer = loop.run_until_complete(
+        asyncio.wait_for(proto.received.get(), 5, loop=loop))
+    assert data == b'echo:pong'
+
+    with pytest.raises(ValueError):
+        tr.sendto(b'data', ('127.0.0.1', 1))
+
+    tr.close()
+    srv.close()
+    loop.run_until_complete(asyncio.sleep(0.01, loop=loop))
+
+
+def test_create_datagram_endpoint_error_received(loop):
+    # port without listener
+    with socket.socket(socket.AF_INET, socket.SOCK_DGRAM) as sock:
+        sock.bind(('127.0.0.1', 0))
+        addr = sock.getsockname()
+
+    tr, proto = loop.run_until_complete(loop.create_datagram_endpoint(
+        lambda: RecvProto(loop), remote_addr=addr))
+
+    async def wait_error():
+        for _ in range(100):
+            if proto.errors:
+                return
+            tr.sendto(b'ping')
+            await asyncio.sleep(0.01, loop=loop)
+
+    loop.run_until_complete(wait_error())
+    assert isinstance(proto.errors[0], ConnectionRefusedError)
+    assert not tr.is_closing()
+
+    tr.close()
+    loop.run_until_complete(asyncio.sleep(0.01, loop=loop))
+
+
+def test_create_datagram_endpoint_allow_broadcast(loop):
+    with socket.socket(socket.AF_INET, socket.SOCK_DGRAM) as sock:
+        sock.settimeout(5)
+        sock.bind(('0.0.0.0', 0))
+        addr = ('127.255.255.255', sock.getsockname()[1])
+
+        # broadcast is not allowed by default
+        tr, proto = loop.run_until_complete(loop.create_datagram_endpoint(
+            lambda: RecvProto(loop), local_addr=('127.0.0.1', 0)))
+        tr.sendto(b'ping', addr)
+        loop.run_until_complete(asyncio.sleep(0.01, loop=loop))
+        assert isinstance(proto.errors[0], PermissionError)
+        tr.close()
+
+        tr, proto = loop.run_until_complete(loop.create_datagram_endpoint(
+            lambda: RecvProto(loop), local_addr=('127.0.0.1', 0),
+            allow_broadcast=True))
+        tr.sendto(b'ping', addr)
+        data, _ = loop.run_until_complete(
+            loop.run_in_executor(None, sock.recvfrom, 1024))
+        assert data == b'ping'
+        assert proto.errors == []
+        tr.close()
+
+    loop.run_until_complete(asyncio.sleep(0.01, loop=loop))
@@ -6,10 +6,12 @@
 import asyncio
 import os
 import socket
+import stat
 import tempfile
 
 import pytest
 
+import tokio
 import _testbase as tb
 from test_ssl import (ONLYCERT, ONLYKEY, create_client_ssl_context,
                       create_server_ssl_context)
@@ -68,8 +70,8 @@ def test_create_unix_server_1(loop):
                 loop=loop)
 
             try:
-                # srv_socks = srv.sockets
-                # assert srv_socks
+                srv_socks = srv.sockets
+                assert srv_socks
 
                 tasks = []
                 for _ in range(TOTAL_CNT):
@@ -84,11 +86,15 @@ def test_create_unix_server_1(loop):
                 await srv.wait_closed()
 
                 # Check that the server cleaned-up proxy-sockets
-                # for srv_sock in srv_socks:
-                #    assert srv_sock.fileno() == -1
+                for srv_sock in srv_socks:
+                    assert srv_sock.fileno() == -1
 
-            # asyncio doesn't cleanup the sock file
-            assert os.path.exists(sock_name)
+            # asyncio doesn't cleanup the sock file, tokio removes it
+            # unless cleanup_socket=False is passed
+            if isinstance(loop, tokio.Loop):
+                assert not os.path.exists(sock_name)
+            else:
+                assert os.path.exists(sock_name)
 
     async def start_server_sock(start_server):
         nonlocal CNT
@@ -103,8 +109,8 @@ def test_create_unix_server_1(loop):
             await asyncio.sleep(0.1, loop=loop)
 
             try:
-                # srv_socks = srv.sockets
-                # self.assertTrue(srv_socks)
+                srv_socks = srv.sockets
+                assert srv_socks
 
                 tasks = []
                 for _ in range(TOTAL_CNT):
@@ -119,10 +125,10 @@ def test_create_unix_server_1(loop):
                 await srv.wait_closed()
 
                 # Check that the server cleaned-up proxy-sockets
-                # for srv_sock in srv_socks:
-                #    self.assertEqual(srv_sock.fileno(), -1)
+                for srv_sock in srv_socks:
+                    assert srv_sock.fileno() == -1
 
-            # asyncio doesn't cleanup the sock file
+            # file of socket passed by caller is not removed by any loop
             assert os.path.exists(sock_name)
 
     # with self.subTest(func='start_unix_server(host, port)'):
@@ -161,6 +167,71 @@ def test_create_unix_server_2(loop):
         excinfo.match('in use')
 
 
+def test_unix_peercred(loop):
+    if not isinstance(loop, tokio.Loop):
+        pytest.skip('peercred is tokio specific')
+
+    transports = []
+
+    class Proto(asyncio.Protocol):
+        def connection_made(self, tr):
+            transports.append(tr)
+
+    with tempfile.TemporaryDirectory() as td:
+        sock_name = os.path.join(td, 'sock')
+        srv = loop.run_until_complete(
+            loop.create_unix_server(Proto, sock_name))
+
+        tr, _ = loop.run_until_complete(
+            loop.create_unix_connection(asyncio.Protocol, sock_name))
+        loop.run_until_complete(asyncio.sleep(0.01, loop=loop))
+
+        cred = tr.get_extra_info('peercred')
+        assert cred[1:] == (os.getuid(), os.getgid())
+        assert cred[0] in (os.getpid(), None)
+        assert transports[0].get_extra_info('peercred') == cred
+
+        # client socket is unnamed
+        assert transports[0].get_extra_info('sockname') == sock_name
+        assert transports[0].get_extra_info('peername') == ''
+        assert tr.get_extra_info('peername') == sock_name
+
+        tr.close()
+        srv.close()
+
+
+def test_unix_server_socket_file(loop):
+    if not isinstance(loop, tokio.Loop):
+        pytest.skip('mode and owner are tokio specific')
+
+    with tempfile.TemporaryDirectory() as td:
+        sock_name = os.path.join(td, 'sock')
+
+        # stale socket of previous process
+        stale = socket.socket(socket.AF_UNIX)
+        stale.bind(sock_name)
+        stale.close()
+
+        srv = loop.run_until_complete(
+            loop.create_unix_server(
+                asyncio.Protocol, sock_name, mode=0o600,
+                owner=(os.getuid(), -1)))
+        st = os.stat(sock_name)
+        assert stat.S_ISSOCK(st.st_mode)
+        assert stat.S_IMODE(st.st_mode) == 0o600
+        assert st.st_uid == os.getuid()
+
+        srv.close()
+        assert not os.path.exists(sock_name)
+
+        # socket file is kept without cleanup
+        srv = loop.run_until_complete(
+            loop.create_unix_server(
+                asyncio.Protocol, sock_name, cleanup_socket=False))
+        srv.close()
+        assert os.path.exists(sock_name)
+
+
 def test_create_unix_connection_1(loop):
     CNT = 0
     TOTAL_CNT = 100
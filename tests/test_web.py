@@ -0,0 +1,157 @@
+import asyncio
+
+import pytest
+
+import tokio
+from tokio.test_utils import loop_context
+from tokio.web import WebRequestHandler, create_web_server
+
+
+@pytest.fixture
+def loop():
+    with loop_context(tokio.EventLoopPolicy, fast=False) as _loop:
+        yield _loop
+
+
+def run_briefly(loop):
+    loop.run_until_complete(asyncio.sleep(0.05, loop=loop))
+
+
+class Response:
+    """Response with interface of aiohttp.web.Response"""
+
+    def __init__(self, body=b'', status=200, reason='OK'):
+        self.body = body
+        self.status = status
+        self.reason = reason
+        self._writer = None
+
+    async def prepare(self, request):
+        if self._writer is not None:
+            return
+        await request._prepare_hook(self)
+        self._writer = request._payload_writer
+        self._writer.write_headers(
+            'HTTP/1.1 {} {}\r\n'.format(self.status, self.reason),
+            {'Date': request.time_service.strtime(),
+             'Content-Length': len(self.body)})
+
+    async def write_eof(self):
+        await self._writer.write_eof(self.body)
+
+
+class HTTPNotFound(Response, Exception):
+
+    def __init__(self):
+        Response.__init__(self, b'not found', 404, 'Not Found')
+
+
+async def handler(request):
+    request._match_info = {'name': request.path[1:]}
+    if request.path == '/missing':
+        raise HTTPNotFound()
+    if request.path == '/error':
+        raise ValueError('error')
+    if request.path == '/stream':
+        resp = Response(b'stream')
+        await resp.prepare(request)
+        return resp
+    return Response(request.match_info['name'].encode())
+
+
+def test_web_request_handler(loop):
+    errors = []
+    loop.set_exception_handler(lambda loop, ctx: errors.append(ctx))
+
+    cap = loop._http_capture(lambda: WebRequestHandler(handler, loop))
+    cap.feed_data(b'GET /hello HTTP/1.1\r\n\r\n'
+                  b'GET /missing HTTP/1.1\r\n\r\n'
+                  b'GET /stream HTTP/1.1\r\n\r\n')
+    run_briefly(loop)
+
+    assert len(cap.responses) == 3
+    for resp, status, body in zip(cap.responses,
+                                  (b'200 OK', b'404 Not Found', b'200 OK'),
+                                  (b'hello', b'not found', b'stream')):
+        head, payload = resp.split(b'\r\n\r\n', 1)
+        head = head.split(b'\r\n')
+        assert head[0] == b'HTTP/1.1 ' + status
+        assert head[1].startswith(b'Date: ') and head[1].endswith(b' GMT')
+        assert payload == body
+    assert cap.requests[0].match_info == {'name': 'hello'}
+    assert errors == []
+
+    # other errors close connection
+    cap = loop._http_capture(lambda: WebRequestHandler(handler, loop))
+    cap.feed_data(b'GET /error HTTP/1.1\r\n\r\n')
+    run_briefly(loop)
+    assert cap.responses == []
+    assert len(errors) == 1
+    assert isinstance(errors[0]['exception'], ValueError)
+
+
+def test_create_web_server(loop):
+    class App:
+        frozen = started = False
+
+        def freeze(self):
+            self.frozen = True
+
+        async def startup(self):
+            self.started = True
+
+        async def _handle(self, request):
+            return Response(b'app')
+
+    app = App()
+    srv = loop.run_until_complete(
+        create_web_server(loop, app, '127.0.0.1', 0, max_requests=1))
+    port = srv.sockets[0].getsockname()[1]
+    assert app.frozen and app.started
+
+    async def client():
+        reader, writer = await asyncio.open_connection(
+            '127.0.0.1', port, loop=loop)
+        writer.write(b'GET / HTTP/1.1\r\n\r\n')
+        data = await asyncio.wait_for(reader.read(), 5, loop=loop)
+        writer.close()
+        return data
+
+    data = loop.run_until_complete(client())
+    assert data.startswith(b'HTTP/1.1 200 OK\r\n')
+    assert data.endswith(b'\r\n\r\napp')
+
+    srv.close()
+
+
+def test_create_web_server_aiohttp(loop):
+    web = pytest.importorskip('aiohttp.web')
+
+    async def hello(request):
+        return web.Response(text='hello ' + request.match_info['name'])
+
+    app = web.Application()
+    app.router.add_get('/{name}', hello)
+    srv = loop.run_until_complete(
+        create_web_server(loop, app, '127.0.0.1', 0, max_requests=1))
+    port = srv.sockets[0].getsockname()[1]
+
+    async def client(path):
+        reader, writer = await asyncio.open_connection(
+            '127.0.0.1', port, loop=loop)
+        writer.write(b'GET ' + path + b' HTTP/1.1\r\nHost: localhost\r\n\r\n')
+        data = await asyncio.wait_for(reader.read(), 5, loop=loop)
+        writer.close()
+        return data
+
+    data = loop.run_until_complete(client(b'/world'))
+    head, body = data.split(b'\r\n\r\n', 1)
+    assert head.startswith(b'HTTP/1.1 200 OK\r\n')
+    assert b'Content-Type: text/plain; charset=utf-8' in head
+    assert body == b'hello world'
+
+    # unknown route is answered by router's HTTPNotFound
+    data = loop.run_until_complete(client(b'/a/b'))
+    assert data.startswith(b'HTTP/1.1 404 Not Found\r\n')
+
+    srv.close()
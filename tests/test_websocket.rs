@@ -0,0 +1,220 @@
+extern crate bytes;
+extern crate tokio_io;
+extern crate async_tokio;
+
+use bytes::{Bytes, BytesMut};
+use tokio_io::codec::{Decoder, Encoder};
+use async_tokio::http::websocket::{
+    Deflater, Error, Frame, Inflater, OpCode, PerMessageDeflate,
+    WebSocketCodec, accept_key, apply_mask};
+
+const MASK: [u8; 4] = [0x37, 0xfa, 0x21, 0x3d];
+
+// masked client frame
+fn frame(first: u8, payload: &[u8]) -> BytesMut {
+    let mut buf = BytesMut::new();
+    let len = payload.len();
+    if len < 126 {
+        buf.extend(&[first, 0x80 | len as u8]);
+    } else {
+        buf.extend(&[first, 0x80 | 126, (len >> 8) as u8, len as u8]);
+    }
+    buf.extend(&MASK);
+    let mut data = payload.to_vec();
+    apply_mask(&mut data, MASK);
+    buf.extend(&data);
+    buf
+}
+
+
+#[test]
+fn test_accept_key() {
+    // RFC 6455 section 1.3
+    assert_eq!(accept_key(b"dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+}
+
+#[test]
+fn test_decode_text() {
+    let mut codec = WebSocketCodec::new(1024);
+    let mut buf = frame(0x81, b"Hello");
+    buf.extend(b"rest");
+
+    let frame = codec.decode(&mut buf).unwrap().unwrap();
+    assert!(frame.fin);
+    assert_eq!(frame.opcode, OpCode::Text);
+    assert_eq!(frame.payload, Bytes::from(&b"Hello"[..]));
+    assert_eq!(buf[..], b"rest"[..]);
+}
+
+#[test]
+fn test_decode_partial() {
+    let mut codec = WebSocketCodec::new(1024);
+    let data = frame(0x82, &[1; 300]);
+
+    let mut buf = BytesMut::from(&data[..3]);
+    assert!(codec.decode(&mut buf).unwrap().is_none());
+    buf.extend(&data[3..100]);
+    assert!(codec.decode(&mut buf).unwrap().is_none());
+    buf.extend(&data[100..]);
+
+    let frame = codec.decode(&mut buf).unwrap().unwrap();
+    assert_eq!(frame.opcode, OpCode::Binary);
+    assert_eq!(frame.payload.len(), 300);
+    assert!(buf.is_empty());
+}
+
+#[test]
+fn test_decode_fragment() {
+    let mut codec = WebSocketCodec::new(1024);
+    let mut buf = frame(0x01, b"Hel");
+    buf.extend(&frame(0x80, b"lo"));
+
+    let first = codec.decode(&mut buf).unwrap().unwrap();
+    assert!(!first.fin);
+    assert_eq!(first.opcode, OpCode::Text);
+    let last = codec.decode(&mut buf).unwrap().unwrap();
+    assert!(last.fin);
+    assert_eq!(last.opcode, OpCode::Continue);
+    assert_eq!(last.payload, Bytes::from(&b"lo"[..]));
+}
+
+#[test]
+fn test_decode_errors() {
+    let mut codec = WebSocketCodec::new(4);
+
+    // not masked
+    let mut buf = BytesMut::from(&b"\x81\x02ok"[..]);
+    match codec.decode(&mut buf) {
+        Err(Error::Protocol(..)) => (),
+        res => panic!("Unexpected result: {:?}", res),
+    }
+
+    // reserved bits
+    let mut buf = frame(0xC1, b"ok");
+    match codec.decode(&mut buf) {
+        Err(Error::Protocol(..)) => (),
+        res => panic!("Unexpected result: {:?}", res),
+    }
+
+    // fragmented control frame
+    let mut buf = frame(0x09, b"ok");
+    match codec.decode(&mut buf) {
+        Err(Error::Protocol(..)) => (),
+        res => panic!("Unexpected result: {:?}", res),
+    }
+
+    // payload is larger than max size
+    let mut buf = frame(0x82, b"large");
+    match codec.decode(&mut buf) {
+        Err(Error::TooBig) => (),
+        res => panic!("Unexpected result: {:?}", res),
+    }
+}
+
+#[test]
+fn test_encode() {
+    let mut codec = WebSocketCodec::new(1024);
+    let mut buf = BytesMut::new();
+
+    codec.encode(Frame::new(OpCode::Text, Bytes::from(&b"Hello"[..])), &mut buf).unwrap();
+    assert_eq!(buf[..], b"\x81\x05Hello"[..]);
+
+    buf.clear();
+    codec.encode(Frame::new(OpCode::Binary, Bytes::from(vec![0; 256])), &mut buf).unwrap();
+    assert_eq!(buf[..4], b"\x82\x7e\x01\x00"[..]);
+    assert_eq!(buf.len(), 260);
+
+    buf.clear();
+    codec.encode(Frame::close(1000, "bye"), &mut buf).unwrap();
+    assert_eq!(buf[..], b"\x88\x05\x03\xe8bye"[..]);
+}
+
+#[test]
+fn test_close_status() {
+    assert_eq!(Frame::close(1001, "away").close_status().unwrap(),
+               (1001, "away".to_owned()));
+    assert_eq!(Frame::new(OpCode::Close, Bytes::new()).close_status().unwrap().0, 1005);
+    assert!(Frame::new(OpCode::Close, Bytes::from(&b"\x03"[..])).close_status().is_err());
+    assert!(Frame::close(1005, "").close_status().is_err());
+}
+
+#[test]
+fn test_decode_compressed() {
+    let mut codec = WebSocketCodec::with_deflate(1024);
+    let mut buf = frame(0xC1, b"ok");
+    assert!(codec.decode(&mut buf).unwrap().unwrap().compressed);
+
+    // RSV1 on control frame
+    let mut buf = frame(0xC9, b"ok");
+    match codec.decode(&mut buf) {
+        Err(Error::Protocol(..)) => (),
+        res => panic!("Unexpected result: {:?}", res),
+    }
+}
+
+#[test]
+fn test_negotiate_deflate() {
+    assert_eq!(PerMessageDeflate::negotiate("permessage-deflate"),
+               Some(PerMessageDeflate::default()));
+    assert_eq!(PerMessageDeflate::negotiate("x-webkit-deflate-frame"), None);
+
+    let deflate = PerMessageDeflate::negotiate(
+        "permessage-deflate; server_max_window_bits=20, \
+         permessage-deflate; client_no_context_takeover; server_max_window_bits=10").unwrap();
+    assert!(deflate.client_no_context_takeover);
+    assert_eq!(deflate.server_max_window_bits, Some(10));
+    assert_eq!(deflate.header(),
+               "permessage-deflate; client_no_context_takeover; server_max_window_bits=10");
+
+    assert_eq!(PerMessageDeflate::negotiate(
+        "permessage-deflate; server_no_context_takeover; server_no_context_takeover"), None);
+}
+
+#[test]
+fn test_inflate() {
+    // RFC 7692 section 7.2.3.2
+    let mut inflater = Inflater::new(true);
+    assert_eq!(inflater.inflate(b"\xf2\x48\xcd\xc9\xc9\x07\x00", 1024).unwrap(),
+               b"Hello".to_vec());
+    assert_eq!(inflater.inflate(b"\xf2\x00\x11\x00\x00", 1024).unwrap(),
+               b"Hello".to_vec());
+
+    match inflater.inflate(b"\xf2\x48\xcd\xc9\xc9\x07\x00", 4) {
+        Err(Error::TooBig) => (),
+        res => panic!("Unexpected result: {:?}", res),
+    }
+    match Inflater::new(false).inflate(b"\xff\xff", 1024) {
+        Err(Error::Protocol(..)) => (),
+        res => panic!("Unexpected result: {:?}", res),
+    }
+}
+
+#[test]
+fn test_deflate_roundtrip() {
+    let mut deflater = Deflater::new(15, true);
+    let mut inflater = Inflater::new(true);
+    let data = b"websocket message, websocket message, websocket message".to_vec();
+
+    let first = deflater.deflate(&data);
+    assert!(first.len() < data.len());
+    assert_eq!(inflater.inflate(&first, 1024).unwrap(), data);
+    // second message refers to previous one
+    let second = deflater.deflate(&data);
+    assert!(second.len() < first.len());
+    assert_eq!(inflater.inflate(&second, 1024).unwrap(), data);
+}
+
+#[test]
+fn test_deflate_sliding_window() {
+    // history is longer than window after few messages
+    for &takeover in &[true, false] {
+        let mut deflater = Deflater::new(9, takeover);
+        let mut inflater = Inflater::new(takeover);
+        for idx in 0..50 {
+            let data = format!("message {} of sliding window, message {}", idx, idx % 7)
+                .repeat(idx % 5 + 1).into_bytes();
+            let compressed = deflater.deflate(&data);
+            assert_eq!(inflater.inflate(&compressed, 4096).unwrap(), data);
+        }
+    }
+}
@@ -7,8 +7,18 @@ from asyncio.events import AbstractEventLoop
 from asyncio.unix_events import DefaultEventLoopPolicy
 
 from . import _tokio
-
-__all__ = ('new_event_loop', 'Loop', 'EventLoopPolicy')
+from ._tokio import HttpRequestParser, HttpResponseParser, WriteBuffer
+from ._tokio import ResponseHeaders
+from ._tokio import (HttpError, HttpParseError, PayloadError,
+                     ServerTimeoutError, WebSocketError)
+from .sharding import ShardedServer, start_sharded_server
+
+__all__ = ('new_event_loop', 'Loop', 'EventLoopPolicy',
+           'HttpRequestParser', 'HttpResponseParser', 'WriteBuffer',
+           'ResponseHeaders',
+           'HttpError', 'HttpParseError', 'PayloadError',
+           'ServerTimeoutError', 'WebSocketError',
+           'ShardedServer', 'start_sharded_server')
 
 
 class Loop(_tokio.TokioEventLoop, AbstractEventLoop):
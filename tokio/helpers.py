@@ -1,5 +1,7 @@
+import asyncio
 import reprlib
-from asyncio import events
+from asyncio import coroutines, events
+from asyncio.log import logger
 
 
 def _format_callbacks(cb):
@@ -48,3 +50,39 @@ def future_repr(name, future):
         info.append('created at %s:%s' % (frame[0], frame[1]))
 
     return '<%s %s>' % (name, ' '.join(info))
+
+
+def log_slow_task_step(task, coro, duration):
+    """helper function for slow task step warning"""
+    logger.warning('Executing step of %r (%s) took %.3f seconds',
+                   task, coroutines._format_coroutine(coro), duration)
+
+
+async def enter_services(loop, pending, active):
+    """helper function for loop.add_service(), enters services
+    in registration order"""
+    while pending:
+        service, timeout = pending.pop(0)
+        await service.__aenter__()
+        active.append((service, timeout))
+
+
+async def exit_services(loop, active):
+    """helper function for loop.close(), exits services in reverse
+    order, each __aexit__() call is limited by service timeout"""
+    while active:
+        service, timeout = active.pop()
+        try:
+            await asyncio.wait_for(
+                service.__aexit__(None, None, None), timeout, loop=loop)
+        except asyncio.TimeoutError:
+            loop.call_exception_handler({
+                'message': 'Timeout exiting service {!r}'.format(service),
+                'service': service,
+            })
+        except Exception as exc:
+            loop.call_exception_handler({
+                'message': 'Error exiting service {!r}'.format(service),
+                'exception': exc,
+                'service': service,
+            })
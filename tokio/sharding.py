@@ -0,0 +1,122 @@
+import asyncio
+import threading
+
+__all__ = ('ShardedServer', 'start_sharded_server')
+
+
+class _Shard:
+
+    def __init__(self, index, loop_factory):
+        self.index = index
+        self.loop = loop_factory()
+        self.server = None
+        self.thread = None
+
+    def start(self, protocol_factory, host, port, kwargs):
+        started = threading.Event()
+        result = {}
+
+        def run():
+            asyncio.set_event_loop(self.loop)
+            try:
+                self.server = self.loop.run_until_complete(
+                    self.loop.create_server(
+                        protocol_factory, host, port,
+                        reuse_port=True, **kwargs))
+            except BaseException as exc:
+                result['error'] = exc
+                started.set()
+                self.loop.close()
+                return
+
+            started.set()
+            try:
+                self.loop.run_forever()
+            finally:
+                self.loop.close()
+
+        self.thread = threading.Thread(
+            target=run, name='tokio-shard-{}'.format(self.index), daemon=True)
+        self.thread.start()
+        started.wait()
+
+        if 'error' in result:
+            self.thread.join()
+            raise result['error']
+
+    async def _shutdown(self, timeout):
+        self.server.close()
+        try:
+            await asyncio.wait_for(
+                self.server.wait_closed(), timeout, loop=self.loop)
+        except asyncio.TimeoutError:
+            pass
+        self.loop.stop()
+
+    def close(self, timeout):
+        if self.thread.is_alive():
+            self.loop.call_soon_threadsafe(
+                lambda: asyncio.ensure_future(
+                    self._shutdown(timeout), loop=self.loop))
+
+
+class ShardedServer:
+    """Same (host, port) served by several event loops, each one runs
+    in its own thread. Listening sockets are bound with SO_REUSEPORT,
+    so kernel balances accepted connections across shards."""
+
+    def __init__(self, shards):
+        self._shards = shards
+
+    @property
+    def sockets(self):
+        return [sock for shard in self._shards
+                for sock in shard.server.sockets]
+
+    @property
+    def loops(self):
+        return [shard.loop for shard in self._shards]
+
+    def close(self, timeout=None):
+        """Close all shards, each shard waits up to timeout seconds
+        for its connections before its loop is stopped. Blocks until
+        all shard threads are finished."""
+        for shard in self._shards:
+            shard.close(timeout)
+        for shard in self._shards:
+            shard.thread.join()
+
+    def __enter__(self):
+        return self
+
+    def __exit__(self, *exc):
+        self.close()
+
+
+def start_sharded_server(protocol_factory, host=None, port=None, *,
+                         shards, loop_factory=None, **kwargs):
+    """Start TCP server on `shards` event loops, see ShardedServer.
+
+    protocol_factory is called in thread of loop which accepted
+    connection. Other keyword arguments are passed to create_server().
+    Port 0 binds ephemeral port for first shard, rest of shards
+    share it."""
+    if shards < 1:
+        raise ValueError('shards must be positive')
+    if loop_factory is None:
+        from . import new_event_loop as loop_factory
+
+    started = []
+    try:
+        for index in range(shards):
+            shard = _Shard(index, loop_factory)
+            shard.start(protocol_factory, host, port, kwargs)
+            started.append(shard)
+
+            if not port:
+                port = shard.server.sockets[0].getsockname()[1]
+    except BaseException:
+        ShardedServer(started).close()
+        raise
+
+    return ShardedServer(started)
@@ -0,0 +1,338 @@
+import collections
+import collections.abc
+import inspect
+import ssl
+from asyncio import sslproto
+
+SESSION_CACHE_SIZE = 256
+SSL_SHUTDOWN_TIMEOUT = 30.0
+
+# asyncio implements shutdown timeout since python 3.11
+_NATIVE_SHUTDOWN_TIMEOUT = 'ssl_shutdown_timeout' in inspect.signature(
+    sslproto.SSLProtocol.__init__).parameters
+_NATIVE_HANDSHAKE_TIMEOUT = 'ssl_handshake_timeout' in inspect.signature(
+    sslproto.SSLProtocol.__init__).parameters
+
+
+class SSLProtocol(sslproto.SSLProtocol):
+    """asyncio SSL protocol with peer certificate details in extra info.
+
+    Adds ``peercert_der`` (certificate in DER form) and ``peercert_info``
+    (dict with ``subject``, ``issuer`` and ``san`` entries) keys. Parsed
+    fields are available only if certificate was validated.
+    ``peer_identity`` is common name (or first DNS name) of validated
+    peer certificate, e.g. client identity on mTLS server.
+    ``alpn_protocol`` is protocol selected with ALPN or None.
+    ``session_reused`` is True if client resumed cached TLS session.
+
+    Client connections created with ``session_key`` (host and port)
+    resume TLS session of previous connection to the same endpoint
+    made with the same ssl context.
+
+    close() sends close_notify and waits up to ``shutdown_timeout``
+    seconds for peer's close_notify, then connection is aborted.
+    Connection is aborted if handshake is not finished in
+    ``handshake_timeout`` seconds.
+    """
+
+    def __init__(self, loop, app_protocol, sslcontext, waiter,
+                 server_side=False, server_hostname=None,
+                 session_key=None, shutdown_timeout=None,
+                 handshake_timeout=None, **kwargs):
+        if shutdown_timeout is None:
+            shutdown_timeout = SSL_SHUTDOWN_TIMEOUT
+        self._shutdown_timeout = shutdown_timeout
+        self._shutdown_timer = None
+        if _NATIVE_SHUTDOWN_TIMEOUT:
+            kwargs['ssl_shutdown_timeout'] = shutdown_timeout
+        self._handshake_timeout = handshake_timeout
+        self._handshake_timer = None
+        if _NATIVE_HANDSHAKE_TIMEOUT and handshake_timeout is not None:
+            kwargs['ssl_handshake_timeout'] = handshake_timeout
+
+        self._session_key = None
+        self._session_context = sslcontext
+        if session_key is not None and isinstance(sslcontext, ssl.SSLContext):
+            self._session_key = session_key
+            session = sessions.get(session_key, sslcontext)
+            if session is not None:
+                sslcontext = _SessionContext(sslcontext, session)
+
+        super().__init__(loop, app_protocol, sslcontext, waiter,
+                         server_side, server_hostname, **kwargs)
+
+    def connection_made(self, transport):
+        super().connection_made(transport)
+        if not _NATIVE_HANDSHAKE_TIMEOUT and self._handshake_timeout is not None:
+            self._handshake_timer = self._loop.call_later(
+                self._handshake_timeout, self._on_handshake_timeout)
+
+    def _on_handshake_timeout(self):
+        self._handshake_timer = None
+        if self._transport is not None:
+            self._transport.abort()
+
+    def _on_handshake_complete(self, handshake_exc):
+        if self._handshake_timer is not None:
+            self._handshake_timer.cancel()
+            self._handshake_timer = None
+        super()._on_handshake_complete(handshake_exc)
+        self._save_session()
+
+    def connection_lost(self, exc):
+        if (self._server_side and self._waiter is not None and
+                not self._waiter.done()):
+            # peer went away before handshake is finished,
+            # server counts it as failed handshake
+            self._wakeup_waiter(exc or ConnectionResetError(
+                'Connection lost during TLS handshake'))
+        if self._handshake_timer is not None:
+            self._handshake_timer.cancel()
+            self._handshake_timer = None
+        if self._shutdown_timer is not None:
+            self._shutdown_timer.cancel()
+            self._shutdown_timer = None
+        # TLSv1.3 session tickets are received after handshake
+        self._save_session()
+        super().connection_lost(exc)
+
+    def _start_shutdown(self):
+        super()._start_shutdown()
+        if (not _NATIVE_SHUTDOWN_TIMEOUT and self._shutdown_timer is None and
+                self._transport is not None):
+            self._shutdown_timer = self._loop.call_later(
+                self._shutdown_timeout, self._on_shutdown_timeout)
+
+    def _on_shutdown_timeout(self):
+        # peer did not answer close_notify
+        self._shutdown_timer = None
+        if self._transport is not None:
+            self._transport.abort()
+
+    def _save_session(self):
+        if self._session_key is None:
+            return
+        sslobj = self._extra.get('ssl_object')
+        if sslobj is not None and sslobj.session is not None:
+            sessions.put(self._session_key, self._session_context, sslobj.session)
+
+    def _get_extra_info(self, name, default=None):
+        if name == 'peercert_der':
+            sslobj = self._extra.get('ssl_object')
+            if sslobj is None:
+                return default
+            return sslobj.getpeercert(binary_form=True)
+        elif name == 'peercert_info':
+            peercert = self._extra.get('peercert')
+            if peercert is None:
+                return default
+            return _cert_info(peercert)
+        elif name == 'alpn_protocol':
+            sslobj = self._extra.get('ssl_object')
+            if sslobj is None:
+                return default
+            return sslobj.selected_alpn_protocol()
+        elif name == 'peer_identity':
+            peercert = self._extra.get('peercert')
+            if not peercert:
+                return default
+            return _cert_identity(peercert, default)
+        elif name == 'session_reused':
+            sslobj = self._extra.get('ssl_object')
+            if sslobj is None:
+                return default
+            return sslobj.session_reused
+
+        return super()._get_extra_info(name, default)
+
+
+class SessionCache:
+    """LRU cache of client TLS sessions per (host, port).
+
+    Session can be resumed only with ssl context it was created with.
+    """
+
+    def __init__(self, maxsize=SESSION_CACHE_SIZE):
+        self.maxsize = maxsize
+        self._sessions = collections.OrderedDict()
+
+    def get(self, key, sslcontext):
+        entry = self._sessions.get(key)
+        if entry is None or entry[0] is not sslcontext:
+            return None
+        self._sessions.move_to_end(key)
+        return entry[1]
+
+    def put(self, key, sslcontext, session):
+        self._sessions[key] = (sslcontext, session)
+        self._sessions.move_to_end(key)
+        while len(self._sessions) > self.maxsize:
+            self._sessions.popitem(last=False)
+
+    def clear(self):
+        self._sessions.clear()
+
+    def __len__(self):
+        return len(self._sessions)
+
+
+sessions = SessionCache()
+
+
+class _SessionContext:
+    """SSLContext proxy, resumes session in wrap_bio()"""
+
+    def __init__(self, sslcontext, session):
+        self._sslcontext = sslcontext
+        self._session = session
+
+    def wrap_bio(self, incoming, outgoing, server_side=False,
+                 server_hostname=None, session=None):
+        return self._sslcontext.wrap_bio(
+            incoming, outgoing, server_side=server_side,
+            server_hostname=server_hostname, session=self._session)
+
+    def __getattr__(self, name):
+        return getattr(self._sslcontext, name)
+
+
+def _cert_info(peercert):
+    def names(rdns):
+        return {key: value for rdn in rdns for key, value in rdn}
+
+    return {
+        'subject': names(peercert.get('subject', ())),
+        'issuer': names(peercert.get('issuer', ())),
+        'san': list(peercert.get('subjectAltName', ())),
+    }
+
+
+def _cert_identity(peercert, default):
+    for rdn in peercert.get('subject', ()):
+        for key, value in rdn:
+            if key == 'commonName':
+                return value
+    for kind, value in peercert.get('subjectAltName', ()):
+        if kind == 'DNS':
+            return value
+    return default
+
+
+def configure_context(sslcontext, server_side,
+                      min_version=None, max_version=None, ciphers=None,
+                      certfile=None, keyfile=None, client_ca=None,
+                      alpn_protocols=None, sni=None):
+    """Apply TLS protocol version and cipher suite constraints.
+
+    ``sslcontext`` is modified in place. For client connections ``ssl=True``
+    is replaced with default context. Versions are ``ssl.TLSVersion`` members
+    or names like ``'TLSv1.2'``, ciphers use OpenSSL cipher list format.
+
+    ``certfile`` and ``keyfile`` load certificate chain presented to peer.
+    ``client_ca`` (server only) makes client certificate signed by given
+    CA bundle required. ``alpn_protocols`` is list of protocols
+    offered (client) or accepted (server) in preference order.
+
+    ``sni`` (server only) selects certificate by server name sent by
+    client. It is either mapping of host names (``'*.example.com'``
+    matches one label) to ``SSLContext``, certfile or ``(certfile,
+    keyfile)`` tuple, or callable receiving server name and returning
+    ``SSLContext`` or None. ``sslcontext`` certificate is used if
+    nothing matches. Contexts created for mapped certfiles get the same
+    version, cipher, ``client_ca`` and ``alpn_protocols`` options.
+    """
+    if not isinstance(sslcontext, ssl.SSLContext):
+        if server_side:
+            raise TypeError('SSLContext is required for server side ssl')
+        sslcontext = ssl.create_default_context()
+
+    if certfile is not None:
+        sslcontext.load_cert_chain(certfile, keyfile)
+    elif keyfile is not None:
+        raise ValueError('keyfile requires certfile')
+    if client_ca is not None:
+        if not server_side:
+            raise ValueError('client_ca is only meaningful for server')
+        sslcontext.load_verify_locations(client_ca)
+        sslcontext.verify_mode = ssl.CERT_REQUIRED
+    if alpn_protocols is not None:
+        if isinstance(alpn_protocols, str):
+            raise TypeError('alpn_protocols must be a list of strings')
+        sslcontext.set_alpn_protocols(list(alpn_protocols))
+    if sni is not None:
+        if not server_side:
+            raise ValueError('sni is only meaningful for server')
+        sslcontext.sni_callback = _sni_callback(
+            sni, min_version=min_version, max_version=max_version,
+            ciphers=ciphers, client_ca=client_ca,
+            alpn_protocols=alpn_protocols)
+
+    if min_version is not None:
+        sslcontext.minimum_version = _tls_version(min_version)
+    if max_version is not None:
+        sslcontext.maximum_version = _tls_version(max_version)
+    if ciphers is not None:
+        sslcontext.set_ciphers(ciphers)
+
+    return sslcontext
+
+
+def reload_context(sslcontext, config):
+    """Replace TLS configuration of running server for new handshakes.
+
+    ``config`` is either new ``SSLContext`` or dict of
+    ``configure_context()`` options, e.g. ``certfile``, ``keyfile``
+    and ``sni``, applied to current context in place. Established
+    connections keep certificate they were created with.
+    """
+    if isinstance(config, ssl.SSLContext):
+        return config
+    if not isinstance(config, collections.abc.Mapping):
+        raise TypeError('config must be SSLContext or dict')
+    return configure_context(sslcontext, True, **config)
+
+
+def _sni_callback(sni, **options):
+    if callable(sni):
+        select = sni
+    else:
+        contexts = {name.lower(): _server_context(value, options)
+                    for name, value in sni.items()}
+
+        def select(server_name):
+            sslcontext = contexts.get(server_name)
+            if sslcontext is None and '.' in server_name:
+                sslcontext = contexts.get('*.' + server_name.split('.', 1)[1])
+            return sslcontext
+
+    def callback(sslobj, server_name, sslcontext):
+        if server_name is None:
+            return None
+        selected = select(server_name.lower())
+        if selected is not None:
+            sslobj.context = selected
+        return None
+
+    return callback
+
+
+def _server_context(value, options):
+    if isinstance(value, ssl.SSLContext):
+        return value
+    if isinstance(value, str):
+        certfile, keyfile = value, None
+    else:
+        certfile, keyfile = value
+    return configure_context(
+        ssl.SSLContext(ssl.PROTOCOL_TLS_SERVER), True,
+        certfile=certfile, keyfile=keyfile, **options)
+
+
+def _tls_version(version):
+    if isinstance(version, str):
+        try:
+            return ssl.TLSVersion[version.replace('.', '_')]
+        except KeyError:
+            raise ValueError(
+                'Unknown TLS version: {!r}'.format(version)) from None
+    return ssl.TLSVersion(version)
@@ -0,0 +1,87 @@
+import time
+from email.utils import formatdate
+
+__all__ = ('WebRequestHandler', 'create_web_server')
+
+
+class _TimeService:
+    """Replacement of aiohttp's TimeService, requests of native
+    server do not have one. Date value is formatted once a second."""
+
+    def __init__(self, loop):
+        self._loop = loop
+        self._now = None
+        self._value = None
+
+    def time(self):
+        return self._loop.time()
+
+    def strtime(self):
+        now = int(time.time())
+        if now != self._now:
+            self._now = now
+            self._value = formatdate(now, usegmt=True)
+        return self._value
+
+
+class WebRequestHandler:
+    """Protocol for loop.create_http_server() which runs aiohttp.web style
+    handler, e.g. Application. Native requests are passed to handler as
+    is, they provide attributes aiohttp responses and router rely on.
+
+    Returned response is prepared and finished same way aiohttp's
+    RequestHandler does it. Raised exception which is a response
+    (HTTPException) is sent as response, other errors are logged and
+    connection is closed."""
+
+    def __init__(self, handler, loop, *, time_service=None):
+        self._handler = getattr(handler, '_handle', handler)
+        self._loop = loop
+        self._time_service = time_service or _TimeService(loop)
+        self.transport = None
+
+    def connection_made(self, transport):
+        self.transport = transport
+
+    def connection_lost(self, exc):
+        self.transport = None
+
+    def data_received(self, req):
+        # requests are dispatched to handle_request()
+        pass
+
+    async def handle_request(self, req):
+        req.time_service = self._time_service
+        try:
+            try:
+                resp = await self._handler(req)
+            except Exception as exc:
+                if not hasattr(exc, 'prepare'):
+                    raise
+                resp = exc
+
+            await resp.prepare(req)
+            await resp.write_eof()
+        except Exception as exc:
+            self._loop.call_exception_handler({
+                'message': 'Error handling request',
+                'exception': exc,
+                'protocol': self,
+            })
+            if self.transport is not None:
+                self.transport.close()
+
+
+async def create_web_server(loop, handler, host=None, port=None, **kwargs):
+    """Serve aiohttp Application or handler coroutine function with
+    loop's native http server. Application is frozen and started first.
+    Other keyword arguments are passed to create_http_server()."""
+    if hasattr(handler, 'freeze'):
+        handler.freeze()
+    if hasattr(handler, 'startup'):
+        await handler.startup()
+
+    time_service = _TimeService(loop)
+    return await loop.create_http_server(
+        lambda: WebRequestHandler(handler, loop, time_service=time_service),
+        host, port, **kwargs)